@@ -1,12 +1,11 @@
 ///! This module provides utilities to build Faust dsp files in order to use them
 ///! as foxlive's DSP.
-use std::collections::BTreeMap;
 use std::fs;
-use std::iter::FromIterator;
 use std::path::Path;
 use std::process::Command;
 
 use inflector::cases::classcase::to_class_case;
+use regex::{Captures,Regex};
 
 use super::utils::*;
 
@@ -45,6 +44,8 @@ impl FaustGenerator {
         let output = Command::new("faust").args(&[&self.source, "-lang", "rust"])
                         .output();
         match output {
+            Ok(ref output) if !output.status.success() =>
+                Err(format!("{}: {}", self.source, String::from_utf8_lossy(&output.stderr))),
             Ok(output) => String::from_utf8(output.stdout)
                               .map_err(|_| format!("can't get output code from utf8."))
                               .and_then(|stdout| self.render(stdout)),
@@ -55,10 +56,118 @@ impl FaustGenerator {
     /// Render rust source code into its final form.
     fn render(&self, source: String) -> Result<(), String> {
         let source = source.replace("mydsp", &self.struct_name);
+        let source = format!("{}\n{}\n{}", RENDER_HEADER, heap_arrays(&source), self.render_impl());
         fs::write(&self.dest, source).map_err(|_| format!("can't write into {}", self.dest))
     }
+
+    /// Render `FaustDsp` implementation for the generated struct.
+    fn render_impl(&self) -> String {
+        format!(r#"
+            impl Default for {name} {{
+                fn default() -> Self {{
+                    {name}::new()
+                }}
+            }}
+
+            impl FaustDsp for {name} {{
+                fn metadata(&mut self, m: &mut dyn Meta) {{
+                    {name}::metadata(self, m)
+                }}
+
+                fn num_inputs(&mut self) -> i32 {{
+                    self.getNumInputs()
+                }}
+
+                fn num_outputs(&mut self) -> i32 {{
+                    self.getNumOutputs()
+                }}
+
+                fn init(&mut self, sample_rate: i32) {{
+                    {name}::init(self, sample_rate)
+                }}
+
+                fn build_user_interface(&mut self, ui: &mut dyn UI<f32>) {{
+                    self.buildUserInterface(ui)
+                }}
+
+                fn compute(&mut self, count: i32, inputs: &[&[f32]], outputs: &mut [&mut [f32]]) {{
+                    {name}::compute(self, count, inputs, outputs)
+                }}
+            }}
+            "#, name = self.struct_name).replace("\n            ", "\n")
+    }
+}
+
+
+/// Header of generated DSP files
+const RENDER_HEADER: &str = r#"//! Generated from Faust code by `build/faust_generator.rs`: don't edit.
+#![allow(non_snake_case,non_camel_case_types,non_upper_case_globals,unused_mut,unused_parens,unused_variables,dead_code,bare_trait_objects,clippy::all)]
+use crate::dsp::faust::{FaustDsp,Meta,UI};
+"#;
+
+/// Arrays of this size or more are allocated on the heap.
+const HEAP_ARRAY_SIZE: usize = 1024;
+
+/// Move large arrays (such as delay lines) to the heap, replacing their type by a `Vec`.
+///
+/// Only struct fields and their initializers in `new()` are rewritten: `static` items
+/// (such as lookup tables) are declared with `=` and left as is.
+fn heap_arrays(source: &str) -> String {
+    let fields = Regex::new(r"(?m)^(\s*)(\w+): \[([^\[\];]+);\s*(\d+)\],").unwrap();
+    fields.replace_all(source, |caps: &Captures| {
+        let (indent, name, item) = (&caps[1], &caps[2], caps[3].trim());
+        match caps[4].parse::<usize>() {
+            // field's type is an identifier, initializer's value a literal
+            Ok(n) if n >= HEAP_ARRAY_SIZE => match item.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+                true => format!("{}{}: Vec<{}>,", indent, name, item),
+                false => format!("{}{}: vec![{};{}],", indent, name, item, n),
+            },
+            _ => caps[0].to_string(),
+        }
+    }).to_string()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Excerpt of Faust's rust output, with a static table and a delay line
+    const SOURCE: &str = "
+static mut ftbl0mydspSIG0: [f32;65536] = [0.0;65536];
+
+pub struct mydsp {
+	fRec0: [f32;2097152],
+	iVec0: [i32;2],
 }
 
+impl mydsp {
+	pub fn new() -> mydsp {
+		mydsp {
+			fRec0: [0.0;2097152],
+			iVec0: [0;2],
+		}
+	}
+	pub fn classInit(sample_rate: i32) {
+		let mut sig0: mydspSIG0 = newmydspSIG0();
+		sig0.fillmydspSIG0(65536, unsafe { &mut ftbl0mydspSIG0 });
+	}
+}
+";
+
+    /// Test: large fields and their initializers are moved to the heap, static tables
+    /// and small arrays are left untouched.
+    #[test]
+    fn heap_arrays_static_table() {
+        let source = heap_arrays(SOURCE);
+        assert!(source.contains("static mut ftbl0mydspSIG0: [f32;65536] = [0.0;65536];"));
+        assert!(source.contains("\tfRec0: Vec<f32>,"));
+        assert!(source.contains("\t\t\tfRec0: vec![0.0;2097152],"));
+        assert!(source.contains("\tiVec0: [i32;2],"));
+        assert!(source.contains("\t\t\tiVec0: [0;2],"));
+        assert!(source.contains("&mut ftbl0mydspSIG0"));
+    }
+}
 
 ///! Generates all Faust DSP of a given directory. The build process will try to
 ///! build all files and creates a `mod.rs` (overwrites existing file).
@@ -86,25 +195,30 @@ impl FaustGenerators {
             = (Vec::new(), Vec::new(), Vec::new());
 
         for generator in self.generators.iter() {
-            use_modules.push(format!("pub use {};", generator.name));
+            use_modules.push(format!("pub mod {};", generator.name));
             list_plugins.push(format!("\"{}\",", generator.name));
-            new_plugins.push(format!("\"{}\" => {}::{}::new(),",
+            new_plugins.push(format!("\"{}\" => Some(Box::new(FaustDSP::<{}::{},PS>::new())),",
                              generator.name, generator.name, generator.struct_name));
         }
 
-        let modules = self.generators.iter().map(|g| g.name.clone()).collect::<Vec<_>>();
-        let content = format!(r#"
-            use libfoxlive::dsp::DSP;
+        let content = format!(r#"//! Faust plugins generated from `.dsp` files of this directory by
+            //! `build/faust_generator.rs`: don't edit.
+            use crate::dsp::BoxedDSP;
+            use crate::dsp::faust::FaustDSP;
+            use crate::dsp::graph::ProcessScope;
 
             {}
 
-            pub fn list_plugins() -> Vec<String> {{
+            /// List available plugins
+            pub fn list_plugins() -> Vec<&'static str> {{
                 vec![{}]
             }}
 
-            pub fn new_plugin(name: &str) -> Option<Box<DSP>> {{
+            /// Instanciate plugin by name
+            pub fn new_plugin<PS: ProcessScope>(name: &str) -> Option<BoxedDSP<f32,PS>> {{
                 match name {{
                     {}
+                    _ => None,
                 }}
             }}
             "#,
@@ -112,15 +226,10 @@ impl FaustGenerators {
             list_plugins.join(" "),
             new_plugins.join("\n"),
         );
-        let content = content.replace("\n            ", "\n");
+        let content = content.replace("\n                    ", "\n        ")
+                             .replace("\n            ", "\n");
 
         let path = self.path.clone() + "mod.rs";
         fs::write(&path, content).map_err(|_| format!("can't write {}", path))
     }
 }
-
-
-// TODO:
-// - impl DSP for dsp struct
-// - impl Object for dsp struct
-
@@ -1,5 +1,7 @@
 extern crate bindgen;
 
+use std::env;
+
 mod bindings;
 mod faust_generator;
 mod utils;
@@ -17,5 +19,20 @@ fn main() {
 
     bindings::build("src/data/ffi.h", true);
     bindings::build("src/format/ffi.h", true);
+
+    if env::var("CARGO_FEATURE_WITH_LADSPA").is_ok() {
+        println!("cargo:rustc-link-lib=dl");
+        bindings::build("src/dsp/ladspa/ffi.h", true);
+    }
+
+    if env::var("CARGO_FEATURE_WITH_LV2").is_ok() {
+        println!("cargo:rustc-link-lib=lilv-0");
+        bindings::build("src/dsp/lv2/ffi.h", true);
+    }
+
+    if env::var("CARGO_FEATURE_WITH_LIBFAUST").is_ok() {
+        println!("cargo:rustc-link-lib=faust");
+        bindings::build("src/dsp/libfaust/ffi.h", true);
+    }
 }
 
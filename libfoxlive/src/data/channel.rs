@@ -120,8 +120,9 @@ macro_rules! ImplChannel {
 
         impl<'a,S: 'a> ExactSizeIterator for $name<'a,S> {
             fn len(&self) -> usize {
-                let offset = unsafe { self.end.offset_from(self.ptr.as_ptr() as *const S) };
-                offset as usize / self.step as usize
+                // last sample of a channel other than the first is less than a step before end
+                let offset = unsafe { self.end.offset_from(self.ptr.as_ptr() as *const S) }.max(0);
+                (offset as usize).div_ceil(self.step as usize)
             }
         }
 
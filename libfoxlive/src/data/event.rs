@@ -0,0 +1,156 @@
+//! Timestamped events (MIDI, control) processed along with audio blocks.
+use super::midi::{MidiEvent,MidiMessage};
+use super::sample::NFrames;
+use crate::rpc::ObjectIndex;
+
+
+/// Event's data
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum EventData {
+    Midi(MidiMessage),
+    /// Set an object's field to the provided value (converted to the field's type).
+    Control(ObjectIndex, f64),
+}
+
+
+/// Event timestamped relatively to the start of the processed block.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct Event {
+    pub time: NFrames,
+    pub data: EventData,
+}
+
+
+/// Events buffer for a processing block, kept ordered by time. Its capacity is allocated
+/// once: events pushed while it is full are dropped, so it can be used on the audio thread.
+pub struct EventBuffer {
+    events: Vec<Event>,
+}
+
+
+impl Event {
+    pub fn new(time: NFrames, data: EventData) -> Self {
+        Self { time, data }
+    }
+
+    /// Return MIDI message, if any
+    pub fn midi(&self) -> Option<&MidiMessage> {
+        match self.data {
+            EventData::Midi(ref message) => Some(message),
+            _ => None,
+        }
+    }
+}
+
+impl From<MidiEvent> for Event {
+    fn from(event: MidiEvent) -> Self {
+        Self { time: event.time, data: EventData::Midi(event.message) }
+    }
+}
+
+
+impl EventBuffer {
+    /// New buffer with capacity for `cap` events.
+    pub fn with_capacity(cap: usize) -> Self {
+        Self { events: Vec::with_capacity(cap) }
+    }
+
+    /// Maximum number of events
+    pub fn capacity(&self) -> usize {
+        self.events.capacity()
+    }
+
+    /// Number of events
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Remove all events
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+
+    /// Events as slice
+    pub fn as_slice(&self) -> &[Event] {
+        &self.events
+    }
+
+    /// Iterate over events
+    pub fn iter(&self) -> std::slice::Iter<Event> {
+        self.events.iter()
+    }
+
+    /// Insert an event after those of same or prior time. Return false if buffer is full.
+    pub fn push(&mut self, event: Event) -> bool {
+        if self.events.len() == self.events.capacity() {
+            return false;
+        }
+
+        let pos = self.events.iter().rposition(|e| e.time <= event.time)
+                             .map(|p| p + 1).unwrap_or(0);
+        self.events.insert(pos, event);
+        true
+    }
+
+    /// Merge events from other buffer in time order, events of same time coming after
+    /// self's. Events that don't fit in buffer's capacity are dropped.
+    pub fn merge(&mut self, other: &EventBuffer) {
+        let (n, take) = (self.events.len(),
+                         other.len().min(self.events.capacity() - self.events.len()));
+        if take == 0 {
+            return;
+        }
+
+        self.events.resize(n + take, other.events[0]);
+
+        // merge from the end, so there is no need of extra buffer
+        let (mut i, mut j, mut k) = (n, take, n + take);
+        while j > 0 {
+            if i > 0 && self.events[i-1].time > other.events[j-1].time {
+                self.events[k-1] = self.events[i-1];
+                i -= 1;
+            }
+            else {
+                self.events[k-1] = other.events[j-1];
+                j -= 1;
+            }
+            k -= 1;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn control(time: NFrames, value: f64) -> Event {
+        Event::new(time, EventData::Control(0, value))
+    }
+
+    /// Test: EventBuffer::push, EventBuffer::merge
+    #[test]
+    fn event_buffer_merge() {
+        let mut a = EventBuffer::with_capacity(6);
+        assert!(a.push(control(10, 0.0)));
+        assert!(a.push(control(0, 1.0)));
+        assert!(a.push(control(10, 2.0)));
+
+        let mut b = EventBuffer::with_capacity(4);
+        for (time, value) in [(0, 3.0), (5, 4.0), (10, 5.0), (20, 6.0)].iter() {
+            b.push(control(*time, *value));
+        }
+
+        a.merge(&b);
+        let values: Vec<_> = a.iter().map(|e| match e.data {
+            EventData::Control(_, v) => v,
+            _ => panic!("invalid event"),
+        }).collect();
+        assert_eq!(values, vec![1.0, 3.0, 4.0, 0.0, 2.0, 5.0]);
+        assert!(!a.push(control(0, 7.0)));
+    }
+}
@@ -0,0 +1,143 @@
+//! MIDI messages, events and sources.
+use std::collections::VecDeque;
+
+use super::sample::NFrames;
+
+
+/// MIDI channel message.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum MidiMessage {
+    NoteOff { channel: u8, note: u8, velocity: u8 },
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    PolyPressure { channel: u8, note: u8, pressure: u8 },
+    ControlChange { channel: u8, control: u8, value: u8 },
+    ProgramChange { channel: u8, program: u8 },
+    ChannelPressure { channel: u8, pressure: u8 },
+    /// Pitch bend value in `0..16384`, centered at `8192`
+    PitchBend { channel: u8, value: u16 },
+}
+
+
+/// MIDI message timestamped relatively to the start of the processed block.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct MidiEvent {
+    pub time: NFrames,
+    pub message: MidiMessage,
+}
+
+
+/// Provides MIDI events from an input (device, port, etc.).
+pub trait MidiSource {
+    /// Return next available event, if any.
+    fn next_event(&mut self) -> Option<MidiEvent>;
+}
+
+
+/// MIDI source whose events are pushed by hand.
+pub struct SyntheticSource {
+    events: VecDeque<MidiEvent>,
+}
+
+
+impl MidiMessage {
+    /// Parse message from raw bytes. Return `None` for system and incomplete messages.
+    /// Note on with a velocity of 0 are returned as note off.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let status = *bytes.first()?;
+        let channel = status & 0x0F;
+        let data = |i: usize| bytes.get(i).map(|b| b & 0x7F);
+
+        match status & 0xF0 {
+            0x80 => Some(MidiMessage::NoteOff { channel, note: data(1)?, velocity: data(2)? }),
+            0x90 => match data(2)? {
+                0 => Some(MidiMessage::NoteOff { channel, note: data(1)?, velocity: 0 }),
+                velocity => Some(MidiMessage::NoteOn { channel, note: data(1)?, velocity }),
+            },
+            0xA0 => Some(MidiMessage::PolyPressure { channel, note: data(1)?, pressure: data(2)? }),
+            0xB0 => Some(MidiMessage::ControlChange { channel, control: data(1)?, value: data(2)? }),
+            0xC0 => Some(MidiMessage::ProgramChange { channel, program: data(1)? }),
+            0xD0 => Some(MidiMessage::ChannelPressure { channel, pressure: data(1)? }),
+            0xE0 => Some(MidiMessage::PitchBend { channel,
+                                                  value: data(1)? as u16 | (data(2)? as u16) << 7 }),
+            _ => None,
+        }
+    }
+
+    /// Write message into the provided bytes, returning the number of written bytes.
+    pub fn to_bytes(&self, bytes: &mut [u8;3]) -> usize {
+        let (status, a, b, len) = match *self {
+            MidiMessage::NoteOff { channel, note, velocity } => (0x80 | channel, note, velocity, 3),
+            MidiMessage::NoteOn { channel, note, velocity } => (0x90 | channel, note, velocity, 3),
+            MidiMessage::PolyPressure { channel, note, pressure } => (0xA0 | channel, note, pressure, 3),
+            MidiMessage::ControlChange { channel, control, value } => (0xB0 | channel, control, value, 3),
+            MidiMessage::ProgramChange { channel, program } => (0xC0 | channel, program, 0, 2),
+            MidiMessage::ChannelPressure { channel, pressure } => (0xD0 | channel, pressure, 0, 2),
+            MidiMessage::PitchBend { channel, value } =>
+                (0xE0 | channel, (value & 0x7F) as u8, ((value >> 7) & 0x7F) as u8, 3),
+        };
+        bytes[0] = status;
+        bytes[1] = a;
+        bytes[2] = b;
+        len
+    }
+
+    /// Message's channel
+    pub fn channel(&self) -> u8 {
+        match *self {
+            MidiMessage::NoteOff { channel, .. } | MidiMessage::NoteOn { channel, .. } |
+            MidiMessage::PolyPressure { channel, .. } | MidiMessage::ControlChange { channel, .. } |
+            MidiMessage::ProgramChange { channel, .. } | MidiMessage::ChannelPressure { channel, .. } |
+            MidiMessage::PitchBend { channel, .. } => channel,
+        }
+    }
+}
+
+
+impl SyntheticSource {
+    pub fn new() -> Self {
+        Self { events: VecDeque::new() }
+    }
+
+    /// Push a message at the provided time
+    pub fn push(&mut self, time: NFrames, message: MidiMessage) {
+        self.events.push_back(MidiEvent { time, message });
+    }
+
+    /// Push a message from raw bytes, return false if message is not supported.
+    pub fn push_bytes(&mut self, time: NFrames, bytes: &[u8]) -> bool {
+        match MidiMessage::from_bytes(bytes) {
+            Some(message) => { self.push(time, message); true },
+            None => false,
+        }
+    }
+}
+
+impl MidiSource for SyntheticSource {
+    fn next_event(&mut self) -> Option<MidiEvent> {
+        self.events.pop_front()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test: MidiMessage::from_bytes, MidiMessage::to_bytes
+    #[test]
+    fn midi_message_bytes() {
+        assert_eq!(MidiMessage::from_bytes(&[0x91, 60, 100]),
+                   Some(MidiMessage::NoteOn { channel: 1, note: 60, velocity: 100 }));
+        assert_eq!(MidiMessage::from_bytes(&[0x90, 60, 0]),
+                   Some(MidiMessage::NoteOff { channel: 0, note: 60, velocity: 0 }));
+        assert_eq!(MidiMessage::from_bytes(&[0xE0, 0x00, 0x40]),
+                   Some(MidiMessage::PitchBend { channel: 0, value: 8192 }));
+        assert_eq!(MidiMessage::from_bytes(&[0xB0, 7]), None);
+        assert_eq!(MidiMessage::from_bytes(&[0xF8]), None);
+
+        let mut bytes = [0u8;3];
+        let message = MidiMessage::ControlChange { channel: 3, control: 7, value: 64 };
+        assert_eq!(message.to_bytes(&mut bytes), 3);
+        assert_eq!(MidiMessage::from_bytes(&bytes), Some(message));
+    }
+}
@@ -4,11 +4,15 @@ pub mod ffi;
 
 pub mod buffer;
 pub mod channel;
+pub mod event;
+pub mod midi;
 pub mod sample;
 pub mod time;
 
 pub use buffer::{BufferView,Buffer,SliceBuffer,VecBuffer};
 pub use channel::{ChannelLayout,NChannels};
+pub use event::{Event,EventData,EventBuffer};
+pub use midi::{MidiEvent,MidiMessage,MidiSource};
 pub use sample::{Sample,SampleFmt,SampleRate,NSamples,NFrames,IntoSampleFmt};
 pub use time::{Duration,TimeBase};
 
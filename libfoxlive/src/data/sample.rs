@@ -150,6 +150,23 @@ pub fn merge_samples<'a,S: 'a+Sample>(a: impl Iterator<Item=&'a mut S>, b: impl
     zip_map_samples(a, b, &|a: S, b: S| a.add_amp(b.to_signed_sample()))
 }
 
+/// Replace non-finite samples by equilibrium and flush denormals to zero. Return true if
+/// non-finite samples have been found.
+pub fn sanitize_samples<S: Sample>(a: SampleSliceMut<S>) -> bool {
+    let mut non_finite = false;
+    for s in a.iter_mut() {
+        let v: f64 = s.to_float_sample().to_sample();
+        if !v.is_finite() {
+            *s = S::equilibrium();
+            non_finite = true;
+        }
+        else if v != 0.0 && v.abs() < std::f32::MIN_POSITIVE as f64 {
+            *s = S::equilibrium();
+        }
+    }
+    non_finite
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -169,6 +186,17 @@ mod tests {
 
         assert_eq!(a, [2, 4, 6]);
     }
+
+    /// Test: sanitize_samples
+    #[test]
+    fn sanitize_samples() {
+        let mut a = [1.0f32, std::f32::NAN, 1e-40, std::f32::INFINITY];
+        assert!(super::sanitize_samples(&mut a));
+        assert_eq!(a, [1.0, 0.0, 0.0, 0.0]);
+
+        let mut a = [0.5f32, -0.5];
+        assert!(!super::sanitize_samples(&mut a));
+    }
 }
 
 
@@ -0,0 +1,386 @@
+//! Spectrum analyzer, publishing FFT frames to user interfaces.
+//!
+//! `Analyzer` is a pass-through DSP, whose input is copied into a ringbuffer. Spectra are
+//! computed off the audio thread by a worker, which publishes frames to subscribers through a
+//! broadcast stream and keeps a spectrogram history.
+//!
+//! Only whole frames are written to the ringbuffer, so that channels are never shifted.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let analyzer = Analyzer::<Scope>::new(2, 128);
+//! let mut frames = analyzer.subscribe();
+//! graph.add_child(source, analyzer.into());
+//!
+//! while let Ok(spectrum) = frames.recv() {
+//!     draw(&spectrum.channels[0]);
+//! }
+//! ```
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::thread;
+use std::time::Duration;
+
+use bus::{Bus,BusReader};
+use ringbuf::{Consumer,Producer,RingBuffer};
+
+use crate as libfoxlive;
+use libfoxlive_derive::object;
+use crate::data::*;
+use crate::rpc::*;
+
+use super::dsp::{DSP,DEFAULT_RATE};
+use super::fft::{Complex,Fft,Window};
+use super::graph::ProcessScope;
+
+
+/// Capacity of samples ringbuffer
+const RING_CAPACITY: usize = 1 << 16;
+/// Capacity of frames stream
+const STREAM_CAPACITY: usize = 16;
+/// Worker's sleep duration when no samples are available
+const IDLE: Duration = Duration::from_millis(5);
+
+
+/// Magnitude spectrum of a frame.
+#[derive(Clone,Debug)]
+pub struct Spectrum {
+    pub rate: SampleRate,
+    pub size: usize,
+    /// Magnitudes of bins `0..=size/2`, for each channel
+    pub channels: Vec<Vec<f32>>,
+}
+
+/// Analysis settings
+#[derive(Copy,Clone,Debug)]
+struct Settings {
+    rate: SampleRate,
+    size: usize,
+    overlap: f32,
+    window: Window,
+    averaging: f32,
+}
+
+/// State shared with the worker.
+struct Shared {
+    settings: Mutex<Settings>,
+    stream: Mutex<Bus<Arc<Spectrum>>>,
+    history: Mutex<VecDeque<Arc<Spectrum>>>,
+    history_len: usize,
+    stopped: AtomicBool,
+}
+
+/// Compute spectra from samples written by the analyzer.
+struct Worker {
+    shared: Arc<Shared>,
+    consumer: Consumer<f32>,
+    n_channels: usize,
+    settings: Settings,
+    fft: Fft,
+    window: Vec<f32>,
+    /// Samples not yet analyzed, per channel
+    fifos: Vec<Vec<f32>>,
+    averages: Vec<Vec<f32>>,
+    frame: Vec<Complex>,
+}
+
+
+/// Pass-through spectrum analyzer.
+#[object("analyzer")]
+pub struct Analyzer<PS>
+    where PS: ProcessScope
+{
+    /// FFT size, rounded to a power of two
+    #[field("size", I32(2048), range(64,32768,64), set(set_size))]
+    size: i32,
+    /// Overlap between two consecutive frames
+    #[field("overlap", F32(0.5), range(0.0,0.95,0.05), set(set_overlap))]
+    overlap: f32,
+    /// Window function: rectangular, hann, hamming, blackman
+    #[field("window", U8(1), range(0,3,1), set(set_window))]
+    window: u8,
+    /// Averaging of successive frames, as previous frame's weight
+    #[field("averaging", F32(0.5), range(0.0,0.99,0.01), set(set_averaging))]
+    averaging: f32,
+    n_channels: NChannels,
+    producer: Producer<f32>,
+    shared: Arc<Shared>,
+    phantom: PhantomData<PS>,
+}
+
+
+impl Spectrum {
+    /// Center frequency of the provided bin.
+    pub fn frequency(&self, bin: usize) -> f32 {
+        bin as f32 * self.rate as f32 / self.size as f32
+    }
+}
+
+
+impl Worker {
+    fn new(shared: Arc<Shared>, consumer: Consumer<f32>, n_channels: usize) -> Self {
+        let settings = *shared.settings.lock().unwrap();
+        let mut worker = Self {
+            shared, consumer, n_channels, settings,
+            fft: Fft::new(settings.size),
+            window: Vec::new(),
+            fifos: vec![Vec::new(); n_channels],
+            averages: Vec::new(),
+            frame: Vec::new(),
+        };
+        worker.configure(settings);
+        worker
+    }
+
+    /// Apply settings, resetting analysis state.
+    fn configure(&mut self, settings: Settings) {
+        if settings.size != self.fft.size() {
+            self.fft = Fft::new(settings.size);
+        }
+        self.window = settings.window.coefs(settings.size);
+        self.averages = vec![vec![0.0; settings.size / 2 + 1]; self.n_channels];
+        self.frame = vec![Complex::default(); settings.size];
+        self.settings = settings;
+    }
+
+    fn run(&mut self) {
+        let mut samples = vec![0.0f32; 4096 * self.n_channels];
+        // samples of an incomplete frame, kept at the start of `samples`
+        let mut pending = 0;
+        while !self.shared.stopped.load(Ordering::Relaxed) {
+            let settings = *self.shared.settings.lock().unwrap();
+            if settings.size != self.settings.size || settings.window != self.settings.window {
+                self.configure(settings);
+            }
+            self.settings = settings;
+
+            let count = self.consumer.pop_slice(&mut samples[pending..]);
+            if count == 0 {
+                thread::sleep(IDLE);
+                continue;
+            }
+
+            let count = pending + count;
+            let whole = count - count % self.n_channels;
+            for frame in samples[..whole].chunks(self.n_channels) {
+                for (fifo, sample) in self.fifos.iter_mut().zip(frame.iter()) {
+                    fifo.push(*sample);
+                }
+            }
+            samples.copy_within(whole..count, 0);
+            pending = count - whole;
+
+            let hop = ((settings.size as f32 * (1.0 - settings.overlap)) as usize).max(1);
+            while self.fifos[0].len() >= settings.size {
+                self.analyze();
+                for fifo in self.fifos.iter_mut() {
+                    fifo.drain(..hop.min(fifo.len()));
+                }
+            }
+        }
+    }
+
+    /// Analyze a frame and publish it.
+    fn analyze(&mut self) {
+        let Settings { rate, size, averaging, .. } = self.settings;
+        let scale = 2.0 / self.window.iter().sum::<f32>().max(std::f32::EPSILON);
+
+        for (fifo, average) in self.fifos.iter().zip(self.averages.iter_mut()) {
+            for ((value, sample), w) in self.frame.iter_mut().zip(fifo.iter()).zip(self.window.iter()) {
+                *value = Complex::new(sample * w, 0.0);
+            }
+            self.fft.forward(&mut self.frame);
+
+            for (avg, value) in average.iter_mut().zip(self.frame.iter()) {
+                *avg = *avg * averaging + value.norm() * scale * (1.0 - averaging);
+            }
+        }
+
+        let spectrum = Arc::new(Spectrum { rate, size, channels: self.averages.clone() });
+        self.shared.stream.lock().unwrap().try_broadcast(spectrum.clone()).ok();
+
+        let mut history = self.shared.history.lock().unwrap();
+        if history.len() >= self.shared.history_len {
+            history.pop_front();
+        }
+        history.push_back(spectrum);
+    }
+}
+
+
+impl<PS> Analyzer<PS>
+    where PS: ProcessScope
+{
+    /// Create analyzer for the provided number of channels, keeping `history` frames for
+    /// spectrogram.
+    pub fn new(n_channels: NChannels, history: usize) -> Self {
+        let settings = Settings { rate: DEFAULT_RATE, size: 2048, overlap: 0.5, window: Window::Hann,
+                                  averaging: 0.5 };
+        let shared = Arc::new(Shared {
+            settings: Mutex::new(settings),
+            stream: Mutex::new(Bus::new(STREAM_CAPACITY)),
+            history: Mutex::new(VecDeque::with_capacity(history)),
+            history_len: history,
+            stopped: AtomicBool::new(false),
+        });
+
+        let n_channels = n_channels.max(1);
+        let (producer, consumer) = RingBuffer::new(RING_CAPACITY).split();
+        let mut worker = Worker::new(shared.clone(), consumer, n_channels as usize);
+        thread::spawn(move || worker.run());
+
+        Self {
+            size: 2048, overlap: 0.5, window: 1, averaging: 0.5,
+            n_channels, producer, shared,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Subscribe to published spectra.
+    pub fn subscribe(&self) -> BusReader<Arc<Spectrum>> {
+        self.shared.stream.lock().unwrap().add_rx()
+    }
+
+    /// Return spectrogram history, from the oldest frame.
+    pub fn spectrogram(&self) -> Vec<Arc<Spectrum>> {
+        self.shared.history.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn set_size(&mut self, size: i32) -> Result<i32, ()> {
+        let size = (size.max(64).min(32768) as usize).next_power_of_two();
+        self.shared.settings.lock().unwrap().size = size;
+        self.size = size as i32;
+        Ok(self.size)
+    }
+
+    pub fn set_overlap(&mut self, overlap: f32) -> Result<f32, ()> {
+        self.overlap = overlap.max(0.0).min(0.95);
+        self.shared.settings.lock().unwrap().overlap = self.overlap;
+        Ok(self.overlap)
+    }
+
+    pub fn set_window(&mut self, window: u8) -> Result<u8, ()> {
+        let w = Window::from_index(window as usize).ok_or(())?;
+        self.shared.settings.lock().unwrap().window = w;
+        self.window = window;
+        Ok(window)
+    }
+
+    pub fn set_averaging(&mut self, averaging: f32) -> Result<f32, ()> {
+        self.averaging = averaging.max(0.0).min(0.99);
+        self.shared.settings.lock().unwrap().averaging = self.averaging;
+        Ok(self.averaging)
+    }
+}
+
+impl<PS> Drop for Analyzer<PS>
+    where PS: ProcessScope
+{
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+
+impl<PS> DSP for Analyzer<PS>
+    where PS: ProcessScope
+{
+    type Sample = f32;
+    type Scope = PS;
+
+    fn prepare(&mut self, rate: SampleRate) {
+        self.shared.settings.lock().unwrap().rate = rate;
+    }
+
+    fn process_audio(&mut self, scope: &Self::Scope, input: Option<&dyn BufferView<Sample=Self::Sample>>,
+                     output: Option<&mut dyn BufferView<Sample=Self::Sample>>) -> usize
+    {
+        let input = match input {
+            Some(input) => input,
+            None => return 0,
+        };
+        let n_samples = scope.n_samples().min(input.n_samples());
+
+        // frames are dropped when worker is late, but never partially written
+        let n_frames = n_samples.min(self.producer.remaining() / self.n_channels as usize);
+        if input.interleaved() && input.n_channels() == self.n_channels {
+            self.producer.push_slice(&input.as_slice()[..n_frames * self.n_channels as usize]);
+        }
+        else {
+            for i in 0..n_frames {
+                for channel in 0..self.n_channels {
+                    let sample = input.channel(channel).map(|c| c[i]).unwrap_or(0.0);
+                    self.producer.push(sample).ok();
+                }
+            }
+        }
+
+        match output {
+            Some(output) => {
+                let mut n = 0;
+                for channel in 0..self.n_channels {
+                    if let (Some(src), Some(dst)) = (input.channel(channel), output.channel_mut(channel)) {
+                        for (dst, src) in dst.zip(src).take(n_samples) {
+                            *dst = *src;
+                            n += 1;
+                        }
+                    }
+                }
+                n
+            },
+            None => 0,
+        }
+    }
+
+    fn n_channels(&self) -> NChannels {
+        self.n_channels
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+    use super::*;
+    use super::super::block::BlockScope;
+
+    /// Return index of the highest bin
+    fn peak(bins: &[f32]) -> usize {
+        bins.iter().enumerate().fold((0, 0.0), |max, (i, v)| if *v > max.1 { (i, *v) } else { max }).0
+    }
+
+    /// Test: sines peak in the expected bin of their channel, with interleaved and planar input
+    #[test]
+    fn peak_bin() {
+        for interleaved in [true, false].iter() {
+            let mut analyzer = Analyzer::<BlockScope>::new(2, 4);
+            analyzer.set_size(256).unwrap();
+            analyzer.prepare(48000);
+            let mut frames = analyzer.subscribe();
+
+            // channels' sines at bins 8 and 32, in blocks of an odd size
+            let (n_samples, bins) = (100, [8.0, 32.0]);
+            let mut time = 0;
+            for _ in 0..20 {
+                let mut input: Buffer<f32,Vec<f32>> = (*interleaved, 2, vec![0.0; n_samples * 2]).into();
+                for (channel, bin) in bins.iter().enumerate() {
+                    let samples = input.channel_mut(channel as NChannels).unwrap();
+                    for (i, sample) in samples.enumerate() {
+                        *sample = (2.0 * PI * bin * (time + i) as f32 / 256.0).sin();
+                    }
+                }
+                analyzer.process_audio(&BlockScope::new(n_samples, time as NFrames, None), Some(&input), None);
+                time += n_samples;
+            }
+
+            let spectrum = frames.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!((spectrum.size, spectrum.rate), (256, 48000));
+            assert_eq!(peak(&spectrum.channels[0]), 8);
+            assert_eq!(peak(&spectrum.channels[1]), 32);
+            assert_eq!(spectrum.frequency(8), 1500.0);
+        }
+    }
+}
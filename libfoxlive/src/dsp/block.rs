@@ -0,0 +1,288 @@
+//! Run DSP on blocks of constant size, whatever the size provided by the driver.
+//!
+//! `FixedBlock` buffers its input until a full block is available, which is then processed by
+//! the inner DSP. Output is delayed by the block size, which is reported as latency. Inner DSP
+//! run with a `BlockScope`, whose `n_samples` is always the block size.
+//!
+//! Events are not forwarded to the inner DSP.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let reverb = Convolution::<BlockScope>::new(256, Partitioning::Uniform);
+//! graph.add_child(source, FixedBlock::<_,Scope>::new(reverb, 256).into());
+//! ```
+use std::marker::PhantomData;
+
+use crate::data::*;
+use crate::rpc::*;
+use super::dsp::DSP;
+use super::graph::ProcessScope;
+use super::transport::Transport;
+
+
+/// Scope passed to DSP wrapped in a `FixedBlock` or an `Oversampling`.
+#[derive(Copy,Clone)]
+pub struct BlockScope {
+    n_samples: NSamples,
+    last_frame_time: NFrames,
+    transport: Option<Transport>,
+}
+
+
+/// Wrapper processing inner DSP on blocks of a fixed number of frames.
+pub struct FixedBlock<D,PS>
+    where D: DSP<Scope=BlockScope>, PS: ProcessScope
+{
+    dsp: D,
+    block: NSamples,
+    n_channels: NChannels,
+    /// Input block being filled
+    input: Buffer<D::Sample,Vec<D::Sample>>,
+    /// Last processed block, being output
+    output: Buffer<D::Sample,Vec<D::Sample>>,
+    /// Number of frames in current block
+    fill: NSamples,
+    phantom: PhantomData<PS>,
+}
+
+unsafe impl<D,PS> Sync for FixedBlock<D,PS>
+    where D: DSP<Scope=BlockScope>+Sync, PS: ProcessScope
+{}
+
+
+impl BlockScope {
+    pub(crate) fn new(n_samples: NSamples, last_frame_time: NFrames, transport: Option<Transport>) -> Self {
+        Self { n_samples, last_frame_time, transport }
+    }
+}
+
+impl ProcessScope for BlockScope {
+    fn n_samples(&self) -> NSamples {
+        self.n_samples
+    }
+
+    fn last_frame_time(&self) -> NFrames {
+        self.last_frame_time
+    }
+
+    fn transport(&self) -> Option<Transport> {
+        self.transport
+    }
+}
+
+
+impl<D,PS> FixedBlock<D,PS>
+    where D: DSP<Scope=BlockScope>, PS: ProcessScope
+{
+    /// Wrap DSP processing blocks of `block` frames.
+    pub fn new(dsp: D, block: NSamples) -> Self {
+        let mut dsp = dsp;
+        dsp.set_max_samples(block);
+        let n_channels = dsp.n_channels().max(1);
+        let mut input = Buffer::with_capacity(true, n_channels, block);
+        let mut output = Buffer::with_capacity(true, n_channels, block);
+        input.resize(n_channels, block);
+        output.resize(n_channels, block);
+        input.fill(D::Sample::equilibrium());
+        output.fill(D::Sample::equilibrium());
+
+        Self { dsp, block, n_channels, input, output, fill: 0, phantom: PhantomData }
+    }
+
+    /// Inner DSP
+    pub fn dsp(&self) -> &D {
+        &self.dsp
+    }
+
+    /// Mutable inner DSP
+    pub fn dsp_mut(&mut self) -> &mut D {
+        &mut self.dsp
+    }
+
+    /// Block size
+    pub fn block(&self) -> NSamples {
+        self.block
+    }
+
+    /// Process full input block into output block.
+    fn process_block(&mut self, scope: &PS, end: NSamples) {
+        // block started `block` frames before the end of the current frame
+        let scope = BlockScope::new(self.block,
+                                    scope.last_frame_time().wrapping_add(end as NFrames)
+                                                           .wrapping_sub(self.block as NFrames),
+                                    scope.transport());
+
+        self.output.fill(D::Sample::equilibrium());
+        match (self.dsp.is_source(), self.dsp.is_sink()) {
+            (true, _) => self.dsp.process_audio(&scope, None, Some(&mut self.output)),
+            (false, true) => self.dsp.process_audio(&scope, Some(&self.input), None),
+            (false, false) => self.dsp.process_audio(&scope, Some(&self.input), Some(&mut self.output)),
+        };
+    }
+}
+
+
+impl<D,PS> Object for FixedBlock<D,PS>
+    where D: DSP<Scope=BlockScope>, PS: ProcessScope
+{
+    fn object_meta(&self) -> ObjectMeta {
+        self.dsp.object_meta()
+    }
+
+    fn get_value(&self, index: ObjectIndex) -> Option<Value> {
+        self.dsp.get_value(index)
+    }
+
+    fn set_value(&mut self, index: ObjectIndex, value: Value) -> Result<Value, ()> {
+        self.dsp.set_value(index, value)
+    }
+
+    fn map_object(&self, mapper: &mut dyn ObjectMapper) {
+        self.dsp.map_object(mapper)
+    }
+}
+
+
+impl<D,PS> DSP for FixedBlock<D,PS>
+    where D: DSP<Scope=BlockScope>, PS: ProcessScope
+{
+    type Sample = D::Sample;
+    type Scope = PS;
+
+    fn prepare(&mut self, rate: SampleRate) {
+        self.dsp.prepare(rate);
+        self.input.fill(D::Sample::equilibrium());
+        self.output.fill(D::Sample::equilibrium());
+        self.fill = 0;
+    }
+
+    fn set_max_samples(&mut self, _max_samples: NSamples) {
+        // inner DSP only processes blocks of constant size, whatever the driver's
+        self.dsp.set_max_samples(self.block);
+    }
+
+    fn process_audio(&mut self, scope: &Self::Scope, input: Option<&dyn BufferView<Sample=Self::Sample>>,
+                     output: Option<&mut dyn BufferView<Sample=Self::Sample>>) -> usize
+    {
+        let n_samples = scope.n_samples();
+        let mut output = output;
+        let mut pos = 0;
+
+        while pos < n_samples {
+            let count = (self.block - self.fill).min(n_samples - pos);
+
+            // exchange `count` frames with current block
+            for channel in 0..self.n_channels {
+                if let (Some(src), Some(dst)) = (input.and_then(|i| i.channel(channel)), self.input.channel_mut(channel)) {
+                    for (dst, src) in dst.skip(self.fill).zip(src.skip(pos)).take(count) {
+                        *dst = *src;
+                    }
+                }
+                if let Some(src) = self.output.channel(channel) {
+                    if let Some(dst) = output.as_mut().and_then(|o| o.channel_mut(channel)) {
+                        for (dst, src) in dst.skip(pos).zip(src.skip(self.fill)).take(count) {
+                            *dst = *src;
+                        }
+                    }
+                }
+            }
+
+            self.fill += count;
+            pos += count;
+            if self.fill == self.block {
+                self.process_block(scope, pos);
+                self.fill = 0;
+            }
+        }
+
+        match output.is_some() && !self.dsp.is_sink() {
+            true => n_samples * self.n_channels as usize,
+            false => 0,
+        }
+    }
+
+    fn n_channels(&self) -> NChannels {
+        self.dsp.n_channels()
+    }
+
+    fn is_sink(&self) -> bool {
+        self.dsp.is_sink()
+    }
+
+    fn is_source(&self) -> bool {
+        self.dsp.is_source()
+    }
+
+    fn latency(&self) -> NSamples {
+        self.block + self.dsp.latency()
+    }
+
+    fn wet(&self) -> <Self::Sample as Sample>::Float {
+        self.dsp.wet()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate as libfoxlive;
+    use libfoxlive_derive::object;
+
+    /// Copy input to output, checking blocks' size
+    #[object("identity")]
+    struct Identity {
+        max_samples: NSamples,
+        latency: NSamples,
+    }
+
+    impl DSP for Identity {
+        type Sample = f32;
+        type Scope = BlockScope;
+
+        fn set_max_samples(&mut self, max_samples: NSamples) {
+            self.max_samples = max_samples;
+        }
+
+        fn process_audio(&mut self, scope: &Self::Scope, input: Option<&dyn BufferView<Sample=f32>>,
+                         output: Option<&mut dyn BufferView<Sample=f32>>) -> usize
+        {
+            assert_eq!(scope.n_samples(), self.max_samples);
+            let (input, output) = (input.unwrap().as_slice(), output.unwrap().as_slice_mut());
+            assert_eq!(input.len(), scope.n_samples());
+            output.copy_from_slice(input);
+            output.len()
+        }
+
+        fn n_channels(&self) -> NChannels { 1 }
+
+        fn latency(&self) -> NSamples { self.latency }
+    }
+
+    /// Test: blocks that are not a multiple of the driver's are output delayed by block size
+    #[test]
+    fn blocks() {
+        let mut dsp = FixedBlock::<_,BlockScope>::new(Identity { max_samples: 0, latency: 0 }, 3);
+        dsp.set_max_samples(4);
+        assert_eq!(dsp.dsp().max_samples, 3);
+
+        let mut output = Vec::new();
+        for block in 0..5 {
+            let input: Buffer<f32,Vec<f32>> = (true, 1, (0..4).map(|i| (block * 4 + i + 1) as f32).collect()).into();
+            let mut buffer: Buffer<f32,Vec<f32>> = (true, 1, vec![0.0; 4]).into();
+            assert_eq!(dsp.process_audio(&BlockScope::new(4, block * 4, None), Some(&input), Some(&mut buffer)), 4);
+            output.extend(buffer.buffer);
+        }
+
+        let expected: Vec<f32> = (0..20).map(|i| (i as f32 - 2.0).max(0.0)).collect();
+        assert_eq!(output, expected);
+    }
+
+    /// Test: latency is block size plus inner DSP's latency
+    #[test]
+    fn latency() {
+        let dsp = FixedBlock::<_,BlockScope>::new(Identity { max_samples: 0, latency: 5 }, 64);
+        assert_eq!(dsp.latency(), 69);
+    }
+}
@@ -0,0 +1,502 @@
+//! Serial chain of DSPs processed in place.
+//!
+//! A `Chain` runs an ordered list of DSPs (slots) on the same block, each slot's output being
+//! the next slot's input. Audio ping-pongs between two buffers owned by the chain, instead of
+//! going through the graph's buffer arena and input merging for each of them. Slots can be
+//! bypassed and reordered.
+//!
+//! Slots' fields are exposed by the chain's object, as `slot << SLOT_SHIFT | field`. Each slot
+//! also has a `bypass` field at `slot << SLOT_SHIFT | BYPASS_FIELD`. Indices of a slot's fields
+//! change when it is moved.
+//!
+//! Graph builds chains for linear runs of nodes when fusion is enabled (see `Graph::set_fuse`).
+//!
+//! # Example
+//!
+//! ```
+//! use libfoxlive::dsp::{BoxedDSP,Graph};
+//! use libfoxlive::dsp::block::BlockScope;
+//! use libfoxlive::dsp::chain::Chain;
+//! use libfoxlive::dsp::graph::NodeIndex;
+//!
+//! fn add_effects(graph: &mut Graph<f32,BlockScope>, source: NodeIndex,
+//!                eq: BoxedDSP<f32,BlockScope>, compressor: BoxedDSP<f32,BlockScope>) -> NodeIndex
+//! {
+//!     let mut chain = Chain::new(2);
+//!     chain.push(eq);
+//!     chain.push(compressor);
+//!     chain.move_slot(1, 0);
+//!     graph.add_child(source, chain.into())
+//! }
+//! ```
+use crate::data::*;
+use crate::data::buffer::zip_map;
+use crate::data::sample::fill_samples;
+use crate::rpc::*;
+
+use super::dsp::{DSP,BoxedDSP};
+use super::graph::{ProcessScope,EVENTS_CAPACITY,MAX_SAMPLES};
+
+
+/// Number of bits of a slot field index
+pub const SLOT_SHIFT: u32 = 16;
+/// Index of slots' bypass field
+pub const BYPASS_FIELD: ObjectIndex = (1 << SLOT_SHIFT) - 1;
+
+
+/// Chain's slot
+pub struct Slot<S,PS>
+    where S: 'static+Sample, PS: ProcessScope
+{
+    pub dsp: BoxedDSP<S,PS>,
+    /// Slot is skipped
+    pub bypass: bool,
+}
+
+/// Serial chain of DSPs.
+pub struct Chain<S,PS>
+    where S: 'static+Sample, PS: ProcessScope
+{
+    slots: Vec<Slot<S,PS>>,
+    n_channels: NChannels,
+    rate: SampleRate,
+    max_samples: NSamples,
+    /// Number of channels buffers are allocated for
+    capacity: NChannels,
+    /// Ping-pong buffers, allocated by `set_max_samples`
+    buffers: [Buffer<S,Vec<S>>; 2],
+    /// Ping-pong event buffers
+    events: [EventBuffer; 2],
+}
+
+
+impl<S,PS> Chain<S,PS>
+    where S: 'static+Sample, PS: ProcessScope
+{
+    /// Create an empty chain for the provided number of channels.
+    pub fn new(n_channels: NChannels) -> Self {
+        let mut chain = Self {
+            slots: Vec::new(),
+            n_channels,
+            rate: 0,
+            max_samples: MAX_SAMPLES,
+            capacity: 0,
+            buffers: [Buffer::with_capacity(true, n_channels.max(1), MAX_SAMPLES),
+                      Buffer::with_capacity(true, n_channels.max(1), MAX_SAMPLES)],
+            events: [EventBuffer::with_capacity(0), EventBuffer::with_capacity(0)],
+        };
+        chain.allocate_buffers();
+        chain
+    }
+
+    /// Create an empty chain with capacity for `slots` DSPs of up to `n_channels` channels,
+    /// processed at the provided sample rate and maximum samples. Its buffers are allocated, so
+    /// that DSPs can be appended without allocating (see `append`).
+    pub fn with_capacity(n_channels: NChannels, slots: usize, rate: SampleRate, max_samples: NSamples) -> Self {
+        let n_channels = n_channels.max(1);
+        Self {
+            slots: Vec::with_capacity(slots),
+            n_channels: 0,
+            rate,
+            max_samples,
+            capacity: n_channels,
+            buffers: [Buffer::with_capacity(true, n_channels, max_samples),
+                      Buffer::with_capacity(true, n_channels, max_samples)],
+            events: [EventBuffer::with_capacity(EVENTS_CAPACITY), EventBuffer::with_capacity(EVENTS_CAPACITY)],
+        }
+    }
+
+    /// Return true if `slots` DSPs of `n_channels` channels, prepared for the provided sample
+    /// rate and maximum samples, can be appended without allocating.
+    pub fn fits(&self, n_channels: NChannels, slots: usize, rate: SampleRate, max_samples: NSamples) -> bool {
+        self.slots.capacity() - self.slots.len() >= slots && n_channels <= self.capacity &&
+        self.rate == rate && self.max_samples == max_samples &&
+        self.events.iter().all(|events| events.capacity() > 0)
+    }
+
+    /// Slots in processing order
+    pub fn slots(&self) -> &[Slot<S,PS>] {
+        &self.slots
+    }
+
+    pub fn slot_mut(&mut self, slot: usize) -> Option<&mut Slot<S,PS>> {
+        self.slots.get_mut(slot)
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Append DSP at the end of the chain.
+    pub fn push(&mut self, dsp: BoxedDSP<S,PS>) {
+        let at = self.slots.len();
+        self.insert(at, dsp);
+    }
+
+    /// Insert DSP at the provided position (clamped to chain's length).
+    pub fn insert(&mut self, at: usize, dsp: BoxedDSP<S,PS>) {
+        let mut dsp = dsp;
+        if self.rate > 0 {
+            dsp.prepare(self.rate);
+        }
+        dsp.set_max_samples(self.max_samples);
+
+        self.n_channels = self.n_channels.max(dsp.n_channels());
+        if dsp.has_event_input() || dsp.has_event_output() {
+            for events in self.events.iter_mut().filter(|e| e.capacity() == 0) {
+                *events = EventBuffer::with_capacity(EVENTS_CAPACITY);
+            }
+        }
+
+        let at = at.min(self.slots.len());
+        self.slots.insert(at, Slot { dsp, bypass: false });
+        self.allocate_buffers();
+    }
+
+    /// Append a DSP already prepared for chain's sample rate and maximum samples. Unlike
+    /// `push`, it doesn't allocate as long as it fits chain's capacity (see `fits`).
+    pub fn append(&mut self, dsp: BoxedDSP<S,PS>) {
+        self.n_channels = self.n_channels.max(dsp.n_channels());
+        self.slots.push(Slot { dsp, bypass: false });
+    }
+
+    /// Remove slot, returning its DSP.
+    pub fn remove(&mut self, slot: usize) -> Option<BoxedDSP<S,PS>> {
+        match slot < self.slots.len() {
+            true => Some(self.slots.remove(slot).dsp),
+            false => None,
+        }
+    }
+
+    /// Move slot to another position. Return false if any of them is out of bounds.
+    pub fn move_slot(&mut self, from: usize, to: usize) -> bool {
+        if from >= self.slots.len() || to >= self.slots.len() {
+            return false;
+        }
+        let slot = self.slots.remove(from);
+        self.slots.insert(to, slot);
+        true
+    }
+
+    /// Bypass or re-enable a slot. Return false if there is no such slot.
+    pub fn set_bypass(&mut self, slot: usize, bypass: bool) -> bool {
+        match self.slots.get_mut(slot) {
+            Some(slot) => {
+                slot.bypass = bypass;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Return DSPs, consuming the chain.
+    pub fn into_dsps(self) -> Vec<BoxedDSP<S,PS>> {
+        self.slots.into_iter().map(|slot| slot.dsp).collect()
+    }
+
+    /// Allocate ping-pong buffers for the current number of channels and maximum samples.
+    fn allocate_buffers(&mut self) {
+        for buffer in self.buffers.iter_mut() {
+            buffer.resize(self.n_channels.max(1), self.max_samples);
+        }
+        self.capacity = self.n_channels.max(1);
+    }
+
+    /// Split object index into slot and field index.
+    fn split_index(index: ObjectIndex) -> (usize, ObjectIndex) {
+        ((index >> SLOT_SHIFT) as usize, index & BYPASS_FIELD)
+    }
+}
+
+
+impl<S,PS> Object for Chain<S,PS>
+    where S: 'static+Sample, PS: ProcessScope
+{
+    fn object_meta(&self) -> ObjectMeta {
+        ObjectMeta::new("chain", None)
+    }
+
+    fn get_value(&self, index: ObjectIndex) -> Option<Value> {
+        let (slot, index) = Self::split_index(index);
+        let slot = self.slots.get(slot)?;
+        match index {
+            BYPASS_FIELD => Some(Value::Bool(slot.bypass)),
+            _ => slot.dsp.get_value(index),
+        }
+    }
+
+    fn set_value(&mut self, index: ObjectIndex, value: Value) -> Result<Value, ()> {
+        let (slot, index) = Self::split_index(index);
+        let slot = self.slots.get_mut(slot).ok_or(())?;
+        match (index, value) {
+            (BYPASS_FIELD, Value::Bool(bypass)) => {
+                slot.bypass = bypass;
+                Ok(Value::Bool(bypass))
+            },
+            (BYPASS_FIELD, _) => Err(()),
+            (index, value) => slot.dsp.set_value(index, value),
+        }
+    }
+
+    fn map_object(&self, mapper: &mut dyn ObjectMapper) {
+        for (i, slot) in self.slots.iter().enumerate() {
+            let offset = (i as ObjectIndex) << SLOT_SHIFT;
+            let mut fields = Vec::new();
+            slot.dsp.map_object(&mut fields);
+            for mut field in fields.into_iter().filter(|f| f.index < BYPASS_FIELD) {
+                field.index |= offset;
+                mapper.declare(field);
+            }
+
+            mapper.declare(FieldInfo {
+                index: offset | BYPASS_FIELD,
+                value_type: ValueType::Bool,
+                default: Some(Value::Bool(false)),
+                range: None,
+                metadatas: vec![("label", "bypass")],
+            });
+        }
+    }
+}
+
+
+impl<S,PS> DSP for Chain<S,PS>
+    where S: 'static+Sample, PS: ProcessScope
+{
+    type Sample = S;
+    type Scope = PS;
+
+    fn prepare(&mut self, rate: SampleRate) {
+        self.rate = rate;
+        for slot in self.slots.iter_mut() {
+            slot.dsp.prepare(rate);
+        }
+    }
+
+    fn set_max_samples(&mut self, max_samples: NSamples) {
+        self.max_samples = max_samples;
+        for slot in self.slots.iter_mut() {
+            slot.dsp.set_max_samples(max_samples);
+        }
+        self.allocate_buffers();
+    }
+
+    fn process_audio(&mut self, scope: &Self::Scope, input: Option<&dyn BufferView<Sample=Self::Sample>>,
+                     output: Option<&mut dyn BufferView<Sample=Self::Sample>>) -> usize
+    {
+        // buffers are only resized within their capacity
+        let n_samples = scope.n_samples().min(self.max_samples);
+        for buffer in self.buffers.iter_mut() {
+            buffer.resize(self.n_channels.max(1), n_samples);
+        }
+
+        // buffer holding the current signal, `None` being chain's input
+        let mut current: Option<usize> = None;
+        let mut output = output;
+        if let (None, Some(output), false) = (input, output.as_mut(), self.is_source()) {
+            // in place: output holds input
+            self.buffers[0].copy_inplace(&**output);
+            current = Some(0);
+        }
+
+        for slot in self.slots.iter_mut().filter(|slot| !slot.bypass) {
+            let (first, second) = self.buffers.split_at_mut(1);
+            let (src, dst): (Option<&dyn BufferView<Sample=S>>, &mut Buffer<S,Vec<S>>) = match current {
+                None => (input, &mut first[0]),
+                Some(0) => (Some(&first[0] as &dyn BufferView<Sample=S>), &mut second[0]),
+                Some(_) => (Some(&second[0] as &dyn BufferView<Sample=S>), &mut first[0]),
+            };
+
+            if slot.dsp.is_sink() {
+                slot.dsp.process_audio(scope, src, None);
+                continue;
+            }
+
+            let src = if slot.dsp.is_source() { None } else { src };
+            dst.fill(S::equilibrium());
+            let n = slot.dsp.process_audio(scope, src, Some(&mut *dst));
+            let len = dst.as_slice().len();
+            fill_samples(&mut dst.as_slice_mut()[n.min(len)..], S::equilibrium());
+
+            let wet = slot.dsp.wet();
+            if let (Some(src), true) = (src, wet != S::Float::identity()) {
+                let dry = S::Float::identity() - wet;
+                dst.zip_map_inplace(src, &|a,b| a.mul_amp(wet).add_amp(b.mul_amp(dry).to_signed_sample()));
+            }
+            current = Some(if current == Some(0) { 1 } else { 0 });
+        }
+
+        match (output, current) {
+            (Some(output), Some(current)) => {
+                zip_map(output, &self.buffers[current], |a,b| *a = *b);
+                n_samples * self.n_channels as usize
+            },
+            (Some(output), None) => {
+                match input {
+                    Some(input) => zip_map(output, input, |a,b| *a = *b),
+                    None => output.fill(S::equilibrium()),
+                }
+                n_samples * self.n_channels as usize
+            },
+            _ => 0,
+        }
+    }
+
+    fn process_events(&mut self, scope: &Self::Scope, input: &EventBuffer, output: &mut EventBuffer) {
+        let mut current: Option<usize> = None;
+        for slot in self.slots.iter_mut().filter(|slot| !slot.bypass) {
+            if !slot.dsp.has_event_input() && !slot.dsp.has_event_output() {
+                continue;
+            }
+
+            let (first, second) = self.events.split_at_mut(1);
+            let (src, dst) = match current {
+                None => (input, &mut first[0]),
+                Some(0) => (&first[0], &mut second[0]),
+                Some(_) => (&second[0], &mut first[0]),
+            };
+
+            dst.clear();
+            slot.dsp.process_events(scope, src, dst);
+            // events pass through slots not outputting any
+            if !slot.dsp.has_event_output() {
+                dst.merge(src);
+            }
+            current = Some(if current == Some(0) { 1 } else { 0 });
+        }
+
+        match current {
+            Some(current) => output.merge(&self.events[current]),
+            None => output.merge(input),
+        }
+    }
+
+    fn n_channels(&self) -> NChannels {
+        self.n_channels
+    }
+
+    fn in_place(&self) -> bool {
+        !self.is_source()
+    }
+
+    fn underruns(&self) -> usize {
+        self.slots.iter().map(|slot| slot.dsp.underruns()).sum()
+    }
+
+    fn latency(&self) -> NSamples {
+        self.slots.iter().filter(|slot| !slot.bypass).map(|slot| slot.dsp.latency()).sum()
+    }
+
+    fn is_sink(&self) -> bool {
+        self.slots.last().map(|slot| slot.dsp.is_sink()).unwrap_or(false)
+    }
+
+    fn is_source(&self) -> bool {
+        self.slots.first().map(|slot| slot.dsp.is_source()).unwrap_or(false)
+    }
+
+    fn has_event_input(&self) -> bool {
+        self.slots.iter().any(|slot| slot.dsp.has_event_input())
+    }
+
+    fn has_event_output(&self) -> bool {
+        self.slots.iter().any(|slot| slot.dsp.has_event_output())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate as libfoxlive;
+    use libfoxlive_derive::object;
+    use super::*;
+    use super::super::block::BlockScope;
+
+    /// Output `input * mul + add`
+    #[object("affine")]
+    struct Affine {
+        #[field("mul", F32(1.0))]
+        mul: f32,
+        #[field("add", F32(0.0))]
+        add: f32,
+    }
+
+    impl DSP for Affine {
+        type Sample = f32;
+        type Scope = BlockScope;
+
+        fn process_audio(&mut self, _scope: &Self::Scope, input: Option<&dyn BufferView<Sample=f32>>,
+                         output: Option<&mut dyn BufferView<Sample=f32>>) -> usize
+        {
+            let output = output.unwrap();
+            let input = input.unwrap();
+            let (mul, add) = (self.mul, self.add);
+            zip_map(output, input, |a,b| *a = *b * mul + add);
+            output.len()
+        }
+
+        fn n_channels(&self) -> NChannels { 1 }
+    }
+
+    fn affine(mul: f32, add: f32) -> BoxedDSP<f32,BlockScope> {
+        Box::new(Affine { mul, add })
+    }
+
+    /// Process a block of ones through the chain
+    fn process(chain: &mut Chain<f32,BlockScope>, n_samples: NSamples) -> Vec<f32> {
+        let input: Buffer<f32,Vec<f32>> = (true, 1, vec![1.0; n_samples]).into();
+        let mut output: Buffer<f32,Vec<f32>> = (true, 1, vec![0.0; n_samples]).into();
+        chain.process_audio(&BlockScope::new(n_samples, 0, None), Some(&input), Some(&mut output));
+        output.buffer
+    }
+
+    /// Test: slots are processed in order, skipping bypassed ones
+    #[test]
+    fn slots() {
+        let mut chain = Chain::new(1);
+        chain.push(affine(1.0, 1.0));
+        chain.push(affine(2.0, 0.0));
+        assert_eq!(process(&mut chain, 4), vec![4.0; 4]);
+
+        assert!(chain.move_slot(1, 0));
+        assert_eq!(process(&mut chain, 4), vec![3.0; 4]);
+
+        assert!(chain.set_bypass(0, true));
+        assert_eq!(process(&mut chain, 4), vec![2.0; 4]);
+        assert!(!chain.move_slot(0, 2));
+    }
+
+    /// Test: slots' fields are offset by slot index
+    #[test]
+    fn fields() {
+        let mut chain = Chain::new(1);
+        chain.push(affine(1.0, 0.0));
+        chain.push(affine(1.0, 0.0));
+
+        let index = (1 << SLOT_SHIFT) | 1;
+        assert!(chain.set_value(index, Value::F32(5.0)).is_ok());
+        assert!(matches!(chain.get_value(index), Some(Value::F32(v)) if v == 5.0));
+        assert_eq!(process(&mut chain, 4), vec![6.0; 4]);
+
+        assert!(chain.set_value(1 << SLOT_SHIFT | BYPASS_FIELD, Value::Bool(true)).is_ok());
+        assert_eq!(process(&mut chain, 4), vec![1.0; 4]);
+        assert!(chain.set_value(2 << SLOT_SHIFT, Value::F32(1.0)).is_err());
+    }
+
+    /// Test: buffers are allocated by `set_max_samples`, larger blocks being truncated
+    #[test]
+    fn max_samples() {
+        let mut chain = Chain::new(1);
+        chain.push(affine(2.0, 0.0));
+        chain.set_max_samples(4);
+        let ptr = chain.buffers[0].as_slice().as_ptr();
+
+        let output = process(&mut chain, 8);
+        assert_eq!(chain.buffers[0].as_slice().as_ptr(), ptr);
+        assert_eq!(&output[..4], &[2.0; 4]);
+    }
+}
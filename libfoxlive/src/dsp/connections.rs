@@ -0,0 +1,374 @@
+//! Manage JACK connections, applying auto-connect rules and restoring connections when foxlive
+//! or peer clients restart.
+//!
+//! The manager uses its own jack client in order to get port registration notifications. Those
+//! are handled outside of jack's notification thread by `ConnectionManager::process_notifications`,
+//! since connecting ports is not allowed from there.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use libfoxlive::dsp::connections::ConnectionManager;
+//!
+//! let mut manager = ConnectionManager::new("foxlive_connections").unwrap();
+//! manager.add_rule("^foxlive:master_".to_string(), "^system:playback_".to_string()).unwrap();
+//! manager.load("connections.txt").ok();
+//!
+//! loop {
+//!     manager.process_notifications();
+//!     std::thread::sleep(std::time::Duration::from_millis(100));
+//! }
+//! ```
+//!
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::sync::mpsc;
+use std::time::{Duration,Instant};
+
+use jack as j;
+use regex::Regex;
+
+use crate as libfoxlive;
+use libfoxlive_derive::service;
+
+
+/// Delay before forgetting a connection removed while its ports still exist: a quitting client's
+/// ports are disconnected before being unregistered.
+const FORGET_DELAY: Duration = Duration::from_secs(1);
+
+
+/// Auto-connect rule: output ports matching `source` regex are connected to input ports matching
+/// `destination` regex. Ports are connected pairwise in name order, cycling over the shortest
+/// list (e.g. a mono output is connected to both stereo inputs).
+#[derive(Clone)]
+pub struct ConnectRule {
+    pub source: Regex,
+    pub destination: Regex,
+}
+
+
+/// Notification sent from jack's notification thread.
+enum Notification {
+    PortRegistered,
+    Connected(String, String),
+    Disconnected(String, String),
+}
+
+
+/// Jack notifications handler forwarding notifications to the manager.
+pub struct Notifications {
+    sender: mpsc::Sender<Notification>,
+}
+
+
+/// Connection manager, handling auto-connect rules and connections' persistence.
+pub struct ConnectionManager {
+    client: j::AsyncClient<Notifications, ()>,
+    receiver: mpsc::Receiver<Notification>,
+    /// Auto-connect rules
+    rules: Vec<ConnectRule>,
+    /// Known connections as `(source, destination)`, restored when ports are registered again.
+    known: BTreeSet<(String,String)>,
+    /// Connections removed while their ports existed, forgotten after `FORGET_DELAY`.
+    disconnected: Vec<((String,String), Instant)>,
+}
+
+
+impl ConnectRule {
+    pub fn new(source: &str, destination: &str) -> Result<Self, String> {
+        let source = Regex::new(source).map_err(|e| format!("{}", e))?;
+        let destination = Regex::new(destination).map_err(|e| format!("{}", e))?;
+        Ok(Self { source, destination })
+    }
+
+    /// Return connections to make for the provided output and input ports.
+    fn connections(&self, outputs: &[String], inputs: &[String]) -> Vec<(String,String)> {
+        let sources: Vec<&String> = outputs.iter().filter(|p| self.source.is_match(p)).collect();
+        let destinations: Vec<&String> = inputs.iter().filter(|p| self.destination.is_match(p)).collect();
+        if sources.is_empty() || destinations.is_empty() {
+            return Vec::new();
+        }
+
+        (0..sources.len().max(destinations.len()))
+            .map(|i| (sources[i % sources.len()].clone(),
+                      destinations[i % destinations.len()].clone()))
+            .collect()
+    }
+}
+
+
+/// Forget connections disconnected for at least `FORGET_DELAY` whose ports still exist, i.e.
+/// removed by hand. Connections whose port is gone are kept, to be restored.
+fn forget_disconnected<F>(known: &mut BTreeSet<(String,String)>,
+                          disconnected: &mut Vec<((String,String), Instant)>,
+                          now: Instant, exists: F)
+    where F: Fn(&str) -> bool
+{
+    disconnected.retain(|((a, b), at)| {
+        if !exists(a) || !exists(b) {
+            return false;
+        }
+        if now.duration_since(*at) < FORGET_DELAY {
+            return true;
+        }
+        known.remove(&(a.clone(), b.clone()));
+        false
+    });
+}
+
+
+impl j::NotificationHandler for Notifications {
+    fn port_registration(&mut self, _client: &j::Client, _port_id: j::PortId, is_registered: bool) {
+        if is_registered {
+            self.sender.send(Notification::PortRegistered).ok();
+        }
+    }
+
+    fn ports_connected(&mut self, client: &j::Client, port_id_a: j::PortId, port_id_b: j::PortId,
+                       are_connected: bool)
+    {
+        let (a, b) = match (client.port_by_id(port_id_a), client.port_by_id(port_id_b)) {
+            (Some(a), Some(b)) => match (a.name(), b.name()) {
+                (Ok(a), Ok(b)) => (a, b),
+                _ => return,
+            },
+            _ => return,
+        };
+
+        self.sender.send(match are_connected {
+            true => Notification::Connected(a, b),
+            false => Notification::Disconnected(a, b),
+        }).ok();
+    }
+}
+
+
+impl ConnectionManager {
+    /// Create a new manager, registering a jack client of the provided name.
+    pub fn new(name: &str) -> Result<Self, j::Error> {
+        let (client, _) = j::Client::new(name, j::ClientOptions::NO_START_SERVER)?;
+        let (sender, receiver) = mpsc::channel();
+        let client = client.activate_async(Notifications { sender }, ())?;
+
+        Ok(Self {
+            client, receiver,
+            rules: Vec::new(),
+            known: BTreeSet::new(),
+            disconnected: Vec::new(),
+        })
+    }
+
+    /// Jack client used by the manager.
+    pub fn client(&self) -> &j::Client {
+        self.client.as_client()
+    }
+
+    /// Handle pending notifications, applying rules and restoring connections when new ports
+    /// are registered.
+    pub fn process_notifications(&mut self) {
+        let mut registered = false;
+        while let Ok(notification) = self.receiver.try_recv() {
+            match notification {
+                Notification::PortRegistered => registered = true,
+                Notification::Connected(a, b) => {
+                    self.disconnected.retain(|(c, _)| c.0 != a || c.1 != b);
+                    self.known.insert((a, b));
+                },
+                Notification::Disconnected(a, b) =>
+                    if self.known.contains(&(a.clone(), b.clone())) {
+                        self.disconnected.push(((a, b), Instant::now()));
+                    },
+            }
+        }
+
+        let client = self.client.as_client();
+        forget_disconnected(&mut self.known, &mut self.disconnected, Instant::now(),
+                            |port| client.port_by_name(port).is_some());
+
+        if registered {
+            self.apply_rules();
+            self.restore();
+        }
+    }
+
+    /// Apply all auto-connect rules to existing ports.
+    pub fn apply_rules(&self) {
+        let client = self.client.as_client();
+        let mut outputs = client.ports(None, None, j::PortFlags::IS_OUTPUT);
+        let mut inputs = client.ports(None, None, j::PortFlags::IS_INPUT);
+        outputs.sort();
+        inputs.sort();
+
+        for rule in self.rules.iter() {
+            for (a, b) in rule.connections(&outputs, &inputs) {
+                client.connect_ports_by_name(&a, &b).ok();
+            }
+        }
+    }
+
+    /// Restore known connections whose ports exist.
+    pub fn restore(&self) {
+        let client = self.client.as_client();
+        for (a, b) in self.known.iter() {
+            if client.port_by_name(a).is_some() && client.port_by_name(b).is_some() {
+                client.connect_ports_by_name(a, b).ok();
+            }
+        }
+    }
+
+    /// Save rules and known connections into a file, one per line as tab separated values.
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let rules = self.rules.iter()
+            .map(|rule| format!("rule\t{}\t{}", rule.source.as_str(), rule.destination.as_str()));
+        let known = self.known.iter()
+            .map(|(a, b)| format!("connect\t{}\t{}", a, b));
+
+        let content = rules.chain(known).collect::<Vec<_>>().join("\n");
+        fs::write(path, content)
+    }
+
+    /// Load rules and connections previously saved, restoring them.
+    pub fn load(&mut self, path: &str) -> io::Result<()> {
+        let content = fs::read_to_string(path)?;
+        for line in content.lines() {
+            let mut items = line.splitn(3, '\t');
+            match (items.next(), items.next(), items.next()) {
+                (Some("rule"), Some(a), Some(b)) =>
+                    if let Ok(rule) = ConnectRule::new(a, b) {
+                        self.rules.push(rule);
+                    },
+                (Some("connect"), Some(a), Some(b)) => {
+                    self.known.insert((a.to_string(), b.to_string()));
+                },
+                _ => {},
+            }
+        }
+
+        self.apply_rules();
+        self.restore();
+        Ok(())
+    }
+}
+
+
+#[service]
+impl ConnectionManager {
+    /// Connect two ports by name.
+    pub fn connect(&mut self, source: String, destination: String) -> bool {
+        let r = self.client.as_client().connect_ports_by_name(&source, &destination).is_ok();
+        if r {
+            self.known.insert((source, destination));
+        }
+        r
+    }
+
+    /// Disconnect two ports by name.
+    pub fn disconnect(&mut self, source: String, destination: String) -> bool {
+        self.disconnected.retain(|(c, _)| c.0 != source || c.1 != destination);
+        self.known.remove(&(source.clone(), destination.clone()));
+        self.client.as_client().disconnect_ports_by_name(&source, &destination).is_ok()
+    }
+
+    /// List ports, optionally filtered by jack's port name pattern.
+    pub fn ports(&self, pattern: Option<String>) -> Vec<String> {
+        self.client.as_client().ports(pattern.as_ref().map(|p| p.as_str()), None, j::PortFlags::empty())
+    }
+
+    /// List current connections as `(source, destination)`.
+    pub fn connections(&self) -> Vec<(String,String)> {
+        let client = self.client.as_client();
+        let destinations = client.ports(None, None, j::PortFlags::IS_INPUT);
+        client.ports(None, None, j::PortFlags::IS_OUTPUT).into_iter()
+            .filter_map(|source| client.port_by_name(&source).map(|port| (source, port)))
+            .flat_map(|(source, port)|
+                destinations.iter()
+                    .filter(|destination| port.is_connected_to(destination).unwrap_or(false))
+                    .map(|destination| (source.clone(), destination.clone()))
+                    .collect::<Vec<_>>())
+            .collect()
+    }
+
+    /// Add an auto-connect rule and apply it.
+    pub fn add_rule(&mut self, source: String, destination: String) -> Result<(), String> {
+        let rule = ConnectRule::new(&source, &destination)?;
+        self.rules.push(rule);
+        self.apply_rules();
+        Ok(())
+    }
+
+    /// Remove auto-connect rule at the provided index.
+    pub fn remove_rule(&mut self, index: usize) -> bool {
+        if index < self.rules.len() {
+            self.rules.remove(index);
+            true
+        }
+        else { false }
+    }
+
+    /// List auto-connect rules as `(source, destination)` patterns.
+    pub fn rules(&self) -> Vec<(String,String)> {
+        self.rules.iter()
+            .map(|rule| (rule.source.as_str().to_string(), rule.destination.as_str().to_string()))
+            .collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String,String)> {
+        pairs.iter().map(|(a, b)| (a.to_string(), b.to_string())).collect()
+    }
+
+    /// Test: rule connects matching ports pairwise, cycling over the shortest list
+    #[test]
+    fn connections() {
+        let outputs = names(&["fx:out", "synth:out_1", "synth:out_2"]);
+        let inputs = names(&["fx:in", "system:playback_1", "system:playback_2"]);
+
+        let rule = ConnectRule::new("^synth:out_", "^system:playback_").unwrap();
+        assert_eq!(rule.connections(&outputs, &inputs),
+                   pairs(&[("synth:out_1", "system:playback_1"), ("synth:out_2", "system:playback_2")]));
+
+        let rule = ConnectRule::new("^fx:out", "^system:playback_").unwrap();
+        assert_eq!(rule.connections(&outputs, &inputs),
+                   pairs(&[("fx:out", "system:playback_1"), ("fx:out", "system:playback_2")]));
+
+        let rule = ConnectRule::new("^synth:out_", "^fx:in").unwrap();
+        assert_eq!(rule.connections(&outputs, &inputs),
+                   pairs(&[("synth:out_1", "fx:in"), ("synth:out_2", "fx:in")]));
+
+        let rule = ConnectRule::new("^none:", "^fx:in").unwrap();
+        assert!(rule.connections(&outputs, &inputs).is_empty());
+        assert!(ConnectRule::new("(", "^fx:in").is_err());
+    }
+
+    /// Test: connections of a quitting peer are kept, those removed by hand are forgotten
+    #[test]
+    fn forget() {
+        let removed = ("fx:out".to_string(), "system:playback_1".to_string());
+        let quit = ("synth:out".to_string(), "fx:in".to_string());
+        let mut known: BTreeSet<_> = vec![removed.clone(), quit.clone()].into_iter().collect();
+        let at = Instant::now();
+        let mut disconnected = vec![(removed.clone(), at), (quit.clone(), at)];
+
+        // peer's ports still exist right after disconnection
+        forget_disconnected(&mut known, &mut disconnected, at, |_| true);
+        assert_eq!((known.len(), disconnected.len()), (2, 2));
+
+        forget_disconnected(&mut known, &mut disconnected, at, |p| !p.starts_with("synth:"));
+        assert_eq!(disconnected, vec![(removed.clone(), at)]);
+
+        forget_disconnected(&mut known, &mut disconnected, at + FORGET_DELAY,
+                            |p| !p.starts_with("synth:"));
+        assert!(disconnected.is_empty());
+        assert_eq!(known.into_iter().collect::<Vec<_>>(), vec![quit]);
+    }
+}
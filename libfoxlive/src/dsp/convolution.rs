@@ -0,0 +1,460 @@
+//! Convolution reverb and FIR filtering, using partitioned FFT convolution.
+//!
+//! Impulse responses are convolved using uniformly partitioned overlap-save convolution,
+//! whose latency is the partition size. With zero-latency partitioning, the first partition
+//! of the IR is convolved directly in time domain and the rest in frequency domain, which
+//! results in no latency at the cost of more CPU.
+//!
+//! `Convolution` supports mono, stereo and true-stereo (4 channels: `LL`, `LR`, `RL`, `RR`)
+//! impulse responses, read through `format::Reader` and resampled to graph's rate. Setting
+//! `impulse` field posts the file to a `PathLoader`, then the IR is swapped in the audio
+//! thread. When sample rate changes, the IR is loaded again from its file.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let mut reverb = Convolution::<Scope>::new(256, Partitioning::ZeroLatency);
+//! reverb.load("hall.wav".to_string());
+//! graph.add_child(source, reverb.into());
+//! ```
+use std::marker::PhantomData;
+
+use crate as libfoxlive;
+use libfoxlive_derive::object;
+use crate::data::*;
+use crate::format::{Error,Reader};
+use crate::rpc::*;
+
+use super::dsp::{DSP,DEFAULT_RATE};
+use super::fft::{Complex,Fft};
+use super::graph::ProcessScope;
+use super::swap::{PathLoader,Swap};
+
+
+/// Maximum predelay, in milliseconds
+pub const MAX_PREDELAY: f32 = 500.0;
+/// Highest sample rate for which predelay lines are allocated
+const MAX_RATE: SampleRate = 192000;
+
+
+/// How the impulse response is partitioned.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Partitioning {
+    /// All partitions have the same size, latency is one partition
+    Uniform,
+    /// First partition is convolved in time domain, without latency
+    ZeroLatency,
+}
+
+
+/// Convolution of a signal by a single channel impulse response.
+pub struct Convolver {
+    block: usize,
+    fft: Fft,
+    /// IR head, convolved in time domain
+    head: Vec<f32>,
+    /// Input history for head convolution, written twice to be read as a contiguous slice
+    history: Vec<f32>,
+    history_pos: usize,
+    /// IR partitions' spectra
+    partitions: Vec<Vec<Complex>>,
+    /// Frequency-domain delay line of input blocks' spectra
+    fdl: Vec<Vec<Complex>>,
+    fdl_pos: usize,
+    /// Last two input blocks
+    window: Vec<f32>,
+    /// Input block being filled
+    input: Vec<f32>,
+    /// Output of previous block
+    output: Vec<f32>,
+    fill: usize,
+    spectrum: Vec<Complex>,
+}
+
+
+impl Convolver {
+    /// Create convolver for the provided IR and partition size (power of two).
+    pub fn new(ir: &[f32], block: usize, partitioning: Partitioning) -> Self {
+        let fft = Fft::new(block * 2);
+        let (head, tail) = match partitioning {
+            Partitioning::Uniform => (&ir[..0], ir),
+            Partitioning::ZeroLatency => ir.split_at(ir.len().min(block)),
+        };
+
+        let partitions: Vec<Vec<Complex>> = tail.chunks(block).map(|chunk| {
+            let mut spectrum = vec![Complex::default(); block * 2];
+            for (s, v) in spectrum.iter_mut().zip(chunk) {
+                s.re = *v;
+            }
+            fft.forward(&mut spectrum);
+            spectrum
+        }).collect();
+
+        Self {
+            block, fft,
+            head: head.to_vec(),
+            history: vec![0.0; head.len() * 2],
+            history_pos: 0,
+            fdl: vec![vec![Complex::default(); block * 2]; partitions.len()],
+            fdl_pos: 0,
+            partitions,
+            window: vec![0.0; block * 2],
+            input: vec![0.0; block],
+            output: vec![0.0; block],
+            fill: 0,
+            spectrum: vec![Complex::default(); block * 2],
+        }
+    }
+
+    /// Latency in samples.
+    pub fn latency(&self) -> usize {
+        match self.head.is_empty() {
+            true => self.block,
+            false => 0,
+        }
+    }
+
+    /// Process a single sample.
+    pub fn process(&mut self, x: f32) -> f32 {
+        let mut y = 0.0;
+        if !self.head.is_empty() {
+            let len = self.head.len();
+            self.history_pos = (self.history_pos + 1) % len;
+            self.history[self.history_pos] = x;
+            self.history[self.history_pos + len] = x;
+
+            // history slice is in chronological order, ending with `x`
+            let history = &self.history[self.history_pos + 1..=self.history_pos + len];
+            y = self.head.iter().zip(history.iter().rev()).map(|(h, x)| h * x).sum();
+        }
+
+        if self.partitions.is_empty() {
+            return y;
+        }
+
+        self.input[self.fill] = x;
+        y += self.output[self.fill];
+        self.fill += 1;
+        if self.fill == self.block {
+            self.process_block();
+            self.fill = 0;
+        }
+        y
+    }
+
+    /// Clear internal state.
+    pub fn reset(&mut self) {
+        for v in self.history.iter_mut().chain(self.window.iter_mut()).chain(self.output.iter_mut()) {
+            *v = 0.0;
+        }
+        for spectrum in self.fdl.iter_mut() {
+            for v in spectrum.iter_mut() {
+                *v = Complex::default();
+            }
+        }
+        self.fill = 0;
+    }
+
+    fn process_block(&mut self) {
+        let block = self.block;
+        self.window.copy_within(block.., 0);
+        self.window[block..].copy_from_slice(&self.input);
+
+        let n_parts = self.fdl.len();
+        self.fdl_pos = (self.fdl_pos + 1) % n_parts;
+        let spectrum = &mut self.fdl[self.fdl_pos];
+        for (s, v) in spectrum.iter_mut().zip(self.window.iter()) {
+            *s = Complex::new(*v, 0.0);
+        }
+        self.fft.forward(spectrum);
+
+        // multiply-accumulate partitions with the matching past input spectra
+        for v in self.spectrum.iter_mut() {
+            *v = Complex::default();
+        }
+        for (p, partition) in self.partitions.iter().enumerate() {
+            let input = &self.fdl[(self.fdl_pos + n_parts - p) % n_parts];
+            for ((acc, x), h) in self.spectrum.iter_mut().zip(input.iter()).zip(partition.iter()) {
+                *acc += *x * *h;
+            }
+        }
+        self.fft.inverse(&mut self.spectrum);
+
+        for (y, v) in self.output.iter_mut().zip(self.spectrum[block..].iter()) {
+            *y = v.re;
+        }
+    }
+}
+
+
+/// Convolution of an input channel into an output channel
+struct Engine {
+    input: usize,
+    output: usize,
+    convolver: Convolver,
+}
+
+
+/// Stereo convolution reverb or FIR filter.
+#[object("convolution")]
+pub struct Convolution<PS>
+    where PS: ProcessScope
+{
+    /// Impulse response file path
+    #[field("impulse", String, get(impulse), set(load))]
+    impulse: PathLoader<(SampleRate, usize, Partitioning)>,
+    /// Gain of convolved signal
+    #[field("wet", F32(0.3), range(0.0,1.0,0.01))]
+    pub wet: f32,
+    /// Gain of input signal
+    #[field("dry", F32(1.0), range(0.0,1.0,0.01))]
+    pub dry: f32,
+    /// Delay before convolved signal, in milliseconds
+    #[field("predelay", F32(0.0), range(0.0,500.0,1.0))]
+    pub predelay: f32,
+    block: usize,
+    partitioning: Partitioning,
+    rate: SampleRate,
+    engines: Vec<Engine>,
+    /// IR loaded in background, swapped by the audio thread
+    swap: Swap<Vec<Engine>>,
+    /// Predelay lines, interleaved stereo, allocated up to `MAX_RATE`
+    delay: Vec<f32>,
+    delay_pos: usize,
+    phantom: PhantomData<PS>,
+}
+
+
+impl<PS> Convolution<PS>
+    where PS: ProcessScope
+{
+    /// Create convolution with the provided partition size (power of two).
+    pub fn new(block: usize, partitioning: Partitioning) -> Self {
+        let swap = Swap::new();
+        let impulse = PathLoader::new(swap.loader(), |path, (rate, block, partitioning)| {
+            Reader::<f32>::read_all(path, rate, None).and_then(|ir| Self::engines(&ir, block, partitioning))
+        });
+        let mut convolution = Self {
+            impulse,
+            wet: 0.3, dry: 1.0, predelay: 0.0,
+            block, partitioning,
+            rate: DEFAULT_RATE,
+            engines: Vec::new(),
+            swap,
+            delay: Vec::with_capacity(Self::delay_len(MAX_RATE)),
+            delay_pos: 0,
+            phantom: PhantomData,
+        };
+        convolution.prepare(DEFAULT_RATE);
+        convolution
+    }
+
+    /// Create a FIR filter (full wet, no dry signal) from an interleaved impulse response.
+    pub fn fir(ir: &dyn BufferView<Sample=f32>, block: usize, partitioning: Partitioning) -> Result<Self, Error> {
+        let mut convolution = Self::new(block, partitioning);
+        convolution.wet = 1.0;
+        convolution.dry = 0.0;
+        convolution.engines = Self::engines(ir, block, partitioning)?;
+        Ok(convolution)
+    }
+
+    /// Impulse response file path
+    pub fn impulse(&self) -> String {
+        self.impulse.path()
+    }
+
+    /// Load impulse response file in background. It will be used once loaded. It can be called
+    /// from the audio thread: the path is given back as error when it can't be posted.
+    pub fn load(&mut self, path: String) -> Result<String, Error> {
+        match self.impulse.post(&path, (self.rate, self.block, self.partitioning)) {
+            true => Ok(path),
+            false => Err(Error::reader(path)),
+        }
+    }
+
+    /// Set impulse response from an interleaved buffer, at graph's rate. It will be swapped in
+    /// by the audio thread.
+    pub fn set_impulse_response(&mut self, ir: &dyn BufferView<Sample=f32>) -> Result<(), Error> {
+        self.swap.set(Self::engines(ir, self.block, self.partitioning)?);
+        self.impulse.clear();
+        Ok(())
+    }
+
+    /// Length of predelay lines at the provided rate.
+    fn delay_len(rate: SampleRate) -> usize {
+        2 * (MAX_PREDELAY * rate.clamp(1, MAX_RATE) as f32 / 1000.0) as usize + 2
+    }
+
+    /// Create convolution engines for the provided impulse response.
+    fn engines(ir: &dyn BufferView<Sample=f32>, block: usize, partitioning: Partitioning)
+        -> Result<Vec<Engine>, Error>
+    {
+        // (ir channel, input, output)
+        let routing: &[(NChannels,usize,usize)] = match ir.n_channels() {
+            1 => &[(0,0,0), (0,1,1)],
+            2 => &[(0,0,0), (1,1,1)],
+            4 => &[(0,0,0), (1,0,1), (2,1,0), (3,1,1)],
+            n => return Err(Error::media(format!("unsupported impulse response channels: {}", n))),
+        };
+
+        Ok(routing.iter().map(|(channel, input, output)| {
+            let ir: Vec<f32> = ir.channel(*channel).unwrap().copied().collect();
+            Engine { input: *input, output: *output, convolver: Convolver::new(&ir, block, partitioning) }
+        }).collect())
+    }
+
+    /// Swap in loaded impulse response, if any.
+    fn swap_engines(&mut self) {
+        self.swap.swap(&mut self.engines, |_| true);
+    }
+}
+
+
+impl<PS> DSP for Convolution<PS>
+    where PS: ProcessScope
+{
+    type Sample = f32;
+    type Scope = PS;
+
+    fn prepare(&mut self, rate: SampleRate) {
+        let changed = rate != self.rate;
+        self.rate = rate;
+        // delay lines are allocated up to `MAX_RATE`, predelay being shorter above
+        self.delay.clear();
+        self.delay.resize(Self::delay_len(rate), 0.0);
+        self.delay_pos = 0;
+        for engine in self.engines.iter_mut() {
+            engine.convolver.reset();
+        }
+
+        // IR must be resampled to the new rate
+        if changed {
+            self.impulse.reload((rate, self.block, self.partitioning));
+        }
+    }
+
+    fn process_audio(&mut self, scope: &Self::Scope, input: Option<&dyn BufferView<Sample=Self::Sample>>,
+                     output: Option<&mut dyn BufferView<Sample=Self::Sample>>) -> usize
+    {
+        self.swap_engines();
+
+        let (input, output) = match (input, output) {
+            (Some(input), Some(output)) => (input, output),
+            _ => return 0,
+        };
+        let (left, right) = match input.channel(0) {
+            Some(left) => (left, input.channel(1).or_else(|| input.channel(0)).unwrap()),
+            None => return 0,
+        };
+
+        let (interleaved, n_channels, out_samples) =
+            (output.interleaved(), output.n_channels() as usize, output.n_samples());
+        let out = output.as_slice_mut();
+        let n_samples = scope.n_samples().min(out_samples).min(left.len()).min(right.len());
+        let delay_len = self.delay.len() / 2;
+        let predelay = ((self.predelay.max(0.0).min(MAX_PREDELAY) * self.rate as f32 / 1000.0) as usize)
+                            .min(delay_len - 1);
+
+        for i in 0..n_samples {
+            let x = [left[i], right[i]];
+
+            // predelay
+            self.delay[self.delay_pos * 2] = x[0];
+            self.delay[self.delay_pos * 2 + 1] = x[1];
+            let pos = (self.delay_pos + delay_len - predelay) % delay_len;
+            let d = [self.delay[pos * 2], self.delay[pos * 2 + 1]];
+            self.delay_pos = (self.delay_pos + 1) % delay_len;
+
+            let mut wet = [0.0f32; 2];
+            for engine in self.engines.iter_mut() {
+                wet[engine.output] += engine.convolver.process(d[engine.input]);
+            }
+            for channel in 0..n_channels.min(2) {
+                let index = match interleaved {
+                    true => i * n_channels + channel,
+                    false => channel * out_samples + i,
+                };
+                out[index] = self.dry * x[channel] + self.wet * wet[channel];
+            }
+        }
+        n_samples * n_channels.min(2)
+    }
+
+    fn n_channels(&self) -> NChannels {
+        2
+    }
+
+    fn latency(&self) -> NSamples {
+        self.engines.first().map(|e| e.convolver.latency()).unwrap_or(0)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::block::BlockScope;
+
+    /// Test: partitioned convolution against direct convolution
+    #[test]
+    fn convolver() {
+        let ir: Vec<f32> = (0..300).map(|i| ((i * 7919) % 23) as f32 / 23.0 - 0.5).collect();
+        let input: Vec<f32> = (0..1000).map(|i| ((i * 104729) % 31) as f32 / 31.0 - 0.5).collect();
+        let expected: Vec<f32> = (0..input.len()).map(|n| {
+            (0..ir.len().min(n + 1)).map(|k| ir[k] * input[n - k]).sum()
+        }).collect();
+
+        for partitioning in [Partitioning::Uniform, Partitioning::ZeroLatency].iter() {
+            let mut convolver = Convolver::new(&ir, 64, *partitioning);
+            let latency = convolver.latency();
+            let output: Vec<f32> = input.iter().map(|x| convolver.process(*x)).collect();
+            for (n, y) in output[latency..].iter().enumerate() {
+                assert!((y - expected[n]).abs() < 1e-3);
+            }
+        }
+    }
+
+    /// Test: dry and wet signals of a stereo IR, on interleaved and planar outputs
+    #[test]
+    fn process() {
+        // left is delayed by 3 samples, right is halved
+        let mut ir = vec![0.0; 16];
+        ir[6] = 1.0;
+        ir[1] = 0.5;
+        let ir: Buffer<f32,Vec<f32>> = (true, 2, ir).into();
+        let input: Buffer<f32,Vec<f32>> = (true, 2, (0..16).map(|i| (i / 2 + 1) as f32).collect::<Vec<_>>()).into();
+
+        for interleaved in [true, false].iter() {
+            let mut reverb = Convolution::<BlockScope>::new(4, Partitioning::ZeroLatency);
+            reverb.wet = 0.5;
+            reverb.set_impulse_response(&ir).unwrap();
+
+            let mut output: Buffer<f32,Vec<f32>> = (*interleaved, 2, vec![0.0; 16]).into();
+            assert_eq!(reverb.process_audio(&BlockScope::new(8, 0, None), Some(&input), Some(&mut output)), 16);
+            assert_eq!(output.interleaved(), *interleaved);
+            assert_eq!(reverb.latency(), 0);
+
+            let out = output.as_slice();
+            for i in 0..8 {
+                let x = (i + 1) as f32;
+                let delayed = if i >= 3 { (i - 2) as f32 } else { 0.0 };
+                let (left, right) = match interleaved {
+                    true => (out[i * 2], out[i * 2 + 1]),
+                    false => (out[i], out[8 + i]),
+                };
+                assert!((left - (x + 0.5 * delayed)).abs() < 1e-4);
+                assert!((right - (x + 0.25 * x)).abs() < 1e-4);
+            }
+        }
+    }
+
+    /// Test: predelay lines are not reallocated when rate changes
+    #[test]
+    fn prepare() {
+        let mut reverb = Convolution::<BlockScope>::new(4, Partitioning::Uniform);
+        let delay = reverb.delay.as_ptr();
+        reverb.prepare(MAX_RATE * 2);
+        assert_eq!(reverb.delay.as_ptr(), delay);
+        assert_eq!(reverb.delay.len(), Convolution::<BlockScope>::delay_len(MAX_RATE));
+    }
+}
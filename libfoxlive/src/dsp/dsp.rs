@@ -1,21 +1,50 @@
 use std::any::Any;
 
 use crate::rpc::Object;
-use crate::data::{BufferView,Sample,NChannels};
+use crate::data::{BufferView,EventBuffer,Sample,NChannels,NSamples,SampleRate};
 use super::graph::ProcessScope;
 
 
+/// Sample rate used by DSP when graph's rate is not yet known.
+pub const DEFAULT_RATE: SampleRate = 48000;
+
+
 /// Generic DSP trait in order to process audio from graph.
 pub trait DSP: Any+Object {
     type Sample: Sample;
     type Scope: ProcessScope;
 
+    /// Prepare DSP for the provided sample rate. It is called when DSP is added to a graph
+    /// and when graph's rate changes, never while processing.
+    fn prepare(&mut self, _rate: SampleRate) {}
+
+    /// Set the maximum number of samples per channel of processed blocks, so that DSP can
+    /// allocate the buffers it uses while processing. It is called when DSP is added to a
+    /// graph and when graph's maximum changes, never while processing.
+    fn set_max_samples(&mut self, _max_samples: NSamples) {}
+
     /// Process audio using provided input and output. Return total number of written samples
     /// nevermind the channel.
     /// Sink always return 0 since they don't write to provided output.
     fn process_audio(&mut self, scope: &Self::Scope, input: Option<&dyn BufferView<Sample=Self::Sample>>,
                      output: Option<&mut dyn BufferView<Sample=Self::Sample>>) -> usize;
 
+    /// Process audio using one input buffer per input bus, as declared by `input_buses()`.
+    /// Source DSPs have no input.
+    ///
+    /// Default implementation calls `process_audio` with the main bus.
+    fn process_buses(&mut self, scope: &Self::Scope, inputs: &[&dyn BufferView<Sample=Self::Sample>],
+                     output: Option<&mut dyn BufferView<Sample=Self::Sample>>) -> usize
+    {
+        self.process_audio(scope, inputs.first().map(|b| *b), output)
+    }
+
+    /// Process events of the current block, called before `process_audio`. Input events are
+    /// merged in time order from parents' outputs.
+    ///
+    /// It is only called when DSP has event input or output.
+    fn process_events(&mut self, _scope: &Self::Scope, _input: &EventBuffer, _output: &mut EventBuffer) {}
+
     // FIXME: return number of optional NChannels
     //
     // Having a DSP Graph such as:
@@ -37,12 +66,32 @@ pub trait DSP: Any+Object {
         0
     }
 
+    /// Latency added by the DSP to its output, in samples.
+    fn latency(&self) -> NSamples { 0 }
+
+    /// Number of times the DSP lacked data to output, such as reader cache underruns.
+    fn underruns(&self) -> usize { 0 }
+
+    /// Return True if the DSP supports in-place processing: graph may then call
+    /// `process_audio` without input, output buffer holding the input.
+    fn in_place(&self) -> bool { false }
+
     /// Return True if the DSP has inputs
     fn is_sink(&self) -> bool { false }
 
     /// Return True if the DSP has outputs
     fn is_source(&self) -> bool { false }
 
+    /// Names of the input buses (e.g. `main` and `sidechain`), the first one being the main
+    /// bus. Parents are connected to one of them, and are summed into its buffer.
+    fn input_buses(&self) -> &'static [&'static str] { &["main"] }
+
+    /// Return True if the DSP receives events
+    fn has_event_input(&self) -> bool { false }
+
+    /// Return True if the DSP outputs events
+    fn has_event_output(&self) -> bool { false }
+
     /// Dry/Wet mix percentage, as 1.0 is full wet, 0.0 is full dry
     fn wet(&self) -> <<Self as DSP>::Sample as Sample>::Float { Self::Sample::identity() }
 }
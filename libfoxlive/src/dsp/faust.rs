@@ -0,0 +1,443 @@
+//! Run DSP generated by Faust's rust backend.
+//!
+//! Faust code is generated at build time from `.dsp` files in `dsp/plugins` (see
+//! `build/faust_generator.rs`), which implements `FaustDsp` for the generated structs.
+//! `FaustDSP` wraps them as graph's `DSP`, adapting Faust's planar buffers, and exposes
+//! widgets declared by `buildUserInterface` as object's fields.
+//!
+//! Widget's metadata (e.g. `[unit:Hz]`, `[scale:log]`) are provided as field's metadatas.
+use std::iter;
+use std::marker::PhantomData;
+
+use smallvec::SmallVec;
+
+use crate::data::{BufferView,NChannels,NSamples,SampleRate};
+use crate::rpc::*;
+use super::dsp::{DSP,DEFAULT_RATE};
+use super::graph::{ProcessScope,MAX_SAMPLES};
+
+
+/// Receive DSP's global metadata.
+pub trait Meta {
+    fn declare(&mut self, key: &'static str, value: &'static str);
+}
+
+
+/// Receive DSP's user interface declaration, as generated by Faust.
+#[allow(non_snake_case)]
+pub trait UI<T> {
+    fn openTabBox(&mut self, label: &'static str);
+    fn openHorizontalBox(&mut self, label: &'static str);
+    fn openVerticalBox(&mut self, label: &'static str);
+    fn closeBox(&mut self);
+
+    fn addButton(&mut self, label: &'static str, zone: &mut T);
+    fn addCheckButton(&mut self, label: &'static str, zone: &mut T);
+    fn addVerticalSlider(&mut self, label: &'static str, zone: &mut T, init: T, min: T, max: T, step: T);
+    fn addHorizontalSlider(&mut self, label: &'static str, zone: &mut T, init: T, min: T, max: T, step: T);
+    fn addNumEntry(&mut self, label: &'static str, zone: &mut T, init: T, min: T, max: T, step: T);
+
+    fn addHorizontalBargraph(&mut self, label: &'static str, zone: &mut T, min: T, max: T);
+    fn addVerticalBargraph(&mut self, label: &'static str, zone: &mut T, min: T, max: T);
+
+    /// Declare metadata for the following widget (`zone`), or box (`zone` being a dummy).
+    fn declare(&mut self, zone: &mut T, key: &'static str, value: &'static str);
+}
+
+
+/// DSP generated by Faust.
+pub trait FaustDsp: 'static+Send {
+    fn metadata(&mut self, m: &mut dyn Meta);
+    fn num_inputs(&mut self) -> i32;
+    fn num_outputs(&mut self) -> i32;
+    /// Initialize DSP for the provided sample rate, resetting its state and controls.
+    fn init(&mut self, sample_rate: i32);
+    fn build_user_interface(&mut self, ui: &mut dyn UI<f32>);
+    fn compute(&mut self, count: i32, inputs: &[&[f32]], outputs: &mut [&mut [f32]]);
+}
+
+
+/// Kind of Faust widget
+#[derive(Copy,Clone,Debug,PartialEq)]
+enum Widget {
+    Button,
+    CheckButton,
+    Slider,
+    NumEntry,
+    Bargraph,
+}
+
+
+/// Widget exposed as an object's field, pointing to DSP's value.
+struct Field {
+    zone: *mut f32,
+    widget: Widget,
+    init: f32,
+    range: (f32,f32,f32),
+    metadatas: Metadatas,
+}
+
+
+/// Collect metadata and widgets of a Faust DSP.
+#[derive(Default)]
+struct Collector {
+    metadatas: Metadatas,
+    fields: Vec<Field>,
+    /// Metadatas declared for the next widget
+    declared: Vec<(*const f32, &'static str, &'static str)>,
+}
+
+
+/// Faust DSP wrapped as a graph's DSP.
+pub struct FaustDSP<D: FaustDsp, PS: ProcessScope> {
+    dsp: Box<D>,
+    /// DSP's metadatas (e.g. "name", "author")
+    metadatas: Metadatas,
+    fields: Vec<Field>,
+    /// Planar buffers of `max_samples` samples
+    inputs: Vec<Vec<f32>>,
+    outputs: Vec<Vec<f32>>,
+    max_samples: NSamples,
+    phantom: PhantomData<PS>,
+}
+
+// Fields' zones point into the boxed DSP, which is owned by the wrapper.
+unsafe impl<D: FaustDsp, PS: ProcessScope> Send for FaustDSP<D,PS> {}
+unsafe impl<D: FaustDsp, PS: ProcessScope> Sync for FaustDSP<D,PS> {}
+
+
+impl Collector {
+    fn add(&mut self, label: &'static str, zone: &mut f32, widget: Widget, init: f32, range: (f32,f32,f32)) {
+        let zone = zone as *mut f32;
+        let mut metadatas = vec![("label", label)];
+        metadatas.extend(self.declared.iter().filter(|(z, _, _)| *z == zone as *const f32)
+                                             .map(|(_, key, value)| (*key, *value)));
+        self.declared.retain(|(z, _, _)| *z != zone as *const f32);
+        if widget == Widget::Bargraph {
+            metadatas.push(("output", "true"));
+        }
+
+        self.fields.push(Field { zone, widget, init, range, metadatas });
+    }
+}
+
+impl Meta for Collector {
+    fn declare(&mut self, key: &'static str, value: &'static str) {
+        self.metadatas.push((key, value));
+    }
+}
+
+impl UI<f32> for Collector {
+    // boxes' metadatas are ignored
+    fn openTabBox(&mut self, _label: &'static str) { self.declared.clear() }
+    fn openHorizontalBox(&mut self, _label: &'static str) { self.declared.clear() }
+    fn openVerticalBox(&mut self, _label: &'static str) { self.declared.clear() }
+    fn closeBox(&mut self) { self.declared.clear() }
+
+    fn addButton(&mut self, label: &'static str, zone: &mut f32) {
+        self.add(label, zone, Widget::Button, 0.0, (0.0, 1.0, 1.0));
+    }
+
+    fn addCheckButton(&mut self, label: &'static str, zone: &mut f32) {
+        self.add(label, zone, Widget::CheckButton, 0.0, (0.0, 1.0, 1.0));
+    }
+
+    fn addVerticalSlider(&mut self, label: &'static str, zone: &mut f32, init: f32, min: f32, max: f32, step: f32) {
+        self.add(label, zone, Widget::Slider, init, (min, max, step));
+    }
+
+    fn addHorizontalSlider(&mut self, label: &'static str, zone: &mut f32, init: f32, min: f32, max: f32, step: f32) {
+        self.add(label, zone, Widget::Slider, init, (min, max, step));
+    }
+
+    fn addNumEntry(&mut self, label: &'static str, zone: &mut f32, init: f32, min: f32, max: f32, step: f32) {
+        self.add(label, zone, Widget::NumEntry, init, (min, max, step));
+    }
+
+    fn addHorizontalBargraph(&mut self, label: &'static str, zone: &mut f32, min: f32, max: f32) {
+        self.add(label, zone, Widget::Bargraph, min, (min, max, 0.0));
+    }
+
+    fn addVerticalBargraph(&mut self, label: &'static str, zone: &mut f32, min: f32, max: f32) {
+        self.add(label, zone, Widget::Bargraph, min, (min, max, 0.0));
+    }
+
+    fn declare(&mut self, zone: &mut f32, key: &'static str, value: &'static str) {
+        self.declared.push((zone as *const f32, key, value));
+    }
+}
+
+
+impl Field {
+    fn value_type(&self) -> ValueType {
+        match self.widget {
+            Widget::Button | Widget::CheckButton => ValueType::Bool,
+            _ => ValueType::F32,
+        }
+    }
+
+    fn get(&self) -> f32 {
+        unsafe { *self.zone }
+    }
+
+    fn set(&self, value: f32) {
+        unsafe { *self.zone = value }
+    }
+}
+
+
+impl<D: FaustDsp+Default, PS: ProcessScope> FaustDSP<D,PS> {
+    /// Create and initialize DSP at `DEFAULT_RATE`.
+    pub fn new() -> Self {
+        Self::from_dsp(Box::new(D::default()))
+    }
+}
+
+impl<D: FaustDsp, PS: ProcessScope> FaustDSP<D,PS> {
+    /// Wrap the provided DSP, initializing it at `DEFAULT_RATE`.
+    pub fn from_dsp(mut dsp: Box<D>) -> Self {
+        dsp.init(DEFAULT_RATE);
+
+        let mut collector = Collector::default();
+        dsp.metadata(&mut collector);
+        dsp.build_user_interface(&mut collector);
+
+        let (n_inputs, n_outputs) = (dsp.num_inputs().max(0) as usize, dsp.num_outputs().max(0) as usize);
+        Self {
+            dsp,
+            metadatas: collector.metadatas,
+            fields: collector.fields,
+            inputs: vec![vec![0.0; MAX_SAMPLES]; n_inputs],
+            outputs: vec![vec![0.0; MAX_SAMPLES]; n_outputs],
+            max_samples: MAX_SAMPLES,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Wrapped DSP
+    pub fn dsp(&self) -> &D {
+        &self.dsp
+    }
+}
+
+
+impl<D: FaustDsp, PS: ProcessScope> Object for FaustDSP<D,PS> {
+    fn object_meta(&self) -> ObjectMeta {
+        let name = self.metadatas.iter().find(|(key, _)| *key == "name")
+                                 .map(|(_, value)| *value).unwrap_or("faust");
+        ObjectMeta::new(name, Some(self.metadatas.clone()))
+    }
+
+    fn get_value(&self, index: ObjectIndex) -> Option<Value> {
+        let field = self.fields.get(index as usize)?;
+        Value::from_f64(field.value_type(), field.get() as f64)
+    }
+
+    fn set_value(&mut self, index: ObjectIndex, value: Value) -> Result<Value, ()> {
+        let field = self.fields.get(index as usize).ok_or(())?;
+        if field.widget == Widget::Bargraph {
+            return Err(());
+        }
+
+        let value = (value.as_f64().ok_or(())? as f32).max(field.range.0).min(field.range.1);
+        field.set(value);
+        Value::from_f64(field.value_type(), value as f64).ok_or(())
+    }
+
+    fn map_object(&self, mapper: &mut dyn ObjectMapper) {
+        for (index, field) in self.fields.iter().enumerate() {
+            let value_type = field.value_type();
+            mapper.declare(FieldInfo {
+                index: index as ObjectIndex,
+                value_type,
+                default: Value::from_f64(value_type, field.init as f64),
+                range: match value_type {
+                    ValueType::Bool => None,
+                    _ => Some(Range::F32(field.range.0, field.range.1, field.range.2)),
+                },
+                metadatas: field.metadatas.clone(),
+            });
+        }
+    }
+}
+
+
+impl<D: FaustDsp, PS: ProcessScope> DSP for FaustDSP<D,PS> {
+    type Sample = f32;
+    type Scope = PS;
+
+    fn prepare(&mut self, rate: SampleRate) {
+        // `init` resets controls: keep their current values
+        let values: Vec<f32> = self.fields.iter().map(|f| f.get()).collect();
+        self.dsp.init(rate);
+        for (field, value) in self.fields.iter().zip(values) {
+            if field.widget != Widget::Bargraph {
+                field.set(value);
+            }
+        }
+    }
+
+    fn set_max_samples(&mut self, max_samples: NSamples) {
+        self.max_samples = max_samples;
+        for buffer in self.inputs.iter_mut().chain(self.outputs.iter_mut()) {
+            buffer.resize(max_samples, 0.0);
+        }
+    }
+
+    fn process_audio(&mut self, _scope: &Self::Scope, input: Option<&dyn BufferView<Sample=Self::Sample>>,
+                     output: Option<&mut dyn BufferView<Sample=Self::Sample>>) -> usize
+    {
+        let n_samples = match (input.as_ref(), output.as_ref()) {
+            (_, Some(output)) => output.n_samples(),
+            (Some(input), None) => input.n_samples(),
+            (None, None) => return 0,
+        };
+        // buffers can't be reallocated here: samples beyond maximum are silent
+        let n_samples = n_samples.min(self.max_samples);
+
+        // Faust uses non-interleaved buffers: copy input channels into them
+        for (channel, buffer) in self.inputs.iter_mut().enumerate() {
+            let buffer = &mut buffer[..n_samples];
+            match input.and_then(|input| input.channel(channel as NChannels)) {
+                Some(samples) => for (dst, src) in buffer.iter_mut().zip(samples.chain(iter::repeat(&0.0))) {
+                    *dst = *src;
+                },
+                None => for dst in buffer.iter_mut() {
+                    *dst = 0.0;
+                },
+            }
+        }
+
+        {
+            let inputs: SmallVec<[&[f32];8]> = self.inputs.iter().map(|b| &b[..n_samples]).collect();
+            let mut outputs: SmallVec<[&mut [f32];8]> = self.outputs.iter_mut()
+                                                            .map(|b| &mut b[..n_samples]).collect();
+            self.dsp.compute(n_samples as i32, &inputs, &mut outputs);
+        }
+
+        match output {
+            Some(output) => {
+                // channels without DSP's output are silent
+                for channel in 0..output.n_channels() {
+                    let buffer = self.outputs.get(channel as usize).map(|b| &b[..n_samples]).unwrap_or(&[]);
+                    if let Some(samples) = output.channel_mut(channel) {
+                        for (dst, src) in samples.zip(buffer.iter().chain(iter::repeat(&0.0))) {
+                            *dst = *src;
+                        }
+                    }
+                }
+                output.len()
+            },
+            None => 0,
+        }
+    }
+
+    fn n_channels(&self) -> NChannels {
+        self.outputs.len().max(self.inputs.len()) as NChannels
+    }
+
+    fn is_sink(&self) -> bool {
+        self.outputs.is_empty()
+    }
+
+    fn is_source(&self) -> bool {
+        self.inputs.is_empty()
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::Buffer;
+    use super::super::block::BlockScope;
+
+    /// Stereo gain, as Faust would generate from:
+    /// `process = par(i, 2, *(hslider("gain[unit:dB]", 1, 0, 2, 0.1)) * (1 - checkbox("mute")));`
+    #[derive(Default)]
+    struct Gain {
+        gain: f32,
+        mute: f32,
+        level: f32,
+        rate: i32,
+    }
+
+    impl FaustDsp for Gain {
+        fn metadata(&mut self, m: &mut dyn Meta) {
+            m.declare("name", "gain");
+        }
+
+        fn num_inputs(&mut self) -> i32 { 2 }
+        fn num_outputs(&mut self) -> i32 { 2 }
+
+        fn init(&mut self, sample_rate: i32) {
+            self.rate = sample_rate;
+            self.gain = 1.0;
+            self.mute = 0.0;
+        }
+
+        fn build_user_interface(&mut self, ui: &mut dyn UI<f32>) {
+            ui.openVerticalBox("gain");
+            ui.declare(&mut self.gain, "unit", "dB");
+            ui.addHorizontalSlider("gain", &mut self.gain, 1.0, 0.0, 2.0, 0.1);
+            ui.addCheckButton("mute", &mut self.mute);
+            ui.addHorizontalBargraph("level", &mut self.level, 0.0, 1.0);
+            ui.closeBox();
+        }
+
+        fn compute(&mut self, count: i32, inputs: &[&[f32]], outputs: &mut [&mut [f32]]) {
+            let gain = self.gain * (1.0 - self.mute);
+            for (input, output) in inputs.iter().zip(outputs.iter_mut()) {
+                for i in 0..count as usize {
+                    output[i] = input[i] * gain;
+                }
+            }
+            self.level = gain;
+        }
+    }
+
+    fn run(dsp: &mut FaustDSP<Gain,BlockScope>, n_samples: NSamples) -> Vec<f32> {
+        let input: Buffer<f32,Vec<f32>> = (true, 2, (0..n_samples*2).map(|i| i as f32).collect()).into();
+        let mut output: Buffer<f32,Vec<f32>> = (true, 2, vec![0.0; n_samples*2]).into();
+        dsp.process_audio(&BlockScope::new(n_samples, 0, None), Some(&input), Some(&mut output));
+        output.buffer
+    }
+
+    /// Test: widgets are collected as fields with their metadatas and ranges
+    #[test]
+    fn fields() {
+        let dsp = FaustDSP::<Gain,BlockScope>::new();
+        assert_eq!(dsp.object_meta().name, "gain");
+        assert_eq!(dsp.n_channels(), 2);
+
+        let mut fields: Vec<FieldInfo> = Vec::new();
+        dsp.map_object(&mut fields);
+        assert_eq!(fields.len(), 3);
+
+        assert_eq!(fields[0].metadatas, vec![("label", "gain"), ("unit", "dB")]);
+        assert!(matches!(fields[0].range, Some(Range::F32(min, max, step)) if min == 0.0 && max == 2.0 && step == 0.1));
+        assert!(matches!(fields[0].default, Some(Value::F32(v)) if v == 1.0));
+        assert!(matches!(fields[1].value_type, ValueType::Bool));
+        assert!(fields[1].range.is_none());
+        assert_eq!(fields[2].metadatas, vec![("label", "level"), ("output", "true")]);
+    }
+
+    /// Test: fields' values are mapped to the DSP, and kept when it is prepared
+    #[test]
+    fn process() {
+        let mut dsp = FaustDSP::<Gain,BlockScope>::new();
+        assert_eq!(run(&mut dsp, 4), vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0]);
+
+        // clamped to range
+        assert!(matches!(dsp.set_value(0, Value::F32(4.0)), Ok(Value::F32(v)) if v == 2.0));
+        dsp.prepare(48000);
+        assert_eq!(dsp.dsp().rate, 48000);
+        assert_eq!(run(&mut dsp, 2), vec![0.0, 2.0, 4.0, 6.0]);
+        assert!(matches!(dsp.get_value(2), Some(Value::F32(v)) if v == 2.0));
+        assert!(dsp.set_value(2, Value::F32(0.0)).is_err());
+
+        assert!(dsp.set_value(1, Value::Bool(true)).is_ok());
+        assert_eq!(run(&mut dsp, 2), vec![0.0; 4]);
+    }
+}
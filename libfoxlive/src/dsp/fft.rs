@@ -0,0 +1,210 @@
+//! Provide a radix-2 FFT used by spectral DSP (convolution, analysis).
+use std::f32::consts::PI;
+use std::ops::{Add,AddAssign,Mul,Sub};
+
+
+/// Complex number
+#[derive(Copy,Clone,Debug,Default,PartialEq)]
+pub struct Complex {
+    pub re: f32,
+    pub im: f32,
+}
+
+/// Window function applied before transform.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Window {
+    Rectangular,
+    Hann,
+    Hamming,
+    Blackman,
+}
+
+/// Precomputed FFT of a given size.
+pub struct Fft {
+    size: usize,
+    /// `exp(-2iπk/size)` for `k` in `0..size/2`
+    twiddles: Vec<Complex>,
+    /// Bit-reversed indices
+    reversed: Vec<usize>,
+}
+
+
+impl Complex {
+    pub fn new(re: f32, im: f32) -> Self {
+        Self { re, im }
+    }
+
+    /// Complex of modulus 1 and the provided argument
+    pub fn from_arg(arg: f32) -> Self {
+        Self { re: arg.cos(), im: arg.sin() }
+    }
+
+    pub fn conj(&self) -> Self {
+        Self { re: self.re, im: -self.im }
+    }
+
+    /// Modulus
+    pub fn norm(&self) -> f32 {
+        self.norm_sqr().sqrt()
+    }
+
+    /// Squared modulus
+    pub fn norm_sqr(&self) -> f32 {
+        self.re * self.re + self.im * self.im
+    }
+}
+
+impl Add for Complex {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        Self { re: self.re + rhs.re, im: self.im + rhs.im }
+    }
+}
+
+impl AddAssign for Complex {
+    fn add_assign(&mut self, rhs: Self) {
+        self.re += rhs.re;
+        self.im += rhs.im;
+    }
+}
+
+impl Sub for Complex {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        Self { re: self.re - rhs.re, im: self.im - rhs.im }
+    }
+}
+
+impl Mul for Complex {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self {
+        Self { re: self.re * rhs.re - self.im * rhs.im,
+               im: self.re * rhs.im + self.im * rhs.re }
+    }
+}
+
+impl Mul<f32> for Complex {
+    type Output = Self;
+
+    fn mul(self, rhs: f32) -> Self {
+        Self { re: self.re * rhs, im: self.im * rhs }
+    }
+}
+
+
+impl Window {
+    /// Window for the provided index, in declaration order.
+    pub fn from_index(index: usize) -> Option<Self> {
+        [Window::Rectangular, Window::Hann, Window::Hamming, Window::Blackman].get(index).copied()
+    }
+
+    /// Window's coefficients for the provided size.
+    pub fn coefs(&self, size: usize) -> Vec<f32> {
+        let n = (size.max(2) - 1) as f32;
+        (0..size).map(|i| {
+            let phase = 2.0 * PI * i as f32 / n;
+            match self {
+                Window::Rectangular => 1.0,
+                Window::Hann => 0.5 - 0.5 * phase.cos(),
+                Window::Hamming => 0.54 - 0.46 * phase.cos(),
+                Window::Blackman => 0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos(),
+            }
+        }).collect()
+    }
+}
+
+
+impl Fft {
+    /// Create FFT for the provided size, which must be a power of two.
+    pub fn new(size: usize) -> Self {
+        assert!(size.is_power_of_two(), "FFT size must be a power of two");
+
+        let bits = size.trailing_zeros();
+        let reversed = (0..size).map(|i| match bits {
+            0 => 0,
+            _ => i.reverse_bits() >> (usize::max_value().count_ones() - bits),
+        }).collect();
+        let twiddles = (0..size/2).map(|k| Complex::from_arg(-2.0 * PI * k as f32 / size as f32))
+                                  .collect();
+        Self { size, twiddles, reversed }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Forward transform, in place.
+    pub fn forward(&self, data: &mut [Complex]) {
+        self.transform(data, false);
+    }
+
+    /// Inverse transform, in place. Result is scaled by `1/size`.
+    pub fn inverse(&self, data: &mut [Complex]) {
+        self.transform(data, true);
+        let scale = 1.0 / self.size as f32;
+        for value in data.iter_mut() {
+            *value = *value * scale;
+        }
+    }
+
+    fn transform(&self, data: &mut [Complex], inverse: bool) {
+        assert_eq!(data.len(), self.size);
+
+        for (i, j) in self.reversed.iter().enumerate() {
+            if i < *j {
+                data.swap(i, *j);
+            }
+        }
+
+        let mut len = 2;
+        while len <= self.size {
+            let (half, stride) = (len / 2, self.size / len);
+            for start in (0..self.size).step_by(len) {
+                for k in 0..half {
+                    let w = match inverse {
+                        true => self.twiddles[k * stride].conj(),
+                        false => self.twiddles[k * stride],
+                    };
+                    let (a, b) = (data[start + k], data[start + k + half] * w);
+                    data[start + k] = a + b;
+                    data[start + k + half] = a - b;
+                }
+            }
+            len *= 2;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test: forward and inverse transform
+    #[test]
+    fn fft() {
+        let fft = Fft::new(16);
+
+        // impulse has a flat spectrum
+        let mut data = vec![Complex::default(); 16];
+        data[0] = Complex::new(1.0, 0.0);
+        fft.forward(&mut data);
+        assert!(data.iter().all(|v| (v.re - 1.0).abs() < 1e-6 && v.im.abs() < 1e-6));
+
+        // cosine at bin 3
+        let input: Vec<Complex> = (0..16).map(|i| Complex::new((2.0 * PI * 3.0 * i as f32 / 16.0).cos(), 0.0))
+                                         .collect();
+        let mut data = input.clone();
+        fft.forward(&mut data);
+        for (bin, value) in data.iter().enumerate() {
+            let expected = if bin == 3 || bin == 13 { 8.0 } else { 0.0 };
+            assert!((value.norm() - expected).abs() < 1e-4);
+        }
+
+        fft.inverse(&mut data);
+        assert!(data.iter().zip(input.iter()).all(|(a, b)| (a.re - b.re).abs() < 1e-5 && a.im.abs() < 1e-5));
+    }
+}
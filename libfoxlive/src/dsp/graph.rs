@@ -1,25 +1,58 @@
+use std::any::Any;
 use std::ops::Deref;
 use std::convert::Into;
 use std::collections::BTreeMap;
-use std::sync::atomic::{AtomicBool,Ordering};
+use std::mem;
+use std::panic::{self,AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool,AtomicI32,AtomicUsize,Ordering};
+use std::time::Instant;
 
 use petgraph as pg;
 use petgraph::stable_graph as sg;
+use petgraph::visit::{EdgeRef,IntoEdgeReferences,NodeIndexable};
+use smallvec::SmallVec;
 
 use crate as libfoxlive;
 use libfoxlive_derive::service;
 use crate::data::*;
-use crate::data::sample::fill_samples;
+use crate::data::sample::{fill_samples,sanitize_samples};
 use crate::rpc::channel::*;
 use crate::rpc::*;
 
+use super::chain::{Chain,SLOT_SHIFT};
 use super::dsp::{DSP,BoxedDSP};
+use super::modulation::{Adsr,Lfo,Matrix,Modulator,Polarity,Route,Shape};
+use super::registry::{Build,Builder,PluginArgs,PluginInfo,Registry};
+use super::release::{Provider,Releaser};
+use super::send::{AuxReturn,AuxSend};
+use super::stats::{EngineLoad,NodeStats,Profiler,Stats,Timing};
+use super::transport::Transport;
+
+
+/// Capacity of events buffers
+pub const EVENTS_CAPACITY: usize = 1024;
+/// Duration of bypass crossfade, in samples
+pub const FADE_SAMPLES: usize = 256;
+/// Default maximum number of samples per block
+pub const MAX_SAMPLES: NSamples = 1024;
+/// Capacity of the queue of removed DSPs waiting to be released
+const RELEASE_CAPACITY: usize = 64;
+/// Maximum number of slots of chains built by fusion
+pub const CHAIN_SLOTS: usize = 16;
+/// Number of spare chains allocated for fusion
+const SPARE_CHAINS: usize = 2;
 
 
 /// Scope passed to graph objects when processing audio
 pub trait ProcessScope : 'static {
     fn n_samples(&self) -> NSamples;
     fn last_frame_time(&self) -> NFrames;
+
+    /// Transport state at the start of the cycle, if any.
+    fn transport(&self) -> Option<Transport> {
+        None
+    }
 }
 
 
@@ -27,20 +60,176 @@ pub trait ProcessScope : 'static {
 pub struct Unit<S,PS>
     where S: 'static+Sync+Sample, PS: 'static+Sync+ProcessScope
 {
-    /// Rendered buffer
-    pub order: usize,
+    /// Position of output buffer in the buffers arena, in channels
+    pub offset: usize,
+    /// Number of channels of output buffer
+    pub channels: NChannels,
+    /// Output buffer is the one of its only parent, processed in place
+    pub in_place: bool,
     /// Wether node have been mapped
     mapped: bool,
     /// Unit is being processing some audio
     pub processing: AtomicBool,
+    /// Output events
+    pub events: EventBuffer,
+    /// Gain applied to output, except for pre-fader edges
+    pub fader: f32,
+    /// Unit is manually bypassed
+    pub bypass: bool,
+    /// Fault detected while processing, which bypasses the unit until cleared
+    pub fault: Option<Fault>,
+    /// Fault has been reported through transport
+    fault_reported: bool,
+    /// Crossfade gain of processed output against bypassed one
+    fade: f32,
+    /// Processing durations, when profiling
+    pub timing: Timing,
+    /// Fields declared by the DSP, mapped when the unit is created. They are grouped by index
+    /// offset, as chains built by fusion take the fields of their slots' units.
+    fields: Fields,
+    /// Sample rate and maximum samples the DSP has been prepared for
+    prepared: Option<(SampleRate, NSamples)>,
     /// Contained dsp
     pub dsp: BoxedDSP<S, PS>,
 }
 
+/// Fields of a unit, as `(offset, fields)`, fields' indices being relative to the offset.
+type Fields = Vec<(ObjectIndex, Vec<FieldInfo>)>;
+
+/// Parts of removed and replaced units sent to the releaser, only held to be dropped. DSPs are
+/// moved across threads as are the graph's requests.
+#[allow(dead_code)]
+struct Released<S,PS>
+    where S: 'static+Sync+Sample, PS: 'static+Sync+ProcessScope
+{
+    dsp: Option<BoxedDSP<S,PS>>,
+    fields: Fields,
+    events: EventBuffer,
+}
+
+unsafe impl<S,PS> Send for Released<S,PS>
+    where S: 'static+Sync+Sample, PS: 'static+Sync+ProcessScope
+{}
+
+/// Unit of an empty chain allocated by the spares' worker, into which nodes are fused.
+struct Spare<S,PS>(Unit<S,PS>)
+    where S: 'static+Sync+Sample, PS: 'static+Sync+ProcessScope;
+
+unsafe impl<S,PS> Send for Spare<S,PS>
+    where S: 'static+Sync+Sample, PS: 'static+Sync+ProcessScope
+{}
+
 pub type Ix = ObjectIndex;
 pub type NodeIndex = sg::NodeIndex<Ix>;
 pub type EdgeIndex = sg::EdgeIndex<Ix>;
-pub type Dag<S,PS> = sg::StableGraph<Unit<S,PS>, (), pg::Directed, Ix>;
+pub type Dag<S,PS> = sg::StableGraph<Unit<S,PS>, Edge, pg::Directed, Ix>;
+
+
+/// Graph's sample rate and maximum samples, shared with builders preparing units for it.
+#[derive(Debug,Default)]
+pub struct GraphFormat {
+    rate: AtomicI32,
+    max_samples: AtomicUsize,
+    channels: AtomicUsize,
+}
+
+impl GraphFormat {
+    /// Sample rate, `0` if not yet known.
+    pub fn rate(&self) -> SampleRate {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    /// Maximum number of samples per block.
+    pub fn max_samples(&self) -> NSamples {
+        self.max_samples.load(Ordering::Relaxed)
+    }
+
+    /// Maximum number of channels of nodes.
+    pub fn channels(&self) -> NChannels {
+        self.channels.load(Ordering::Relaxed) as NChannels
+    }
+}
+
+
+/// Iterate over fields, with their absolute index.
+fn iter_fields(fields: &Fields) -> impl Iterator<Item=(ObjectIndex, &FieldInfo)> {
+    fields.iter().flat_map(|(offset, fields)| fields.iter().map(move |field| (offset | field.index, field)))
+}
+
+
+impl<S,PS> From<Unit<S,PS>> for Released<S,PS>
+    where S: 'static+Sync+Sample, PS: 'static+Sync+ProcessScope
+{
+    fn from(unit: Unit<S,PS>) -> Self {
+        Released { dsp: Some(unit.dsp), fields: unit.fields, events: unit.events }
+    }
+}
+
+
+impl<S,PS> Spare<S,PS>
+    where S: 'static+Sync+Sample, PS: 'static+Sync+ProcessScope
+{
+    /// Allocate a spare chain for the provided graph's format.
+    fn new(format: &GraphFormat) -> Self {
+        let chain = Chain::with_capacity(format.channels(), CHAIN_SLOTS, format.rate(), format.max_samples());
+        let mut unit = Unit::new(Box::new(chain));
+        unit.fields = Vec::with_capacity(CHAIN_SLOTS);
+        unit.events = EventBuffer::with_capacity(EVENTS_CAPACITY);
+        Spare(unit)
+    }
+}
+
+
+/// Fault detected while processing a node.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Fault {
+    /// DSP panicked
+    Panic,
+    /// DSP output NaN or infinite samples
+    NonFinite,
+}
+
+/// Fault of a node, as reported through graph's transport.
+#[derive(Copy,Clone,Debug)]
+pub struct NodeFault {
+    pub node: NodeIndex,
+    pub fault: Fault,
+}
+
+
+/// Graph edge, connecting parent's output to one of child's input buses.
+#[derive(Copy,Clone,Debug,Default)]
+pub struct Edge {
+    /// Index of child's input bus
+    pub bus: usize,
+    /// Take parent's output before its fader
+    pub pre_fader: bool,
+}
+
+
+/// Allocator of ranges of channels in the buffers arena.
+#[derive(Default)]
+struct Arena {
+    /// Free ranges as (offset, channels), sorted by offset
+    free: Vec<(usize, usize)>,
+    /// Total number of channels
+    size: usize,
+}
+
+
+/// Memory used to sort nodes and allocate their buffers, indexed by node index. It is reserved
+/// when nodes are added so that `Graph::updated()` doesn't allocate.
+#[derive(Default)]
+struct Schedule {
+    /// Number of parents not yet sorted while sorting, then position in processing order
+    positions: Vec<usize>,
+    /// Allocated buffer range as `(offset, channels)`
+    ranges: Vec<Option<(usize, usize)>>,
+    /// Nodes holding a buffer, with the position of their last child
+    releases: Vec<(usize, NodeIndex)>,
+    /// Nodes of the run being fused, but its head
+    run: Vec<NodeIndex>,
+}
 
 
 /// Audio graph processing directed acyclic DSP nodes.
@@ -53,12 +242,44 @@ pub struct Graph<S,PS>
     ordered_nodes: Vec<NodeIndex>,
     /// Max number of channels supported by nodes
     n_channels: NChannels,
-    /// Buffer arena used to store nodes outputs.
+    /// Max number of input buses of nodes
+    n_buses: usize,
+    /// Sample rate, `0` if not yet known
+    rate: SampleRate,
+    /// Maximum number of samples per block, for which buffers are allocated
+    max_samples: NSamples,
+    /// Catch panics and check nodes' output for non-finite values and denormals
+    guard: bool,
+    /// Fuse linear runs of nodes into chains when updated
+    fuse: bool,
+    /// Buffer arena used to store nodes outputs, allocated by `updated()`.
     buffers: Vec<S>,
-    /// A temporary buffer used in processing
-    dry_buffer: Buffer<S,Vec<S>>,
+    /// Allocator of nodes' output buffers in `buffers`
+    arena: Arena,
+    /// Memory used by `updated()`
+    schedule: Schedule,
+    /// Temporary buffers used in processing, one for each input bus.
+    dry_buffers: Vec<Buffer<S,Vec<S>>>,
+    /// Number of channels and samples of the dry buffers
+    dry_size: (NChannels, NSamples),
+    /// Input events of the node being processed
+    dry_events: EventBuffer,
+    /// Sample rate and maximum samples, shared with builders
+    format: Arc<GraphFormat>,
+    /// Empty chains allocated for graph's format, into which nodes are fused
+    spares: Provider<Spare<S,PS>>,
+    /// Some runs of nodes have not been fused for lack of a spare chain
+    unfused: bool,
+    /// Return buses by name
+    buses: BTreeMap<String, NodeIndex>,
+    /// Modulators routed to nodes' fields
+    modulation: Matrix,
+    /// DSP load profiler
+    profiler: Profiler,
     /// Node objects values map
     objects_map: BTreeMap<ObjectIndex, (NodeIndex,FieldInfo)>,
+    /// Removed and replaced DSPs, dropped outside of the audio thread
+    releaser: Releaser<Released<S,PS>>,
     /// Events transport broadcasting responses to all receivers (this allows to have a pubsub
     /// without the cost of multiple event queues).
     transport: Option<BroadcastChannel<service::Response<S,PS>,service::Request<S,PS>>>,
@@ -68,21 +289,110 @@ pub struct Graph<S,PS>
 impl<S,PS> Unit<S,PS>
     where S: 'static+Sync+Sample, PS: 'static+Sync+ProcessScope
 {
-    /// Create a new unit
-    fn new(dsp: BoxedDSP<S, PS>) -> Self
+    /// Create a new unit, mapping DSP's fields and allocating its events buffer. Units are
+    /// created outside of the audio thread, then added to the graph through its service.
+    pub fn new(dsp: BoxedDSP<S, PS>) -> Self
     {
+        let events = match dsp.has_event_output() {
+            true => EventBuffer::with_capacity(EVENTS_CAPACITY),
+            false => EventBuffer::with_capacity(0),
+        };
+        let mut fields = Vec::new();
+        dsp.map_object(&mut fields);
+        let fields = vec![(0, fields)];
+
         Unit {
-            order: 0,
+            offset: 0,
+            channels: 0,
+            in_place: false,
             mapped: false,
             processing: AtomicBool::new(false),
+            events: events,
+            fader: 1.0,
+            bypass: false,
+            fault: None,
+            fault_reported: false,
+            fade: 1.0,
+            timing: Timing::default(),
+            fields,
+            prepared: None,
             dsp: dsp,
         }
     }
 
+    /// Prepare DSP for graph's sample rate, when known, and maximum number of samples. This
+    /// may allocate: it is done before the unit is added to the graph (see `Builder`).
+    pub fn prepare(&mut self, rate: SampleRate, max_samples: NSamples) {
+        if rate > 0 {
+            self.dsp.prepare(rate);
+        }
+        self.dsp.set_max_samples(max_samples);
+        self.prepared = Some((rate, max_samples));
+    }
+
+    /// Return DSP's field info.
+    pub fn field(&self, index: ObjectIndex) -> Option<&FieldInfo> {
+        iter_fields(&self.fields).find(|(i, _)| *i == index).map(|(_, field)| field)
+    }
+
+    /// Return true if DSP is a chain.
+    fn is_chain(&self) -> bool {
+        (&*self.dsp as &dyn Any).is::<Chain<S,PS>>()
+    }
+
     /// Get buffer slice in the provided buffers arena
-    fn buffer<'a>(&self, buffers: &'a mut Vec<S>, buffer_len: usize) -> SliceBuffer<'a,S> {
-        let pos = self.order * buffer_len;
-        (true,self.dsp.n_channels(),&mut buffers[pos..pos+buffer_len]).into()
+    fn buffer<'a>(&self, buffers: &'a mut Vec<S>, n_samples: NSamples) -> SliceBuffer<'a,S> {
+        let pos = self.offset * n_samples;
+        let len = self.channels as usize * n_samples;
+        (true,self.channels,&mut buffers[pos..pos+len]).into()
+    }
+
+    /// Copy unit's buffer allocation from another unit.
+    fn copy_allocation(&mut self, other: &Self) {
+        self.offset = other.offset;
+        self.channels = other.channels;
+        self.in_place = other.in_place;
+    }
+
+    /// Return true if unit is neither bypassed nor faulty.
+    pub fn is_active(&self) -> bool {
+        !self.bypass && self.fault.is_none()
+    }
+
+    /// Process buses, catching panics when `guard` is true.
+    fn process_guarded(&mut self, guard: bool, scope: &PS, inputs: &[&dyn BufferView<Sample=S>],
+                     output: Option<&mut dyn BufferView<Sample=S>>) -> Result<usize, Fault>
+    {
+        if !guard {
+            return Ok(self.dsp.process_buses(scope, inputs, output));
+        }
+
+        let dsp = &mut self.dsp;
+        panic::catch_unwind(AssertUnwindSafe(|| dsp.process_buses(scope, inputs, output)))
+            .map_err(|_| Fault::Panic)
+    }
+
+    /// Crossfade processed output with input (or silence) while fading in or out of bypass.
+    fn crossfade(&mut self, output: &mut dyn BufferView<Sample=S>, input: Option<&dyn BufferView<Sample=S>>) {
+        let target = if self.is_active() { 1.0 } else { 0.0 };
+        if self.fade == target {
+            return;
+        }
+
+        let n_channels = (output.n_channels() as usize).max(1);
+        let step = (if target > self.fade { 1.0 } else { -1.0 }) / FADE_SAMPLES as f32;
+        let dry = input.map(|input| input.as_slice());
+        for (i, frame) in output.as_slice_mut().chunks_mut(n_channels).enumerate() {
+            let fade = (self.fade + step * i as f32).max(0.0).min(1.0);
+            let (wet, dry_gain): (S::Float, S::Float) = (fade.to_sample(), (1.0 - fade).to_sample());
+            for (c, sample) in frame.iter_mut().enumerate() {
+                let d = dry.and_then(|d| d.get(i * n_channels + c)).copied().unwrap_or(S::equilibrium());
+                *sample = sample.mul_amp(wet).add_amp(d.mul_amp(dry_gain).to_signed_sample());
+            }
+        }
+
+        let n_frames = output.as_slice().len() / n_channels;
+        self.fade = (self.fade + step * n_frames as f32).max(0.0).min(1.0);
     }
 
     /*fn process_audio(&mut self, scope: &PS, input: Option<&dyn BufferView<Sample=S>>) {
@@ -111,6 +421,47 @@ impl<S,PS> Deref for Unit<S,PS>
 }
 
 
+impl Arena {
+    /// Release all ranges
+    fn clear(&mut self) {
+        self.free.clear();
+        self.size = 0;
+    }
+
+    /// Allocate a range of channels, returning `(offset, channels)`.
+    fn alloc(&mut self, channels: usize) -> (usize, usize) {
+        match self.free.iter().position(|(_, len)| *len >= channels) {
+            Some(i) => {
+                let (offset, len) = self.free[i];
+                match len == channels {
+                    true => { self.free.remove(i); },
+                    false => self.free[i] = (offset + channels, len - channels),
+                }
+                (offset, channels)
+            },
+            None => {
+                self.size += channels;
+                (self.size - channels, channels)
+            },
+        }
+    }
+
+    /// Release an allocated range, merging it with adjacent free ranges.
+    fn release(&mut self, range: (usize, usize)) {
+        let at = self.free.iter().position(|(offset, _)| *offset > range.0).unwrap_or(self.free.len());
+        self.free.insert(at, range);
+        if at + 1 < self.free.len() && self.free[at].0 + self.free[at].1 == self.free[at+1].0 {
+            self.free[at].1 += self.free[at+1].1;
+            self.free.remove(at + 1);
+        }
+        if at > 0 && self.free[at-1].0 + self.free[at-1].1 == self.free[at].0 {
+            self.free[at-1].1 += self.free[at].1;
+            self.free.remove(at);
+        }
+    }
+}
+
+
 unsafe impl<S,PS> Sync for Graph<S,PS>
     where S: 'static+Sync+Sample, PS: 'static+Sync+ProcessScope+Clone
 {}
@@ -127,17 +478,48 @@ impl<S,PS> Graph<S,PS>
         Graph::with_capacity(0,0)
     }
 
-    /// Create a new `Graph` with capacity for the provided nodes and edges.
+    /// Create a new `Graph` with capacity for the provided nodes and edges. Memory used to
+    /// schedule and process them is reserved, so that adding up to `nodes` nodes doesn't
+    /// allocate on the audio thread (see also `reserve_channels`).
     pub fn with_capacity(nodes: usize, edges: usize) -> Graph<S, PS> {
-        Graph {
+        let format = Arc::new(GraphFormat::default());
+        format.max_samples.store(MAX_SAMPLES, Ordering::Relaxed);
+        let mut graph = Graph {
             dag: Dag::with_capacity(nodes, edges),
             ordered_nodes: Vec::with_capacity(nodes),
             n_channels: 0,
+            n_buses: 1,
+            rate: 0,
+            max_samples: MAX_SAMPLES,
+            guard: false,
+            fuse: false,
             buffers: Vec::new(),
-            dry_buffer: Buffer::with_capacity(true, 2, 1024),
+            arena: Arena::default(),
+            schedule: Schedule::default(),
+            dry_buffers: vec![Buffer::with_capacity(true, 2, MAX_SAMPLES)],
+            dry_size: (2, MAX_SAMPLES),
+            dry_events: EventBuffer::with_capacity(EVENTS_CAPACITY),
+            format: format.clone(),
+            spares: Provider::new(SPARE_CHAINS, move || Spare::new(&format)),
+            unfused: false,
+            buses: BTreeMap::new(),
+            modulation: Matrix::new(),
+            profiler: Profiler::new(),
             objects_map: BTreeMap::new(),
+            releaser: Releaser::new(RELEASE_CAPACITY),
             transport: None,
-        }
+        };
+        graph.schedule.run.reserve(CHAIN_SLOTS);
+        graph.reserve();
+        graph
+    }
+
+    /// Reserve buffers for nodes of up to `n_channels` channels and `n_buses` input buses, so
+    /// that adding such nodes doesn't allocate on the audio thread.
+    pub fn reserve_channels(&mut self, n_channels: NChannels, n_buses: usize) {
+        self.n_channels = self.n_channels.max(n_channels);
+        self.n_buses = self.n_buses.max(n_buses);
+        self.reserve();
     }
 
     /// Init event channel, returning other channel of the channel
@@ -153,6 +535,74 @@ impl<S,PS> Graph<S,PS>
         Some(b)
     }
 
+    /// Create the ringbuffer statistics are streamed to (see `set_stats_interval`), replacing
+    /// the previous one. Its capacity should hold engine's load and the statistics of all nodes.
+    pub fn stats_stream(&mut self, capacity: usize) -> ringbuf::Consumer<Stats> {
+        self.profiler.stream(capacity)
+    }
+
+    /// Graph's sample rate, `0` if not yet known.
+    pub fn rate(&self) -> SampleRate {
+        self.rate
+    }
+
+    /// Set sample rate, preparing all nodes for it.
+    pub fn set_rate(&mut self, rate: SampleRate) {
+        if rate == self.rate {
+            return;
+        }
+
+        self.rate = rate;
+        self.format.rate.store(rate, Ordering::Relaxed);
+        self.modulation.prepare(rate);
+        for node in self.dag.node_weights_mut() {
+            node.dsp.prepare(rate);
+        }
+    }
+
+    /// Maximum number of samples per block.
+    pub fn max_samples(&self) -> NSamples {
+        self.max_samples
+    }
+
+    /// Set maximum number of samples per block (e.g. driver's buffer size), reallocating
+    /// buffers. Samples of larger blocks beyond the maximum are not processed.
+    pub fn set_max_samples(&mut self, max_samples: NSamples) {
+        if max_samples != self.max_samples {
+            self.max_samples = max_samples;
+            self.format.max_samples.store(max_samples, Ordering::Relaxed);
+            for node in self.dag.node_weights_mut() {
+                node.dsp.set_max_samples(max_samples);
+            }
+            self.reserve();
+            self.allocate_buffers();
+        }
+    }
+
+    /// Counter of driver's xruns, to be incremented by the driver (e.g. from JACK's xrun
+    /// callback).
+    pub fn xrun_counter(&self) -> Arc<AtomicUsize> {
+        self.profiler.xrun_counter()
+    }
+
+    /// Return true if nodes are guarded against panics and non-finite output.
+    pub fn guard(&self) -> bool {
+        self.guard
+    }
+
+    /// Return true if linear runs of nodes are fused into chains.
+    pub fn fuse(&self) -> bool {
+        self.fuse
+    }
+
+    /// Return a client building requests' DSP and plugins from `registry` for this graph,
+    /// then sending them using `client`.
+    pub fn builder<C>(&self, registry: Arc<Registry<S,PS>>, client: C) -> Builder<S,PS,C>
+        where C: service::Client<S,PS>
+    {
+        Builder::new(registry, self.format.clone(), client)
+    }
+
     /// Return node for the provided index.
     pub fn node(&self, index: NodeIndex) -> Option<&Unit<S,PS>> {
         self.dag.node_weight(index)
@@ -170,9 +620,16 @@ impl<S,PS> Graph<S,PS>
 
     /// Process graph nodes
     pub fn process_nodes(&mut self, scope: &PS) {
-        let buffer_len = scope.n_samples() * self.n_channels as usize;
-        self.buffers.resize(buffer_len * self.ordered_nodes.len(), S::equilibrium());
-        let mut order = 0;
+        // buffers can't be reallocated here: larger blocks are truncated
+        let n_samples = scope.n_samples().min(self.max_samples);
+
+        let start = match self.profiler.enabled {
+            true => Some(Instant::now()),
+            false => None,
+        };
+
+        let transport = scope.transport();
+        self.modulation.process(n_samples, transport.as_ref());
 
         for node_index in self.ordered_nodes.iter() {
             let node_index = *node_index;
@@ -185,78 +642,488 @@ impl<S,PS> Graph<S,PS>
 
             let node = node.unwrap();
             node.processing.store(true, Ordering::Relaxed);
+            let node_start = start.map(|_| Instant::now());
 
-            // ensure buffer size
-            let input =
-                // Source: no need to process inputs nodes
-                if node.is_source() {
-                    None
+            // gather input events
+            let has_events = node.has_event_input() || node.has_event_output();
+            self.dry_events.clear();
+            if node.has_event_input() {
+                let inputs = self.dag.neighbors_directed(node_index, pg::Direction::Incoming);
+                for input in inputs {
+                    if let Some(input) = self.dag.node_weight(input) {
+                        self.dry_events.merge(&input.events);
+                    }
                 }
+            }
+
+            // gather input buffers, one per input bus
+            let n_buses = match node.is_source() {
+                // Source: no need to process inputs nodes
+                true => 0,
                 // Filters and sink
-                else {
-                    let buffer = &mut self.dry_buffer;
-                    buffer.resize(node.n_channels(), scope.n_samples());
-                    buffer.fill(S::equilibrium());
-
-                    // gather input buffers
-                    let inputs = self.dag.neighbors_directed(node_index, pg::Direction::Incoming);
-                    for input in inputs {
-                        // take input if not removed
-                        if let Some(input) = self.dag.node_weight(input) {
-                            let node_buffer = input.buffer(&mut self.buffers, buffer_len);
-                            buffer.merge_inplace(&node_buffer);
-                        }
+                false => node.input_buses().len().max(1).min(self.dry_buffers.len()),
+            };
+
+            for buffer in self.dry_buffers[..n_buses].iter_mut() {
+                buffer.resize(node.channels, n_samples);
+                buffer.fill(S::equilibrium());
+            }
+
+            // in place: output buffer already holds the only parent's output, to which its
+            // fader is applied. It is copied as input when processing needs it.
+            let in_place = node.in_place && n_buses > 0;
+            let direct = in_place && node.is_active() && node.fade == 1.0 && node.wet() == S::identity();
+            if in_place {
+                if let Some(edge) = self.dag.edges_directed(node_index, pg::Direction::Incoming).next() {
+                    match self.dag.node_weight(edge.source()) {
+                        Some(parent) if !edge.weight().pre_fader && parent.fader != 1.0 => {
+                            let fader: S::Float = parent.fader.to_sample();
+                            node.buffer(&mut self.buffers, n_samples).map_inplace(&|_, s| s.mul_amp(fader));
+                        },
+                        _ => {},
                     }
+                }
+                if !direct {
+                    self.dry_buffers[0].copy_inplace(&node.buffer(&mut self.buffers, n_samples));
+                }
+            }
+            else if n_buses > 0 {
+                for edge in self.dag.edges_directed(node_index, pg::Direction::Incoming) {
+                    let Edge { bus, pre_fader } = *edge.weight();
+                    // take input if not removed
+                    match self.dag.node_weight(edge.source()) {
+                        Some(input) if bus < n_buses => {
+                            let node_buffer = input.buffer(&mut self.buffers, n_samples);
+                            match pre_fader || input.fader == 1.0 {
+                                true => self.dry_buffers[bus].merge_inplace(&node_buffer),
+                                false => {
+                                    let fader: S::Float = input.fader.to_sample();
+                                    self.dry_buffers[bus].zip_map_inplace(&node_buffer,
+                                        &|a,b| a.add_amp(b.mul_amp(fader).to_signed_sample()));
+                                },
+                            }
+                        },
+                        _ => {},
+                    }
+                }
+            }
 
-                    Some(&self.dry_buffer as &dyn BufferView<Sample=S>)
-                };
+            let n_inputs = if direct { 0 } else { n_buses };
+            let inputs: SmallVec<[&dyn BufferView<Sample=S>; 4]> =
+                self.dry_buffers[..n_inputs].iter().map(|b| b as &dyn BufferView<Sample=S>).collect();
+            let input = inputs.first().map(|b| *b);
 
             // process node
+            let guard = self.guard;
             let mut node = self.dag.node_weight_mut(node_index).expect("");
-            node.order = order;
-            if node.is_sink() {
-                node.dsp.process_audio(scope, input, None);
+
+            // apply modulations, as events when supported
+            for (route, value) in self.modulation.node_values(node_index) {
+                match node.has_event_input() {
+                    true => { self.dry_events.push(Event::new(0, EventData::Control(route.field, value))); },
+                    false => if let Some(value) = Value::from_f64(route.value_type, value) {
+                        node.dsp.set_value(route.field, value).ok();
+                    },
+                }
+            }
+
+            if has_events {
+                node.events.clear();
+                match node.is_active() {
+                    true => node.dsp.process_events(scope, &self.dry_events, &mut node.events),
+                    false => node.events.merge(&self.dry_events),
+                }
+            }
+
+            // bypassed: input passes through once crossfade is over
+            if !node.is_active() && node.fade == 0.0 {
+                if !node.is_sink() {
+                    let mut node_buffer = node.buffer(&mut self.buffers, n_samples);
+                    node_buffer.fill(S::equilibrium());
+                    if let Some(input) = input {
+                        node_buffer.copy_inplace(input);
+                    }
+                }
+            }
+            else if node.is_sink() {
+                if let Err(fault) = node.process_guarded(guard, scope, &inputs, None) {
+                    node.fault = Some(fault);
+                }
             }
             else {
-                let mut node_buffer = node.buffer(&mut self.buffers, buffer_len);
+                let mut node_buffer = node.buffer(&mut self.buffers, n_samples);
 
-                let n = node.dsp.process_audio(scope, input, Some(&mut node_buffer));
+                let n = match node.process_guarded(guard, scope, &inputs, Some(&mut node_buffer)) {
+                    Ok(n) => n,
+                    Err(fault) => {
+                        node.fault = Some(fault);
+                        0
+                    },
+                };
                 fill_samples(&mut node_buffer.as_slice_mut()[n..], S::equilibrium());
+                if guard && sanitize_samples(node_buffer.as_slice_mut()) && node.fault.is_none() {
+                    node.fault = Some(Fault::NonFinite);
+                }
 
                 if input.is_some() && node.wet() != S::identity() {
                     let input = input.unwrap();
                     let (dry, wet) = (-node.wet(), node.wet());
                     node_buffer.zip_map_inplace(input, &|a,b| a.mul_amp(wet).add_amp(b.mul_amp(dry).to_signed_sample()));
                 }
+                node.crossfade(&mut node_buffer, input);
+            }
+
+            if node.is_sink() {
+                node.fade = if node.is_active() { 1.0 } else { 0.0 };
+            }
+
+            // report fault
+            if node.fault.is_some() && !node.fault_reported {
+                node.fault_reported = true;
+                let fault = NodeFault { node: node_index, fault: node.fault.unwrap() };
+                rt_warn!("node {:?} bypassed after fault: {:?}", node_index, fault.fault);
+                if let Some(transport) = self.transport.as_mut() {
+                    transport.sender.try_send(service::Response::Fault(Some(fault))).ok();
+                }
+            }
+
+            if let Some(node_start) = node_start {
+                node.timing.add(node_start.elapsed());
             }
             node.processing.store(false, Ordering::Relaxed);
-            order += 1;
+        }
+
+        // stream statistics
+        if let Some(start) = start {
+            let rate = transport.map(|t| t.rate).filter(|rate| *rate > 0).unwrap_or(self.rate);
+            if self.profiler.block(start.elapsed(), n_samples, rate) && self.profiler.is_streaming() {
+                let load = self.profiler.load();
+                self.profiler.send(Stats::Load(load));
+                for index in self.ordered_nodes.iter() {
+                    if let Some(unit) = self.dag.node_weight(*index) {
+                        self.profiler.send(Stats::Node(NodeStats::new(*index, &unit.timing, unit.underruns())));
+                    }
+                }
+            }
         }
     }
 
     /// Notify graph that it has been updated after changes have been made.
+    ///
+    /// It doesn't allocate as long as nodes, their channels and input buses fit in reserved
+    /// memory (see `with_capacity` and `reserve_channels`).
     pub fn updated(&mut self) {
-        self.ordered_nodes = pg::algo::toposort(&self.dag, None)
-                                 .expect("cycles are not allowed");
+        self.reserve();
+        self.sort_nodes();
+        if self.fuse {
+            self.unfused = !self.fuse_nodes();
+            self.sort_nodes();
+        }
+        self.allocate_buffers();
+    }
+
+    /// Reserve memory used by `updated()` and processing for the current nodes, or graph's
+    /// capacity if larger. It only allocates when nodes beyond it have been added or changed
+    /// their number of channels.
+    fn reserve(&mut self) {
+        fn reserve_total<T>(vec: &mut Vec<T>, total: usize) {
+            if vec.capacity() < total {
+                vec.reserve(total - vec.len());
+            }
+        }
+
+        let capacity = self.dag.capacity().0;
+        let (bound, count) = (self.dag.node_bound().max(capacity), self.dag.node_count().max(capacity));
+        let n_channels = self.dag.node_indices().filter_map(|index| self.dag.node_weight(index))
+                             .map(|node| node.n_channels()).max().unwrap_or(0);
+        self.n_channels = self.n_channels.max(n_channels);
+        self.format.channels.store(self.n_channels as usize, Ordering::Relaxed);
+
+        reserve_total(&mut self.ordered_nodes, count);
+        reserve_total(&mut self.arena.free, count);
+        reserve_total(&mut self.schedule.releases, count);
+        if self.schedule.positions.len() < bound {
+            self.schedule.positions.resize(bound, 0);
+            self.schedule.ranges.resize(bound, None);
+        }
+
+        // nodes' buffers, without reuse
+        let n_channels = self.n_channels.max(1) as usize;
+        reserve_total(&mut self.buffers, count * n_channels * self.max_samples);
+
+        // one dry buffer per input bus
+        let n_buses = self.dag.node_indices()
+                          .filter_map(|index| self.dag.node_weight(index))
+                          .map(|node| node.input_buses().len())
+                          .max().unwrap_or(1).max(self.n_buses);
+        self.n_buses = n_buses;
+        let (n_channels, max_samples) = (self.n_channels.max(1), self.max_samples);
+        if self.dry_buffers.len() < n_buses || self.dry_size != (n_channels, max_samples) {
+            self.dry_buffers = (0..n_buses).map(|_| Buffer::with_capacity(true, n_channels, max_samples))
+                                           .collect();
+            self.dry_size = (n_channels, max_samples);
+        }
+    }
+
+    /// Sort nodes topologically into `ordered_nodes`.
+    fn sort_nodes(&mut self) {
+        let dag = &self.dag;
+        let (ordered, parents) = (&mut self.ordered_nodes, &mut self.schedule.positions);
+        for count in parents.iter_mut() {
+            *count = 0;
+        }
+        for edge in dag.edge_references() {
+            parents[edge.target().index()] += 1;
+        }
+
+        // ordered nodes are also the queue of nodes whose parents are all sorted
+        ordered.clear();
+        ordered.extend(dag.node_indices().filter(|index| parents[index.index()] == 0));
+        let mut i = 0;
+        while i < ordered.len() {
+            for child in dag.neighbors_directed(ordered[i], pg::Direction::Outgoing) {
+                parents[child.index()] -= 1;
+                if parents[child.index()] == 0 {
+                    ordered.push(child);
+                }
+            }
+            i += 1;
+        }
+        assert!(ordered.len() == dag.node_count(), "cycles are not allowed");
     }
 
-    /// Process all available events at once.
+    /// Allocate nodes' output buffers in the arena.
+    ///
+    /// Buffers are sized to their node's number of channels. A buffer is reused once all of
+    /// its node's children have been processed. Nodes supporting in-place processing take
+    /// the buffer of their only parent when they are its only child.
+    fn allocate_buffers(&mut self) {
+        let Schedule { positions, ranges, releases, .. } = &mut self.schedule;
+        let (dag, arena) = (&mut self.dag, &mut self.arena);
+        for (i, index) in self.ordered_nodes.iter().enumerate() {
+            positions[index.index()] = i;
+        }
+        for range in ranges.iter_mut() {
+            *range = None;
+        }
+        releases.clear();
+        arena.clear();
+
+        for (i, index) in self.ordered_nodes.iter().enumerate() {
+            let index = *index;
+            let unit = match dag.node_weight(index) {
+                Some(unit) => unit,
+                None => continue,
+            };
+
+            let channels = match unit.n_channels() {
+                0 => self.n_channels.max(1),
+                n => n,
+            };
+            let last_use = dag.neighbors_directed(index, pg::Direction::Outgoing)
+                               .map(|child| positions[child.index()])
+                               .max().unwrap_or(i);
+
+            let mut edges = dag.edges_directed(index, pg::Direction::Incoming);
+            let parent = match (edges.next(), edges.next()) {
+                (Some(edge), None) if edge.weight().bus == 0 => Some(edge.source()),
+                _ => None,
+            };
+            let in_place = unit.dsp.in_place() && !unit.is_source() && !unit.is_sink() &&
+                parent.map(|parent| dag.neighbors_directed(parent, pg::Direction::Outgoing).count() == 1 &&
+                                    ranges[parent.index()].map(|r| r.1 == channels as usize).unwrap_or(false))
+                      .unwrap_or(false);
+
+            let range = match (in_place, unit.is_sink()) {
+                (true, _) => ranges[parent.unwrap().index()].take(),
+                (false, true) => None,
+                (false, false) => Some(arena.alloc(channels as usize)),
+            };
+            if let Some(range) = range {
+                ranges[index.index()] = Some(range);
+                releases.push((last_use, index));
+            }
+
+            let mut j = 0;
+            while j < releases.len() {
+                match releases[j].0 == i {
+                    true => if let Some(range) = ranges[releases.swap_remove(j).1.index()].take() {
+                        arena.release(range);
+                    },
+                    false => j += 1,
+                }
+            }
+
+            let unit = dag.node_weight_mut(index).expect("node exists");
+            unit.offset = range.map(|(offset, _)| offset).unwrap_or(0);
+            unit.channels = channels;
+            unit.in_place = in_place;
+        }
+
+        self.buffers.clear();
+        self.buffers.resize(arena.size * self.max_samples, S::equilibrium());
+    }
+
+    /// Process all available events at once. Graph is updated only when requests changed its
+    /// topology or channel layout, or when runs of nodes are waiting for a spare chain to be
+    /// fused into.
     pub fn process_requests(&mut self) {
-        // FIXME: here nodes_updated detection
-        let nodes_updated = true;
+        let mut nodes_updated = false;
 
         while let Ok(Some(request)) = self.transport.as_mut().unwrap().receiver.try_recv() {
+            nodes_updated |= Self::updates_layout(&request);
             let r = self.process_request(request);
             if let Some(r) = r {
                 self.transport.as_mut().unwrap().sender.try_send(r);
             }
         }
 
-        if nodes_updated {
+        if nodes_updated || (self.unfused && self.spares.is_ready()) {
             self.updated();
         }
     }
 
+    /// Return true if request changes graph's topology or nodes' channels, which requires
+    /// to update it.
+    fn updates_layout(request: &service::Request<S,PS>) -> bool {
+        use self::service::Request::*;
+        matches!(request, AddNode(..) | AddChild(..) | AddEdge(..) | AddBusEdge(..) | AddSend(..) |
+                          AddPlugin(..) | ReplaceNode(..) | SetFuse(..) | RemoveNode(..) | RemoveEdge(..) |
+                          DisconnectNodes(..))
+    }
+
+    /// Return the only child of node if they can be fused into a chain.
+    ///
+    /// Node must be active, fully wet, at unity gain, have a single input bus and a single edge
+    /// to child's main bus: a chain only exposes the main bus of its first slot. Child must be an
+    /// active and fully wet filter with the same number of channels, having node as only parent,
+    /// and not be a return bus. Neither can already be a chain.
+    fn fusable_child(&self, node: NodeIndex) -> Option<NodeIndex> {
+        let unit = self.dag.node_weight(node)?;
+        if unit.is_sink() || !unit.is_active() || unit.fade != 1.0 || unit.fader != 1.0 ||
+           unit.wet() != S::identity() || unit.input_buses().len() != 1 || unit.is_chain()
+        {
+            return None;
+        }
+
+        let mut edges = self.dag.edges_directed(node, pg::Direction::Outgoing);
+        let edge = match (edges.next(), edges.next()) {
+            (Some(edge), None) if edge.weight().bus == 0 && !edge.weight().pre_fader => edge,
+            _ => return None,
+        };
+
+        let child = edge.target();
+        let child_unit = self.dag.node_weight(child)?;
+        let fusable = !child_unit.is_source() && child_unit.is_active() && child_unit.fade == 1.0 &&
+                      child_unit.wet() == S::identity() && !child_unit.is_chain() &&
+                      child_unit.input_buses().len() == 1 &&
+                      child_unit.n_channels() == unit.n_channels() &&
+                      self.dag.edges_directed(child, pg::Direction::Incoming).count() == 1 &&
+                      !self.buses.values().any(|index| *index == child);
+        match fusable {
+            true => Some(child),
+            false => None,
+        }
+    }
+
+    /// Remove a node along with its buses and modulation routes, returning it.
+    fn take_node(&mut self, node: NodeIndex) -> Option<Unit<S,PS>> {
+        self.buses.retain(|_, index| *index != node);
+        self.modulation.remove_node(node);
+        self.dag.remove_node(node)
+    }
+
+    /// Fuse linear runs of nodes into chains, returning false if some of them lacked a spare
+    /// chain. The head of a run is kept and its unit is replaced by a `Chain` of the run's DSPs,
+    /// while the other nodes are removed. Runs longer than `CHAIN_SLOTS` are split.
+    ///
+    /// Chains are spares allocated for graph's format by a worker thread, and DSPs and their
+    /// fields are moved into them: fusion doesn't allocate.
+    fn fuse_nodes(&mut self) -> bool {
+        let mut fused = true;
+        let mut run = mem::take(&mut self.schedule.run);
+        for i in 0..self.ordered_nodes.len() {
+            let head = self.ordered_nodes[i];
+            if self.dag.node_weight(head).is_none() {
+                continue;
+            }
+
+            run.clear();
+            let mut last = head;
+            while run.len() + 1 < CHAIN_SLOTS {
+                match self.fusable_child(last) {
+                    Some(child) => {
+                        run.push(child);
+                        last = child;
+                    },
+                    None => break,
+                }
+            }
+            if !run.is_empty() && !self.fuse_run(head, &run) {
+                fused = false;
+            }
+        }
+        self.schedule.run = run;
+        fused
+    }
+
+    /// Fuse head and the provided run of its children into a spare chain. Return false if
+    /// there is no spare chain fitting them.
+    fn fuse_run(&mut self, head: NodeIndex, run: &[NodeIndex]) -> bool {
+        let n_channels = self.dag[head].n_channels();
+        let spare = match self.spares.provide() {
+            Some(Spare(spare)) => spare,
+            None => return false,
+        };
+        let fits = (&*spare.dsp as &dyn Any).downcast_ref::<Chain<S,PS>>()
+            .map(|chain| chain.fits(n_channels, run.len() + 1, self.rate, self.max_samples))
+            .unwrap_or(false);
+        if !fits {
+            // allocated for a previous format
+            self.releaser.release(spare.into());
+            return false;
+        }
+
+        // outgoing edges of the run now leave its head, reusing the removed ones
+        let last = run[run.len() - 1];
+        while let Some((edge, child, weight)) = self.dag.edges_directed(last, pg::Direction::Outgoing).next()
+                                                    .map(|edge| (edge.id(), edge.target(), *edge.weight()))
+        {
+            self.dag.remove_edge(edge);
+            self.dag.add_edge(head, child, weight);
+        }
+
+        let fader = self.dag[last].fader;
+        let previous = mem::replace(&mut self.dag[head], spare);
+        let unit = &mut self.dag[head];
+        unit.copy_allocation(&previous);
+        unit.prepared = previous.prepared;
+        unit.fader = fader;
+        self.append_slot(head, previous, 0);
+
+        for (i, index) in run.iter().enumerate() {
+            // modulation routes follow the node into its slot
+            self.modulation.move_node(*index, head, ((i + 1) as ObjectIndex) << SLOT_SHIFT);
+            if let Some(unit) = self.take_node(*index) {
+                self.append_slot(head, unit, i + 1);
+            }
+        }
+        true
+    }
+
+    /// Append unit's DSP to head's chain along with its fields, releasing its remaining parts.
+    fn append_slot(&mut self, head: NodeIndex, unit: Unit<S,PS>, slot: usize) {
+        let Unit { dsp, mut fields, events, .. } = unit;
+        let head = &mut self.dag[head];
+        if let Some(chain) = (&mut *head.dsp as &mut dyn Any).downcast_mut::<Chain<S,PS>>() {
+            chain.append(dsp);
+        }
+
+        let offset = (slot as ObjectIndex) << SLOT_SHIFT;
+        head.fields.extend(fields.drain(..).map(|(o, fields)| (o | offset, fields)));
+        self.releaser.release(Released { dsp: None, fields, events });
+    }
+
     /// Map object for a provided node
     fn map_node_object(&mut self, index: NodeIndex) {
         /*match self.dag.node_weight_mut(index) {
@@ -277,33 +1144,300 @@ impl<S,PS> Graph<S,PS>
 impl<S,PS> Graph<S,PS>
     where S: 'static+Sync+Sample, PS: 'static+Sync+ProcessScope+Clone
 {
-    /// Add a new node for the provided `DSP`.
-    pub fn add_node(&mut self, dsp: BoxedDSP<S,PS>) -> NodeIndex
+    /// Add a new node for the provided unit, which should already be prepared for graph's rate
+    /// and maximum samples (see `Builder`). Otherwise it is prepared here, which may allocate.
+    pub fn add_node(&mut self, unit: Unit<S,PS>) -> NodeIndex
     {
-        self.n_channels = self.n_channels.max(dsp.n_channels());
-
-        let index = self.dag.add_node(Unit::new(dsp));
+        let mut unit = unit;
+        if unit.prepared != Some((self.rate, self.max_samples)) {
+            unit.prepare(self.rate, self.max_samples);
+        }
+        self.n_channels = self.n_channels.max(unit.n_channels());
+        let index = self.dag.add_node(unit);
         self.map_node_object(index);
         index
     }
 
     /// Add a new node as child of the provided parent.
-    pub fn add_child(&mut self, parent: NodeIndex, dsp: BoxedDSP<S,PS>) -> NodeIndex {
-        let child = self.add_node(dsp);
-        self.dag.add_edge(parent, child, ());
+    pub fn add_child(&mut self, parent: NodeIndex, unit: Unit<S,PS>) -> NodeIndex {
+        let child = self.add_node(unit);
+        self.dag.add_edge(parent, child, Edge::default());
         child
     }
 
-    /// Add edge between two nodes
+    /// Add edge between two nodes, to child's main input bus.
     pub fn add_edge(&mut self, parent: NodeIndex, child: NodeIndex) -> EdgeIndex {
-        self.dag.add_edge(parent, child, ())
+        self.dag.add_edge(parent, child, Edge::default())
+    }
+
+    /// Add edge between parent and the named input bus of child. Return `None` if child has no
+    /// such bus.
+    pub fn add_bus_edge(&mut self, parent: NodeIndex, child: NodeIndex, bus: String) -> Option<EdgeIndex> {
+        let bus = self.dag.node_weight(child)?
+                      .input_buses().iter().position(|b| *b == bus)?;
+        Some(self.dag.add_edge(parent, child, Edge { bus, pre_fader: false }))
+    }
+
+    /// Set node's fader gain. Return false if there is no such node.
+    pub fn set_fader(&mut self, node: NodeIndex, gain: f32) -> bool {
+        match self.dag.node_weight_mut(node) {
+            Some(unit) => {
+                unit.fader = gain;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Add a send from node to the named return bus, taken before or after node's fader. The
+    /// return bus is created if it doesn't exist yet. Return the send node, whose `level` field
+    /// is the send level.
+    pub fn add_send(&mut self, node: NodeIndex, bus: String, level: f32, pre_fader: bool) -> Option<NodeIndex> {
+        let n_channels = self.dag.node_weight(node)?.n_channels();
+        let bus = match self.buses.get(&bus) {
+            Some(index) => *index,
+            None => {
+                let index = self.add_node(Unit::new(Box::new(AuxReturn::new(bus.clone(), n_channels))));
+                self.buses.insert(bus, index);
+                index
+            },
+        };
+
+        let send = self.add_node(Unit::new(Box::new(AuxSend::new(n_channels, level))));
+        self.dag.add_edge(node, send, Edge { bus: 0, pre_fader });
+        self.dag.add_edge(send, bus, Edge::default());
+        Some(send)
+    }
+
+    /// Return the node of the named return bus.
+    pub fn return_bus(&self, bus: String) -> Option<NodeIndex> {
+        self.buses.get(&bus).copied()
+    }
+
+    /// Add a new node for a registered plugin, instanciated by a `Builder` from its name and
+    /// parameters. Return instanciation error, or plugin's name if it hasn't been built.
+    pub fn add_plugin(&mut self, plugin: Build<PluginArgs, Result<Unit<S,PS>,String>>) -> Result<NodeIndex,String> {
+        match plugin {
+            Build::Built(unit) => unit.map(|unit| self.add_node(unit)),
+            Build::Args((name, _)) => Err(name),
+        }
+    }
+
+    /// List registered plugins, optionally filtered by category. Plugins are listed by a
+    /// `Builder`, none otherwise.
+    pub fn list_plugins(&self, plugins: Build<Option<String>, Vec<PluginInfo>>) -> Vec<PluginInfo> {
+        match plugins {
+            Build::Built(plugins) => plugins,
+            Build::Args(_) => Vec::new(),
+        }
+    }
+
+    /// Replace node's unit, keeping its edges, fader and bypass. The new unit should already be
+    /// prepared (see `Builder`), otherwise it is prepared here. Values of the previous DSP's
+    /// fields are set to the new DSP's fields of the same label, using the fields mapped when
+    /// units were created. The previous DSP is released outside of the audio thread. Return
+    /// false if there is no such node.
+    pub fn replace_node(&mut self, node: NodeIndex, unit: Unit<S,PS>) -> bool {
+        let mut unit = unit;
+        if unit.prepared != Some((self.rate, self.max_samples)) {
+            unit.prepare(self.rate, self.max_samples);
+        }
+        let previous = match self.dag.node_weight_mut(node) {
+            Some(previous) => previous,
+            None => return false,
+        };
+
+        let label = |field: &FieldInfo| field.metadatas.iter().find(|(key, _)| *key == "label")
+                                                      .map(|(_, value)| *value);
+        for (index, field) in iter_fields(&unit.fields) {
+            let value = label(field)
+                .and_then(|l| iter_fields(&previous.fields).find(|(_, p)| label(p) == Some(l)))
+                .and_then(|(p, _)| previous.dsp.get_value(p))
+                .and_then(|v| match v.get_type() == field.value_type {
+                    true => Some(v),
+                    false => v.as_f64().and_then(|v| Value::from_f64(field.value_type, v)),
+                });
+            if let Some(value) = value {
+                unit.dsp.set_value(index, value).ok();
+            }
+        }
+
+        self.n_channels = self.n_channels.max(unit.n_channels());
+        let previous = mem::replace(previous, unit);
+        let unit = self.dag.node_weight_mut(node).expect("node exists");
+        unit.copy_allocation(&previous);
+        unit.fader = previous.fader;
+        unit.bypass = previous.bypass;
+        unit.fade = previous.fade;
+        self.releaser.release(previous.into());
+        true
+    }
+
+    /// Enable or disable guarding of nodes: panics are caught, non-finite output samples are
+    /// detected and denormals are flushed. Faulty nodes are bypassed and reported as
+    /// `Response::Fault`.
+    pub fn set_guard(&mut self, guard: bool) {
+        self.guard = guard;
+    }
+
+    /// Enable or disable fusion of linear runs of nodes into chains, which is done when graph
+    /// is updated. Fused nodes are removed, the head of the run keeping a `Chain` whose slots
+    /// are the run's DSPs. Chains are allocated in advance by a worker thread: runs lacking
+    /// one are fused by a later call to `process_requests`.
+    ///
+    /// Indices of fused nodes (but the head) are invalidated and can be reused by nodes added
+    /// later: they must not be used once the graph has been updated.
+    pub fn set_fuse(&mut self, fuse: bool) {
+        self.fuse = fuse;
+    }
+
+    /// Add a LFO modulator, synced to transport when `sync` is a cycle duration in beats.
+    /// Return modulator's index.
+    pub fn add_lfo(&mut self, shape: Shape, frequency: f32, sync: Option<f64>) -> usize {
+        self.modulation.add_modulator(Box::new(Lfo::new(shape, frequency, sync)))
+    }
+
+    /// Add an ADSR envelope modulator, triggered by `gate_modulator`. Durations are in
+    /// seconds. Return modulator's index.
+    pub fn add_adsr(&mut self, attack: f32, decay: f32, sustain: f32, release: f32) -> usize {
+        self.modulation.add_modulator(Box::new(Adsr::new(attack, decay, sustain, release)))
+    }
+
+    /// Add a modulator (e.g. an envelope follower's source), returning its index.
+    pub fn add_modulator(&mut self, modulator: Box<dyn Modulator>) -> usize {
+        self.modulation.add_modulator(modulator)
+    }
+
+    /// Remove a modulator and its routes. Return false if there is no such modulator.
+    pub fn remove_modulator(&mut self, modulator: usize) -> bool {
+        self.modulation.remove_modulator(modulator)
+    }
+
+    /// Open or close modulator's gate. Return false if there is no such modulator.
+    pub fn gate_modulator(&mut self, modulator: usize, on: bool) -> bool {
+        match self.modulation.modulator_mut(modulator) {
+            Some(modulator) => {
+                modulator.gate(on);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Route a modulator to a node's field, around the field's current value. Depth is
+    /// relative to field's range. Return route's index, or `None` if modulator or field doesn't
+    /// exist or is not numeric.
+    pub fn add_route(&mut self, source: usize, node: NodeIndex, field: ObjectIndex, depth: f32,
+                     polarity: Polarity) -> Option<usize>
+    {
+        let unit = self.dag.node_weight(node)?;
+        let info = unit.field(field)?;
+        let base = match unit.dsp.get_value(field) {
+            Some(value) => value.as_f64()?,
+            None => info.default.as_ref()?.as_f64()?,
+        };
+        let range = info.range.map(|r| r.as_f64()).map(|(min, max, _)| (min, max))
+                        .unwrap_or((base.min(0.0), base.max(1.0)));
+
+        self.modulation.add_route(Route { source, node, field, depth, polarity,
+                                          value_type: info.value_type, base, range })
+    }
+
+    /// Remove a modulation route. Return false if there is no such route.
+    pub fn remove_route(&mut self, route: usize) -> bool {
+        self.modulation.remove_route(route)
+    }
+
+    /// Set route's depth. Return false if there is no such route.
+    pub fn set_route_depth(&mut self, route: usize, depth: f32) -> bool {
+        match self.modulation.route_mut(route) {
+            Some(route) => {
+                route.depth = depth;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Set a node's field, which is also the base value of its modulation routes. Return the
+    /// value actually set, or `None` if there is no such node or field.
+    pub fn set_node_value(&mut self, node: NodeIndex, field: ObjectIndex, value: Value) -> Option<Value> {
+        let value = self.dag.node_weight_mut(node)?.dsp.set_value(field, value).ok()?;
+        if let Some(base) = value.as_f64() {
+            self.modulation.set_base(node, field, base);
+        }
+        Some(value)
+    }
+
+    /// Enable or disable profiling of nodes and DSP load.
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.profiler.enabled = enabled;
+    }
+
+    /// Stream statistics every `interval_ms` milliseconds while profiling, as `Stats::Load`
+    /// and `Stats::Node` for each node (see `stats_stream`). `0` disables streaming.
+    pub fn set_stats_interval(&mut self, interval_ms: u32) {
+        self.profiler.set_interval(interval_ms);
+    }
+
+    /// Reset statistics.
+    pub fn reset_stats(&mut self) {
+        self.profiler.reset();
+        for unit in self.dag.node_weights_mut() {
+            unit.timing = Timing::default();
+        }
+    }
+
+    /// Return engine's DSP load, overloads and xruns.
+    pub fn load(&self) -> EngineLoad {
+        self.profiler.load()
+    }
+
+    /// Return node's statistics.
+    pub fn node_stats(&self, node: NodeIndex) -> Option<NodeStats> {
+        let unit = self.dag.node_weight(node)?;
+        Some(NodeStats::new(node, &unit.timing, unit.underruns()))
+    }
+
+    /// Return statistics of all nodes in processing order, filling the provided vector up to
+    /// its capacity: it is allocated by the caller, not on the audio thread.
+    pub fn stats(&self, stats: Vec<NodeStats>) -> Vec<NodeStats> {
+        let mut stats = stats;
+        stats.clear();
+        let capacity = stats.capacity();
+        stats.extend(self.ordered_nodes.iter().filter_map(|index| self.node_stats(*index)).take(capacity));
+        stats
+    }
+
+    /// Bypass node or re-enable it, clearing its fault. Return false if there is no such node.
+    pub fn set_bypass(&mut self, node: NodeIndex, bypass: bool) -> bool {
+        match self.dag.node_weight_mut(node) {
+            Some(unit) => {
+                unit.bypass = bypass;
+                if !bypass {
+                    unit.fault = None;
+                    unit.fault_reported = false;
+                }
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Return node's fault, if any.
+    pub fn fault(&self, node: NodeIndex) -> Option<NodeFault> {
+        let fault = self.dag.node_weight(node)?.fault?;
+        Some(NodeFault { node, fault })
     }
 
     /// Remove a node
     pub fn remove_node(&mut self, node: NodeIndex) {
-        self.dag.remove_node(node);
+        if let Some(unit) = self.take_node(node) {
+            self.releaser.release(unit.into());
+        }
     }
 
+
     /// Remove an edge
     pub fn remove_edge(&mut self, edge: EdgeIndex) {
         self.dag.remove_edge(edge);
@@ -367,3 +1501,414 @@ impl<'a> ObjectMapper for GraphObjectMapper<'a> {
 
 
 
+
+
+#[cfg(test)]
+mod tests {
+    use libfoxlive_derive::object;
+    use super::*;
+    use crate::data::buffer::zip_map;
+    use std::time::Duration;
+    use super::super::block::BlockScope;
+
+    type TestGraph = Graph<f32,BlockScope>;
+
+    /// Source outputting a constant value
+    #[object("constant")]
+    struct Constant {
+        #[field("value", F32(1.0))]
+        value: f32,
+    }
+
+    impl DSP for Constant {
+        type Sample = f32;
+        type Scope = BlockScope;
+
+        fn process_audio(&mut self, _scope: &Self::Scope, _input: Option<&dyn BufferView<Sample=f32>>,
+                         output: Option<&mut dyn BufferView<Sample=f32>>) -> usize
+        {
+            let output = output.unwrap();
+            output.fill(self.value);
+            output.len()
+        }
+
+        fn n_channels(&self) -> NChannels { 1 }
+        fn is_source(&self) -> bool { true }
+    }
+
+    /// Output main bus plus ten times the sidechain bus
+    #[object("sidechain")]
+    struct Sidechain {
+        #[field("gain", F32(10.0))]
+        gain: f32,
+    }
+
+    impl DSP for Sidechain {
+        type Sample = f32;
+        type Scope = BlockScope;
+
+        fn process_audio(&mut self, _scope: &Self::Scope, _input: Option<&dyn BufferView<Sample=f32>>,
+                         _output: Option<&mut dyn BufferView<Sample=f32>>) -> usize
+        {
+            0
+        }
+
+        fn process_buses(&mut self, _scope: &Self::Scope, inputs: &[&dyn BufferView<Sample=f32>],
+                         output: Option<&mut dyn BufferView<Sample=f32>>) -> usize
+        {
+            let output = output.unwrap().as_slice_mut();
+            let (main, side) = (inputs[0].as_slice(), inputs[1].as_slice());
+            for i in 0..output.len() {
+                output[i] = main[i] + self.gain * side[i];
+            }
+            output.len()
+        }
+
+        fn n_channels(&self) -> NChannels { 1 }
+        fn input_buses(&self) -> &'static [&'static str] { &["main", "side"] }
+    }
+
+    /// Multiply input by gain, optionally in place
+    #[object("gain")]
+    struct Gain {
+        #[field("gain", F32(1.0))]
+        gain: f32,
+        in_place: bool,
+    }
+
+    impl DSP for Gain {
+        type Sample = f32;
+        type Scope = BlockScope;
+
+        fn process_audio(&mut self, _scope: &Self::Scope, input: Option<&dyn BufferView<Sample=f32>>,
+                         output: Option<&mut dyn BufferView<Sample=f32>>) -> usize
+        {
+            let output = output.unwrap();
+            if let Some(input) = input {
+                zip_map(output, input, |a,b| *a = *b);
+            }
+            let gain = self.gain;
+            output.map_inplace(&|_, s| s * gain);
+            output.len()
+        }
+
+        fn n_channels(&self) -> NChannels { 1 }
+        fn in_place(&self) -> bool { self.in_place }
+    }
+
+    /// Copy input, being partially wet
+    #[object("wet")]
+    struct Wet {
+        wet: f32,
+    }
+
+    impl DSP for Wet {
+        type Sample = f32;
+        type Scope = BlockScope;
+
+        fn process_audio(&mut self, _scope: &Self::Scope, input: Option<&dyn BufferView<Sample=f32>>,
+                         output: Option<&mut dyn BufferView<Sample=f32>>) -> usize
+        {
+            let output = output.unwrap();
+            zip_map(output, input.unwrap(), |a,b| *a = *b);
+            output.len()
+        }
+
+        fn n_channels(&self) -> NChannels { 1 }
+        fn wet(&self) -> f32 { self.wet }
+    }
+
+    fn constant(value: f32) -> Unit<f32,BlockScope> {
+        Constant { value }.into()
+    }
+
+    /// Process a block of `n_samples`
+    fn process(graph: &mut TestGraph, n_samples: NSamples) {
+        graph.process_nodes(&BlockScope::new(n_samples, 0, None));
+    }
+
+    /// Return node's output of the last processed block
+    fn output(graph: &mut TestGraph, node: NodeIndex, n_samples: NSamples) -> Vec<f32> {
+        let unit = graph.dag.node_weight(node).unwrap();
+        unit.buffer(&mut graph.buffers, n_samples).as_slice().to_vec()
+    }
+
+    /// Test: parents are summed into the input bus they are connected to
+    #[test]
+    fn bus_routing() {
+        let mut graph = TestGraph::new();
+        let (a, b, c) = (graph.add_node(constant(1.0)), graph.add_node(constant(2.0)),
+                         graph.add_node(constant(3.0)));
+        let mixer = graph.add_node(Sidechain { gain: 10.0 }.into());
+        graph.add_edge(a, mixer);
+        graph.add_edge(c, mixer);
+        assert!(graph.add_bus_edge(b, mixer, "side".to_string()).is_some());
+        assert!(graph.add_bus_edge(b, mixer, "unknown".to_string()).is_none());
+        graph.updated();
+
+        process(&mut graph, 4);
+        assert_eq!(output(&mut graph, mixer, 4), vec![24.0; 4]);
+    }
+
+    /// Test: sends feed return bus with their level, before or after source's fader
+    #[test]
+    fn sends() {
+        let mut graph = TestGraph::new();
+        let source = graph.add_node(constant(1.0));
+        graph.set_fader(source, 0.5);
+        let post = graph.add_send(source, "fx".to_string(), 1.0, false).unwrap();
+        let pre = graph.add_send(source, "fx".to_string(), 0.25, true).unwrap();
+        let fx = graph.return_bus("fx".to_string()).unwrap();
+        assert_eq!(graph.graph().node_count(), 4);
+        graph.updated();
+
+        process(&mut graph, 4);
+        assert_eq!(output(&mut graph, post, 4), vec![0.5; 4]);
+        assert_eq!(output(&mut graph, pre, 4), vec![0.25; 4]);
+        assert_eq!(output(&mut graph, fx, 4), vec![0.75; 4]);
+    }
+
+    /// Test: replaced node keeps its edges, fader, bypass and values of fields of same label
+    #[test]
+    fn replace_node() {
+        let mut graph = TestGraph::new();
+        let a = graph.add_node(constant(1.0));
+        let b = graph.add_child(a, gain(2.0, false));
+        let c = graph.add_child(b, gain(1.0, false));
+        graph.set_fader(b, 0.5);
+        graph.set_bypass(b, true);
+        graph.updated();
+        process(&mut graph, FADE_SAMPLES);
+
+        assert!(graph.replace_node(b, gain(3.0, false)));
+        let unit = graph.dag.node_weight(b).unwrap();
+        assert_eq!((unit.fader, unit.bypass), (0.5, true));
+        process(&mut graph, 4);
+        assert_eq!(output(&mut graph, c, 4), vec![0.5; 4]);
+
+        graph.set_bypass(b, false);
+        process(&mut graph, FADE_SAMPLES);
+        process(&mut graph, 4);
+        assert_eq!(output(&mut graph, c, 4), vec![1.0; 4]);
+        assert!(!graph.replace_node(NodeIndex::new(10), gain(1.0, false)));
+    }
+
+    /// Update graph, waiting for spare chains until its runs are fused
+    fn fuse(graph: &mut TestGraph) {
+        let start = Instant::now();
+        graph.updated();
+        while graph.unfused && start.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(1));
+            graph.updated();
+        }
+        assert!(!graph.unfused, "runs have not been fused");
+    }
+
+    fn gain(gain: f32, in_place: bool) -> Unit<f32,BlockScope> {
+        Gain { gain, in_place }.into()
+    }
+
+    fn offset(graph: &TestGraph, node: NodeIndex) -> usize {
+        graph.dag.node_weight(node).unwrap().offset
+    }
+
+    /// Test: buffers are reused once all children of their node are processed
+    #[test]
+    fn buffer_reuse() {
+        let mut graph = TestGraph::new();
+        let a = graph.add_node(constant(1.0));
+        let (b, c) = (graph.add_node(gain(2.0, false)), graph.add_node(gain(3.0, false)));
+        graph.add_edge(a, b);
+        graph.add_edge(b, c);
+        graph.updated();
+
+        assert_eq!((offset(&graph, a), offset(&graph, b), offset(&graph, c)), (0, 1, 0));
+        assert_eq!(graph.arena.size, 2);
+
+        process(&mut graph, 4);
+        assert_eq!(output(&mut graph, c, 4), vec![6.0; 4]);
+    }
+
+    /// Test: in-place node shares its only parent's buffer, to which parent's fader is applied
+    #[test]
+    fn in_place() {
+        let mut graph = TestGraph::new();
+        let a = graph.add_node(constant(1.0));
+        let b = graph.add_node(gain(3.0, true));
+        graph.set_fader(a, 0.5);
+        graph.add_edge(a, b);
+        graph.updated();
+
+        assert!(graph.dag.node_weight(b).unwrap().in_place);
+        assert_eq!(offset(&graph, a), offset(&graph, b));
+        assert_eq!(graph.arena.size, 1);
+        process(&mut graph, 4);
+        assert_eq!(output(&mut graph, b, 4), vec![1.5; 4]);
+
+        // parent's output is also read by another child
+        let c = graph.add_node(gain(1.0, false));
+        graph.add_edge(a, c);
+        graph.updated();
+
+        assert!(!graph.dag.node_weight(b).unwrap().in_place);
+        assert!(offset(&graph, a) != offset(&graph, b) && offset(&graph, a) != offset(&graph, c));
+    }
+
+    /// Test: blocks larger than max samples are truncated without reallocating buffers
+    #[test]
+    fn max_samples() {
+        let mut graph = TestGraph::new();
+        graph.set_max_samples(4);
+        let a = graph.add_node(constant(1.0));
+        let b = graph.add_node(gain(2.0, false));
+        graph.add_edge(a, b);
+        graph.updated();
+
+        let (ptr, len) = (graph.buffers.as_ptr(), graph.buffers.len());
+        process(&mut graph, 8);
+        assert_eq!((graph.buffers.as_ptr(), graph.buffers.len()), (ptr, len));
+        assert_eq!(output(&mut graph, b, 4), vec![2.0; 4]);
+    }
+
+    /// Test: adding nodes within reserved capacity doesn't reallocate buffers
+    #[test]
+    fn capacity() {
+        let mut graph = TestGraph::with_capacity(4, 4);
+        graph.reserve_channels(1, 2);
+        let (buffers, dry) = (graph.buffers.as_ptr(), graph.dry_buffers.as_ptr());
+
+        let (a, b) = (graph.add_node(constant(1.0)), graph.add_node(constant(2.0)));
+        let mixer = graph.add_node(Sidechain { gain: 10.0 }.into());
+        graph.add_edge(a, mixer);
+        assert!(graph.add_bus_edge(b, mixer, "side".to_string()).is_some());
+        graph.add_child(mixer, gain(0.5, false));
+        graph.updated();
+        assert_eq!((graph.buffers.as_ptr(), graph.dry_buffers.as_ptr()), (buffers, dry));
+
+        process(&mut graph, 4);
+        assert_eq!(output(&mut graph, mixer, 4), vec![21.0; 4]);
+    }
+
+    /// Test: linear runs are fused into a chain, modulation routes following their node
+    #[test]
+    fn fusion() {
+        let mut graph = TestGraph::new();
+        let a = graph.add_node(constant(1.0));
+        let (b, c) = (graph.add_node(gain(2.0, false)), graph.add_node(gain(3.0, false)));
+        graph.add_edge(a, b);
+        graph.add_edge(b, c);
+        // modulation sets gain to its base value, only when applied to the right slot's field
+        let lfo = graph.add_lfo(Shape::Sine, 1.0, None);
+        assert!(graph.add_route(lfo, c, 0, 0.0, Polarity::Unipolar).is_some());
+
+        graph.set_fuse(true);
+        fuse(&mut graph);
+        assert_eq!(graph.graph().node_count(), 1);
+        assert_eq!(graph.modulation.node_values(c).count(), 0);
+        let fields: Vec<ObjectIndex> = graph.modulation.node_values(a).map(|(r, _)| r.field).collect();
+        assert_eq!(fields, vec![2 << SLOT_SHIFT]);
+        // fields are moved along with their DSP
+        assert!(graph.node(a).unwrap().field(2 << SLOT_SHIFT).is_some());
+
+        process(&mut graph, 4);
+        assert_eq!(output(&mut graph, a, 4), vec![6.0; 4]);
+
+        // head with a sidechain is not fused, which would lose its side bus
+        let (side, mixer) = (graph.add_node(constant(1.0)), graph.add_node(Sidechain { gain: 10.0 }.into()));
+        let d = graph.add_node(gain(2.0, false));
+        graph.add_edge(a, mixer);
+        assert!(graph.add_bus_edge(side, mixer, "side".to_string()).is_some());
+        graph.add_edge(mixer, d);
+        fuse(&mut graph);
+        assert_eq!(graph.graph().node_count(), 4);
+
+        process(&mut graph, 4);
+        assert_eq!(output(&mut graph, d, 4), vec![32.0; 4]);
+    }
+
+    /// Test: chains and partially wet nodes are not fused
+    #[test]
+    fn fusion_rules() {
+        let mut graph = TestGraph::new();
+        let mut chain = Chain::new(1);
+        chain.push(Box::new(Gain { gain: 2.0, in_place: false }));
+        let a = graph.add_node(constant(1.0));
+        let b = graph.add_child(a, chain.into());
+        let c = graph.add_child(b, gain(3.0, false));
+        let d = graph.add_child(c, Wet { wet: 0.5 }.into());
+        graph.set_fuse(true);
+        fuse(&mut graph);
+        assert!(graph.node(d).is_some());
+
+        // nested chain would break slots' indices
+        graph.remove_node(a);
+        let e = graph.add_node(constant(1.0));
+        graph.add_edge(e, b);
+        fuse(&mut graph);
+        assert_eq!(graph.graph().node_count(), 4);
+    }
+
+    /// Test: statistics are returned in the provided vector and streamed apart from responses
+    #[test]
+    fn stats() {
+        let mut graph = TestGraph::new();
+        graph.set_rate(48000);
+        let a = graph.add_node(constant(1.0));
+        let b = graph.add_child(a, gain(2.0, false));
+        graph.updated();
+        let mut stream = graph.stats_stream(8);
+        graph.set_profiling(true);
+        graph.set_stats_interval(1);
+        process(&mut graph, 48);
+
+        let stats = graph.stats(Vec::with_capacity(1));
+        assert_eq!(stats.len(), 1);
+        assert_eq!((stats[0].node, stats[0].blocks), (a, 1));
+
+        assert!(matches!(stream.pop(), Some(Stats::Load(load)) if load.blocks == 1));
+        assert!(matches!(stream.pop(), Some(Stats::Node(stats)) if stats.node == a));
+        assert!(matches!(stream.pop(), Some(Stats::Node(stats)) if stats.node == b));
+        assert!(stream.pop().is_none());
+    }
+
+    /// Test: only requests changing graph's layout update it
+    #[test]
+    fn updates_layout() {
+        use service::Request;
+        let mut graph = TestGraph::new();
+        let mut client = graph.init_transport(16).unwrap();
+        client.sender.try_send(Request::AddNode(constant(1.0))).ok();
+        graph.process_requests();
+        assert_eq!(graph.ordered_nodes.len(), 1);
+
+        // read-only requests leave graph as is
+        graph.ordered_nodes.clear();
+        client.sender.try_send(Request::Load()).ok();
+        client.sender.try_send(Request::NodeStats(NodeIndex::new(0))).ok();
+        graph.process_requests();
+        assert!(graph.ordered_nodes.is_empty());
+
+        client.sender.try_send(Request::SetFuse(false)).ok();
+        graph.process_requests();
+        assert_eq!(graph.ordered_nodes.len(), 1);
+    }
+
+    /// Test: arena reuses and merges released ranges
+    #[test]
+    fn arena() {
+        let mut arena = Arena::default();
+        let a = arena.alloc(2);
+        let b = arena.alloc(1);
+        let c = arena.alloc(2);
+        assert_eq!((a, b, c), ((0, 2), (2, 1), (3, 2)));
+
+        arena.release(a);
+        assert_eq!(arena.alloc(1), (0, 1));
+        arena.release(c);
+        arena.release(b);
+        assert_eq!(arena.free, vec![(1, 4)]);
+
+        assert_eq!(arena.alloc(4), (1, 4));
+        assert_eq!(arena.size, 5);
+    }
+}
@@ -1,4 +1,7 @@
 //! Implement DSP for jack audio ports, as source and sink dsp nodes in the audio g`Graph`.
+//!
+//! Jack transport is provided to nodes through `ProcessScope::transport()`, and foxlive can
+//! act as timebase master using `TimebaseMaster`.
 //! 
 //! # Examples
 //!
@@ -22,8 +25,9 @@
 //!     let reader = media.read_audio(None, 48000, None);
 
 //!     let mut graph = Graph::new();
-//!     let media_view = graph.add_node(MediaView::new(media, 1.0));
-//!     let master = graph.add_child(media_view, JackOutput::acquire(&client, "master", 2));
+//!     let media_view = graph.add_node(MediaView::new(media, 1.0).into());
+//!     let master = graph.add_child(media_view, JackOutput::acquire(&client, "master", 2).into());
+//!     graph.set_max_samples(client.buffer_size() as usize);
 //!     graph.updated();
 
 //!     let process_handler = j::ClosureProcessHandler::new(
@@ -44,14 +48,18 @@
 use std::iter::FromIterator;
 
 use jack as j;
+use jack_sys as js;
+use ringbuf::{Consumer,Producer,RingBuffer};
 use smallvec::SmallVec;
 
 use crate as libfoxlive;
 use libfoxlive_derive::object;
-use crate::data::{BufferView,NChannels,NSamples,NFrames};
+use crate::data::{BufferView,NChannels,NSamples,NFrames,SampleRate};
+use crate::data::midi::*;
 use crate::data::sample::*;
 use super::dsp::DSP;
 use super::graph::ProcessScope;
+use super::transport::*;
 
 
 impl ProcessScope for j::ProcessScope {
@@ -62,6 +70,58 @@ impl ProcessScope for j::ProcessScope {
     fn last_frame_time(&self) -> NFrames {
         <j::ProcessScope>::last_frame_time(self)
     }
+
+    fn transport(&self) -> Option<Transport> {
+        let mut pos = js::jack_position_t::default();
+        let state = unsafe { js::jack_transport_query(self.client_ptr(), &mut pos) };
+        Some(transport_from_raw(state, &pos))
+    }
+}
+
+
+/// Return `Transport` from jack's transport state and position.
+fn transport_from_raw(state: js::jack_transport_state_t, pos: &js::jack_position_t) -> Transport {
+    let state = match state {
+        js::JackTransportRolling => TransportState::Rolling,
+        js::JackTransportLooping => TransportState::Looping,
+        js::JackTransportStarting => TransportState::Starting,
+        _ => TransportState::Stopped,
+    };
+
+    let bbt = match pos.valid & js::JackPositionBBT {
+        0 => None,
+        _ => Some(Bbt {
+            bar: pos.bar,
+            beat: pos.beat,
+            tick: pos.tick,
+            bar_start_tick: pos.bar_start_tick,
+            beats_per_bar: pos.beats_per_bar,
+            beat_type: pos.beat_type,
+            ticks_per_beat: pos.ticks_per_beat,
+            beats_per_minute: pos.beats_per_minute,
+        }),
+    };
+
+    Transport {
+        state, bbt,
+        frame: pos.frame,
+        rate: pos.frame_rate as SampleRate,
+    }
+}
+
+
+impl TransportControl for j::Client {
+    fn start(&self) {
+        unsafe { js::jack_transport_start(self.raw()) }
+    }
+
+    fn stop(&self) {
+        unsafe { js::jack_transport_stop(self.raw()) }
+    }
+
+    fn locate(&self, frame: NFrames) -> bool {
+        unsafe { js::jack_transport_locate(self.raw(), frame) == 0 }
+    }
 }
 
 
@@ -147,6 +207,252 @@ pub mod output {
     }
 }
 
+pub mod midi {
+    use super::*;
+    use crate::data::event::*;
+
+    /// Jack MIDI input port, as a graph's source of events. Events can also be received
+    /// outside of the graph through a `MidiReceiver`.
+    #[object("jack_midi_input")]
+    pub struct JackMidiInput {
+        pub port: j::Port<j::MidiIn>,
+        events: Option<Producer<MidiEvent>>,
+    }
+
+    /// Receive events read by a `JackMidiInput`.
+    pub struct MidiReceiver {
+        events: Consumer<MidiEvent>,
+    }
+
+    impl JackMidiInput {
+        /// Create and register a jack MIDI input.
+        pub fn acquire(client: &j::Client, name: &str) -> Self {
+            let port = client.register_port(name, j::MidiIn::default())
+                             .expect("port name too long");
+            JackMidiInput { port, events: None }
+        }
+
+        /// Return a receiver of the input's events, whose queue has the provided capacity.
+        /// It replaces previously created receiver.
+        pub fn receiver(&mut self, capacity: usize) -> MidiReceiver {
+            let (prod, cons) = RingBuffer::new(capacity).split();
+            self.events = Some(prod);
+            MidiReceiver { events: cons }
+        }
+
+        /// Read port's events and push them to the receiver. Must be called in the process
+        /// callback when input is not used in a graph. Events are dropped when the queue is full.
+        pub fn process(&mut self, scope: &j::ProcessScope) {
+            self.read(scope, |_| {});
+        }
+
+        /// Read port's events, calling `func` on each of them.
+        fn read(&mut self, scope: &j::ProcessScope, mut func: impl FnMut(MidiEvent)) {
+            let events = &mut self.events;
+            for raw in self.port.iter(scope) {
+                if let Some(message) = MidiMessage::from_bytes(raw.bytes) {
+                    let event = MidiEvent { time: raw.time, message };
+                    if let Some(events) = events {
+                        events.push(event).ok();
+                    }
+                    func(event);
+                }
+            }
+        }
+    }
+
+    impl DSP for JackMidiInput {
+        type Sample=f32;
+        type Scope=j::ProcessScope;
+
+        fn process_audio(&mut self, _scope: &Self::Scope, _input: Option<&dyn BufferView<Sample=Self::Sample>>,
+                         _output: Option<&mut dyn BufferView<Sample=Self::Sample>>) -> usize
+        {
+            0
+        }
+
+        fn process_events(&mut self, scope: &Self::Scope, _input: &EventBuffer, output: &mut EventBuffer) {
+            self.read(scope, |event| { output.push(event.into()); });
+        }
+
+        fn is_source(&self) -> bool { true }
+        fn has_event_output(&self) -> bool { true }
+    }
+
+    impl MidiSource for MidiReceiver {
+        fn next_event(&mut self) -> Option<MidiEvent> {
+            self.events.pop()
+        }
+    }
+
+
+    /// Jack MIDI output port, as a graph's sink of events.
+    #[object("jack_midi_output")]
+    pub struct JackMidiOutput {
+        pub port: j::Port<j::MidiOut>,
+    }
+
+    impl JackMidiOutput {
+        /// Create and register a jack MIDI output.
+        pub fn acquire(client: &j::Client, name: &str) -> Self {
+            let port = client.register_port(name, j::MidiOut::default())
+                             .expect("port name too long");
+            JackMidiOutput { port }
+        }
+    }
+
+    impl DSP for JackMidiOutput {
+        type Sample=f32;
+        type Scope=j::ProcessScope;
+
+        fn process_audio(&mut self, _scope: &Self::Scope, _input: Option<&dyn BufferView<Sample=Self::Sample>>,
+                         _output: Option<&mut dyn BufferView<Sample=Self::Sample>>) -> usize
+        {
+            0
+        }
+
+        fn process_events(&mut self, scope: &Self::Scope, input: &EventBuffer, _output: &mut EventBuffer) {
+            let mut writer = self.port.writer(scope);
+            let mut bytes = [0u8;3];
+            for event in input.iter() {
+                if let Some(message) = event.midi() {
+                    let len = message.to_bytes(&mut bytes);
+                    writer.write(&j::RawMidi { time: event.time, bytes: &bytes[..len] }).ok();
+                }
+            }
+        }
+
+        fn is_sink(&self) -> bool { true }
+        fn has_event_input(&self) -> bool { true }
+    }
+}
+
+pub mod timebase {
+    use std::os::raw::{c_int,c_void};
+    use std::sync::atomic::{AtomicU32,AtomicU64,Ordering};
+    use super::*;
+
+    /// Tempo and time signature read by the timebase callback, stored as atomics
+    /// so that they can be updated from the control thread.
+    struct Shared {
+        beats_per_minute: AtomicU64,
+        beats_per_bar: AtomicU32,
+        beat_type: AtomicU32,
+    }
+
+    impl Shared {
+        fn tempo(&self) -> Tempo {
+            Tempo::new(f64::from_bits(self.beats_per_minute.load(Ordering::Relaxed)),
+                       f32::from_bits(self.beats_per_bar.load(Ordering::Relaxed)),
+                       f32::from_bits(self.beat_type.load(Ordering::Relaxed)))
+        }
+    }
+
+    /// Make foxlive act as JACK timebase master, providing bar, beat and tick position
+    /// to other clients from its own tempo and time signature.
+    ///
+    /// It must be dropped before the client it has been acquired from.
+    #[object("jack_timebase")]
+    pub struct TimebaseMaster {
+        client: *mut js::jack_client_t,
+        /// State shared with jack's timebase callback
+        shared: Box<Shared>,
+        #[field("tempo", F64(120.0), range(1.0,400.0,0.1), set(set_beats_per_minute))]
+        beats_per_minute: f64,
+        #[field("beats per bar", F32(4.0), range(1.0,32.0,1.0), set(set_beats_per_bar))]
+        beats_per_bar: f32,
+        #[field("beat type", F32(4.0), range(1.0,32.0,1.0), set(set_beat_type))]
+        beat_type: f32,
+    }
+
+    impl TimebaseMaster {
+        /// Register as timebase master using provided tempo. When `conditional` is true,
+        /// it fails if there already is a timebase master.
+        pub fn acquire(client: &j::Client, tempo: Tempo, conditional: bool) -> Result<Self, ()> {
+            let shared = Box::new(Shared {
+                beats_per_minute: AtomicU64::new(tempo.beats_per_minute.to_bits()),
+                beats_per_bar: AtomicU32::new(tempo.beats_per_bar.to_bits()),
+                beat_type: AtomicU32::new(tempo.beat_type.to_bits()),
+            });
+
+            let arg = shared.as_ref() as *const Shared as *mut c_void;
+            match unsafe { js::jack_set_timebase_callback(client.raw(), conditional as c_int,
+                                                          Some(timebase_callback), arg) } {
+                0 => Ok(TimebaseMaster {
+                    client: client.raw(),
+                    shared,
+                    beats_per_minute: tempo.beats_per_minute,
+                    beats_per_bar: tempo.beats_per_bar,
+                    beat_type: tempo.beat_type,
+                }),
+                _ => Err(()),
+            }
+        }
+
+        /// Current tempo and time signature
+        pub fn tempo(&self) -> Tempo {
+            Tempo::new(self.beats_per_minute, self.beats_per_bar, self.beat_type)
+        }
+
+        /// Set tempo in beats per minute, failing if it is not a positive number
+        pub fn set_beats_per_minute(&mut self, value: f64) -> Result<f64, ()> {
+            if !value.is_finite() || value <= 0.0 {
+                return Err(());
+            }
+            self.beats_per_minute = value;
+            self.shared.beats_per_minute.store(value.to_bits(), Ordering::Relaxed);
+            Ok(value)
+        }
+
+        /// Set number of beats per bar, failing if it is not a positive number
+        pub fn set_beats_per_bar(&mut self, value: f32) -> Result<f32, ()> {
+            if !value.is_finite() || value <= 0.0 {
+                return Err(());
+            }
+            self.beats_per_bar = value;
+            self.shared.beats_per_bar.store(value.to_bits(), Ordering::Relaxed);
+            Ok(value)
+        }
+
+        /// Set time signature's beat type, failing if it is not a positive number
+        pub fn set_beat_type(&mut self, value: f32) -> Result<f32, ()> {
+            if !value.is_finite() || value <= 0.0 {
+                return Err(());
+            }
+            self.beat_type = value;
+            self.shared.beat_type.store(value.to_bits(), Ordering::Relaxed);
+            Ok(value)
+        }
+    }
+
+    impl Drop for TimebaseMaster {
+        fn drop(&mut self) {
+            unsafe { js::jack_release_timebase(self.client) };
+        }
+    }
+
+    /// Jack timebase callback, called in the process thread by jack.
+    unsafe extern "C" fn timebase_callback(_state: js::jack_transport_state_t, _n_frames: js::jack_nframes_t,
+                                           pos: *mut js::jack_position_t, _new_pos: c_int, arg: *mut c_void)
+    {
+        let shared = &*(arg as *const Shared);
+        let pos = &mut *pos;
+        let bbt = shared.tempo().bbt(pos.frame, pos.frame_rate as SampleRate);
+
+        pos.valid = pos.valid | js::JackPositionBBT;
+        pos.bar = bbt.bar;
+        pos.beat = bbt.beat;
+        pos.tick = bbt.tick;
+        pos.bar_start_tick = bbt.bar_start_tick;
+        pos.beats_per_bar = bbt.beats_per_bar;
+        pos.beat_type = bbt.beat_type;
+        pos.ticks_per_beat = bbt.ticks_per_beat;
+        pos.beats_per_minute = bbt.beats_per_minute;
+    }
+}
+
 pub use input::*;
 pub use output::*;
+pub use midi::*;
+pub use timebase::*;
 
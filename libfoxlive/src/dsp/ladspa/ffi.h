@@ -0,0 +1,8 @@
+#include <ladspa.h>
+
+//: type LADSPA_Descriptor
+//: type LADSPA_Descriptor_Function
+//: type LADSPA_PortRangeHint
+//
+//: var LADSPA_PORT_.*
+//: var LADSPA_HINT_.*
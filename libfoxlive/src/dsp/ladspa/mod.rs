@@ -0,0 +1,670 @@
+//! Host LADSPA plugins as graph's DSP.
+//!
+//! Plugin libraries are searched in directories listed by the `LADSPA_PATH` environment
+//! variable (defaulting to usual system and user directories), and loaded using `dlopen`.
+//! Libraries are never unloaded, so descriptors (and their strings) are valid for the
+//! program's lifetime.
+//!
+//! Control ports are exposed as object's fields, with their range and default value taken
+//! from the port's hints. Audio ports are mapped to channels in declaration order.
+//!
+//! Plugin is instantiated for a sample rate: when prepared for another rate, it is
+//! instantiated again, keeping control ports' values.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use libfoxlive::dsp::block::BlockScope;
+//! use libfoxlive::dsp::ladspa;
+//!
+//! for name in ladspa::list_plugins() {
+//!     println!("{}", name);
+//! }
+//!
+//! let plugin = ladspa::new_plugin::<BlockScope>("delay_5s", 48000).unwrap();
+//! ```
+use std::ffi::{CStr,CString};
+use std::env;
+use std::fs;
+use std::marker::PhantomData;
+use std::os::raw::{c_char,c_ulong};
+use std::path::{Path,PathBuf};
+use std::ptr;
+use std::sync::Once;
+use std::sync::atomic::{AtomicPtr,Ordering};
+
+use libc;
+
+use crate::data::{BufferView,NChannels,SampleRate};
+use crate::data::sample::fill_samples;
+use crate::rpc::*;
+use super::dsp::{DSP,BoxedDSP};
+use super::graph::{ProcessScope,MAX_SAMPLES};
+
+
+#[allow(warnings)]
+mod ffi;
+
+
+/// Directories searched when `LADSPA_PATH` is not set, `~/.ladspa` being added to them.
+const DEFAULT_PATH: &str = "/usr/lib/ladspa:/usr/local/lib/ladspa";
+
+
+/// Plugin's descriptor, as provided by the plugin's library.
+#[derive(Clone)]
+pub struct Descriptor {
+    /// Library's path
+    pub path: PathBuf,
+    descriptor: *const ffi::LADSPA_Descriptor,
+}
+
+unsafe impl Send for Descriptor {}
+unsafe impl Sync for Descriptor {}
+
+
+/// Port's kind.
+#[derive(Copy,Clone,Debug,PartialEq)]
+enum PortKind {
+    AudioInput,
+    AudioOutput,
+    ControlInput,
+    ControlOutput,
+}
+
+
+/// Control port exposed as an object's field.
+struct Control {
+    port: c_ulong,
+    output: bool,
+    value_type: ValueType,
+    default: f32,
+    range: (f32,f32),
+    metadatas: Metadatas,
+}
+
+
+/// LADSPA plugin instance.
+pub struct LadspaPlugin<PS: ProcessScope> {
+    descriptor: Descriptor,
+    handle: *mut libc::c_void,
+    /// Sample rate the plugin is instantiated for
+    rate: SampleRate,
+    controls: Vec<Control>,
+    /// Control ports' values, connected to the plugin. Its size never changes.
+    values: Box<[f32]>,
+    /// Audio input ports and their buffer, of `MAX_SAMPLES` frames
+    inputs: Vec<(c_ulong, Vec<f32>)>,
+    /// Audio output ports and their buffer, of `MAX_SAMPLES` frames
+    outputs: Vec<(c_ulong, Vec<f32>)>,
+    phantom: PhantomData<PS>,
+}
+
+unsafe impl<PS: ProcessScope> Send for LadspaPlugin<PS> {}
+unsafe impl<PS: ProcessScope> Sync for LadspaPlugin<PS> {}
+
+
+/// Return string for the provided C string, empty if null.
+fn to_str(s: *const c_char) -> &'static str {
+    if s.is_null() {
+        return "";
+    }
+    // libraries are never unloaded
+    unsafe { CStr::from_ptr(s) }.to_str().unwrap_or("")
+}
+
+
+impl Descriptor {
+    fn raw(&self) -> &ffi::LADSPA_Descriptor {
+        unsafe { &*self.descriptor }
+    }
+
+    /// Plugin's unique label (used to instanciate it)
+    pub fn label(&self) -> &'static str {
+        to_str(self.raw().Label)
+    }
+
+    /// Plugin's name for humans
+    pub fn name(&self) -> &'static str {
+        to_str(self.raw().Name)
+    }
+
+    /// Plugin's maker
+    pub fn maker(&self) -> &'static str {
+        to_str(self.raw().Maker)
+    }
+
+    /// Plugin's unique id
+    pub fn unique_id(&self) -> u64 {
+        self.raw().UniqueID as u64
+    }
+
+    /// Number of ports
+    fn port_count(&self) -> c_ulong {
+        self.raw().PortCount
+    }
+
+    /// Return port's kind, `None` if the descriptor is invalid.
+    fn port_kind(&self, port: c_ulong) -> Option<PortKind> {
+        let desc = unsafe { *self.raw().PortDescriptors.offset(port as isize) } as u32;
+        match (desc & ffi::LADSPA_PORT_INPUT != 0, desc & ffi::LADSPA_PORT_AUDIO != 0) {
+            _ if desc & (ffi::LADSPA_PORT_AUDIO | ffi::LADSPA_PORT_CONTROL) == 0 => None,
+            (true, true) => Some(PortKind::AudioInput),
+            (false, true) => Some(PortKind::AudioOutput),
+            (true, false) => Some(PortKind::ControlInput),
+            (false, false) => Some(PortKind::ControlOutput),
+        }
+    }
+
+    /// Port's name
+    fn port_name(&self, port: c_ulong) -> &'static str {
+        to_str(unsafe { *self.raw().PortNames.offset(port as isize) })
+    }
+
+    /// Create control info for the provided port, using its hints.
+    fn control(&self, port: c_ulong, rate: SampleRate) -> Control {
+        let hint = unsafe { &*self.raw().PortRangeHints.offset(port as isize) };
+        let desc = hint.HintDescriptor as u32;
+        let is = |flag: u32| desc & flag != 0;
+
+        let scale = if is(ffi::LADSPA_HINT_SAMPLE_RATE) { rate as f32 } else { 1.0 };
+        let (min, max) = (if is(ffi::LADSPA_HINT_BOUNDED_BELOW) { hint.LowerBound * scale } else { 0.0 },
+                          if is(ffi::LADSPA_HINT_BOUNDED_ABOVE) { hint.UpperBound * scale } else { 1.0 });
+
+        // interpolate between bounds, logarithmically if hinted so
+        let between = |ratio: f32| {
+            if is(ffi::LADSPA_HINT_LOGARITHMIC) && min > 0.0 && max > 0.0 {
+                (min.ln() * (1.0 - ratio) + max.ln() * ratio).exp()
+            }
+            else { min * (1.0 - ratio) + max * ratio }
+        };
+
+        let default = match desc & ffi::LADSPA_HINT_DEFAULT_MASK {
+            ffi::LADSPA_HINT_DEFAULT_MINIMUM => min,
+            ffi::LADSPA_HINT_DEFAULT_LOW => between(0.25),
+            ffi::LADSPA_HINT_DEFAULT_MIDDLE => between(0.5),
+            ffi::LADSPA_HINT_DEFAULT_HIGH => between(0.75),
+            ffi::LADSPA_HINT_DEFAULT_MAXIMUM => max,
+            ffi::LADSPA_HINT_DEFAULT_0 => 0.0,
+            ffi::LADSPA_HINT_DEFAULT_1 => 1.0,
+            ffi::LADSPA_HINT_DEFAULT_100 => 100.0,
+            ffi::LADSPA_HINT_DEFAULT_440 => 440.0,
+            _ => min.max(0.0).min(max),
+        };
+        let default = if is(ffi::LADSPA_HINT_INTEGER) { default.round() } else { default };
+
+        let mut metadatas = vec![("label", self.port_name(port))];
+        if is(ffi::LADSPA_HINT_LOGARITHMIC) {
+            metadatas.push(("scale", "log"));
+        }
+        if is(ffi::LADSPA_HINT_INTEGER) {
+            metadatas.push(("integer", "true"));
+        }
+
+        Control {
+            port,
+            output: self.port_kind(port) == Some(PortKind::ControlOutput),
+            value_type: if is(ffi::LADSPA_HINT_TOGGLED) { ValueType::Bool } else { ValueType::F32 },
+            default, range: (min, max), metadatas,
+        }
+    }
+}
+
+
+/// Load plugin library, returning its descriptors.
+fn load_library(path: &Path) -> Vec<Descriptor> {
+    let c_path = match path.to_str().and_then(|p| CString::new(p).ok()) {
+        Some(p) => p,
+        None => return Vec::new(),
+    };
+
+    let lib = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+    if lib.is_null() {
+        return Vec::new();
+    }
+
+    let symbol = unsafe { libc::dlsym(lib, b"ladspa_descriptor\0".as_ptr() as *const c_char) };
+    if symbol.is_null() {
+        unsafe { libc::dlclose(lib) };
+        return Vec::new();
+    }
+
+    let func: unsafe extern "C" fn(c_ulong) -> *const ffi::LADSPA_Descriptor =
+        unsafe { std::mem::transmute(symbol) };
+    let mut descriptors = Vec::new();
+    for index in 0.. {
+        let descriptor = unsafe { func(index) };
+        if descriptor.is_null() {
+            break;
+        }
+        descriptors.push(Descriptor { path: path.to_path_buf(), descriptor });
+    }
+    descriptors
+}
+
+
+/// Return directories to search plugins in.
+pub fn search_path() -> Vec<PathBuf> {
+    match env::var("LADSPA_PATH") {
+        Ok(path) => env::split_paths(&path).collect(),
+        Err(_) => {
+            let mut path: Vec<PathBuf> = env::split_paths(DEFAULT_PATH).collect();
+            if let Some(home) = env::var_os("HOME") {
+                path.push(Path::new(&home).join(".ladspa"));
+            }
+            path
+        },
+    }
+}
+
+
+/// Scan directories for plugins. Prefer `descriptors()` that scans only once.
+pub fn scan(dirs: &[PathBuf]) -> Vec<Descriptor> {
+    let mut descriptors = Vec::new();
+    for dir in dirs.iter() {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if path.extension().map(|ext| ext == "so").unwrap_or(false) {
+                descriptors.extend(load_library(&path));
+            }
+        }
+    }
+    descriptors
+}
+
+
+/// Return descriptors of available plugins, scanning search path on first call.
+pub fn descriptors() -> &'static [Descriptor] {
+    static SCAN: Once = Once::new();
+    static DESCRIPTORS: AtomicPtr<Vec<Descriptor>> = AtomicPtr::new(ptr::null_mut());
+
+    SCAN.call_once(|| {
+        let descriptors = Box::leak(Box::new(scan(&search_path())));
+        DESCRIPTORS.store(descriptors, Ordering::Release);
+    });
+    // descriptors are never dropped
+    unsafe { &*DESCRIPTORS.load(Ordering::Acquire) }
+}
+
+
+/// List available plugins' labels.
+pub fn list_plugins() -> Vec<&'static str> {
+    descriptors().iter().map(|d| d.label()).collect()
+}
+
+
+/// Instanciate plugin by label.
+pub fn new_plugin<PS: ProcessScope>(name: &str, rate: SampleRate) -> Option<BoxedDSP<f32,PS>> {
+    let descriptor = descriptors().iter().find(|d| d.label() == name)?;
+    LadspaPlugin::new(descriptor, rate).ok()
+        .map(|plugin| Box::new(plugin) as BoxedDSP<f32,PS>)
+}
+
+
+impl<PS: ProcessScope> LadspaPlugin<PS> {
+    /// Instanciate and activate plugin.
+    pub fn new(descriptor: &Descriptor, rate: SampleRate) -> Result<Self, String> {
+        let raw = descriptor.raw();
+        if raw.instantiate.is_none() || raw.connect_port.is_none() || raw.run.is_none() {
+            return Err(format!("{}: invalid descriptor", descriptor.label()));
+        }
+
+        let (mut controls, mut inputs, mut outputs) = (Vec::new(), Vec::new(), Vec::new());
+        for port in 0..descriptor.port_count() {
+            match descriptor.port_kind(port) {
+                Some(PortKind::AudioInput) => inputs.push((port, vec![0.0; MAX_SAMPLES])),
+                Some(PortKind::AudioOutput) => outputs.push((port, vec![0.0; MAX_SAMPLES])),
+                Some(_) => controls.push(descriptor.control(port, rate)),
+                None => {},
+            }
+        }
+
+        let values = controls.iter().map(|c| c.default).collect::<Vec<_>>().into_boxed_slice();
+        let mut plugin = Self {
+            descriptor: descriptor.clone(),
+            handle: ptr::null_mut(),
+            rate, controls, values, inputs, outputs,
+            phantom: PhantomData,
+        };
+        plugin.instantiate(rate)?;
+        Ok(plugin)
+    }
+
+    /// Plugin's descriptor
+    pub fn descriptor(&self) -> &Descriptor {
+        &self.descriptor
+    }
+
+    /// Instanciate plugin for the provided rate, then connect its ports and activate it. The
+    /// previous instance is released, ports' values and buffers being kept.
+    fn instantiate(&mut self, rate: SampleRate) -> Result<(), String> {
+        self.release();
+
+        let raw = self.descriptor.raw();
+        let handle = unsafe { raw.instantiate.unwrap()(self.descriptor.descriptor, rate as c_ulong) };
+        if handle.is_null() {
+            return Err(format!("{}: instanciation failed", self.descriptor.label()));
+        }
+        self.handle = handle;
+        self.rate = rate;
+
+        let connect_port = raw.connect_port.unwrap();
+        for (control, value) in self.controls.iter().zip(self.values.iter_mut()) {
+            unsafe { connect_port(handle, control.port, value as *mut f32) };
+        }
+        for (port, buffer) in self.inputs.iter_mut().chain(self.outputs.iter_mut()) {
+            unsafe { connect_port(handle, *port, buffer.as_mut_ptr()) };
+        }
+
+        if let Some(activate) = raw.activate {
+            unsafe { activate(handle) };
+        }
+        Ok(())
+    }
+
+    /// Deactivate and release plugin's instance, if any.
+    fn release(&mut self) {
+        if self.handle.is_null() {
+            return;
+        }
+
+        let raw = self.descriptor.raw();
+        unsafe {
+            if let Some(deactivate) = raw.deactivate {
+                deactivate(self.handle);
+            }
+            if let Some(cleanup) = raw.cleanup {
+                cleanup(self.handle);
+            }
+        }
+        self.handle = ptr::null_mut();
+    }
+}
+
+
+impl<PS: ProcessScope> Drop for LadspaPlugin<PS> {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+
+impl<PS: ProcessScope> Object for LadspaPlugin<PS> {
+    fn object_meta(&self) -> ObjectMeta {
+        ObjectMeta::new(self.descriptor.name(), Some(vec![
+            ("label", self.descriptor.label()),
+            ("maker", self.descriptor.maker()),
+        ]))
+    }
+
+    fn get_value(&self, index: ObjectIndex) -> Option<Value> {
+        let control = self.controls.get(index as usize)?;
+        Value::from_f64(control.value_type, self.values[index as usize] as f64)
+    }
+
+    fn set_value(&mut self, index: ObjectIndex, value: Value) -> Result<Value, ()> {
+        let control = self.controls.get(index as usize).ok_or(())?;
+        if control.output {
+            return Err(());
+        }
+
+        let value = value.as_f64().ok_or(())? as f32;
+        let value = value.max(control.range.0).min(control.range.1);
+        self.values[index as usize] = value;
+        Value::from_f64(control.value_type, value as f64).ok_or(())
+    }
+
+    fn map_object(&self, mapper: &mut dyn ObjectMapper) {
+        for (index, control) in self.controls.iter().enumerate() {
+            let mut metadatas = control.metadatas.clone();
+            if control.output {
+                metadatas.push(("output", "true"));
+            }
+
+            mapper.declare(FieldInfo {
+                index: index as ObjectIndex,
+                value_type: control.value_type,
+                default: Value::from_f64(control.value_type, control.default as f64),
+                range: match control.value_type {
+                    ValueType::Bool => None,
+                    _ => Some(Range::F32(control.range.0, control.range.1, 0.0)),
+                },
+                metadatas,
+            });
+        }
+    }
+}
+
+
+impl<PS: ProcessScope> DSP for LadspaPlugin<PS> {
+    type Sample = f32;
+    type Scope = PS;
+
+    fn prepare(&mut self, rate: SampleRate) {
+        if rate == self.rate && !self.handle.is_null() {
+            return;
+        }
+
+        // ranges hinted as a fraction of the sample rate are updated, values being kept
+        for (control, value) in self.controls.iter_mut().zip(self.values.iter_mut()) {
+            *control = self.descriptor.control(control.port, rate);
+            if !control.output {
+                *value = value.max(control.range.0).min(control.range.1);
+            }
+        }
+
+        if let Err(err) = self.instantiate(rate) {
+            log::error!("{}", err);
+        }
+    }
+
+    fn process_audio(&mut self, _scope: &Self::Scope, input: Option<&dyn BufferView<Sample=Self::Sample>>,
+                     output: Option<&mut dyn BufferView<Sample=Self::Sample>>) -> usize
+    {
+        let n_samples = match (input.as_ref(), output.as_ref()) {
+            (_, Some(output)) => output.n_samples(),
+            (Some(input), None) => input.n_samples(),
+            (None, None) => return 0,
+        };
+
+        // plugin failed to instantiate
+        if self.handle.is_null() {
+            return match output {
+                Some(output) => {
+                    fill_samples(output.as_slice_mut(), 0.0);
+                    output.len()
+                },
+                None => 0,
+            };
+        }
+
+        // ports buffers hold `MAX_SAMPLES` frames: larger blocks are run in multiple parts
+        let mut output = output;
+        let run = self.descriptor.raw().run.unwrap();
+        let mut offset = 0;
+        while offset < n_samples {
+            let len = (n_samples - offset).min(MAX_SAMPLES);
+
+            // plugin ports use non-interleaved buffers: copy input channels into them
+            for (channel, (_, buffer)) in self.inputs.iter_mut().enumerate() {
+                let mut n = 0;
+                if let Some(samples) = input.and_then(|input| input.channel(channel as NChannels)) {
+                    for (dst, src) in buffer[..len].iter_mut().zip(samples.skip(offset)) {
+                        *dst = *src;
+                        n += 1;
+                    }
+                }
+                fill_samples(&mut buffer[n..len], 0.0);
+            }
+
+            unsafe { run(self.handle, len as c_ulong) };
+
+            if let Some(output) = output.as_mut() {
+                for channel in 0..output.n_channels() {
+                    let samples = match output.channel_mut(channel) {
+                        Some(samples) => samples.skip(offset).take(len),
+                        None => continue,
+                    };
+                    match self.outputs.get(channel as usize) {
+                        Some((_, buffer)) => for (dst, src) in samples.zip(buffer[..len].iter()) {
+                            *dst = *src;
+                        },
+                        // channels without output port are silent
+                        None => for dst in samples {
+                            *dst = 0.0;
+                        },
+                    }
+                }
+            }
+            offset += len;
+        }
+
+        match output {
+            Some(output) => output.len(),
+            None => 0,
+        }
+    }
+
+    fn n_channels(&self) -> NChannels {
+        self.outputs.len().max(self.inputs.len()) as NChannels
+    }
+
+    fn is_sink(&self) -> bool {
+        self.outputs.is_empty()
+    }
+
+    fn is_source(&self) -> bool {
+        self.inputs.is_empty()
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::os::raw::c_int;
+    use super::*;
+    use crate::data::Buffer;
+    use super::super::block::BlockScope;
+
+    /// Test plugin's instance: output is input multiplied by gain, "rate" output port being
+    /// set to instantiation's rate.
+    struct Instance {
+        rate: c_ulong,
+        ports: [*mut f32; 5],
+    }
+
+    unsafe extern "C" fn instantiate(_: *const ffi::LADSPA_Descriptor, rate: c_ulong) -> ffi::LADSPA_Handle {
+        Box::into_raw(Box::new(Instance { rate, ports: [ptr::null_mut(); 5] })) as ffi::LADSPA_Handle
+    }
+
+    unsafe extern "C" fn connect_port(handle: ffi::LADSPA_Handle, port: c_ulong, data: *mut f32) {
+        (*(handle as *mut Instance)).ports[port as usize] = data;
+    }
+
+    unsafe extern "C" fn run(handle: ffi::LADSPA_Handle, count: c_ulong) {
+        let instance = &*(handle as *mut Instance);
+        let gain = *instance.ports[2];
+        for i in 0..count as isize {
+            *instance.ports[1].offset(i) = *instance.ports[0].offset(i) * gain;
+        }
+        *instance.ports[4] = instance.rate as f32;
+    }
+
+    unsafe extern "C" fn cleanup(handle: ffi::LADSPA_Handle) {
+        drop(Box::from_raw(handle as *mut Instance));
+    }
+
+    /// Descriptor of the test plugin, leaked as libraries' ones are never unloaded.
+    fn descriptor() -> Descriptor {
+        let names: Vec<*const c_char> = ["in\0", "out\0", "gain\0", "cutoff\0", "rate\0"].iter()
+                                            .map(|n| n.as_ptr() as *const c_char).collect();
+        let ports: Vec<c_int> = vec![
+            (ffi::LADSPA_PORT_INPUT | ffi::LADSPA_PORT_AUDIO) as c_int,
+            (ffi::LADSPA_PORT_OUTPUT | ffi::LADSPA_PORT_AUDIO) as c_int,
+            (ffi::LADSPA_PORT_INPUT | ffi::LADSPA_PORT_CONTROL) as c_int,
+            (ffi::LADSPA_PORT_INPUT | ffi::LADSPA_PORT_CONTROL) as c_int,
+            (ffi::LADSPA_PORT_OUTPUT | ffi::LADSPA_PORT_CONTROL) as c_int,
+        ];
+        let hint = |desc: u32, lower: f32, upper: f32|
+            ffi::LADSPA_PortRangeHint { HintDescriptor: desc as c_int, LowerBound: lower, UpperBound: upper };
+        let bounded = ffi::LADSPA_HINT_BOUNDED_BELOW | ffi::LADSPA_HINT_BOUNDED_ABOVE;
+        let hints = vec![
+            hint(0, 0.0, 0.0),
+            hint(0, 0.0, 0.0),
+            hint(bounded | ffi::LADSPA_HINT_DEFAULT_1, 0.0, 4.0),
+            hint(bounded | ffi::LADSPA_HINT_SAMPLE_RATE | ffi::LADSPA_HINT_LOGARITHMIC | ffi::LADSPA_HINT_DEFAULT_MAXIMUM,
+                 0.0625, 0.5),
+            hint(0, 0.0, 0.0),
+        ];
+
+        let raw = ffi::LADSPA_Descriptor {
+            UniqueID: 1,
+            Label: "gain\0".as_ptr() as *const c_char,
+            Properties: 0,
+            Name: "Gain\0".as_ptr() as *const c_char,
+            Maker: "foxlive\0".as_ptr() as *const c_char,
+            Copyright: ptr::null(),
+            PortCount: 5,
+            PortDescriptors: Box::leak(ports.into_boxed_slice()).as_ptr(),
+            PortNames: Box::leak(names.into_boxed_slice()).as_ptr(),
+            PortRangeHints: Box::leak(hints.into_boxed_slice()).as_ptr(),
+            ImplementationData: ptr::null_mut(),
+            instantiate: Some(instantiate),
+            connect_port: Some(connect_port),
+            activate: None,
+            run: Some(run),
+            run_adding: None,
+            set_run_adding_gain: None,
+            deactivate: None,
+            cleanup: Some(cleanup),
+        };
+        Descriptor { path: PathBuf::new(), descriptor: Box::leak(Box::new(raw)) }
+    }
+
+    fn process(plugin: &mut LadspaPlugin<BlockScope>) -> Vec<f32> {
+        let input: Buffer<f32,Vec<f32>> = (true, 1, vec![1.0; 4]).into();
+        let mut output: Buffer<f32,Vec<f32>> = (true, 1, vec![0.0; 4]).into();
+        plugin.process_audio(&BlockScope::new(4, 0, None), Some(&input), Some(&mut output));
+        output.buffer
+    }
+
+    /// Test: ports are mapped to channels and fields using their hints
+    #[test]
+    fn ports() {
+        let plugin = LadspaPlugin::<BlockScope>::new(&descriptor(), 1000).unwrap();
+        assert_eq!((plugin.inputs.len(), plugin.outputs.len(), plugin.n_channels()), (1, 1, 1));
+        assert_eq!(plugin.object_meta().name, "Gain");
+
+        let mut fields: Vec<FieldInfo> = Vec::new();
+        plugin.map_object(&mut fields);
+        assert_eq!(fields.len(), 3);
+        assert!(matches!(fields[0].range, Some(Range::F32(min, max, _)) if min == 0.0 && max == 4.0));
+        assert!(matches!(fields[0].default, Some(Value::F32(v)) if v == 1.0));
+        assert!(matches!(fields[1].range, Some(Range::F32(min, max, _)) if min == 62.5 && max == 500.0));
+        assert!(matches!(fields[1].default, Some(Value::F32(v)) if v == 500.0));
+        assert_eq!(fields[1].metadatas, vec![("label", "cutoff"), ("scale", "log")]);
+        assert_eq!(fields[2].metadatas, vec![("label", "rate"), ("output", "true")]);
+    }
+
+    /// Test: plugin is instantiated again for a new rate, keeping values and rescaling ranges
+    #[test]
+    fn prepare() {
+        let mut plugin = LadspaPlugin::<BlockScope>::new(&descriptor(), 1000).unwrap();
+        assert!(plugin.set_value(0, Value::F32(2.0)).is_ok());
+        assert!(plugin.set_value(2, Value::F32(1.0)).is_err());
+        assert_eq!(process(&mut plugin), vec![2.0; 4]);
+        assert!(matches!(plugin.get_value(2), Some(Value::F32(v)) if v == 1000.0));
+
+        plugin.prepare(48000);
+        assert_eq!(process(&mut plugin), vec![2.0; 4]);
+        assert!(matches!(plugin.get_value(2), Some(Value::F32(v)) if v == 48000.0));
+        // kept value is clamped to the rescaled range
+        assert_eq!(plugin.controls[1].range, (3000.0, 24000.0));
+        assert!(matches!(plugin.get_value(1), Some(Value::F32(v)) if v == 3000.0));
+    }
+}
@@ -0,0 +1,6 @@
+#include <faust/dsp/libfaust-c.h>
+#include <faust/dsp/interpreter-dsp-c.h>
+
+//: fn .*CInterpreterDSP.*
+//: type UIGlue
+//: type MetaGlue
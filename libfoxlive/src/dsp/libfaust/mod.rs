@@ -0,0 +1,254 @@
+//! Compile Faust code at runtime with libfaust's interpreter backend.
+//!
+//! Compiled DSP are wrapped by `FaustDSP`, exposing their user interface as object's fields
+//! the same way as Faust plugins generated at build time. Compilation can be slow: it should
+//! be done outside of the audio thread, the resulting DSP being then hot-swapped using
+//! `Graph::replace_node`, which preserves parameters whose labels match.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let source = std::fs::read_to_string("gain.dsp").unwrap();
+//! let dsp = libfaust::compile::<Scope>("gain", &source).unwrap();
+//! // client is a `registry::Builder`, preparing the unit before sending it
+//! client.replace_node(node, dsp.into());
+//! ```
+use std::collections::HashSet;
+use std::ffi::{CStr,CString};
+use std::os::raw::{c_char,c_int,c_void};
+use std::ptr;
+use std::sync::{Arc,Mutex,Once};
+use std::sync::atomic::{AtomicPtr,Ordering};
+
+use smallvec::SmallVec;
+
+use super::dsp::BoxedDSP;
+use super::faust::{FaustDsp,FaustDSP,Meta,UI};
+use super::graph::ProcessScope;
+
+
+#[allow(warnings)]
+mod ffi;
+
+
+/// Size of libfaust's error message buffer
+const ERROR_SIZE: usize = 4096;
+
+
+/// Compiled Faust code, from which DSP instances are created.
+pub struct Factory {
+    factory: *mut ffi::interpreter_dsp_factory,
+}
+
+unsafe impl Send for Factory {}
+unsafe impl Sync for Factory {}
+
+
+/// DSP instance of a compiled Faust code.
+pub struct Interpreter {
+    dsp: *mut ffi::interpreter_dsp,
+    /// Factory must outlive its instances
+    _factory: Arc<Factory>,
+}
+
+unsafe impl Send for Interpreter {}
+
+
+/// Return a static string for labels and metadatas provided by libfaust. Strings are interned
+/// so that recompiling code doesn't leak memory.
+fn intern(s: *const c_char) -> &'static str {
+    static INIT: Once = Once::new();
+    static STRINGS: AtomicPtr<Mutex<HashSet<&'static str>>> = AtomicPtr::new(ptr::null_mut());
+
+    if s.is_null() {
+        return "";
+    }
+
+    let s = unsafe { CStr::from_ptr(s) }.to_str().unwrap_or("");
+    INIT.call_once(|| STRINGS.store(Box::leak(Box::new(Mutex::new(HashSet::new()))), Ordering::Release));
+    // strings set is never dropped
+    let strings = unsafe { &*STRINGS.load(Ordering::Acquire) };
+
+    let mut strings = strings.lock().unwrap();
+    match strings.get(s) {
+        Some(s) => s,
+        None => {
+            let s: &'static str = Box::leak(s.to_string().into_boxed_str());
+            strings.insert(s);
+            s
+        },
+    }
+}
+
+
+impl Factory {
+    /// Compile Faust source code, returning libfaust's error message on failure.
+    pub fn compile(name: &str, source: &str, args: &[&str]) -> Result<Arc<Self>, String> {
+        let name = CString::new(name).map_err(|e| format!("{}", e))?;
+        let source = CString::new(source).map_err(|e| format!("{}", e))?;
+        let args = args.iter().map(|a| CString::new(*a)).collect::<Result<Vec<_>,_>>()
+                       .map_err(|e| format!("{}", e))?;
+        let mut argv: Vec<*const c_char> = args.iter().map(|a| a.as_ptr()).collect();
+        let mut error = vec![0 as c_char; ERROR_SIZE];
+
+        let factory = unsafe { ffi::createCInterpreterDSPFactoryFromString(
+            name.as_ptr(), source.as_ptr(), argv.len() as c_int, argv.as_mut_ptr(),
+            error.as_mut_ptr()) };
+        match factory.is_null() {
+            true => Err(unsafe { CStr::from_ptr(error.as_ptr()) }.to_string_lossy().into_owned()),
+            false => Ok(Arc::new(Self { factory })),
+        }
+    }
+
+    /// Create a new DSP instance
+    pub fn instance(self: &Arc<Self>) -> Result<Interpreter, String> {
+        let dsp = unsafe { ffi::createCInterpreterDSPInstance(self.factory) };
+        match dsp.is_null() {
+            true => Err("can't create DSP instance".to_string()),
+            false => Ok(Interpreter { dsp, _factory: self.clone() }),
+        }
+    }
+}
+
+impl Drop for Factory {
+    fn drop(&mut self) {
+        unsafe { ffi::deleteCInterpreterDSPFactory(self.factory) };
+    }
+}
+
+
+/// Compile source code and wrap it as a graph's DSP.
+pub fn compile<PS: ProcessScope>(name: &str, source: &str) -> Result<BoxedDSP<f32,PS>, String> {
+    let dsp = Factory::compile(name, source, &[])?.instance()?;
+    Ok(Box::new(FaustDSP::<Interpreter,PS>::from_dsp(Box::new(dsp))))
+}
+
+
+impl Drop for Interpreter {
+    fn drop(&mut self) {
+        unsafe { ffi::deleteCInterpreterDSPInstance(self.dsp) };
+    }
+}
+
+impl FaustDsp for Interpreter {
+    fn metadata(&mut self, m: &mut dyn Meta) {
+        let mut m = m;
+        let mut glue = ffi::MetaGlue {
+            metaInterface: &mut m as *mut &mut dyn Meta as *mut c_void,
+            declare: Some(meta_declare),
+        };
+        unsafe { ffi::metadataCInterpreterDSPInstance(self.dsp, &mut glue) };
+    }
+
+    fn num_inputs(&mut self) -> i32 {
+        unsafe { ffi::getNumInputsCInterpreterDSPInstance(self.dsp) }
+    }
+
+    fn num_outputs(&mut self) -> i32 {
+        unsafe { ffi::getNumOutputsCInterpreterDSPInstance(self.dsp) }
+    }
+
+    fn init(&mut self, sample_rate: i32) {
+        unsafe { ffi::initCInterpreterDSPInstance(self.dsp, sample_rate) };
+    }
+
+    fn build_user_interface(&mut self, ui: &mut dyn UI<f32>) {
+        let mut ui = ui;
+        let mut glue = ffi::UIGlue {
+            uiInterface: &mut ui as *mut &mut dyn UI<f32> as *mut c_void,
+            openTabBox: Some(ui_open_tab_box),
+            openHorizontalBox: Some(ui_open_horizontal_box),
+            openVerticalBox: Some(ui_open_vertical_box),
+            closeBox: Some(ui_close_box),
+            addButton: Some(ui_add_button),
+            addCheckButton: Some(ui_add_check_button),
+            addVerticalSlider: Some(ui_add_vertical_slider),
+            addHorizontalSlider: Some(ui_add_horizontal_slider),
+            addNumEntry: Some(ui_add_num_entry),
+            addHorizontalBargraph: Some(ui_add_horizontal_bargraph),
+            addVerticalBargraph: Some(ui_add_vertical_bargraph),
+            addSoundfile: Some(ui_add_soundfile),
+            declare: Some(ui_declare),
+        };
+        unsafe { ffi::buildUserInterfaceCInterpreterDSPInstance(self.dsp, &mut glue) };
+    }
+
+    fn compute(&mut self, count: i32, inputs: &[&[f32]], outputs: &mut [&mut [f32]]) {
+        let mut inputs: SmallVec<[*mut f32;8]> = inputs.iter().map(|b| b.as_ptr() as *mut f32).collect();
+        let mut outputs: SmallVec<[*mut f32;8]> = outputs.iter_mut().map(|b| b.as_mut_ptr()).collect();
+        unsafe { ffi::computeCInterpreterDSPInstance(self.dsp, count, inputs.as_mut_ptr(),
+                                                     outputs.as_mut_ptr()) };
+    }
+}
+
+
+// Glue callbacks forwarding libfaust calls to `Meta` and `UI`.
+
+unsafe fn ui<'a>(ui: *mut c_void) -> &'a mut dyn UI<f32> {
+    &mut **(ui as *mut &mut dyn UI<f32>)
+}
+
+unsafe extern "C" fn meta_declare(meta: *mut c_void, key: *const c_char, value: *const c_char) {
+    (**(meta as *mut &mut dyn Meta)).declare(intern(key), intern(value));
+}
+
+unsafe extern "C" fn ui_open_tab_box(u: *mut c_void, label: *const c_char) {
+    ui(u).openTabBox(intern(label))
+}
+
+unsafe extern "C" fn ui_open_horizontal_box(u: *mut c_void, label: *const c_char) {
+    ui(u).openHorizontalBox(intern(label))
+}
+
+unsafe extern "C" fn ui_open_vertical_box(u: *mut c_void, label: *const c_char) {
+    ui(u).openVerticalBox(intern(label))
+}
+
+unsafe extern "C" fn ui_close_box(u: *mut c_void) {
+    ui(u).closeBox()
+}
+
+unsafe extern "C" fn ui_add_button(u: *mut c_void, label: *const c_char, zone: *mut f32) {
+    ui(u).addButton(intern(label), &mut *zone)
+}
+
+unsafe extern "C" fn ui_add_check_button(u: *mut c_void, label: *const c_char, zone: *mut f32) {
+    ui(u).addCheckButton(intern(label), &mut *zone)
+}
+
+unsafe extern "C" fn ui_add_vertical_slider(u: *mut c_void, label: *const c_char, zone: *mut f32,
+                                            init: f32, min: f32, max: f32, step: f32) {
+    ui(u).addVerticalSlider(intern(label), &mut *zone, init, min, max, step)
+}
+
+unsafe extern "C" fn ui_add_horizontal_slider(u: *mut c_void, label: *const c_char, zone: *mut f32,
+                                              init: f32, min: f32, max: f32, step: f32) {
+    ui(u).addHorizontalSlider(intern(label), &mut *zone, init, min, max, step)
+}
+
+unsafe extern "C" fn ui_add_num_entry(u: *mut c_void, label: *const c_char, zone: *mut f32,
+                                      init: f32, min: f32, max: f32, step: f32) {
+    ui(u).addNumEntry(intern(label), &mut *zone, init, min, max, step)
+}
+
+unsafe extern "C" fn ui_add_horizontal_bargraph(u: *mut c_void, label: *const c_char, zone: *mut f32,
+                                                min: f32, max: f32) {
+    ui(u).addHorizontalBargraph(intern(label), &mut *zone, min, max)
+}
+
+unsafe extern "C" fn ui_add_vertical_bargraph(u: *mut c_void, label: *const c_char, zone: *mut f32,
+                                              min: f32, max: f32) {
+    ui(u).addVerticalBargraph(intern(label), &mut *zone, min, max)
+}
+
+/// Soundfiles are not supported
+unsafe extern "C" fn ui_add_soundfile(_u: *mut c_void, _label: *const c_char, _url: *const c_char,
+                                      _zone: *mut *mut ffi::Soundfile) {
+}
+
+unsafe extern "C" fn ui_declare(u: *mut c_void, zone: *mut f32, key: *const c_char, value: *const c_char) {
+    // boxes' metadatas (null zone) are ignored
+    if !zone.is_null() {
+        ui(u).declare(&mut *zone, intern(key), intern(value))
+    }
+}
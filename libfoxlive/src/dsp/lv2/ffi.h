@@ -0,0 +1,9 @@
+#include <lilv/lilv.h>
+#include <lv2/urid/urid.h>
+
+//: fn lilv_.*
+//: type LilvInstance
+//: type LV2_Descriptor
+//: type LV2_Feature
+//: type LV2_URID_Map
+//: type LV2_URID_Unmap
@@ -0,0 +1,1119 @@
+//! Host LV2 plugins as graph's DSP, using lilv.
+//!
+//! Installed bundles are discovered once by lilv (using `LV2_PATH` or the default
+//! locations), and plugins are instanciated by URI. A plugin exposes:
+//! - its control ports as object's fields, followed by a `preset` field when it has presets;
+//! - its audio ports as channels, in declaration order;
+//! - its first MIDI atom input and output ports as graph event ports.
+//!
+//! Other ports (CV, other atom ports) are connected to buffers owned by the plugin instance:
+//! atom inputs receive empty sequences and outputs are discarded.
+//!
+//! Atom output events are read at the beginning of the next block, introducing one block of
+//! latency on event outputs.
+//!
+//! Presets selected through the `preset` field are loaded by a worker thread, their state
+//! being restored by the audio thread before the next run.
+//!
+//! Plugin is instantiated for a sample rate: when prepared for another rate, it is
+//! instantiated again, its state and control ports' values being restored.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use libfoxlive::dsp::block::BlockScope;
+//! use libfoxlive::dsp::lv2;
+//!
+//! let mut plugin = lv2::Lv2Plugin::<BlockScope>::new(
+//!         lv2::descriptor("http://calf.sourceforge.net/plugins/Reverb").unwrap(), 48000).unwrap();
+//! let state = plugin.save_state().unwrap();
+//! // ...
+//! plugin.restore_state(&state);
+//! ```
+use std::collections::HashMap;
+use std::ffi::{CStr,CString};
+use std::marker::PhantomData;
+use std::os::raw::{c_char,c_void};
+use std::iter;
+use std::ptr;
+use std::sync::{Mutex,Once};
+use std::sync::atomic::{AtomicPtr,Ordering};
+
+use crate::data::{BufferView,EventBuffer,Event,EventData,MidiMessage,NChannels,NSamples,SampleRate};
+use crate::data::sample::fill_samples;
+use crate::rpc::*;
+use super::dsp::{DSP,BoxedDSP};
+use super::graph::{ProcessScope,MAX_SAMPLES};
+use super::swap::{PathLoader,Swap};
+
+
+#[allow(warnings)]
+mod ffi;
+
+
+/// Atom buffers' size in bytes
+const ATOM_CAPACITY: usize = 8192;
+
+const URID_MAP: &[u8] = b"http://lv2plug.in/ns/ext/urid#map\0";
+const URID_UNMAP: &[u8] = b"http://lv2plug.in/ns/ext/urid#unmap\0";
+
+const ATOM_SEQUENCE: &str = "http://lv2plug.in/ns/ext/atom#Sequence";
+const ATOM_CHUNK: &str = "http://lv2plug.in/ns/ext/atom#Chunk";
+const ATOM_FLOAT: &str = "http://lv2plug.in/ns/ext/atom#Float";
+const MIDI_EVENT: &str = "http://lv2plug.in/ns/ext/midi#MidiEvent";
+
+
+/// URIs to URIDs mapping, shared by all plugins.
+struct Urids {
+    map: HashMap<String, u32>,
+    uris: Vec<CString>,
+}
+
+
+/// Nodes used to query plugins' data.
+struct Nodes {
+    audio_port: *mut ffi::LilvNode,
+    control_port: *mut ffi::LilvNode,
+    atom_port: *mut ffi::LilvNode,
+    input_port: *mut ffi::LilvNode,
+    midi_event: *mut ffi::LilvNode,
+    toggled: *mut ffi::LilvNode,
+    integer: *mut ffi::LilvNode,
+    sample_rate: *mut ffi::LilvNode,
+    logarithmic: *mut ffi::LilvNode,
+    preset: *mut ffi::LilvNode,
+    label: *mut ffi::LilvNode,
+}
+
+
+/// URIDs used by the host.
+#[derive(Default)]
+struct HostUrids {
+    sequence: u32,
+    chunk: u32,
+    float: u32,
+    midi: u32,
+}
+
+
+/// Lilv world, created once and never freed.
+struct World {
+    world: *mut ffi::LilvWorld,
+    urids: Mutex<Urids>,
+    map: ffi::LV2_URID_Map,
+    unmap: ffi::LV2_URID_Unmap,
+    map_feature: ffi::LV2_Feature,
+    unmap_feature: ffi::LV2_Feature,
+    /// Null-terminated features list passed to plugins
+    features: [*const ffi::LV2_Feature; 3],
+    nodes: Nodes,
+    host_urids: HostUrids,
+    descriptors: Vec<Descriptor>,
+}
+
+unsafe impl Send for World {}
+unsafe impl Sync for World {}
+
+
+/// Port's kind.
+#[derive(Copy,Clone,Debug,PartialEq)]
+enum PortKind {
+    AudioInput,
+    AudioOutput,
+    ControlInput,
+    ControlOutput,
+    MidiInput,
+    MidiOutput,
+    /// Atom port not supporting MIDI events
+    AtomInput,
+    AtomOutput,
+    /// Unsupported port (e.g. CV), connected to a silent buffer
+    Other,
+}
+
+
+/// Port information, read once when scanning plugins.
+#[derive(Clone)]
+struct Port {
+    index: u32,
+    kind: PortKind,
+    symbol: &'static str,
+    name: &'static str,
+    value_type: ValueType,
+    /// Default value and range (for control ports)
+    default: f32,
+    range: (f32,f32),
+    /// Range is relative to sample rate
+    sample_rate: bool,
+    logarithmic: bool,
+    integer: bool,
+}
+
+
+/// Plugin's descriptor.
+#[derive(Clone)]
+pub struct Descriptor {
+    plugin: *const ffi::LilvPlugin,
+    uri: &'static str,
+    name: &'static str,
+    ports: Vec<Port>,
+}
+
+unsafe impl Send for Descriptor {}
+unsafe impl Sync for Descriptor {}
+
+
+/// Preset of a plugin.
+struct Preset {
+    node: *const ffi::LilvNode,
+    uri: String,
+    label: String,
+}
+
+/// Preset's state, loaded outside of the audio thread.
+struct PresetState {
+    state: *mut ffi::LilvState,
+    /// Preset's index
+    index: usize,
+}
+
+unsafe impl Send for PresetState {}
+
+/// Load presets selected from the audio thread.
+struct PresetLoader {
+    /// Posted paths are presets' URIs
+    uris: PathLoader<usize>,
+    swap: Swap<Option<PresetState>>,
+    /// Last state restored by the audio thread
+    state: Option<PresetState>,
+}
+
+
+/// Control port exposed as object's field.
+struct Control {
+    port: u32,
+    symbol: &'static str,
+    output: bool,
+    value_type: ValueType,
+    default: f32,
+    range: (f32,f32),
+    metadatas: Metadatas,
+}
+
+
+/// Atom sequence buffer, 8 bytes aligned.
+struct AtomBuffer {
+    port: u32,
+    data: Vec<u64>,
+}
+
+
+/// LV2 plugin instance.
+pub struct Lv2Plugin<PS: ProcessScope> {
+    descriptor: Descriptor,
+    /// Plugin's instance, null if instantiation failed
+    instance: *mut ffi::LilvInstance,
+    /// Sample rate the plugin is instantiated for
+    rate: SampleRate,
+    controls: Vec<Control>,
+    /// Control ports' values, connected to the plugin. Its size never changes.
+    values: Box<[f32]>,
+    presets: Vec<Preset>,
+    preset: usize,
+    /// Loader of the `preset` field's values, if plugin has presets
+    preset_loader: Option<PresetLoader>,
+    /// Maximum number of samples per block, size of audio buffers
+    max_samples: NSamples,
+    inputs: Vec<(u32, Vec<f32>)>,
+    outputs: Vec<(u32, Vec<f32>)>,
+    event_input: Option<AtomBuffer>,
+    event_output: Option<AtomBuffer>,
+    /// Atom ports not used for events, as `(inputs, outputs)`
+    atoms: (Vec<AtomBuffer>, Vec<AtomBuffer>),
+    /// Buffers of unsupported ports, sized as audio buffers
+    others: Vec<(u32, Vec<f32>)>,
+    phantom: PhantomData<PS>,
+}
+
+unsafe impl<PS: ProcessScope> Send for Lv2Plugin<PS> {}
+unsafe impl<PS: ProcessScope> Sync for Lv2Plugin<PS> {}
+
+
+/// Return string for the provided C string, empty if null.
+fn to_str<'a>(s: *const c_char) -> &'a str {
+    if s.is_null() {
+        return "";
+    }
+    unsafe { CStr::from_ptr(s) }.to_str().unwrap_or("")
+}
+
+
+extern "C" fn urid_map(handle: ffi::LV2_URID_Map_Handle, uri: *const c_char) -> ffi::LV2_URID {
+    let urids = unsafe { &*(handle as *const Mutex<Urids>) };
+    let uri = to_str(uri);
+    let mut urids = urids.lock().unwrap();
+    if let Some(urid) = urids.map.get(uri) {
+        return *urid;
+    }
+
+    // URID 0 is reserved
+    let urid = urids.uris.len() as u32 + 1;
+    urids.map.insert(uri.to_string(), urid);
+    urids.uris.push(CString::new(uri).unwrap_or_default());
+    urid
+}
+
+extern "C" fn urid_unmap(handle: ffi::LV2_URID_Unmap_Handle, urid: ffi::LV2_URID) -> *const c_char {
+    let urids = unsafe { &*(handle as *const Mutex<Urids>) };
+    let urids = urids.lock().unwrap();
+    match urid {
+        0 => ptr::null(),
+        urid => urids.uris.get(urid as usize - 1).map(|uri| uri.as_ptr()).unwrap_or(ptr::null()),
+    }
+}
+
+
+impl World {
+    /// Return world, loading installed bundles on first call.
+    fn get() -> &'static World {
+        static LOAD: Once = Once::new();
+        static WORLD: AtomicPtr<World> = AtomicPtr::new(ptr::null_mut());
+
+        LOAD.call_once(|| WORLD.store(World::load(), Ordering::Release));
+        // world is never dropped
+        unsafe { &*WORLD.load(Ordering::Acquire) }
+    }
+
+    fn load() -> &'static mut World {
+        let lilv = unsafe { ffi::lilv_world_new() };
+        unsafe { ffi::lilv_world_load_all(lilv) };
+
+        let node = |uri: &str| {
+            let uri = CString::new(uri).unwrap();
+            unsafe { ffi::lilv_new_uri(lilv, uri.as_ptr()) }
+        };
+        let nodes = Nodes {
+            audio_port: node("http://lv2plug.in/ns/lv2core#AudioPort"),
+            control_port: node("http://lv2plug.in/ns/lv2core#ControlPort"),
+            atom_port: node("http://lv2plug.in/ns/ext/atom#AtomPort"),
+            input_port: node("http://lv2plug.in/ns/lv2core#InputPort"),
+            midi_event: node(MIDI_EVENT),
+            toggled: node("http://lv2plug.in/ns/lv2core#toggled"),
+            integer: node("http://lv2plug.in/ns/lv2core#integer"),
+            sample_rate: node("http://lv2plug.in/ns/lv2core#sampleRate"),
+            logarithmic: node("http://lv2plug.in/ns/ext/port-props#logarithmic"),
+            preset: node("http://lv2plug.in/ns/ext/presets#Preset"),
+            label: node("http://www.w3.org/2000/01/rdf-schema#label"),
+        };
+
+        let world: &'static mut World = Box::leak(Box::new(World {
+            world: lilv,
+            urids: Mutex::new(Urids { map: HashMap::new(), uris: Vec::new() }),
+            map: Default::default(),
+            unmap: Default::default(),
+            map_feature: Default::default(),
+            unmap_feature: Default::default(),
+            features: [ptr::null(); 3],
+            nodes,
+            host_urids: Default::default(),
+            descriptors: Vec::new(),
+        }));
+
+        // features point into world, which is never moved
+        let urids = &world.urids as *const Mutex<Urids> as *mut c_void;
+        world.map = ffi::LV2_URID_Map { handle: urids, map: Some(urid_map) };
+        world.unmap = ffi::LV2_URID_Unmap { handle: urids, unmap: Some(urid_unmap) };
+        world.map_feature = ffi::LV2_Feature {
+            URI: URID_MAP.as_ptr() as *const c_char,
+            data: &mut world.map as *mut _ as *mut c_void,
+        };
+        world.unmap_feature = ffi::LV2_Feature {
+            URI: URID_UNMAP.as_ptr() as *const c_char,
+            data: &mut world.unmap as *mut _ as *mut c_void,
+        };
+        world.features = [&world.map_feature, &world.unmap_feature, ptr::null()];
+        world.host_urids = HostUrids {
+            sequence: world.urid(ATOM_SEQUENCE),
+            chunk: world.urid(ATOM_CHUNK),
+            float: world.urid(ATOM_FLOAT),
+            midi: world.urid(MIDI_EVENT),
+        };
+
+        let plugins = unsafe { ffi::lilv_world_get_all_plugins(lilv) };
+        let mut iter = unsafe { ffi::lilv_plugins_begin(plugins) };
+        while !unsafe { ffi::lilv_plugins_is_end(plugins, iter) } {
+            let plugin = unsafe { ffi::lilv_plugins_get(plugins, iter) };
+            let descriptor = world.descriptor(plugin);
+            world.descriptors.push(descriptor);
+            iter = unsafe { ffi::lilv_plugins_next(plugins, iter) };
+        }
+        world
+    }
+
+    /// Map an URI to its URID
+    fn urid(&self, uri: &str) -> u32 {
+        let uri = CString::new(uri).unwrap();
+        urid_map(self.map.handle, uri.as_ptr())
+    }
+
+    /// Read plugin's descriptor. Names are leaked since they live as long as the world.
+    fn descriptor(&self, plugin: *const ffi::LilvPlugin) -> Descriptor {
+        let name = |node: *mut ffi::LilvNode| -> &'static str {
+            if node.is_null() {
+                return "";
+            }
+            let name = to_str(unsafe { ffi::lilv_node_as_string(node) }).to_string();
+            unsafe { ffi::lilv_node_free(node) };
+            Box::leak(name.into_boxed_str())
+        };
+
+        let n_ports = unsafe { ffi::lilv_plugin_get_num_ports(plugin) };
+        let (mut mins, mut maxs, mut defaults) = (vec![0.0f32; n_ports as usize],
+                                                  vec![0.0f32; n_ports as usize],
+                                                  vec![0.0f32; n_ports as usize]);
+        unsafe { ffi::lilv_plugin_get_port_ranges_float(plugin, mins.as_mut_ptr(), maxs.as_mut_ptr(),
+                                                        defaults.as_mut_ptr()) };
+
+        let nodes = &self.nodes;
+        let ports = (0..n_ports).map(|index| {
+            let port = unsafe { ffi::lilv_plugin_get_port_by_index(plugin, index) };
+            let is_a = |class| unsafe { ffi::lilv_port_is_a(plugin, port, class) };
+            let has = |property| unsafe { ffi::lilv_port_has_property(plugin, port, property) };
+
+            let input = is_a(nodes.input_port);
+            let kind = match (input, is_a(nodes.audio_port), is_a(nodes.control_port), is_a(nodes.atom_port)) {
+                (true, true, _, _) => PortKind::AudioInput,
+                (false, true, _, _) => PortKind::AudioOutput,
+                (true, _, true, _) => PortKind::ControlInput,
+                (false, _, true, _) => PortKind::ControlOutput,
+                (input, _, _, true) if unsafe { ffi::lilv_port_supports_event(plugin, port, nodes.midi_event) } =>
+                    if input { PortKind::MidiInput } else { PortKind::MidiOutput },
+                (input, _, _, true) => if input { PortKind::AtomInput } else { PortKind::AtomOutput },
+                _ => PortKind::Other,
+            };
+
+            let i = index as usize;
+            let (min, max) = (if mins[i].is_nan() { 0.0 } else { mins[i] },
+                              if maxs[i].is_nan() { 1.0 } else { maxs[i] });
+            Port {
+                index, kind,
+                symbol: to_str(unsafe { ffi::lilv_node_as_string(ffi::lilv_port_get_symbol(plugin, port)) }),
+                name: name(unsafe { ffi::lilv_port_get_name(plugin, port) }),
+                value_type: if has(nodes.toggled) { ValueType::Bool } else { ValueType::F32 },
+                default: if defaults[i].is_nan() { min } else { defaults[i] },
+                range: (min, max),
+                sample_rate: has(nodes.sample_rate),
+                logarithmic: has(nodes.logarithmic),
+                integer: has(nodes.integer),
+            }
+        }).collect();
+
+        Descriptor {
+            plugin,
+            uri: to_str(unsafe { ffi::lilv_node_as_uri(ffi::lilv_plugin_get_uri(plugin)) }),
+            name: name(unsafe { ffi::lilv_plugin_get_name(plugin) }),
+            ports,
+        }
+    }
+
+    /// Return plugin's presets.
+    fn presets(&self, plugin: *const ffi::LilvPlugin) -> Vec<Preset> {
+        let mut presets = Vec::new();
+        let nodes = unsafe { ffi::lilv_plugin_get_related(plugin, self.nodes.preset) };
+        if nodes.is_null() {
+            return presets;
+        }
+
+        let mut iter = unsafe { ffi::lilv_nodes_begin(nodes) };
+        while !unsafe { ffi::lilv_nodes_is_end(nodes, iter) } {
+            let node = unsafe { ffi::lilv_node_duplicate(ffi::lilv_nodes_get(nodes, iter)) };
+            unsafe { ffi::lilv_world_load_resource(self.world, node) };
+
+            let uri = to_str(unsafe { ffi::lilv_node_as_uri(node) }).to_string();
+            let label = unsafe { ffi::lilv_world_get(self.world, node, self.nodes.label, ptr::null()) };
+            let label = match label.is_null() {
+                true => uri.clone(),
+                false => {
+                    let s = to_str(unsafe { ffi::lilv_node_as_string(label) }).to_string();
+                    unsafe { ffi::lilv_node_free(label) };
+                    s
+                },
+            };
+            presets.push(Preset { node, uri, label });
+            iter = unsafe { ffi::lilv_nodes_next(nodes, iter) };
+        }
+        unsafe { ffi::lilv_nodes_free(nodes) };
+        presets
+    }
+
+    /// Return a new state of the preset (to be freed), `None` if it can't be loaded.
+    fn preset_state(&self, node: *const ffi::LilvNode) -> Option<*mut ffi::LilvState> {
+        let state = unsafe { ffi::lilv_state_new_from_world(self.world, &self.map as *const _ as *mut _, node) };
+        match state.is_null() {
+            true => None,
+            false => Some(state),
+        }
+    }
+}
+
+
+impl Drop for PresetState {
+    fn drop(&mut self) {
+        unsafe { ffi::lilv_state_free(self.state) };
+    }
+}
+
+
+impl PresetLoader {
+    fn new() -> Self {
+        let swap = Swap::new();
+        let uris = PathLoader::new(swap.loader(), |uri, index| {
+            let world = World::get();
+            let uri = CString::new(uri).map_err(|_| "invalid preset URI")?;
+            let node = unsafe { ffi::lilv_new_uri(world.world, uri.as_ptr()) };
+            let state = world.preset_state(node);
+            unsafe { ffi::lilv_node_free(node) };
+            state.map(|state| Some(PresetState { state, index })).ok_or("preset can't be loaded")
+        });
+        Self { uris, swap, state: None }
+    }
+}
+
+
+impl Port {
+    /// Return control for this port, its range being scaled by `rate` if it is relative to the
+    /// sample rate.
+    fn control(&self, rate: SampleRate) -> Control {
+        let scale = if self.sample_rate { rate as f32 } else { 1.0 };
+        let mut metadatas = vec![("label", self.name), ("symbol", self.symbol)];
+        if self.logarithmic {
+            metadatas.push(("scale", "log"));
+        }
+        if self.integer {
+            metadatas.push(("integer", "true"));
+        }
+        if self.kind == PortKind::ControlOutput {
+            metadatas.push(("output", "true"));
+        }
+
+        Control {
+            port: self.index,
+            symbol: self.symbol,
+            output: self.kind == PortKind::ControlOutput,
+            value_type: self.value_type,
+            default: self.default * scale,
+            range: (self.range.0 * scale, self.range.1 * scale),
+            metadatas,
+        }
+    }
+}
+
+
+impl Descriptor {
+    /// Plugin's URI (used to instanciate it)
+    pub fn uri(&self) -> &'static str {
+        self.uri
+    }
+
+    /// Plugin's name for humans
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+
+/// Return descriptors of installed plugins, loading bundles on first call.
+pub fn descriptors() -> &'static [Descriptor] {
+    &World::get().descriptors
+}
+
+/// Return plugin's descriptor by URI.
+pub fn descriptor(uri: &str) -> Option<&'static Descriptor> {
+    descriptors().iter().find(|d| d.uri == uri)
+}
+
+/// List installed plugins' URIs.
+pub fn list_plugins() -> Vec<&'static str> {
+    descriptors().iter().map(|d| d.uri).collect()
+}
+
+/// Instanciate plugin by URI.
+pub fn new_plugin<PS: ProcessScope>(uri: &str, rate: SampleRate) -> Option<BoxedDSP<f32,PS>> {
+    Lv2Plugin::new(descriptor(uri)?, rate).ok()
+        .map(|plugin| Box::new(plugin) as BoxedDSP<f32,PS>)
+}
+
+
+impl AtomBuffer {
+    fn new(port: u32) -> Self {
+        Self { port, data: vec![0u64; ATOM_CAPACITY / 8] }
+    }
+
+    fn bytes(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.data.as_mut_ptr() as *mut u8, self.data.len() * 8) }
+    }
+
+    fn write_u32(&mut self, offset: usize, value: u32) {
+        self.bytes()[offset..offset+4].copy_from_slice(&value.to_ne_bytes());
+    }
+
+    fn read_u32(&mut self, offset: usize) -> u32 {
+        let mut bytes = [0u8;4];
+        bytes.copy_from_slice(&self.bytes()[offset..offset+4]);
+        u32::from_ne_bytes(bytes)
+    }
+
+    /// Reset as an empty sequence (atom's size is the sequence body's size)
+    fn clear(&mut self, sequence: u32) {
+        self.write_u32(0, 8);
+        self.write_u32(4, sequence);
+        self.write_u32(8, 0);
+        self.write_u32(12, 0);
+    }
+
+    /// Set as a chunk of the buffer's size, for plugin's output.
+    fn reserve(&mut self, chunk: u32) {
+        self.write_u32(0, (ATOM_CAPACITY - 8) as u32);
+        self.write_u32(4, chunk);
+    }
+
+    /// Append an event to the sequence. Return false if it is full.
+    fn push(&mut self, frames: i64, event_type: u32, data: &[u8]) -> bool {
+        let size = self.read_u32(0) as usize;
+        let offset = 8 + size;
+        let padded = (16 + data.len() + 7) & !7;
+        if offset + padded > ATOM_CAPACITY {
+            return false;
+        }
+
+        let bytes = self.bytes();
+        bytes[offset..offset+8].copy_from_slice(&frames.to_ne_bytes());
+        bytes[offset+8..offset+12].copy_from_slice(&(data.len() as u32).to_ne_bytes());
+        bytes[offset+12..offset+16].copy_from_slice(&event_type.to_ne_bytes());
+        bytes[offset+16..offset+16+data.len()].copy_from_slice(data);
+        self.write_u32(0, (size + padded) as u32);
+        true
+    }
+
+    /// Call `func` for each event of the sequence as `(frames, type, data)`.
+    fn for_each<F: FnMut(i64, u32, &[u8])>(&mut self, mut func: F) {
+        let end = (8 + self.read_u32(0) as usize).min(ATOM_CAPACITY);
+        let bytes = self.bytes();
+        let mut offset = 16;
+        while offset + 16 <= end {
+            let mut frames = [0u8;8];
+            let (mut size, mut event_type) = ([0u8;4], [0u8;4]);
+            frames.copy_from_slice(&bytes[offset..offset+8]);
+            size.copy_from_slice(&bytes[offset+8..offset+12]);
+            event_type.copy_from_slice(&bytes[offset+12..offset+16]);
+
+            let size = u32::from_ne_bytes(size) as usize;
+            if offset + 16 + size > end {
+                break;
+            }
+            func(i64::from_ne_bytes(frames), u32::from_ne_bytes(event_type),
+                 &bytes[offset+16..offset+16+size]);
+            offset += (16 + size + 7) & !7;
+        }
+    }
+}
+
+
+/// Return port's value, used when saving state.
+extern "C" fn get_port_value(symbol: *const c_char, user_data: *mut c_void, size: *mut u32,
+                             value_type: *mut u32) -> *const c_void
+{
+    let (controls, values, float) = unsafe { &*(user_data as *const (&[Control], &[f32], u32)) };
+    let symbol = to_str(symbol);
+    match controls.iter().position(|c| !c.output && c.symbol == symbol) {
+        Some(index) => unsafe {
+            *size = 4;
+            *value_type = *float;
+            &values[index] as *const f32 as *const c_void
+        },
+        None => ptr::null(),
+    }
+}
+
+/// Set port's value, used when restoring state.
+extern "C" fn set_port_value(symbol: *const c_char, user_data: *mut c_void, value: *const c_void,
+                             size: u32, value_type: u32)
+{
+    let (controls, values, float) = unsafe { &mut *(user_data as *mut (&[Control], &mut [f32], u32)) };
+    let symbol = to_str(symbol);
+    if value_type != *float || size != 4 {
+        return;
+    }
+    if let Some(index) = controls.iter().position(|c| !c.output && c.symbol == symbol) {
+        values[index] = unsafe { *(value as *const f32) };
+    }
+}
+
+
+impl<PS: ProcessScope> Lv2Plugin<PS> {
+    /// Instanciate and activate plugin.
+    pub fn new(descriptor: &Descriptor, rate: SampleRate) -> Result<Self, String> {
+        let world = World::get();
+        let (mut controls, mut inputs, mut outputs) = (Vec::new(), Vec::new(), Vec::new());
+        let (mut event_input, mut event_output) = (None, None);
+        let (mut atoms, mut others) = ((Vec::new(), Vec::new()), Vec::new());
+        for port in descriptor.ports.iter() {
+            match port.kind {
+                PortKind::AudioInput => inputs.push((port.index, Vec::new())),
+                PortKind::AudioOutput => outputs.push((port.index, Vec::new())),
+                PortKind::ControlInput | PortKind::ControlOutput => controls.push(port.control(rate)),
+                PortKind::MidiInput if event_input.is_none() => event_input = Some(AtomBuffer::new(port.index)),
+                PortKind::MidiOutput if event_output.is_none() => event_output = Some(AtomBuffer::new(port.index)),
+                PortKind::MidiInput | PortKind::AtomInput => atoms.0.push(AtomBuffer::new(port.index)),
+                PortKind::MidiOutput | PortKind::AtomOutput => atoms.1.push(AtomBuffer::new(port.index)),
+                PortKind::Other => others.push((port.index, Vec::new())),
+            }
+        }
+
+        let values = controls.iter().map(|c| c.default).collect::<Vec<_>>().into_boxed_slice();
+        let presets = world.presets(descriptor.plugin);
+        let preset_loader = if presets.is_empty() { None } else { Some(PresetLoader::new()) };
+        let mut plugin = Self {
+            descriptor: descriptor.clone(),
+            instance: ptr::null_mut(),
+            rate, controls, values, presets,
+            preset: 0,
+            preset_loader,
+            max_samples: MAX_SAMPLES,
+            inputs, outputs, event_input, event_output, atoms, others,
+            phantom: PhantomData,
+        };
+        plugin.instantiate(rate)?;
+        Ok(plugin)
+    }
+
+    /// Instanciate plugin for the provided rate, then connect its ports and activate it. The
+    /// previous instance is released.
+    fn instantiate(&mut self, rate: SampleRate) -> Result<(), String> {
+        self.release();
+
+        let world = World::get();
+        let instance = unsafe { ffi::lilv_plugin_instantiate(self.descriptor.plugin, rate as f64,
+                                                             world.features.as_ptr()) };
+        if instance.is_null() {
+            return Err(format!("{}: instanciation failed", self.descriptor.uri));
+        }
+        self.instance = instance;
+        self.rate = rate;
+
+        for index in 0..self.controls.len() {
+            let value = &mut self.values[index] as *mut f32 as *mut c_void;
+            self.connect_port(self.controls[index].port, value);
+        }
+
+        // atom buffers are never reallocated
+        let sequence = world.host_urids.sequence;
+        let (atom_inputs, atom_outputs) = &mut self.atoms;
+        for buffer in self.event_input.iter_mut().chain(self.event_output.iter_mut())
+                          .chain(atom_inputs.iter_mut()).chain(atom_outputs.iter_mut())
+        {
+            buffer.clear(sequence);
+            let data = buffer.data.as_mut_ptr() as *mut c_void;
+            unsafe { connect(instance, buffer.port, data) };
+        }
+        self.connect_buffers();
+
+        let lv2 = unsafe { &*(*instance).lv2_descriptor };
+        if let Some(activate) = lv2.activate {
+            unsafe { activate((*instance).lv2_handle) };
+        }
+        Ok(())
+    }
+
+    /// Deactivate and free plugin's instance, if any.
+    fn release(&mut self) {
+        if self.instance.is_null() {
+            return;
+        }
+        unsafe {
+            let lv2 = &*(*self.instance).lv2_descriptor;
+            if let Some(deactivate) = lv2.deactivate {
+                deactivate((*self.instance).lv2_handle);
+            }
+            ffi::lilv_instance_free(self.instance);
+        }
+        self.instance = ptr::null_mut();
+    }
+
+    /// Plugin's descriptor
+    pub fn descriptor(&self) -> &Descriptor {
+        &self.descriptor
+    }
+
+    /// Presets' labels, in field's index order.
+    pub fn presets(&self) -> Vec<&str> {
+        self.presets.iter().map(|p| p.label.as_str()).collect()
+    }
+
+    /// Load preset at the provided index. It must not be called from the audio thread, where
+    /// presets are selected through the `preset` field.
+    pub fn load_preset(&mut self, index: usize) -> bool {
+        let state = match self.presets.get(index).and_then(|p| World::get().preset_state(p.node)) {
+            Some(state) => state,
+            None => return false,
+        };
+        self.restore(state);
+        unsafe { ffi::lilv_state_free(state) };
+        self.preset = index;
+        true
+    }
+
+    /// Save plugin's state (control values and plugin's internal state) as a string.
+    pub fn save_state(&self) -> Option<String> {
+        let world = World::get();
+        let map = &world.map as *const _ as *mut _;
+        let unmap = &world.unmap as *const _ as *mut _;
+        let state = self.state()?;
+
+        let uri = b"urn:foxlive:state\0".as_ptr() as *const c_char;
+        let s = unsafe { ffi::lilv_state_to_string(world.world, map, unmap, state, uri, ptr::null()) };
+        unsafe { ffi::lilv_state_free(state) };
+        if s.is_null() {
+            return None;
+        }
+
+        let r = to_str(s).to_string();
+        unsafe { ffi::lilv_free(s as *mut c_void) };
+        Some(r)
+    }
+
+    /// Restore state previously saved with `save_state`.
+    pub fn restore_state(&mut self, state: &str) -> bool {
+        let world = World::get();
+        let state = match CString::new(state) {
+            Ok(state) => state,
+            Err(_) => return false,
+        };
+
+        let state = unsafe { ffi::lilv_state_new_from_string(world.world, &world.map as *const _ as *mut _,
+                                                             state.as_ptr()) };
+        if state.is_null() {
+            return false;
+        }
+        self.restore(state);
+        unsafe { ffi::lilv_state_free(state) };
+        true
+    }
+
+    /// Return a new state of the instance (to be freed), `None` if there is no instance.
+    fn state(&self) -> Option<*mut ffi::LilvState> {
+        if self.instance.is_null() {
+            return None;
+        }
+
+        let world = World::get();
+        let mut data = (&self.controls[..], &self.values[..], world.host_urids.float);
+        let state = unsafe { ffi::lilv_state_new_from_instance(
+            self.descriptor.plugin, self.instance, &world.map as *const _ as *mut _,
+            ptr::null(), ptr::null(), ptr::null(), ptr::null(),
+            Some(get_port_value), &mut data as *mut _ as *mut c_void,
+            0, world.features.as_ptr()) };
+        match state.is_null() {
+            true => None,
+            false => Some(state),
+        }
+    }
+
+    fn restore(&mut self, state: *const ffi::LilvState) {
+        if self.instance.is_null() {
+            return;
+        }
+        let world = World::get();
+        let mut data = (&self.controls[..], &mut self.values[..], world.host_urids.float);
+        unsafe { ffi::lilv_state_restore(state, self.instance, Some(set_port_value),
+                                         &mut data as *mut _ as *mut c_void, 0,
+                                         world.features.as_ptr()) };
+    }
+
+    /// Post preset at the provided index to be loaded, then restored by the audio thread.
+    /// Return false if it can't be posted.
+    fn post_preset(&mut self, index: usize) -> bool {
+        let (preset, loader) = match (self.presets.get(index), self.preset_loader.as_ref()) {
+            (Some(preset), Some(loader)) => (preset, loader),
+            _ => return false,
+        };
+        if !loader.uris.post(&preset.uri, index) {
+            return false;
+        }
+        self.preset = index;
+        true
+    }
+
+    /// Restore loaded preset's state, if any. The previous one is released outside of the
+    /// audio thread.
+    fn swap_preset(&mut self) {
+        let loader = match self.preset_loader.as_mut() {
+            Some(loader) => loader,
+            None => return,
+        };
+        if !loader.swap.swap(&mut loader.state, |_| true) {
+            return;
+        }
+        if let Some((state, index)) = loader.state.as_ref().map(|s| (s.state, s.index)) {
+            self.restore(state);
+            self.preset = index;
+        }
+    }
+
+    fn connect_port(&mut self, port: u32, data: *mut c_void) {
+        unsafe { connect(self.instance, port, data) };
+    }
+
+    /// Allocate audio and unsupported ports' buffers of `max_samples`, connecting them to the
+    /// plugin.
+    fn connect_buffers(&mut self) {
+        let (instance, max_samples) = (self.instance, self.max_samples);
+        for (port, buffer) in self.inputs.iter_mut().chain(self.outputs.iter_mut())
+                                  .chain(self.others.iter_mut())
+        {
+            buffer.clear();
+            buffer.resize(max_samples, 0.0);
+            unsafe { connect(instance, *port, buffer.as_mut_ptr() as *mut c_void) };
+        }
+    }
+}
+
+
+/// Connect instance's port (`lilv_instance_connect_port` being inline).
+unsafe fn connect(instance: *mut ffi::LilvInstance, port: u32, data: *mut c_void) {
+    if instance.is_null() {
+        return;
+    }
+    let lv2 = &*(*instance).lv2_descriptor;
+    if let Some(connect_port) = lv2.connect_port {
+        connect_port((*instance).lv2_handle, port, data);
+    }
+}
+
+
+impl<PS: ProcessScope> Drop for Lv2Plugin<PS> {
+    fn drop(&mut self) {
+        self.release();
+        for preset in self.presets.iter() {
+            unsafe { ffi::lilv_node_free(preset.node as *mut _) };
+        }
+    }
+}
+
+
+impl<PS: ProcessScope> Object for Lv2Plugin<PS> {
+    fn object_meta(&self) -> ObjectMeta {
+        ObjectMeta::new(self.descriptor.name, Some(vec![("uri", self.descriptor.uri)]))
+    }
+
+    fn get_value(&self, index: ObjectIndex) -> Option<Value> {
+        match self.controls.get(index as usize) {
+            Some(control) => Value::from_f64(control.value_type, self.values[index as usize] as f64),
+            None if index as usize == self.controls.len() && !self.presets.is_empty() =>
+                Some(Value::Index(self.preset)),
+            None => None,
+        }
+    }
+
+    fn set_value(&mut self, index: ObjectIndex, value: Value) -> Result<Value, ()> {
+        if index as usize == self.controls.len() && !self.presets.is_empty() {
+            let preset = value.as_f64().ok_or(())? as usize;
+            return match self.post_preset(preset) {
+                true => Ok(Value::Index(preset)),
+                false => Err(()),
+            };
+        }
+
+        let control = self.controls.get(index as usize).ok_or(())?;
+        if control.output {
+            return Err(());
+        }
+
+        let value = value.as_f64().ok_or(())? as f32;
+        let value = value.max(control.range.0).min(control.range.1);
+        self.values[index as usize] = value;
+        Value::from_f64(control.value_type, value as f64).ok_or(())
+    }
+
+    fn map_object(&self, mapper: &mut dyn ObjectMapper) {
+        for (index, control) in self.controls.iter().enumerate() {
+            mapper.declare(FieldInfo {
+                index: index as ObjectIndex,
+                value_type: control.value_type,
+                default: Value::from_f64(control.value_type, control.default as f64),
+                range: match control.value_type {
+                    ValueType::Bool => None,
+                    _ => Some(Range::F32(control.range.0, control.range.1, 0.0)),
+                },
+                metadatas: control.metadatas.clone(),
+            });
+        }
+
+        if !self.presets.is_empty() {
+            mapper.declare(FieldInfo {
+                index: self.controls.len() as ObjectIndex,
+                value_type: ValueType::Index,
+                default: Some(Value::Index(0)),
+                range: None,
+                metadatas: vec![("label", "preset")],
+            });
+        }
+    }
+}
+
+
+impl<PS: ProcessScope> DSP for Lv2Plugin<PS> {
+    type Sample = f32;
+    type Scope = PS;
+
+    fn prepare(&mut self, rate: SampleRate) {
+        if rate == self.rate && !self.instance.is_null() {
+            return;
+        }
+
+        // plugin's internal state is restored on the new instance
+        let state = self.state();
+        if let Err(err) = self.instantiate(rate) {
+            log::error!("{}", err);
+        }
+        if let Some(state) = state {
+            self.restore(state);
+            unsafe { ffi::lilv_state_free(state) };
+        }
+
+        // ranges relative to the sample rate are updated, values being kept
+        let ports = &self.descriptor.ports;
+        for (control, value) in self.controls.iter_mut().zip(self.values.iter_mut()) {
+            *control = ports[control.port as usize].control(rate);
+            if !control.output {
+                *value = value.max(control.range.0).min(control.range.1);
+            }
+        }
+    }
+
+    fn set_max_samples(&mut self, max_samples: NSamples) {
+        if max_samples != self.max_samples {
+            self.max_samples = max_samples;
+            self.connect_buffers();
+        }
+    }
+
+    fn process_audio(&mut self, _scope: &Self::Scope, input: Option<&dyn BufferView<Sample=Self::Sample>>,
+                     output: Option<&mut dyn BufferView<Sample=Self::Sample>>) -> usize
+    {
+        let n_samples = match (input.as_ref(), output.as_ref()) {
+            (_, Some(output)) => output.n_samples(),
+            (Some(input), None) => input.n_samples(),
+            (None, None) => return 0,
+        };
+        // buffers can't be reallocated here: samples beyond maximum are silent
+        let n_samples = n_samples.min(self.max_samples);
+
+        // plugin failed to instantiate
+        if self.instance.is_null() {
+            return match output {
+                Some(output) => {
+                    fill_samples(output.as_slice_mut(), 0.0);
+                    output.len()
+                },
+                None => 0,
+            };
+        }
+
+        for (channel, (_, buffer)) in self.inputs.iter_mut().enumerate() {
+            let buffer = &mut buffer[..n_samples];
+            match input.and_then(|input| input.channel(channel as NChannels)) {
+                Some(samples) => for (dst, src) in buffer.iter_mut().zip(samples.chain(iter::repeat(&0.0))) {
+                    *dst = *src;
+                },
+                None => for dst in buffer.iter_mut() {
+                    *dst = 0.0;
+                },
+            }
+        }
+
+        // state can't be restored while plugin runs
+        self.swap_preset();
+
+        let world = World::get();
+        for buffer in self.event_output.iter_mut().chain(self.atoms.1.iter_mut()) {
+            buffer.reserve(world.host_urids.chunk);
+        }
+
+        unsafe {
+            let lv2 = &*(*self.instance).lv2_descriptor;
+            if let Some(run) = lv2.run {
+                run((*self.instance).lv2_handle, n_samples as u32);
+            }
+        }
+
+        for buffer in self.event_input.iter_mut().chain(self.atoms.0.iter_mut()) {
+            buffer.clear(world.host_urids.sequence);
+        }
+
+        match output {
+            Some(output) => {
+                // channels without output port are silent
+                for channel in 0..output.n_channels() {
+                    let buffer = self.outputs.get(channel as usize).map(|(_, b)| &b[..n_samples]).unwrap_or(&[]);
+                    if let Some(samples) = output.channel_mut(channel) {
+                        for (dst, src) in samples.zip(buffer.iter().chain(iter::repeat(&0.0))) {
+                            *dst = *src;
+                        }
+                    }
+                }
+                output.len()
+            },
+            None => 0,
+        }
+    }
+
+    fn process_events(&mut self, _scope: &Self::Scope, input: &EventBuffer, output: &mut EventBuffer) {
+        let world = World::get();
+        let midi = world.host_urids.midi;
+
+        // events output by previous run
+        if let Some(buffer) = self.event_output.as_mut() {
+            buffer.for_each(|frames, event_type, data| {
+                if event_type == midi {
+                    if let Some(message) = MidiMessage::from_bytes(data) {
+                        output.push(Event::new(frames.max(0) as _, EventData::Midi(message)));
+                    }
+                }
+            });
+        }
+
+        if let Some(buffer) = self.event_input.as_mut() {
+            let mut bytes = [0u8;3];
+            for event in input.iter() {
+                if let Some(message) = event.midi() {
+                    let len = message.to_bytes(&mut bytes);
+                    if !buffer.push(event.time as i64, midi, &bytes[..len]) {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    fn n_channels(&self) -> NChannels {
+        self.outputs.len().max(self.inputs.len()) as NChannels
+    }
+
+    fn is_sink(&self) -> bool {
+        self.outputs.is_empty() && self.event_output.is_none()
+    }
+
+    fn is_source(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    fn has_event_input(&self) -> bool {
+        self.event_input.is_some()
+    }
+
+    fn has_event_output(&self) -> bool {
+        self.event_output.is_some()
+    }
+}
+
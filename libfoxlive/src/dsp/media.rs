@@ -33,8 +33,15 @@ pub struct MediaView<S,PS>
     /// Reading position
     #[field("position", Duration, get(tell), set(seek))]
     pos: Duration,
+    /// Play only when transport is rolling
+    #[field("follow transport", Bool(false))]
+    pub follow_transport: bool,
     /// Stream information
     pub infos: Option<StreamInfo>,
+    /// Number of times cache ran out of data, including end of stream
+    underruns: usize,
+    /// Cache ran out of data on last block
+    starved: bool,
     phantom: PhantomData<PS>,
 }
 
@@ -54,7 +61,10 @@ impl<S,PS> MediaView<S,PS>
             cache: cons,
             amp: S::identity(),
             pos: Duration::new(0,0),
+            follow_transport: false,
             infos: None,
+            underruns: 0,
+            starved: false,
             phantom: PhantomData
         }
     }
@@ -106,9 +116,17 @@ impl<S,PS> DSP for MediaView<S,PS>
     type Sample = S;
     type Scope = PS;
 
-    fn process_audio(&mut self, _scope: &Self::Scope, _input: Option<&dyn BufferView<Sample=Self::Sample>>,
+    fn process_audio(&mut self, scope: &Self::Scope, _input: Option<&dyn BufferView<Sample=Self::Sample>>,
                      output: Option<&mut dyn BufferView<Sample=Self::Sample>>) -> usize
     {
+        // transport is stopped: keep cache for when it will roll again
+        if self.follow_transport {
+            match scope.transport() {
+                Some(ref transport) if !transport.is_rolling() => return 0,
+                _ => {},
+            }
+        }
+
         let output = output.unwrap();
         // ensure output is interleaved data buffer, since reading is
         output.set_interleaved(true);
@@ -122,6 +140,12 @@ impl<S,PS> DSP for MediaView<S,PS>
         for i in 0..count {
             slice[i] = slice[i].mul_amp(self.amp);
         }
+
+        let starved = count < slice.len();
+        if starved && !self.starved {
+            self.underruns += 1;
+        }
+        self.starved = starved;
         // self.pos += ts_ count;
         count
     }
@@ -133,6 +157,10 @@ impl<S,PS> DSP for MediaView<S,PS>
         }
     }
     fn is_source(&self) -> bool { true }
+
+    fn underruns(&self) -> usize {
+        self.underruns
+    }
 }
 
 
@@ -1,15 +1,50 @@
 
+#[macro_use]
+pub mod rtlog;
+
 pub mod dsp;
 pub mod graph;
+pub mod transport;
+pub mod release;
+pub mod swap;
 
 pub mod closure;
+pub mod chain;
+pub mod block;
+pub mod oversampling;
 
 #[cfg(feature="with_jack")]
 pub mod jack;
+#[cfg(feature="with_jack")]
+pub mod connections;
 
 pub mod media;
+pub mod fft;
+pub mod convolution;
+pub mod analyzer;
+pub mod registry;
+pub mod send;
+pub mod modulation;
+pub mod stats;
+pub mod voice;
+pub mod synth;
+pub mod sampler;
+pub mod soundfont;
+#[cfg(target_os="linux")]
+pub mod sandbox;
+
+pub mod faust;
+pub mod plugins;
+#[cfg(feature="with_libfaust")]
+pub mod libfaust;
+
+#[cfg(feature="with_ladspa")]
+pub mod ladspa;
+#[cfg(feature="with_lv2")]
+pub mod lv2;
 
 
 pub use dsp::{DSP,BoxedDSP};
 pub use graph::Graph;
+pub use transport::{Transport,TransportState,TransportControl};
 
@@ -0,0 +1,548 @@
+//! Modulation matrix, routing control-rate modulators to objects' fields.
+//!
+//! Modulators (LFOs, envelopes, envelope followers, random) are evaluated once per block by
+//! the graph. Each route applies a modulator to a node's field, with a depth relative to the
+//! field's range and a polarity. Modulated values are sent as `EventData::Control` events to
+//! nodes having an event input, and set with `Object::set_value` otherwise. Setting a field
+//! with `Graph::set_node_value` changes the base value of its routes.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let lfo = graph.add_lfo(Shape::Sine, 0.5, Some(4.0));
+//! graph.add_route(lfo, filter, 0, 0.3, Polarity::Bipolar);
+//!
+//! // follow kick's level
+//! let follower = EnvelopeFollower::<f32,Scope>::new(2);
+//! let source = graph.add_modulator(Box::new(follower.source()));
+//! graph.add_child(kick, follower.into());
+//! graph.add_route(source, pad, 1, -0.5, Polarity::Unipolar);
+//! ```
+use std::f32::consts::PI;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32,Ordering};
+
+use crate as libfoxlive;
+use libfoxlive_derive::object;
+use crate::data::*;
+use crate::data::buffer::zip_map;
+use crate::rpc::*;
+
+use super::dsp::{DSP,DEFAULT_RATE};
+use super::graph::{NodeIndex,ProcessScope};
+use super::transport::Transport;
+
+
+/// Control-rate modulation source, evaluated once per block.
+pub trait Modulator: Send+Sync {
+    /// Prepare modulator for the provided sample rate.
+    fn prepare(&mut self, _rate: SampleRate) {}
+
+    /// Compute value for a block of `n_samples`, in `-1.0..=1.0` for bipolar modulators and
+    /// `0.0..=1.0` otherwise.
+    fn next(&mut self, n_samples: NSamples, transport: Option<&Transport>) -> f32;
+
+    /// Return true if modulator's output is bipolar.
+    fn bipolar(&self) -> bool { false }
+
+    /// Open (`true`) or close the modulator's gate, e.g. to trigger an envelope.
+    fn gate(&mut self, _on: bool) {}
+}
+
+
+/// LFO waveform
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Shape {
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+    /// Random value held for a cycle
+    SampleHold,
+    /// Random values, interpolated over a cycle
+    Random,
+}
+
+/// Low frequency oscillator, optionally synced to transport's tempo.
+pub struct Lfo {
+    pub shape: Shape,
+    /// Frequency in Hz, when not synced
+    pub frequency: f32,
+    /// Cycle duration in beats, synced to transport when it provides bbt
+    pub sync: Option<f64>,
+    rate: SampleRate,
+    phase: f64,
+    /// Random values at the start and end of the current cycle
+    held: f32,
+    target: f32,
+    seed: u32,
+}
+
+/// Envelope stage
+#[derive(Copy,Clone,Debug,PartialEq)]
+enum Stage {
+    Idle,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+/// Linear ADSR envelope, triggered by modulator's gate.
+pub struct Adsr {
+    /// Attack duration, in seconds
+    pub attack: f32,
+    /// Decay duration, in seconds
+    pub decay: f32,
+    /// Sustain level
+    pub sustain: f32,
+    /// Release duration from full level, in seconds
+    pub release: f32,
+    rate: SampleRate,
+    stage: Stage,
+    level: f32,
+}
+
+/// Pass-through DSP following the level of its input, read by a `FollowerSource`.
+#[object("follower")]
+pub struct EnvelopeFollower<S,PS>
+    where S: 'static+Sample, PS: ProcessScope
+{
+    /// Attack time in milliseconds
+    #[field("attack", F32(10.0), range(0.1,500.0,0.1))]
+    pub attack: f32,
+    /// Release time in milliseconds
+    #[field("release", F32(100.0), range(1.0,5000.0,1.0))]
+    pub release: f32,
+    n_channels: NChannels,
+    rate: SampleRate,
+    envelope: f32,
+    /// Current level as `f32` bits
+    level: Arc<AtomicU32>,
+    phantom: PhantomData<(S,PS)>,
+}
+
+/// Modulator reading the level of an `EnvelopeFollower`.
+pub struct FollowerSource {
+    level: Arc<AtomicU32>,
+}
+
+
+/// Polarity of a route's modulation.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Polarity {
+    /// Modulation in `0.0..=1.0`, above base value
+    Unipolar,
+    /// Modulation in `-1.0..=1.0`, around base value
+    Bipolar,
+}
+
+/// Route of a modulator to a node's field.
+#[derive(Clone,Debug)]
+pub struct Route {
+    /// Modulator index
+    pub source: usize,
+    pub node: NodeIndex,
+    pub field: ObjectIndex,
+    /// Modulation depth, relative to field's range
+    pub depth: f32,
+    pub polarity: Polarity,
+    pub value_type: ValueType,
+    /// Field's value when modulation is zero
+    pub base: f64,
+    /// Field's `(min, max)`
+    pub range: (f64, f64),
+}
+
+/// Modulators and their routes.
+pub struct Matrix {
+    modulators: Vec<Option<Box<dyn Modulator>>>,
+    /// Modulators' values for the current block
+    values: Vec<f32>,
+    routes: Vec<Option<Route>>,
+    rate: SampleRate,
+}
+
+
+/// Xorshift random value in `-1.0..=1.0`
+fn random(seed: &mut u32) -> f32 {
+    *seed ^= *seed << 13;
+    *seed ^= *seed >> 17;
+    *seed ^= *seed << 5;
+    *seed as f32 / std::u32::MAX as f32 * 2.0 - 1.0
+}
+
+
+impl Lfo {
+    pub fn new(shape: Shape, frequency: f32, sync: Option<f64>) -> Self {
+        Self { shape, frequency, sync, rate: DEFAULT_RATE, phase: 0.0, held: 0.0, target: 0.0,
+               seed: 0x9e37_79b9 }
+    }
+
+    /// Waveform value at the provided phase.
+    fn value(&self, phase: f64) -> f32 {
+        let phase = phase as f32;
+        match self.shape {
+            Shape::Sine => (2.0 * PI * phase).sin(),
+            Shape::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Shape::Saw => 2.0 * phase - 1.0,
+            Shape::Square => if phase < 0.5 { 1.0 } else { -1.0 },
+            Shape::SampleHold => self.held,
+            Shape::Random => self.held + (self.target - self.held) * phase,
+        }
+    }
+}
+
+impl Modulator for Lfo {
+    fn prepare(&mut self, rate: SampleRate) {
+        self.rate = rate;
+    }
+
+    fn next(&mut self, n_samples: NSamples, transport: Option<&Transport>) -> f32 {
+        let synced = match (self.sync, transport) {
+            (Some(beats), Some(transport)) if beats > 0.0 =>
+                transport.beats().zip(transport.frames_per_beat())
+                         .map(|(position, frames)| ((position / beats).fract(), frames * beats)),
+            _ => None,
+        };
+
+        let (phase, increment) = match synced {
+            Some((phase, frames)) => (phase, n_samples as f64 / frames),
+            None => (self.phase, self.frequency as f64 * n_samples as f64 / self.rate.max(1) as f64),
+        };
+
+        let value = self.value(phase);
+        if phase + increment >= 1.0 {
+            self.held = self.target;
+            self.target = random(&mut self.seed);
+        }
+        self.phase = (phase + increment).fract();
+        value
+    }
+
+    fn bipolar(&self) -> bool {
+        true
+    }
+
+    /// Restart cycle
+    fn gate(&mut self, on: bool) {
+        if on {
+            self.phase = 0.0;
+        }
+    }
+}
+
+
+impl Adsr {
+    pub fn new(attack: f32, decay: f32, sustain: f32, release: f32) -> Self {
+        Self { attack, decay, sustain, release, rate: DEFAULT_RATE, stage: Stage::Idle, level: 0.0 }
+    }
+
+    /// Return true when envelope is over (or not started).
+    pub fn is_idle(&self) -> bool {
+        self.stage == Stage::Idle
+    }
+
+    /// Current level
+    pub fn level(&self) -> f32 {
+        self.level
+    }
+}
+
+impl Modulator for Adsr {
+    fn prepare(&mut self, rate: SampleRate) {
+        self.rate = rate;
+    }
+
+    fn next(&mut self, n_samples: NSamples, _transport: Option<&Transport>) -> f32 {
+        let dt = n_samples as f32 / self.rate.max(1) as f32;
+        let sustain = self.sustain.max(0.0).min(1.0);
+        match self.stage {
+            Stage::Attack => {
+                self.level += dt / self.attack.max(std::f32::EPSILON);
+                if self.level >= 1.0 {
+                    self.level = 1.0;
+                    self.stage = Stage::Decay;
+                }
+            },
+            Stage::Decay => {
+                self.level -= dt * (1.0 - sustain) / self.decay.max(std::f32::EPSILON);
+                if self.level <= sustain {
+                    self.level = sustain;
+                    self.stage = Stage::Sustain;
+                }
+            },
+            Stage::Release => {
+                self.level -= dt / self.release.max(std::f32::EPSILON);
+                if self.level <= 0.0 {
+                    self.level = 0.0;
+                    self.stage = Stage::Idle;
+                }
+            },
+            Stage::Sustain => self.level = sustain,
+            Stage::Idle => {},
+        }
+        self.level
+    }
+
+    fn gate(&mut self, on: bool) {
+        self.stage = match (on, self.stage) {
+            (true, _) => Stage::Attack,
+            (false, Stage::Idle) => Stage::Idle,
+            (false, _) => Stage::Release,
+        };
+    }
+}
+
+
+impl<S,PS> EnvelopeFollower<S,PS>
+    where S: 'static+Sample, PS: ProcessScope
+{
+    pub fn new(n_channels: NChannels) -> Self {
+        Self { attack: 10.0, release: 100.0, n_channels, rate: DEFAULT_RATE, envelope: 0.0,
+               level: Arc::new(AtomicU32::new(0)), phantom: PhantomData }
+    }
+
+    /// Return a modulator reading follower's level.
+    pub fn source(&self) -> FollowerSource {
+        FollowerSource { level: self.level.clone() }
+    }
+}
+
+impl<S,PS> DSP for EnvelopeFollower<S,PS>
+    where S: 'static+Sample, PS: ProcessScope
+{
+    type Sample = S;
+    type Scope = PS;
+
+    fn prepare(&mut self, rate: SampleRate) {
+        self.rate = rate;
+    }
+
+    fn process_audio(&mut self, scope: &Self::Scope, input: Option<&dyn BufferView<Sample=Self::Sample>>,
+                     output: Option<&mut dyn BufferView<Sample=Self::Sample>>) -> usize
+    {
+        let coef = |ms: f32| (-1.0 / (ms.max(0.1) * 0.001 * self.rate as f32)).exp();
+        let (attack, release) = (coef(self.attack), coef(self.release));
+
+        let mut output = output;
+        let buffer = match (input, output.as_mut()) {
+            (Some(input), _) => input,
+            // in place
+            (None, Some(output)) => &**output,
+            (None, None) => return 0,
+        };
+
+        let (n_channels, len) = (buffer.n_channels().max(1) as usize, buffer.n_samples());
+        let (interleaved, samples) = (buffer.interleaved(), buffer.as_slice());
+        let n_samples = scope.n_samples().min(len);
+        let mut envelope = self.envelope;
+        for i in 0..n_samples {
+            let peak = (0..n_channels).map(|c| match interleaved {
+                                          true => samples[i * n_channels + c],
+                                          false => samples[c * len + i],
+                                      })
+                                      .map(|s| s.to_float_sample().to_sample::<f32>().abs())
+                                      .fold(0.0, f32::max);
+            let coef = if peak > envelope { attack } else { release };
+            envelope = peak + coef * (envelope - peak);
+        }
+        self.envelope = envelope;
+        self.level.store(envelope.to_bits(), Ordering::Relaxed);
+
+        match (input, output) {
+            (Some(input), Some(output)) => {
+                zip_map(output, input, |a,b| *a = *b);
+                output.len()
+            },
+            (None, Some(output)) => output.len(),
+            _ => 0,
+        }
+    }
+
+    fn n_channels(&self) -> NChannels {
+        self.n_channels
+    }
+
+    fn in_place(&self) -> bool {
+        true
+    }
+}
+
+
+impl Modulator for FollowerSource {
+    fn next(&mut self, _n_samples: NSamples, _transport: Option<&Transport>) -> f32 {
+        f32::from_bits(self.level.load(Ordering::Relaxed)).min(1.0)
+    }
+}
+
+
+impl Route {
+    /// Field's value for the provided modulator's value.
+    pub fn value(&self, modulation: f32, bipolar: bool) -> f64 {
+        let modulation = match (self.polarity, bipolar) {
+            (Polarity::Unipolar, true) => (modulation + 1.0) * 0.5,
+            (Polarity::Bipolar, false) => modulation * 2.0 - 1.0,
+            _ => modulation,
+        } as f64;
+
+        let (min, max) = self.range;
+        (self.base + self.depth as f64 * modulation * (max - min)).max(min).min(max)
+    }
+}
+
+
+impl Matrix {
+    pub fn new() -> Self {
+        Self { modulators: Vec::new(), values: Vec::new(), routes: Vec::new(), rate: 0 }
+    }
+
+    /// Prepare modulators for the provided sample rate.
+    pub fn prepare(&mut self, rate: SampleRate) {
+        self.rate = rate;
+        for modulator in self.modulators.iter_mut().flatten() {
+            modulator.prepare(rate);
+        }
+    }
+
+    /// Add a modulator, returning its index.
+    pub fn add_modulator(&mut self, modulator: Box<dyn Modulator>) -> usize {
+        let mut modulator = modulator;
+        if self.rate > 0 {
+            modulator.prepare(self.rate);
+        }
+        self.modulators.push(Some(modulator));
+        self.values.push(0.0);
+        self.modulators.len() - 1
+    }
+
+    /// Remove a modulator and its routes. Return false if there is no such modulator.
+    pub fn remove_modulator(&mut self, modulator: usize) -> bool {
+        match self.modulators.get_mut(modulator).and_then(|m| m.take()) {
+            Some(_) => {
+                self.routes.iter_mut().filter(|r| r.as_ref().map(|r| r.source == modulator).unwrap_or(false))
+                           .for_each(|r| *r = None);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn modulator_mut(&mut self, modulator: usize) -> Option<&mut Box<dyn Modulator>> {
+        self.modulators.get_mut(modulator).and_then(|m| m.as_mut())
+    }
+
+    /// Add a route, returning its index. Return `None` if source doesn't exist.
+    pub fn add_route(&mut self, route: Route) -> Option<usize> {
+        self.modulators.get(route.source)?.as_ref()?;
+        self.routes.push(Some(route));
+        Some(self.routes.len() - 1)
+    }
+
+    /// Remove a route. Return false if there is no such route.
+    pub fn remove_route(&mut self, route: usize) -> bool {
+        self.routes.get_mut(route).and_then(|r| r.take()).is_some()
+    }
+
+    pub fn route_mut(&mut self, route: usize) -> Option<&mut Route> {
+        self.routes.get_mut(route).and_then(|r| r.as_mut())
+    }
+
+    /// Set base value of the routes to a node's field, e.g. when the field is set by the user.
+    pub fn set_base(&mut self, node: NodeIndex, field: ObjectIndex, base: f64) {
+        for route in self.routes.iter_mut().flatten().filter(|r| r.node == node && r.field == field) {
+            route.base = base;
+        }
+    }
+
+    /// Remove routes to the provided node.
+    pub fn remove_node(&mut self, node: NodeIndex) {
+        self.routes.iter_mut().filter(|r| r.as_ref().map(|r| r.node == node).unwrap_or(false))
+                   .for_each(|r| *r = None);
+    }
+
+    /// Move routes of a node to another one, offsetting their field index (e.g. when node is
+    /// fused into a chain's slot).
+    pub fn move_node(&mut self, from: NodeIndex, to: NodeIndex, field_offset: ObjectIndex) {
+        for route in self.routes.iter_mut().flatten().filter(|r| r.node == from) {
+            route.node = to;
+            route.field |= field_offset;
+        }
+    }
+
+    /// Evaluate modulators for the current block.
+    pub fn process(&mut self, n_samples: NSamples, transport: Option<&Transport>) {
+        for (modulator, value) in self.modulators.iter_mut().zip(self.values.iter_mut()) {
+            if let Some(modulator) = modulator {
+                *value = modulator.next(n_samples, transport);
+            }
+        }
+    }
+
+    /// Iterate over routes to the provided node, with their modulated value.
+    pub fn node_values<'a>(&'a self, node: NodeIndex) -> impl Iterator<Item=(&'a Route, f64)> + 'a {
+        self.routes.iter().flatten().filter(move |route| route.node == node)
+            .filter_map(move |route| {
+                let bipolar = self.modulators.get(route.source)?.as_ref()?.bipolar();
+                Some((route, route.value(self.values[route.source], bipolar)))
+            })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::block::BlockScope;
+
+    /// Test: ADSR stages and route scaling
+    #[test]
+    fn adsr_route() {
+        let mut adsr = Adsr::new(0.1, 0.1, 0.5, 0.2);
+        adsr.prepare(1000);
+        adsr.gate(true);
+        assert!((adsr.next(50, None) - 0.5).abs() < 1e-5);
+        assert_eq!(adsr.next(50, None), 1.0);
+        assert!((adsr.next(100, None) - 0.5).abs() < 1e-5);
+        assert_eq!(adsr.next(100, None), 0.5);
+        adsr.gate(false);
+        assert!((adsr.next(100, None) - 0.0).abs() < 1e-5);
+        assert_eq!(adsr.stage, Stage::Idle);
+
+        let route = Route { source: 0, node: NodeIndex::new(0), field: 0, depth: 0.5,
+                            polarity: Polarity::Bipolar, value_type: ValueType::F32,
+                            base: 100.0, range: (0.0, 200.0) };
+        assert_eq!(route.value(1.0, true), 200.0);
+        assert_eq!(route.value(-0.5, true), 50.0);
+        assert_eq!(route.value(0.0, false), 0.0);
+
+        let mut matrix = Matrix::new();
+        let source = matrix.add_modulator(Box::new(Lfo::new(Shape::Square, 1.0, None)));
+        matrix.add_route(route).unwrap();
+        matrix.process(1, None);
+        matrix.set_base(NodeIndex::new(0), 0, 40.0);
+        let values: Vec<f64> = matrix.node_values(NodeIndex::new(0)).map(|(_, v)| v).collect();
+        assert_eq!((source, values), (0, vec![140.0]));
+    }
+
+    /// Test: follower's level is the same for interleaved and planar buffers
+    #[test]
+    fn follower() {
+        let mut levels = Vec::new();
+        for interleaved in [true, false].iter() {
+            // silent left channel, constant right channel
+            let samples = (0..128).map(|i| match interleaved {
+                true => (i % 2) as f32,
+                false => (i / 64) as f32,
+            }).collect::<Vec<_>>();
+            let input: Buffer<f32,Vec<f32>> = (*interleaved, 2, samples).into();
+            let mut follower = EnvelopeFollower::<f32,BlockScope>::new(2);
+            follower.prepare(1000);
+            follower.process_audio(&BlockScope::new(64, 0, None), Some(&input), None);
+            levels.push(follower.source().next(64, None));
+        }
+        assert!(levels[0] > 0.9);
+        assert_eq!(levels[0], levels[1]);
+    }
+}
@@ -0,0 +1,447 @@
+//! Run nonlinear DSP at a higher sample rate in order to reduce aliasing.
+//!
+//! Input is upsampled by cascaded 2x polyphase halfband FIR filters, processed by the inner DSP
+//! (prepared for the oversampled rate), then filtered and downsampled back. Filters' delay is
+//! reported as latency. Inner DSP run with a `BlockScope`.
+//!
+//! Inner DSP is told by `set_max_samples` that it processes up to `max_samples * factor` frames.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let drive = plugins::new_plugin::<BlockScope>("saturation").unwrap();
+//! graph.add_child(source, Oversampling::<_,Scope>::new(drive, 4).into());
+//! ```
+use std::f32::consts::PI;
+use std::marker::PhantomData;
+
+use crate::data::*;
+use crate::rpc::*;
+use super::block::BlockScope;
+use super::dsp::DSP;
+use super::graph::{ProcessScope,MAX_SAMPLES};
+
+
+/// Maximum oversampling factor
+pub const MAX_FACTOR: usize = 8;
+/// Number of taps of halfband filters
+pub const HALFBAND_TAPS: usize = 31;
+
+
+/// Samples history, written twice in order to be read as a contiguous slice.
+struct History {
+    data: Vec<f32>,
+    pos: usize,
+}
+
+/// 2x upsampler, as the two polyphase branches of an halfband filter.
+struct Upsampler {
+    even: Vec<f32>,
+    odd: Vec<f32>,
+    history: History,
+}
+
+/// 2x downsampler using an halfband filter.
+struct Downsampler {
+    coefs: Vec<f32>,
+    history: History,
+}
+
+/// Oversampling filters of a single channel.
+pub struct Oversampler {
+    factor: usize,
+    ups: Vec<Upsampler>,
+    /// Downsamplers, from the highest rate
+    downs: Vec<Downsampler>,
+}
+
+
+/// Wrapper running inner DSP oversampled.
+pub struct Oversampling<D,PS>
+    where D: DSP<Sample=f32,Scope=BlockScope>, PS: ProcessScope
+{
+    dsp: D,
+    factor: usize,
+    n_channels: NChannels,
+    channels: Vec<Oversampler>,
+    max_samples: NSamples,
+    /// Upsampled input, allocated by `set_max_samples`
+    input: Buffer<f32,Vec<f32>>,
+    /// Oversampled output, allocated by `set_max_samples`
+    output: Buffer<f32,Vec<f32>>,
+    phantom: PhantomData<PS>,
+}
+
+unsafe impl<D,PS> Sync for Oversampling<D,PS>
+    where D: DSP<Sample=f32,Scope=BlockScope>+Sync, PS: ProcessScope
+{}
+
+
+/// Design an halfband lowpass filter (Blackman windowed sinc), `taps` being odd.
+pub fn halfband(taps: usize) -> Vec<f32> {
+    let center = (taps / 2) as f32;
+    let coefs: Vec<f32> = (0..taps).map(|n| {
+        let x = (n as f32 - center) / 2.0;
+        let sinc = if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) };
+        let phase = 2.0 * PI * n as f32 / (taps - 1) as f32;
+        sinc * (0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos())
+    }).collect();
+
+    let sum: f32 = coefs.iter().sum();
+    coefs.iter().map(|c| c / sum).collect()
+}
+
+
+impl History {
+    fn new(len: usize) -> Self {
+        Self { data: vec![0.0; len * 2], pos: 0 }
+    }
+
+    fn push(&mut self, x: f32) {
+        let len = self.data.len() / 2;
+        self.pos = (self.pos + 1) % len;
+        self.data[self.pos] = x;
+        self.data[self.pos + len] = x;
+    }
+
+    /// Return `sum(coefs[k] * x[n-k])`
+    fn apply(&self, coefs: &[f32]) -> f32 {
+        let len = self.data.len() / 2;
+        let history = &self.data[self.pos + 1..=self.pos + len];
+        coefs.iter().zip(history.iter().rev()).map(|(c, x)| c * x).sum()
+    }
+
+    fn reset(&mut self) {
+        for x in self.data.iter_mut() {
+            *x = 0.0;
+        }
+    }
+}
+
+
+impl Upsampler {
+    fn new(coefs: &[f32]) -> Self {
+        // gain of 2 compensates inserted zeros
+        let even: Vec<f32> = coefs.iter().step_by(2).map(|c| c * 2.0).collect();
+        let odd: Vec<f32> = coefs.iter().skip(1).step_by(2).map(|c| c * 2.0).collect();
+        Self { history: History::new(even.len()), even, odd }
+    }
+
+    fn process(&mut self, x: f32) -> (f32, f32) {
+        self.history.push(x);
+        (self.history.apply(&self.even), self.history.apply(&self.odd))
+    }
+}
+
+
+impl Downsampler {
+    fn new(coefs: &[f32]) -> Self {
+        Self { coefs: coefs.to_vec(), history: History::new(coefs.len()) }
+    }
+
+    fn process(&mut self, a: f32, b: f32) -> f32 {
+        self.history.push(a);
+        self.history.push(b);
+        self.history.apply(&self.coefs)
+    }
+}
+
+
+impl Oversampler {
+    /// Create oversampler for the provided factor: 2, 4 or 8.
+    pub fn new(factor: usize) -> Self {
+        assert!(factor.is_power_of_two() && factor >= 2 && factor <= MAX_FACTOR,
+                "oversampling factor must be 2, 4 or 8");
+
+        let coefs = halfband(HALFBAND_TAPS);
+        let stages = factor.trailing_zeros() as usize;
+        Self {
+            factor,
+            ups: (0..stages).map(|_| Upsampler::new(&coefs)).collect(),
+            downs: (0..stages).map(|_| Downsampler::new(&coefs)).collect(),
+        }
+    }
+
+    /// Delay of upsampling and downsampling, in (fractional) samples at the base rate.
+    pub fn delay(&self) -> f32 {
+        // each stage's filters delay by half of their length at their own rate, downsampling
+        // outputting on the second of two samples
+        let delay = (HALFBAND_TAPS / 2) as f32;
+        (1..=self.ups.len()).map(|stage| (2.0 * delay - 1.0) / (1 << stage) as f32).sum()
+    }
+
+    /// Latency of upsampling and downsampling, in samples at the base rate.
+    pub fn latency(&self) -> NSamples {
+        self.delay().round() as NSamples
+    }
+
+    /// Upsample a sample into `factor` samples.
+    pub fn upsample(&mut self, x: f32, output: &mut [f32]) {
+        let (mut buffer, mut len) = ([0.0f32; MAX_FACTOR], 1);
+        buffer[0] = x;
+        for stage in self.ups.iter_mut() {
+            let mut next = [0.0f32; MAX_FACTOR];
+            for i in 0..len {
+                let (a, b) = stage.process(buffer[i]);
+                next[i * 2] = a;
+                next[i * 2 + 1] = b;
+            }
+            buffer = next;
+            len *= 2;
+        }
+        output[..self.factor].copy_from_slice(&buffer[..self.factor]);
+    }
+
+    /// Downsample `factor` samples into one.
+    pub fn downsample(&mut self, input: &[f32]) -> f32 {
+        let (mut buffer, mut len) = ([0.0f32; MAX_FACTOR], self.factor);
+        buffer[..len].copy_from_slice(&input[..len]);
+        for stage in self.downs.iter_mut() {
+            for i in 0..len / 2 {
+                buffer[i] = stage.process(buffer[i * 2], buffer[i * 2 + 1]);
+            }
+            len /= 2;
+        }
+        buffer[0]
+    }
+
+    /// Clear filters' state.
+    pub fn reset(&mut self) {
+        for stage in self.ups.iter_mut() {
+            stage.history.reset();
+        }
+        for stage in self.downs.iter_mut() {
+            stage.history.reset();
+        }
+    }
+}
+
+
+impl<D,PS> Oversampling<D,PS>
+    where D: DSP<Sample=f32,Scope=BlockScope>, PS: ProcessScope
+{
+    /// Wrap DSP oversampled by the provided factor: 2, 4 or 8.
+    pub fn new(dsp: D, factor: usize) -> Self {
+        let n_channels = dsp.n_channels().max(1);
+        let mut oversampling = Self {
+            dsp, factor, n_channels,
+            channels: (0..n_channels).map(|_| Oversampler::new(factor)).collect(),
+            max_samples: 0,
+            input: Buffer::with_capacity(true, n_channels, 0),
+            output: Buffer::with_capacity(true, n_channels, 0),
+            phantom: PhantomData,
+        };
+        oversampling.set_max_samples(MAX_SAMPLES);
+        oversampling
+    }
+
+    /// Inner DSP
+    pub fn dsp(&self) -> &D {
+        &self.dsp
+    }
+
+    /// Mutable inner DSP
+    pub fn dsp_mut(&mut self) -> &mut D {
+        &mut self.dsp
+    }
+
+    /// Oversampling factor
+    pub fn factor(&self) -> usize {
+        self.factor
+    }
+}
+
+
+impl<D,PS> Object for Oversampling<D,PS>
+    where D: DSP<Sample=f32,Scope=BlockScope>, PS: ProcessScope
+{
+    fn object_meta(&self) -> ObjectMeta {
+        self.dsp.object_meta()
+    }
+
+    fn get_value(&self, index: ObjectIndex) -> Option<Value> {
+        self.dsp.get_value(index)
+    }
+
+    fn set_value(&mut self, index: ObjectIndex, value: Value) -> Result<Value, ()> {
+        self.dsp.set_value(index, value)
+    }
+
+    fn map_object(&self, mapper: &mut dyn ObjectMapper) {
+        self.dsp.map_object(mapper)
+    }
+}
+
+
+impl<D,PS> DSP for Oversampling<D,PS>
+    where D: DSP<Sample=f32,Scope=BlockScope>, PS: ProcessScope
+{
+    type Sample = f32;
+    type Scope = PS;
+
+    fn prepare(&mut self, rate: SampleRate) {
+        self.dsp.prepare(rate * self.factor as SampleRate);
+        for channel in self.channels.iter_mut() {
+            channel.reset();
+        }
+    }
+
+    fn set_max_samples(&mut self, max_samples: NSamples) {
+        self.max_samples = max_samples;
+        self.dsp.set_max_samples(max_samples * self.factor);
+        self.input = Buffer::with_capacity(true, self.n_channels, max_samples * self.factor);
+        self.output = Buffer::with_capacity(true, self.n_channels, max_samples * self.factor);
+    }
+
+    fn process_audio(&mut self, scope: &Self::Scope, input: Option<&dyn BufferView<Sample=Self::Sample>>,
+                     output: Option<&mut dyn BufferView<Sample=Self::Sample>>) -> usize
+    {
+        // buffers are only resized within their capacity
+        let (factor, n_channels) = (self.factor, self.n_channels as usize);
+        let n_samples = scope.n_samples().min(self.max_samples);
+        self.input.resize(self.n_channels, n_samples * factor);
+        self.output.resize(self.n_channels, n_samples * factor);
+        self.input.fill(0.0);
+        self.output.fill(0.0);
+
+        // upsample
+        let mut frame = [0.0f32; MAX_FACTOR];
+        if let Some(input) = input {
+            let samples = self.input.as_slice_mut();
+            for (c, oversampler) in self.channels.iter_mut().enumerate() {
+                if let Some(channel) = input.channel(c as NChannels) {
+                    for (i, x) in channel.take(n_samples).enumerate() {
+                        oversampler.upsample(*x, &mut frame);
+                        for (j, y) in frame[..factor].iter().enumerate() {
+                            samples[(i * factor + j) * n_channels + c] = *y;
+                        }
+                    }
+                }
+            }
+        }
+
+        let scope = BlockScope::new(n_samples * factor,
+                                    scope.last_frame_time().wrapping_mul(factor as NFrames),
+                                    scope.transport());
+        match (self.dsp.is_source(), self.dsp.is_sink()) {
+            (true, _) => self.dsp.process_audio(&scope, None, Some(&mut self.output)),
+            (false, true) => return self.dsp.process_audio(&scope, Some(&self.input), None),
+            (false, false) => self.dsp.process_audio(&scope, Some(&self.input), Some(&mut self.output)),
+        };
+
+        // downsample
+        let output = match output {
+            Some(output) => output,
+            None => return 0,
+        };
+        let samples = self.output.as_slice();
+        for (c, oversampler) in self.channels.iter_mut().enumerate() {
+            if let Some(channel) = output.channel_mut(c as NChannels) {
+                for (i, y) in channel.take(n_samples).enumerate() {
+                    for (j, x) in frame[..factor].iter_mut().enumerate() {
+                        *x = samples[(i * factor + j) * n_channels + c];
+                    }
+                    *y = oversampler.downsample(&frame);
+                }
+            }
+        }
+        n_samples * n_channels
+    }
+
+    fn n_channels(&self) -> NChannels {
+        self.dsp.n_channels()
+    }
+
+    fn is_sink(&self) -> bool {
+        self.dsp.is_sink()
+    }
+
+    fn is_source(&self) -> bool {
+        self.dsp.is_source()
+    }
+
+    fn latency(&self) -> NSamples {
+        self.channels.first().map(|c| c.latency()).unwrap_or(0) + self.dsp.latency() / self.factor
+    }
+
+    fn wet(&self) -> f32 {
+        self.dsp.wet()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate as libfoxlive;
+    use libfoxlive_derive::object;
+
+    /// Copy input to output, recording the maximum number of samples
+    #[object("identity")]
+    struct Identity {
+        max_samples: NSamples,
+    }
+
+    impl DSP for Identity {
+        type Sample = f32;
+        type Scope = BlockScope;
+
+        fn set_max_samples(&mut self, max_samples: NSamples) {
+            self.max_samples = max_samples;
+        }
+
+        fn process_audio(&mut self, scope: &Self::Scope, input: Option<&dyn BufferView<Sample=f32>>,
+                         output: Option<&mut dyn BufferView<Sample=f32>>) -> usize
+        {
+            assert!(scope.n_samples() <= self.max_samples);
+            let (input, output) = (input.unwrap().as_slice(), output.unwrap().as_slice_mut());
+            output.copy_from_slice(input);
+            output.len()
+        }
+
+        fn n_channels(&self) -> NChannels { 1 }
+    }
+
+    /// Sine of the provided period, delayed by `delay` samples
+    fn sine(n_samples: NSamples, period: f32, delay: f32) -> Vec<f32> {
+        (0..n_samples).map(|i| (2.0 * PI * (i as f32 - delay) / period).sin()).collect()
+    }
+
+    /// Assert that `output` is the expected sine, after filters' warm up. Tolerance is below
+    /// the error of a one sample delay (about 0.1).
+    fn assert_sine(output: &[f32], expected: &[f32]) {
+        for (y, x) in output[64..].iter().zip(expected[64..].iter()) {
+            assert!((y - x).abs() < 0.02, "{} != {}", y, x);
+        }
+    }
+
+    /// Test: upsampling then downsampling a low frequency sine, delayed by latency
+    #[test]
+    fn oversampler() {
+        for factor in [2, 4, 8].iter() {
+            let mut oversampler = Oversampler::new(*factor);
+            assert_eq!(oversampler.latency(), oversampler.delay().round() as NSamples);
+            let input = sine(512, 64.0, 0.0);
+
+            let mut frame = [0.0f32; MAX_FACTOR];
+            let output: Vec<f32> = input.iter().map(|x| {
+                oversampler.upsample(*x, &mut frame);
+                oversampler.downsample(&frame[..*factor])
+            }).collect();
+            assert_sine(&output, &sine(512, 64.0, oversampler.delay()));
+        }
+    }
+
+    /// Test: inner DSP is told the oversampled maximum, and processes the whole block
+    #[test]
+    fn max_samples() {
+        let mut oversampling = Oversampling::<_,BlockScope>::new(Identity { max_samples: 0 }, 8);
+        oversampling.set_max_samples(256);
+        assert_eq!(oversampling.dsp().max_samples, 2048);
+
+        let input: Buffer<f32,Vec<f32>> = (true, 1, sine(256, 64.0, 0.0)).into();
+        let mut output: Buffer<f32,Vec<f32>> = (true, 1, vec![0.0; 256]).into();
+        oversampling.process_audio(&BlockScope::new(256, 0, None), Some(&input), Some(&mut output));
+        assert_sine(&output.buffer, &sine(256, 64.0, oversampling.channels[0].delay()));
+    }
+}
@@ -1,3 +1,7 @@
+//! Generated from Faust code by `build/faust_generator.rs`: don't edit.
+#![allow(non_snake_case,non_camel_case_types,non_upper_case_globals,unused_mut,unused_parens,unused_variables,dead_code,bare_trait_objects,clippy::all)]
+use crate::dsp::faust::{FaustDsp,Meta,UI};
+
 
 pub struct Echo {
 	fDummy: f32,
@@ -7,7 +11,7 @@ pub struct Echo {
 	fEntry1: f32,
 	fEntry2: f32,
 	IOTA: i32,
-	fRec0: [f32;2097152],
+	fRec0: Vec<f32>,
 }
 
 impl Echo {
@@ -21,7 +25,7 @@ impl Echo {
 			fEntry1: 0.0,
 			fEntry2: 0.0,
 			IOTA: 0,
-			fRec0: [0.0;2097152],
+			fRec0: vec![0.0;2097152],
 		}
 	}
 	pub fn metadata(&mut self, m: &mut Meta) { 
@@ -118,3 +122,36 @@ impl Echo {
 
 }
 
+
+
+impl Default for Echo {
+    fn default() -> Self {
+        Echo::new()
+    }
+}
+
+impl FaustDsp for Echo {
+    fn metadata(&mut self, m: &mut dyn Meta) {
+        Echo::metadata(self, m)
+    }
+
+    fn num_inputs(&mut self) -> i32 {
+        self.getNumInputs()
+    }
+
+    fn num_outputs(&mut self) -> i32 {
+        self.getNumOutputs()
+    }
+
+    fn init(&mut self, sample_rate: i32) {
+        Echo::init(self, sample_rate)
+    }
+
+    fn build_user_interface(&mut self, ui: &mut dyn UI<f32>) {
+        self.buildUserInterface(ui)
+    }
+
+    fn compute(&mut self, count: i32, inputs: &[&[f32]], outputs: &mut [&mut [f32]]) {
+        Echo::compute(self, count, inputs, outputs)
+    }
+}
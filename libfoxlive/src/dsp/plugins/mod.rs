@@ -1,14 +1,20 @@
+//! Faust plugins generated from `.dsp` files of this directory by
+//! `build/faust_generator.rs`: don't edit.
+use crate::dsp::BoxedDSP;
+use crate::dsp::faust::FaustDSP;
+use crate::dsp::graph::ProcessScope;
 
-use libfoxlive::dsp::DSP;
+pub mod echo;
 
-pub use echo;
-
-pub fn list_plugins() -> Vec<String> {
+/// List available plugins
+pub fn list_plugins() -> Vec<&'static str> {
     vec!["echo",]
 }
 
-pub fn new_plugin(name: &str) -> Option<Box<DSP>> {
+/// Instanciate plugin by name
+pub fn new_plugin<PS: ProcessScope>(name: &str) -> Option<BoxedDSP<f32,PS>> {
     match name {
-        "echo" => echo::Echo::new(),
+        "echo" => Some(Box::new(FaustDSP::<echo::Echo,PS>::new())),
+        _ => None,
     }
 }
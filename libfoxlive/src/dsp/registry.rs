@@ -0,0 +1,424 @@
+//! Registry of available DSP, instanciated by name.
+//!
+//! Each plugin registers its informations along with a constructor taking the graph's sample
+//! rate and parameters. Parameters whose name matches a field's label are then set on the
+//! created DSP, so constructors only need to handle the other ones.
+//!
+//! Built-in instruments, Faust, LADSPA and LV2 plugins are registered using the `register_*`
+//! functions. DSP that require resources (e.g. a jack client) are registered by the
+//! application, using a constructor capturing them.
+//!
+//! Plugins may load libraries and allocate: they are instanciated by name through graph's
+//! service using a `Builder` client, which creates and prepares them on the caller's side
+//! before requests reach the audio thread. It also answers plugins listing from the registry.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let mut registry = Registry::new();
+//! registry::register_faust(&mut registry);
+//! registry.register(PluginInfo::new("output", "jack"), move |_, _| {
+//!     Ok(Box::new(JackOutput::acquire(&client, "master", 2)))
+//! });
+//! let mut client = graph.builder(Arc::new(registry), client);
+//!
+//! let params = vec![("feedback".to_string(), Value::F32(0.2))];
+//! let echo = client.add_plugin(Build::Args(("echo".to_string(), params)));
+//! let effects = client.list_plugins(Build::Args(Some("faust".to_string())));
+//! ```
+use std::collections::BTreeMap;
+
+use std::sync::Arc;
+
+use crate::data::{Sample,SampleRate};
+use crate::rpc::*;
+use super::dsp::{BoxedDSP,DEFAULT_RATE};
+use super::graph::{GraphFormat,ProcessScope,Unit};
+use super::graph::service::{Client,Request};
+
+
+/// Plugin parameters as `(name, value)`.
+pub type Params = [(String, Value)];
+
+/// Plugin constructor, taking sample rate and parameters.
+pub type Constructor<S,PS> = Box<dyn Fn(SampleRate, &Params) -> Result<BoxedDSP<S,PS>, String>+Send+Sync>;
+
+/// Plugin's name and parameters, as passed to graph's `add_plugin`.
+pub type PluginArgs = (String, Vec<(String, Value)>);
+
+
+/// Argument of a graph request, built by a `Builder` from the arguments provided by the
+/// caller before the request is sent.
+pub enum Build<A,T> {
+    /// Arguments provided by the caller
+    Args(A),
+    /// Value built from them
+    Built(T),
+}
+
+
+/// Informations about a registered plugin.
+#[derive(Clone,Debug)]
+pub struct PluginInfo {
+    /// Unique name, used to instanciate it
+    pub name: String,
+    /// Category (e.g. `faust`, `ladspa`)
+    pub category: String,
+    /// Plugin's metadatas (e.g. `maker`, `description`)
+    pub metadatas: Vec<(String,String)>,
+}
+
+
+/// Registry of available plugins.
+pub struct Registry<S,PS>
+    where S: 'static+Sync+Sample, PS: 'static+Sync+ProcessScope
+{
+    plugins: BTreeMap<String, (PluginInfo, Constructor<S,PS>)>,
+}
+
+
+/// Graph's service client creating and preparing DSP on the caller's side, so that requests
+/// don't allocate on the audio thread:
+/// - units passed to `add_node`, `add_child` and `replace_node` are prepared for graph's
+///   format;
+/// - plugins passed to `add_plugin` are instanciated from the registry;
+/// - plugins passed to `list_plugins` are listed from the registry.
+pub struct Builder<S,PS,C>
+    where S: 'static+Sync+Sample, PS: 'static+Sync+ProcessScope+Clone, C: Client<S,PS>
+{
+    registry: Arc<Registry<S,PS>>,
+    format: Arc<GraphFormat>,
+    client: C,
+}
+
+
+impl PluginInfo {
+    pub fn new<N: Into<String>, C: Into<String>>(name: N, category: C) -> Self {
+        Self { name: name.into(), category: category.into(), metadatas: Vec::new() }
+    }
+
+    /// Add a metadata
+    pub fn with<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.metadatas.push((key.into(), value.into()));
+        self
+    }
+}
+
+
+/// Set object's fields from parameters whose name matches field's label, converting numeric
+/// values to field's type. Return names of unmatched parameters.
+pub fn set_params<'a, O: Object+?Sized>(object: &mut O, params: &'a Params) -> Vec<&'a str> {
+    let mut fields = Vec::new();
+    object.map_object(&mut fields);
+
+    let mut unmatched = Vec::new();
+    for (name, value) in params.iter() {
+        let field = fields.iter().find(|f| f.metadatas.iter().any(|(k, v)| *k == "label" && *v == name.as_str()));
+        let value = field.and_then(|f| match value.get_type() == f.value_type {
+            true => Some((f.index, value.clone())),
+            false => value.as_f64().and_then(|v| Value::from_f64(f.value_type, v)).map(|v| (f.index, v)),
+        });
+
+        let set = match value {
+            Some((index, value)) => object.set_value(index, value).is_ok(),
+            None => false,
+        };
+        if !set {
+            unmatched.push(name.as_str());
+        }
+    }
+    unmatched
+}
+
+
+impl<S,PS> Registry<S,PS>
+    where S: 'static+Sync+Sample, PS: 'static+Sync+ProcessScope
+{
+    pub fn new() -> Self {
+        Self { plugins: BTreeMap::new() }
+    }
+
+    /// Register a plugin, replacing existing one of the same name.
+    pub fn register<F>(&mut self, info: PluginInfo, constructor: F)
+        where F: 'static+Fn(SampleRate, &Params) -> Result<BoxedDSP<S,PS>, String>+Send+Sync
+    {
+        self.plugins.insert(info.name.clone(), (info, Box::new(constructor)));
+    }
+
+    /// Unregister a plugin, returning true if it was registered.
+    pub fn unregister(&mut self, name: &str) -> bool {
+        self.plugins.remove(name).is_some()
+    }
+
+    /// Return plugin's informations
+    pub fn info(&self, name: &str) -> Option<&PluginInfo> {
+        self.plugins.get(name).map(|(info, _)| info)
+    }
+
+    /// List plugins, optionally filtered by category.
+    pub fn plugins(&self, category: Option<&str>) -> Vec<PluginInfo> {
+        self.plugins.values()
+            .filter(|(info, _)| category.map(|c| info.category == c).unwrap_or(true))
+            .map(|(info, _)| info.clone())
+            .collect()
+    }
+
+    /// Instanciate plugin by name. Sample rate is `DEFAULT_RATE` when `0`.
+    pub fn create(&self, name: &str, rate: SampleRate, params: &Params) -> Result<BoxedDSP<S,PS>, String> {
+        let (_, constructor) = self.plugins.get(name)
+                                   .ok_or_else(|| format!("{}: plugin not found", name))?;
+        let rate = if rate > 0 { rate } else { DEFAULT_RATE };
+        let mut dsp = constructor(rate, params)?;
+        set_params(&mut *dsp, params);
+        Ok(dsp)
+    }
+}
+
+
+impl<S,PS,C> Builder<S,PS,C>
+    where S: 'static+Sync+Sample, PS: 'static+Sync+ProcessScope+Clone, C: Client<S,PS>
+{
+    /// Create a new builder for the provided graph's format (see `Graph::builder`), sending
+    /// requests with `client`.
+    pub fn new(registry: Arc<Registry<S,PS>>, format: Arc<GraphFormat>, client: C) -> Self {
+        Self { registry, format, client }
+    }
+
+    /// Plugins registry.
+    pub fn registry(&self) -> &Arc<Registry<S,PS>> {
+        &self.registry
+    }
+
+    /// Instanciate a registered plugin and prepare it for graph's format.
+    pub fn create(&self, name: &str, params: &Params) -> Result<Unit<S,PS>, String> {
+        let mut unit = Unit::new(self.registry.create(name, self.format.rate(), params)?);
+        self.prepare(&mut unit);
+        Ok(unit)
+    }
+
+    /// Prepare unit for graph's format.
+    pub fn prepare(&self, unit: &mut Unit<S,PS>) {
+        unit.prepare(self.format.rate(), self.format.max_samples());
+    }
+
+    /// Build request's DSP and plugins, returning the request to send.
+    pub fn build(&self, request: Request<S,PS>) -> Request<S,PS> {
+        match request {
+            Request::AddNode(mut unit) => {
+                self.prepare(&mut unit);
+                Request::AddNode(unit)
+            },
+            Request::AddChild(parent, mut unit) => {
+                self.prepare(&mut unit);
+                Request::AddChild(parent, unit)
+            },
+            Request::ReplaceNode(node, mut unit) => {
+                self.prepare(&mut unit);
+                Request::ReplaceNode(node, unit)
+            },
+            Request::AddPlugin(Build::Args((name, params))) =>
+                Request::AddPlugin(Build::Built(self.create(&name, &params))),
+            Request::ListPlugins(Build::Args(category)) =>
+                Request::ListPlugins(Build::Built(self.registry.plugins(category.as_deref()))),
+            request => request,
+        }
+    }
+}
+
+
+impl<S,PS,C> Client<S,PS> for Builder<S,PS,C>
+    where S: 'static+Sync+Sample, PS: 'static+Sync+ProcessScope+Clone, C: Client<S,PS>
+{
+    type ResponseFut = C::ResponseFut;
+
+    fn send_request(&mut self, request: Request<S,PS>) -> Self::ResponseFut {
+        let request = self.build(request);
+        self.client.send_request(request)
+    }
+}
+
+
+/// Register Faust plugins generated at build time.
+pub fn register_faust<PS>(registry: &mut Registry<f32,PS>)
+    where PS: 'static+Sync+ProcessScope
+{
+    for name in super::plugins::list_plugins() {
+        registry.register(PluginInfo::new(name, "faust"), move |_, _| {
+            super::plugins::new_plugin(name).ok_or_else(|| format!("{}: plugin not found", name))
+        });
+    }
+}
+
+
+/// Register built-in instruments.
+pub fn register_instruments<PS>(registry: &mut Registry<f32,PS>)
+    where PS: 'static+Sync+ProcessScope
+{
+    use super::synth::Synth;
+    use super::sampler::Sampler;
+    use super::soundfont::SoundFontPlayer;
+
+    registry.register(PluginInfo::new("synth", "instrument"), |_, _| {
+        Ok(Box::new(Synth::<PS>::new(2)))
+    });
+    // files are set by the "path" parameter
+    registry.register(PluginInfo::new("sampler", "instrument"), |_, _| {
+        Ok(Box::new(Sampler::<PS>::new(2)))
+    });
+    registry.register(PluginInfo::new("soundfont", "instrument"), |_, _| {
+        Ok(Box::new(SoundFontPlayer::<PS>::new(2)))
+    });
+}
+
+
+/// Register LADSPA plugins found in the search path.
+#[cfg(feature="with_ladspa")]
+pub fn register_ladspa<PS>(registry: &mut Registry<f32,PS>)
+    where PS: 'static+Sync+ProcessScope
+{
+    use super::ladspa;
+
+    for descriptor in ladspa::descriptors() {
+        let info = PluginInfo::new(descriptor.label(), "ladspa")
+                        .with("name", descriptor.name())
+                        .with("maker", descriptor.maker());
+        registry.register(info, move |rate, _| {
+            ladspa::LadspaPlugin::new(descriptor, rate)
+                .map(|plugin| Box::new(plugin) as BoxedDSP<f32,PS>)
+        });
+    }
+}
+
+
+/// Register installed LV2 plugins, by URI.
+#[cfg(feature="with_lv2")]
+pub fn register_lv2<PS>(registry: &mut Registry<f32,PS>)
+    where PS: 'static+Sync+ProcessScope
+{
+    use super::lv2;
+
+    for descriptor in lv2::descriptors() {
+        let info = PluginInfo::new(descriptor.uri(), "lv2").with("name", descriptor.name());
+        registry.register(info, move |rate, _| {
+            lv2::Lv2Plugin::new(descriptor, rate)
+                .map(|plugin| Box::new(plugin) as BoxedDSP<f32,PS>)
+        });
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate as libfoxlive;
+    use libfoxlive_derive::object;
+    use crate::data::{BufferView,NChannels};
+    use super::super::block::BlockScope;
+    use super::super::dsp::DSP;
+    use super::super::graph::Graph;
+    use super::super::graph::service::Response;
+    use crate::rpc::channel::mpsc;
+
+    /// DSP doing nothing, recording its constructor's rate
+    #[object("test")]
+    struct Test {
+        #[field("gain", F32(1.0))]
+        gain: f32,
+        #[field("rate", I32(0))]
+        rate: i32,
+    }
+
+    impl DSP for Test {
+        type Sample = f32;
+        type Scope = BlockScope;
+
+        fn process_audio(&mut self, _scope: &Self::Scope, _input: Option<&dyn BufferView<Sample=f32>>,
+                         _output: Option<&mut dyn BufferView<Sample=f32>>) -> usize
+        {
+            0
+        }
+
+        fn n_channels(&self) -> NChannels { 1 }
+    }
+
+    /// Test: parameters matching fields' labels are set, converted to fields' types
+    #[test]
+    fn set_params() {
+        let mut test = Test { gain: 1.0, rate: 0 };
+        let params = [("gain".to_string(), Value::F64(0.5)), ("rate".to_string(), Value::String("x".into())),
+                      ("other".to_string(), Value::F32(1.0))];
+        assert_eq!(super::set_params(&mut test, &params), vec!["rate", "other"]);
+        assert_eq!(test.gain, 0.5);
+        assert_eq!(test.rate, 0);
+    }
+
+    /// Test: plugins are created by name, with default rate and parameters
+    #[test]
+    fn create() {
+        let mut registry = Registry::<f32,BlockScope>::new();
+        registry.register(PluginInfo::new("test", "tests").with("maker", "foxlive"), |rate, _| {
+            Ok(Box::new(Test { gain: 1.0, rate }))
+        });
+        assert_eq!(registry.plugins(Some("tests")).len(), 1);
+        assert!(registry.plugins(Some("other")).is_empty());
+        assert_eq!(registry.info("test").unwrap().metadatas, vec![("maker".to_string(), "foxlive".to_string())]);
+
+        let dsp = registry.create("test", 0, &[("gain".to_string(), Value::F32(0.25))]).unwrap();
+        assert_eq!(dsp.get_value(0).and_then(|v| v.as_f64()), Some(0.25));
+        assert_eq!(dsp.get_value(1).and_then(|v| v.as_f64()), Some(DEFAULT_RATE as f64));
+
+        let dsp = registry.create("test", 44100, &[]).unwrap();
+        assert_eq!(dsp.get_value(1).and_then(|v| v.as_f64()), Some(44100.0));
+
+        assert!(registry.create("unknown", 0, &[]).is_err());
+        assert!(registry.unregister("test"));
+        assert!(registry.create("test", 0, &[]).is_err());
+    }
+
+    /// Client sending requests through graph's transport, ignoring responses
+    struct TestClient(mpsc::Sender<Request<f32,BlockScope>>);
+
+    impl Client<f32,BlockScope> for TestClient {
+        type ResponseFut = futures::future::Ready<Result<Response<f32,BlockScope>,()>>;
+
+        fn send_request(&mut self, request: Request<f32,BlockScope>) -> Self::ResponseFut {
+            self.0.try_send(request).ok();
+            futures::future::err(())
+        }
+    }
+
+    /// Test: builder instanciates plugins for graph's format and lists them
+    #[test]
+    fn builder() {
+        let mut registry = Registry::<f32,BlockScope>::new();
+        registry.register(PluginInfo::new("test", "tests"), |rate, _| {
+            Ok(Box::new(Test { gain: 1.0, rate }))
+        });
+
+        let mut graph = Graph::<f32,BlockScope>::new();
+        graph.set_rate(44100);
+        let transport = graph.init_transport(16).unwrap();
+        let mut receiver = transport.receiver;
+        let mut client = graph.builder(Arc::new(registry), TestClient(transport.sender));
+
+        let params = vec![("gain".to_string(), Value::F32(0.5))];
+        drop(client.add_plugin(Build::Args(("test".to_string(), params))));
+        drop(client.add_plugin(Build::Args(("unknown".to_string(), Vec::new()))));
+        drop(client.list_plugins(Build::Args(Some("tests".to_string()))));
+        graph.process_requests();
+
+        let node = match receiver.try_recv() {
+            Ok(Response::AddPlugin(Ok(node))) => node,
+            _ => panic!("plugin not added"),
+        };
+        let unit = graph.node(node).unwrap();
+        assert_eq!(unit.dsp.get_value(0).and_then(|v| v.as_f64()), Some(0.5));
+        assert_eq!(unit.dsp.get_value(1).and_then(|v| v.as_f64()), Some(44100.0));
+
+        assert!(matches!(receiver.try_recv(), Ok(Response::AddPlugin(Err(_)))));
+        match receiver.try_recv() {
+            Ok(Response::ListPlugins(plugins)) => assert_eq!(plugins.len(), 1),
+            _ => panic!("plugins not listed"),
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! Release and provide values outside of the audio thread.
+//!
+//! Dropping a DSP or a loaded resource frees memory and may run arbitrary code, which must not
+//! happen on the audio thread. `Releaser` sends such values through a preallocated ringbuffer
+//! to a worker thread that drops them. Conversely, `Provider` receives values allocated by a
+//! worker thread, keeping a few of them ready to be taken.
+//!
+//! # Example
+//!
+//! ```
+//! use libfoxlive::dsp::release::Releaser;
+//!
+//! let mut releaser = Releaser::new(16);
+//! // on the audio thread
+//! releaser.release(vec![0.0f32; 1024]);
+//! ```
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::thread;
+use std::time::Duration;
+
+use ringbuf::{Consumer,Producer,RingBuffer};
+
+
+/// Worker's sleep duration between two releases
+const IDLE: Duration = Duration::from_millis(20);
+
+
+/// Send values to be dropped by a worker thread.
+pub struct Releaser<T: 'static+Send> {
+    producer: Producer<T>,
+    /// Releaser has been dropped: worker releases remaining values and stops.
+    stopped: Arc<AtomicBool>,
+}
+
+
+impl<T: 'static+Send> Releaser<T> {
+    /// Create a new releaser able to hold `capacity` values not yet dropped, spawning its
+    /// worker.
+    pub fn new(capacity: usize) -> Self {
+        let (producer, consumer) = RingBuffer::new(capacity).split();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let s = stopped.clone();
+        thread::spawn(move || Self::run(consumer, s));
+        Self { producer, stopped }
+    }
+
+    /// Queue value to be dropped by the worker. If the queue is full, value is dropped in
+    /// place.
+    pub fn release(&mut self, value: T) {
+        // value is given back when full
+        self.producer.push(value).ok();
+    }
+
+    fn run(mut consumer: Consumer<T>, stopped: Arc<AtomicBool>) {
+        loop {
+            let stop = stopped.load(Ordering::Acquire);
+            while let Some(value) = consumer.pop() {
+                drop(value);
+            }
+            if stop {
+                break;
+            }
+            thread::sleep(IDLE);
+        }
+    }
+}
+
+
+impl<T: 'static+Send> Drop for Releaser<T> {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Release);
+    }
+}
+
+
+/// Receive values created by a worker thread.
+pub struct Provider<T: 'static+Send> {
+    consumer: Consumer<T>,
+    /// Provider has been dropped: worker stops.
+    stopped: Arc<AtomicBool>,
+}
+
+
+impl<T: 'static+Send> Provider<T> {
+    /// Create a new provider keeping up to `capacity` values ready, spawning its worker which
+    /// creates them using `create`.
+    pub fn new<F>(capacity: usize, create: F) -> Self
+        where F: 'static+Fn() -> T+Send
+    {
+        let (producer, consumer) = RingBuffer::new(capacity).split();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let s = stopped.clone();
+        thread::spawn(move || Self::run(producer, create, s));
+        Self { consumer, stopped }
+    }
+
+    /// Take a value, if any is ready.
+    pub fn provide(&mut self) -> Option<T> {
+        self.consumer.pop()
+    }
+
+    /// Return true if a value is ready.
+    pub fn is_ready(&self) -> bool {
+        !self.consumer.is_empty()
+    }
+
+    fn run<F: Fn() -> T>(mut producer: Producer<T>, create: F, stopped: Arc<AtomicBool>) {
+        while !stopped.load(Ordering::Acquire) {
+            while !producer.is_full() {
+                producer.push(create()).ok();
+            }
+            thread::sleep(IDLE);
+        }
+    }
+}
+
+
+impl<T: 'static+Send> Drop for Provider<T> {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Release);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    /// Value recording the thread it is dropped on.
+    struct Tracked(Arc<Mutex<Option<thread::ThreadId>>>);
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            *self.0.lock().unwrap() = Some(thread::current().id());
+        }
+    }
+
+    #[test]
+    fn release() {
+        let dropped = Arc::new(Mutex::new(None));
+        let mut releaser = Releaser::new(4);
+        releaser.release(Tracked(dropped.clone()));
+
+        let start = Instant::now();
+        while dropped.lock().unwrap().is_none() && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let id = dropped.lock().unwrap().expect("value has not been released");
+        assert_ne!(id, thread::current().id());
+    }
+
+    #[test]
+    fn provide() {
+        let mut provider = Provider::new(2, || thread::current().id());
+
+        let start = Instant::now();
+        while !provider.is_ready() && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let id = provider.provide().expect("value has not been provided");
+        assert_ne!(id, thread::current().id());
+    }
+}
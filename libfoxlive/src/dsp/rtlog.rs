@@ -0,0 +1,276 @@
+//! Real-time safe logging, usable from the audio thread.
+//!
+//! Records are formatted into fixed-size buffers (longer messages are truncated) and pushed
+//! into a preallocated lock-free queue, without locking nor allocating. A background thread
+//! drains the queue into the `log` crate. Records are dropped when the queue is full, which is
+//! counted and reported.
+//!
+//! Records above `log`'s maximum level are discarded on the audio thread, the level being
+//! read at initialization and refreshed by the background thread.
+//!
+//! # Example
+//!
+//! ```ignore
+//! rtlog::init(1024);
+//!
+//! // in process_audio
+//! rt_warn!("clipping on channel {}: {}", channel, peak);
+//! ```
+use std::cell::UnsafeCell;
+use std::fmt::{self,Write};
+use std::ptr;
+use std::str;
+use std::sync::atomic::{AtomicPtr,AtomicUsize,Ordering};
+use std::thread;
+use std::time::Duration;
+
+pub use log::Level;
+
+
+/// Maximum size of a record's message, in bytes
+pub const MESSAGE_SIZE: usize = 240;
+/// Duration between two drains of the queue
+const DRAIN_INTERVAL: Duration = Duration::from_millis(10);
+
+
+/// Log record
+#[derive(Copy,Clone)]
+pub struct Record {
+    pub level: Level,
+    pub target: &'static str,
+    len: usize,
+    message: [u8; MESSAGE_SIZE],
+}
+
+/// Queue slot
+struct Slot {
+    sequence: AtomicUsize,
+    record: UnsafeCell<Record>,
+}
+
+/// Bounded lock-free queue of records, supporting multiple producers.
+pub struct Queue {
+    slots: Box<[Slot]>,
+    mask: usize,
+    enqueue: AtomicUsize,
+    dequeue: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+unsafe impl Sync for Queue {}
+unsafe impl Send for Queue {}
+
+
+/// Global queue, set by `init`
+static QUEUE: AtomicPtr<Queue> = AtomicPtr::new(ptr::null_mut());
+/// Maximum enabled level, as `log::LevelFilter`
+static MAX_LEVEL: AtomicUsize = AtomicUsize::new(0);
+
+
+impl Record {
+    /// Format record, truncating message to `MESSAGE_SIZE`.
+    pub fn new(level: Level, target: &'static str, args: fmt::Arguments) -> Self {
+        let mut record = Self { level, target, len: 0, message: [0; MESSAGE_SIZE] };
+        record.write_fmt(args).ok();
+        record
+    }
+
+    pub fn message(&self) -> &str {
+        // only whole characters are written
+        str::from_utf8(&self.message[..self.len]).unwrap_or("")
+    }
+}
+
+impl Write for Record {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut count = s.len().min(MESSAGE_SIZE - self.len);
+        while !s.is_char_boundary(count) {
+            count -= 1;
+        }
+        self.message[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+
+impl Queue {
+    /// Create queue, capacity being rounded to a power of two.
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(2).next_power_of_two();
+        let empty = Record { level: Level::Info, target: "", len: 0, message: [0; MESSAGE_SIZE] };
+        let slots = (0..capacity).map(|i| Slot { sequence: AtomicUsize::new(i),
+                                                  record: UnsafeCell::new(empty) })
+                                 .collect::<Vec<_>>().into_boxed_slice();
+        Self { slots, mask: capacity - 1, enqueue: AtomicUsize::new(0), dequeue: AtomicUsize::new(0),
+               dropped: AtomicUsize::new(0) }
+    }
+
+    /// Push record, return false and count it as dropped if queue is full.
+    pub fn push(&self, record: Record) -> bool {
+        let mut pos = self.enqueue.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos & self.mask];
+            let diff = slot.sequence.load(Ordering::Acquire) as isize - pos as isize;
+            if diff == 0 {
+                match self.enqueue.compare_exchange_weak(pos, pos + 1, Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        unsafe { *slot.record.get() = record; }
+                        slot.sequence.store(pos + 1, Ordering::Release);
+                        return true;
+                    },
+                    Err(current) => pos = current,
+                }
+            }
+            else if diff < 0 {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+            else {
+                pos = self.enqueue.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Pop the oldest record.
+    pub fn pop(&self) -> Option<Record> {
+        let mut pos = self.dequeue.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos & self.mask];
+            let diff = slot.sequence.load(Ordering::Acquire) as isize - (pos + 1) as isize;
+            if diff == 0 {
+                match self.dequeue.compare_exchange_weak(pos, pos + 1, Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        let record = unsafe { *slot.record.get() };
+                        slot.sequence.store(pos + self.mask + 1, Ordering::Release);
+                        return Some(record);
+                    },
+                    Err(current) => pos = current,
+                }
+            }
+            else if diff < 0 {
+                return None;
+            }
+            else {
+                pos = self.dequeue.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Number of dropped records
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+
+/// Initialize global queue of the provided capacity and start draining it to `log`. Return
+/// false if already initialized.
+pub fn init(capacity: usize) -> bool {
+    let queue = Box::into_raw(Box::new(Queue::new(capacity)));
+    if QUEUE.compare_exchange(ptr::null_mut(), queue, Ordering::AcqRel, Ordering::Acquire).is_err() {
+        drop(unsafe { Box::from_raw(queue) });
+        return false;
+    }
+    MAX_LEVEL.store(log::max_level() as usize, Ordering::Relaxed);
+
+    // queue lives until the end of the program
+    let queue: &'static Queue = unsafe { &*queue };
+    thread::spawn(move || {
+        let mut dropped = 0;
+        loop {
+            while let Some(record) = queue.pop() {
+                log::log!(target: record.target, record.level, "{}", record.message());
+            }
+
+            let count = queue.dropped();
+            if count != dropped {
+                log::warn!("{} real-time log records dropped", count - dropped);
+                dropped = count;
+            }
+            MAX_LEVEL.store(log::max_level() as usize, Ordering::Relaxed);
+            thread::sleep(DRAIN_INTERVAL);
+        }
+    });
+    true
+}
+
+/// Push a record to the global queue, without locking nor allocating. Record is dropped if
+/// logging is not initialized, level is not enabled or queue is full.
+pub fn log(level: Level, target: &'static str, args: fmt::Arguments) {
+    let queue = QUEUE.load(Ordering::Acquire);
+    if !queue.is_null() && level as usize <= MAX_LEVEL.load(Ordering::Relaxed) {
+        unsafe { &*queue }.push(Record::new(level, target, args));
+    }
+}
+
+/// Number of records dropped because the global queue was full.
+pub fn dropped() -> usize {
+    let queue = QUEUE.load(Ordering::Acquire);
+    match queue.is_null() {
+        true => 0,
+        false => unsafe { &*queue }.dropped(),
+    }
+}
+
+
+/// Log from the audio thread at the provided level.
+#[macro_export]
+macro_rules! rt_log {
+    ($level:expr, $($arg:tt)+) => {
+        $crate::dsp::rtlog::log($level, module_path!(), format_args!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! rt_error {
+    ($($arg:tt)+) => { $crate::rt_log!($crate::dsp::rtlog::Level::Error, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! rt_warn {
+    ($($arg:tt)+) => { $crate::rt_log!($crate::dsp::rtlog::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! rt_info {
+    ($($arg:tt)+) => { $crate::rt_log!($crate::dsp::rtlog::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! rt_debug {
+    ($($arg:tt)+) => { $crate::rt_log!($crate::dsp::rtlog::Level::Debug, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! rt_trace {
+    ($($arg:tt)+) => { $crate::rt_log!($crate::dsp::rtlog::Level::Trace, $($arg)+) };
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test: queue ordering, overflow and message truncation
+    #[test]
+    fn queue() {
+        let queue = Queue::new(4);
+        for i in 0..5 {
+            let pushed = queue.push(Record::new(Level::Info, "test", format_args!("record {}", i)));
+            assert_eq!(pushed, i < 4);
+        }
+        assert_eq!(queue.dropped(), 1);
+
+        for i in 0..4 {
+            assert_eq!(queue.pop().unwrap().message(), format!("record {}", i));
+        }
+        assert!(queue.pop().is_none());
+        assert!(queue.push(Record::new(Level::Info, "test", format_args!("again"))));
+        assert_eq!(queue.pop().unwrap().message(), "again");
+
+        let long = "é".repeat(MESSAGE_SIZE);
+        let record = Record::new(Level::Info, "test", format_args!("{}", long));
+        assert_eq!(record.message().len(), MESSAGE_SIZE);
+    }
+}
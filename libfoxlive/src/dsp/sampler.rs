@@ -0,0 +1,571 @@
+//! Sampler instrument, playing audio files mapped to key and velocity zones.
+//!
+//! Zones' files are read through `format::Reader` fully into memory, in background, and the
+//! resulting `Keymap` is then swapped in the audio thread. Files set through the `path` field
+//! are loaded by a `PathLoader`, so that the audio thread doesn't allocate. The previous keymap is kept until no
+//! voice plays it anymore, then released outside of the audio thread. Samples are pitched by resampling
+//! relative to zone's root key, and played either once to their end or looped between loop
+//! points while the note is held and released.
+//!
+//! Notes are triggered by MIDI events, or by `EventData::Control` events on the `trigger` and
+//! `stop` fields, whose value is the note. Other control events set the sampler's fields.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let mut sampler = Sampler::<Scope>::new(2);
+//! sampler.load_zones(vec![
+//!     Zone::new("kick.wav", (36, 36), 36),
+//!     Zone::new("snare.wav", (38, 38), 38),
+//!     Zone { playback: Playback::Loop, loop_points: Some((4410, 88200)), ..Zone::new("pad.wav", (48, 72), 60) },
+//! ]);
+//! graph.add_child(midi_input, sampler.into());
+//! ```
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::thread;
+
+use crate as libfoxlive;
+use libfoxlive_derive::object;
+use crate::data::*;
+use crate::format::{Error,Reader};
+use crate::rpc::*;
+
+use super::dsp::{DSP,DEFAULT_RATE};
+use super::graph::ProcessScope;
+use super::modulation::{Adsr,Modulator};
+use super::swap::{PathLoader,Swap};
+use super::voice::*;
+
+
+/// Index of the `trigger` field
+pub const TRIGGER_FIELD: ObjectIndex = 1;
+/// Index of the `stop` field
+pub const STOP_FIELD: ObjectIndex = 2;
+
+
+/// How a zone's sample is played.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Playback {
+    /// Play whole sample, ignoring note-off
+    OneShot,
+    /// Loop between loop points until the end of release
+    Loop,
+}
+
+/// Mapping of a sample file to a range of keys and velocities.
+#[derive(Clone,Debug)]
+pub struct Zone {
+    pub path: String,
+    /// Lowest and highest keys, inclusive
+    pub keys: (u8, u8),
+    /// Lowest and highest velocities, inclusive
+    pub velocities: (u8, u8),
+    /// Key at which sample is played at its original pitch
+    pub root: u8,
+    /// Fine tuning, in cents
+    pub tune: f32,
+    pub gain: f32,
+    pub playback: Playback,
+    /// Loop start and end, in sample's frames. Whole sample is looped when `None`.
+    pub loop_points: Option<(usize, usize)>,
+}
+
+/// Zone and its sample data
+struct Region {
+    zone: Zone,
+    rate: SampleRate,
+    n_channels: usize,
+    /// Interleaved samples
+    data: Vec<f32>,
+}
+
+/// Zones with their loaded samples.
+#[derive(Default)]
+pub struct Keymap {
+    regions: Vec<Region>,
+}
+
+/// Sampler's voice
+pub struct SamplerVoice {
+    /// Current keymap
+    keymap: Arc<Keymap>,
+    /// Keymap and region being played
+    playing: Option<(Arc<Keymap>, usize)>,
+    rate: SampleRate,
+    note: u8,
+    velocity: f32,
+    /// Position in frames and increment at zone's root key
+    position: f64,
+    increment: f64,
+    gain: f32,
+    bend: f32,
+    envelope: Adsr,
+}
+
+
+/// Polyphonic sampler.
+#[object("sampler")]
+pub struct Sampler<PS>
+    where PS: ProcessScope
+{
+    /// Sample file played over the whole keyboard, with root key 60
+    #[field("path", String, get(path), set(load))]
+    path: PathLoader<SampleRate>,
+    /// Play the provided note
+    #[field("trigger", U8(60), range(0,127,1), set(trigger))]
+    trigger: u8,
+    /// Release the provided note
+    #[field("stop", U8(60), range(0,127,1), set(stop))]
+    stop: u8,
+    /// Velocity of notes played with `trigger`
+    #[field("velocity", U8(100), range(1,127,1))]
+    pub velocity: u8,
+    /// Envelope's attack in seconds
+    #[field("attack", F32(0.001), range(0.001,5.0,0.001))]
+    pub attack: f32,
+    #[field("decay", F32(0.1), range(0.001,5.0,0.001))]
+    pub decay: f32,
+    #[field("sustain", F32(1.0), range(0.0,1.0,0.01))]
+    pub sustain: f32,
+    #[field("release", F32(0.1), range(0.001,10.0,0.001))]
+    pub release: f32,
+    /// Velocity's influence on gain
+    #[field("velocity sensitivity", F32(1.0), range(0.0,1.0,0.01))]
+    pub velocity_sensitivity: f32,
+    #[field("gain", F32(1.0), range(0.0,2.0,0.01))]
+    pub gain: f32,
+    #[field("polyphony", U8(16), range(1,64,1), set(set_polyphony))]
+    polyphony: u8,
+    instrument: Instrument<SamplerVoice>,
+    rate: SampleRate,
+    /// Current keymap
+    keymap: Arc<Keymap>,
+    swap: Swap<Arc<Keymap>>,
+    n_channels: NChannels,
+    phantom: PhantomData<PS>,
+}
+
+
+impl Zone {
+    /// Create one-shot zone for the provided keys, at full velocity range.
+    pub fn new<P: Into<String>>(path: P, keys: (u8, u8), root: u8) -> Self {
+        Self { path: path.into(), keys, velocities: (0, 127), root, tune: 0.0, gain: 1.0,
+               playback: Playback::OneShot, loop_points: None }
+    }
+
+    pub fn contains(&self, note: u8, velocity: u8) -> bool {
+        self.keys.0 <= note && note <= self.keys.1 &&
+            self.velocities.0 <= velocity && velocity <= self.velocities.1
+    }
+}
+
+
+impl Region {
+    fn n_frames(&self) -> usize {
+        self.data.len() / self.n_channels
+    }
+
+    /// Loop start and end, bounded to sample's frames
+    fn loop_range(&self) -> (usize, usize) {
+        let n_frames = self.n_frames();
+        match self.zone.loop_points {
+            Some((start, end)) if start < end.min(n_frames) => (start, end.min(n_frames)),
+            _ => (0, n_frames),
+        }
+    }
+
+    fn looped(&self) -> bool {
+        self.zone.playback == Playback::Loop && self.loop_range().1 > self.loop_range().0
+    }
+}
+
+
+impl Keymap {
+    /// Read zones' files, resampled to the provided rate. This blocks until files are decoded
+    /// and must not be used from the audio thread.
+    pub fn load(zones: Vec<Zone>, rate: SampleRate) -> Result<Self, Error> {
+        let mut keymap = Self::default();
+        for zone in zones {
+            let buffer = Reader::<f32>::read_all(&zone.path, rate, None)?;
+            let n_channels = buffer.n_channels() as usize;
+            keymap.push(zone, rate, n_channels, buffer.as_slice().to_vec());
+        }
+        Ok(keymap)
+    }
+
+    /// Add a zone with its interleaved sample data.
+    pub fn push(&mut self, zone: Zone, rate: SampleRate, n_channels: usize, data: Vec<f32>) {
+        let n_channels = n_channels.max(1);
+        self.regions.push(Region { zone, rate, n_channels, data });
+    }
+
+    pub fn len(&self) -> usize {
+        self.regions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    /// Return index of the first zone matching note and velocity.
+    fn find(&self, note: u8, velocity: u8) -> Option<usize> {
+        self.regions.iter().position(|r| r.zone.contains(note, velocity) && !r.data.is_empty())
+    }
+}
+
+
+impl SamplerVoice {
+    fn new(keymap: Arc<Keymap>) -> Self {
+        Self { keymap, playing: None, rate: DEFAULT_RATE, note: 0,
+               velocity: 0.0, position: 0.0, increment: 0.0, gain: 1.0, bend: 0.0,
+               envelope: Adsr::new(0.001, 0.1, 1.0, 0.1) }
+    }
+
+    /// Set resampling increment of the played region for the current note.
+    fn update_increment(&mut self) {
+        if let Some((keymap, index)) = self.playing.as_ref() {
+            let region = &keymap.regions[*index];
+            let semitones = self.note as f32 - region.zone.root as f32 + region.zone.tune / 100.0;
+            self.increment = (semitones / 12.0).exp2() as f64 * region.rate as f64 / self.rate.max(1) as f64;
+        }
+    }
+}
+
+impl Voice for SamplerVoice {
+    fn prepare(&mut self, rate: SampleRate) {
+        self.rate = rate;
+        self.envelope.prepare(rate);
+        self.update_increment();
+    }
+
+    fn note_on(&mut self, note: u8, velocity: f32, legato: bool) {
+        self.note = note;
+        if legato && self.playing.is_some() {
+            self.update_increment();
+            return;
+        }
+
+        let midi_velocity = (velocity * 127.0).round() as u8;
+        self.playing = self.keymap.find(note, midi_velocity).map(|index| (self.keymap.clone(), index));
+        self.velocity = velocity;
+        self.position = 0.0;
+        self.update_increment();
+        self.envelope.gate(self.playing.is_some());
+    }
+
+    fn note_off(&mut self) {
+        let looped = self.playing.as_ref().map(|(keymap, index)| keymap.regions[*index].looped())
+                                 .unwrap_or(false);
+        if looped {
+            self.envelope.gate(false);
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.playing.is_some()
+    }
+
+    fn level(&self) -> f32 {
+        self.envelope.level() * self.velocity
+    }
+
+    fn set_param(&mut self, param: usize, value: f32) {
+        if param == PARAM_PITCH_BEND {
+            self.bend = value;
+        }
+    }
+
+    fn render(&mut self, output: &mut [f32], n_channels: usize) {
+        let (keymap, index) = match self.playing.as_ref() {
+            Some((keymap, index)) => (keymap, *index),
+            None => return,
+        };
+        let region = &keymap.regions[index];
+        let (n_frames, channels) = (region.n_frames(), region.n_channels);
+        let (looped, (loop_start, loop_end)) = (region.looped(), region.loop_range());
+        let increment = self.increment * (self.bend / 12.0).exp2() as f64;
+        let gain = self.gain * region.zone.gain;
+
+        let mut ended = false;
+        for frame in output.chunks_mut(n_channels) {
+            let level = self.envelope.next(1, None);
+            let pos = self.position as usize;
+            if self.envelope.is_idle() || pos >= n_frames {
+                ended = true;
+                break;
+            }
+
+            // linear interpolation with next frame, wrapping at loop's end
+            let next = match pos + 1 {
+                next if looped && next >= loop_end => Some(loop_start),
+                next if next < n_frames => Some(next),
+                _ => None,
+            };
+            let frac = (self.position - pos as f64) as f32;
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let channel = channel % channels;
+                let a = region.data[pos * channels + channel];
+                let b = next.map(|next| region.data[next * channels + channel]).unwrap_or(0.0);
+                *sample += (a + (b - a) * frac) * level * gain;
+            }
+
+            self.position += increment;
+            if looped {
+                while self.position >= loop_end as f64 {
+                    self.position -= (loop_end - loop_start) as f64;
+                }
+            }
+        }
+
+        if ended {
+            self.playing = None;
+            self.envelope.gate(false);
+        }
+    }
+}
+
+
+impl<PS> Sampler<PS>
+    where PS: ProcessScope
+{
+    pub fn new(n_channels: NChannels) -> Self {
+        // voices share the same keymap, so that it is retired at once
+        let keymap = Arc::new(Keymap::default());
+        let swap = Swap::new();
+        Self {
+            path: PathLoader::new(swap.loader(), |path, rate| {
+                Keymap::load(vec![Zone::new(path, (0, 127), 60)], rate).map(Arc::new)
+            }),
+            trigger: 60, stop: 60, velocity: 100,
+            attack: 0.001, decay: 0.1, sustain: 1.0, release: 0.1, velocity_sensitivity: 1.0, gain: 1.0,
+            polyphony: 16,
+            instrument: Instrument::new((0..MAX_VOICES).map(|_| SamplerVoice::new(keymap.clone())).collect(), 16),
+            rate: DEFAULT_RATE,
+            keymap,
+            swap,
+            n_channels: n_channels.max(1),
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn voices_mut(&mut self) -> &mut Voices<SamplerVoice> {
+        &mut self.instrument.voices
+    }
+
+    /// Sample file path, when loaded with `load`
+    pub fn path(&self) -> String {
+        self.path.path()
+    }
+
+    /// Load a sample file in background, played over the whole keyboard with root key 60. It
+    /// can be called from the audio thread.
+    pub fn load(&mut self, path: String) -> Result<String, ()> {
+        match self.path.post(&path, self.rate) {
+            true => Ok(path),
+            false => Err(()),
+        }
+    }
+
+    /// Load zones' files in background. The keymap will be used once loaded.
+    pub fn load_zones(&mut self, zones: Vec<Zone>) {
+        let (loader, rate) = (self.swap.loader(), self.rate);
+        self.path.clear();
+
+        thread::spawn(move || {
+            match Keymap::load(zones, rate) {
+                Ok(keymap) => loader.set(Arc::new(keymap)),
+                Err(err) => log::error!("can't load sampler zones: {:?}", err),
+            }
+        });
+    }
+
+    /// Set keymap, which will be used at next block.
+    pub fn set_keymap(&mut self, keymap: Keymap) {
+        self.swap.set(Arc::new(keymap));
+    }
+
+    pub fn trigger(&mut self, note: u8) -> Result<u8, ()> {
+        self.instrument.voices.note_on(note.min(127), self.velocity as f32 / 127.0);
+        self.trigger = note;
+        Ok(note)
+    }
+
+    pub fn stop(&mut self, note: u8) -> Result<u8, ()> {
+        self.instrument.voices.note_off(note.min(127));
+        self.stop = note;
+        Ok(note)
+    }
+
+    pub fn set_polyphony(&mut self, polyphony: u8) -> Result<u8, ()> {
+        self.instrument.voices.set_polyphony(polyphony as usize);
+        self.polyphony = self.instrument.voices.polyphony() as u8;
+        Ok(self.polyphony)
+    }
+
+    /// Swap in loaded keymap, if any. The previous one is released once no voice plays it.
+    fn swap_keymap(&mut self) {
+        if self.swap.swap(&mut self.keymap, |keymap| Arc::strong_count(keymap) == 1) {
+            for voice in self.instrument.voices.voices_mut() {
+                voice.keymap = self.keymap.clone();
+            }
+        }
+    }
+}
+
+
+impl<PS> DSP for Sampler<PS>
+    where PS: ProcessScope
+{
+    type Sample = f32;
+    type Scope = PS;
+
+    fn prepare(&mut self, rate: SampleRate) {
+        self.rate = rate;
+        self.instrument.voices.prepare(rate);
+    }
+
+    /// Triggers are converted to MIDI notes, so that they are played at their time.
+    fn process_events(&mut self, _scope: &Self::Scope, input: &EventBuffer, _output: &mut EventBuffer) {
+        let channel = self.instrument.voices.channel.unwrap_or(0);
+        let note = |value: f64| value.max(0.0).min(127.0) as u8;
+        self.instrument.events.clear();
+        for event in input.iter() {
+            let data = match event.data {
+                EventData::Control(TRIGGER_FIELD, value) =>
+                    EventData::Midi(MidiMessage::NoteOn { channel, note: note(value), velocity: self.velocity }),
+                EventData::Control(STOP_FIELD, value) =>
+                    EventData::Midi(MidiMessage::NoteOff { channel, note: note(value), velocity: 0 }),
+                data => data,
+            };
+            self.instrument.events.push(Event::new(event.time, data));
+        }
+    }
+
+    fn process_audio(&mut self, scope: &Self::Scope, _input: Option<&dyn BufferView<Sample=Self::Sample>>,
+                     output: Option<&mut dyn BufferView<Sample=Self::Sample>>) -> usize
+    {
+        self.swap_keymap();
+
+        let output = match output {
+            Some(output) => output,
+            None => return 0,
+        };
+
+        for i in 0..self.instrument.events.len() {
+            if let EventData::Control(index, value) = self.instrument.events.as_slice()[i].data {
+                control(self, index, value);
+            }
+        }
+
+        let sensitivity = self.velocity_sensitivity.max(0.0).min(1.0);
+        for voice in self.instrument.voices.voices_mut() {
+            voice.envelope.attack = self.attack;
+            voice.envelope.decay = self.decay;
+            voice.envelope.sustain = self.sustain;
+            voice.envelope.release = self.release;
+            voice.gain = self.gain * (1.0 - sensitivity + sensitivity * voice.velocity);
+        }
+
+        self.instrument.render(output, scope.n_samples())
+    }
+
+    fn n_channels(&self) -> NChannels {
+        self.n_channels
+    }
+
+    fn is_source(&self) -> bool {
+        true
+    }
+
+    fn has_event_input(&self) -> bool {
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration,Instant};
+    use crate::dsp::block::BlockScope;
+
+    fn voice(playback: Playback, data: Vec<f32>) -> SamplerVoice {
+        let mut keymap = Keymap::default();
+        keymap.push(Zone { playback, loop_points: Some((2, 4)), ..Zone::new("", (60, 72), 60) },
+                    DEFAULT_RATE, 1, data);
+        let mut voice = SamplerVoice::new(Arc::new(keymap));
+        voice.envelope = Adsr::new(0.0, 0.0, 1.0, 0.0);
+        voice
+    }
+
+    /// Test: one-shot plays until sample's end, loop wraps at loop points
+    #[test]
+    fn playback() {
+        let mut v = voice(Playback::OneShot, vec![1.0, 2.0, 3.0, 4.0, 5.0]);
+        v.note_on(50, 1.0, false);
+        assert!(!v.is_active());
+
+        v.note_on(60, 1.0, false);
+        v.note_off();
+        let mut out = vec![0.0; 8];
+        v.render(&mut out, 1);
+        assert_eq!(out, vec![1.0, 2.0, 3.0, 4.0, 5.0, 0.0, 0.0, 0.0]);
+        assert!(!v.is_active());
+
+        let mut v = voice(Playback::Loop, vec![1.0, 2.0, 3.0, 4.0, 5.0]);
+        v.note_on(72, 1.0, false);
+        let mut out = vec![0.0; 6];
+        v.render(&mut out, 1);
+        assert_eq!(out, vec![1.0, 3.0, 3.0, 3.0, 3.0, 3.0]);
+        assert!(v.is_active());
+    }
+
+    fn process(sampler: &mut Sampler<BlockScope>) {
+        let mut output: Buffer<f32,Vec<f32>> = (true, 1, vec![0.0; 16]).into();
+        sampler.process_audio(&BlockScope::new(16, 0, None), None, Some(&mut output));
+    }
+
+    /// Test: replaced keymap is kept while a voice plays it, then released off the audio thread
+    #[test]
+    fn retired_keymap() {
+        let keymap = || {
+            let mut keymap = Keymap::default();
+            keymap.push(Zone { playback: Playback::Loop, ..Zone::new("", (0, 127), 60) },
+                        DEFAULT_RATE, 1, vec![1.0; 64]);
+            keymap
+        };
+
+        let mut sampler = Sampler::<BlockScope>::new(1);
+        sampler.release = 0.0;
+        sampler.set_keymap(keymap());
+        process(&mut sampler);
+        sampler.trigger(60).unwrap();
+        process(&mut sampler);
+        let playing = Arc::downgrade(&sampler.keymap);
+
+        sampler.set_keymap(keymap());
+        process(&mut sampler);
+        assert!(sampler.swap.retired().is_some());
+        assert!(playing.upgrade().is_some());
+
+        sampler.stop(60).unwrap();
+        process(&mut sampler);
+        process(&mut sampler);
+        assert!(sampler.swap.retired().is_none());
+
+        let start = Instant::now();
+        while playing.upgrade().is_some() && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(playing.upgrade().is_none());
+    }
+
+    /// Test: path set through the field is posted to the loader
+    #[test]
+    fn load() {
+        let mut sampler = Sampler::<BlockScope>::new(1);
+        assert!(sampler.set_value(0, Value::String("missing.wav".to_string())).is_ok());
+        assert!(matches!(sampler.get_value(0), Some(Value::String(path)) if path == "missing.wav"));
+    }
+}
@@ -0,0 +1,1496 @@
+//! Run a DSP in a child process, so that a crashing plugin doesn't take the audio engine down.
+//!
+//! Sandboxed DSP are created by name from constructors registered with `register`. The child
+//! process is a helper program, by default the engine's executable, in which the same
+//! constructors are registered before calling `run_helper` at the start of `main`. The helper
+//! is executed (not only forked) so that no state of the engine's threads leaks into it.
+//!
+//! Audio blocks are exchanged through ringbuffers in shared memory, signalled using futexes.
+//! Processing is pipelined: each block returns the child's output for the previous one, so
+//! the audio thread never waits for the child, at the cost of one block of latency.
+//!
+//! The child serves `Remote`'s requests, sent as frames through a pipe. DSP are only
+//! instanciated in the child, which describes it when first started: the engine mirrors
+//! object's metadata and values from this description. Parameters are queued by the audio
+//! thread into a preallocated channel, then forwarded to the child by a bridge thread.
+//!
+//! A supervisor thread watches the child: when it dies, the node is bypassed (input is copied
+//! to output) and the child is restarted, parameters' values being restored. The supervisor
+//! also restarts the child when sample rate or block size change. A child that is late on a
+//! block makes the node bypassed until it catches up.
+//!
+//! Sandboxed DSP run with a `SandboxScope` (copied from the engine's scope), and their events
+//! are not forwarded.
+//!
+//! # Example
+//!
+//! ```ignore
+//! fn main() {
+//!     sandbox::register("tap_reverb", || ladspa::new_plugin("tap_reverb", 48000).unwrap());
+//!     sandbox::run_helper();
+//!
+//!     let sandbox = Sandbox::<Scope>::new("tap_reverb").unwrap();
+//!     graph.add_node(sandbox.into());
+//! }
+//! ```
+use std::cell::UnsafeCell;
+use std::collections::{BTreeMap,BTreeSet};
+use std::env;
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::os::unix::process::CommandExt;
+use std::process::{self,Command,Stdio};
+use std::ptr;
+use std::sync::{Arc,Mutex,Once};
+use std::sync::atomic::{AtomicBool,AtomicI32,AtomicPtr,AtomicU32,AtomicUsize,Ordering};
+use std::thread;
+use std::time::Duration;
+
+use libc;
+
+use crate as libfoxlive;
+use libfoxlive_derive::service;
+use crate::data::{Buffer,BufferView,NChannels,NFrames,NSamples,SampleRate};
+use crate::rpc::channel::ChannelSender;
+use crate::rpc::*;
+use super::dsp::{DSP,BoxedDSP,DEFAULT_RATE};
+use super::graph::{ProcessScope,MAX_SAMPLES};
+use super::swap::{Loader,Swap};
+use super::transport::Transport;
+use self::service::{Request,Response};
+
+
+/// Maximum number of channels of a sandboxed DSP
+pub const MAX_CHANNELS: usize = 8;
+
+/// Environment variable passing rate, file descriptors and DSP name to the helper
+const HELPER_ENV: &str = "FOXLIVE_SANDBOX";
+/// Delay before restarting a dead child
+const RESTART_DELAY: Duration = Duration::from_millis(500);
+/// Supervisor's sleep duration between two checks of the child
+const SUPERVISE_INTERVAL: Duration = Duration::from_millis(10);
+/// Delay for a child to describe its DSP
+const DESCRIBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Capacity of the parameters channel
+const PARAMS_CAPACITY: usize = 256;
+/// Bridge's sleep duration between two reads of parameters
+const PARAMS_INTERVAL: Duration = Duration::from_millis(10);
+/// Value types exchanged with the child, by tag
+const VALUE_TYPES: [ValueType; 9] = [ValueType::Bool, ValueType::U8, ValueType::I16, ValueType::I32,
+                                     ValueType::F32, ValueType::F64, ValueType::Duration, ValueType::Index,
+                                     ValueType::String];
+
+
+/// Scope passed to sandboxed DSP.
+#[derive(Copy,Clone)]
+pub struct SandboxScope {
+    n_samples: NSamples,
+    last_frame_time: NFrames,
+    transport: Option<Transport>,
+}
+
+/// DSP that can be sandboxed.
+pub type SandboxedDSP = BoxedDSP<f32, SandboxScope>;
+
+/// Constructor of a sandboxed DSP.
+type Constructor = dyn Fn() -> SandboxedDSP+Send+Sync;
+
+/// Parameter queued by the audio thread
+type Param = (ObjectIndex, Value);
+
+
+/// Registered constructors and helper's command.
+struct Helper {
+    constructors: BTreeMap<String, Arc<Constructor>>,
+    command: Option<Box<dyn Fn() -> Command+Send+Sync>>,
+    /// Metadatas' strings received from children
+    strings: BTreeSet<&'static str>,
+}
+
+
+/// Sandboxed DSP's field, as described by the child.
+#[derive(Clone,Debug)]
+pub struct Field {
+    pub index: ObjectIndex,
+    pub value_type: ValueType,
+    pub default: Option<Value>,
+    pub range: Option<Range>,
+    pub metadatas: Metadatas,
+    /// Current value
+    pub value: Option<Value>,
+}
+
+/// Sandboxed DSP's metadata and values, as described by the child.
+#[derive(Clone,Debug)]
+pub struct Description {
+    pub name: String,
+    pub metadatas: Metadatas,
+    pub n_channels: NChannels,
+    pub latency: NSamples,
+    pub wet: f32,
+    pub is_sink: bool,
+    pub is_source: bool,
+    pub fields: Vec<Field>,
+}
+
+/// Sandboxed DSP run by the child, serving engine's requests.
+pub struct Remote {
+    dsp: SandboxedDSP,
+}
+
+
+/// Indices of a ringbuffer in shared memory. Only the producer moves `head`, and only the
+/// consumer moves `tail`.
+#[repr(C)]
+struct RingIndices {
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+/// Single producer, single consumer ringbuffer in shared memory.
+struct ShmRing<'a> {
+    indices: &'a RingIndices,
+    data: *mut f32,
+    size: usize,
+}
+
+
+/// Memory shared with the child process, followed by input and output rings' data.
+#[repr(C)]
+struct Shared {
+    /// Futex: count of blocks requested by parent
+    request: AtomicU32,
+    /// Futex: count of blocks processed by child
+    response: AtomicU32,
+    /// Count of children spawned with this memory
+    generation: AtomicU32,
+    scope: UnsafeCell<SandboxScope>,
+    /// Size of each ring, in samples
+    size: usize,
+    input: RingIndices,
+    output: RingIndices,
+}
+
+/// Shared memory mapping.
+struct Mapping {
+    fd: RawFd,
+    shared: *mut Shared,
+    len: usize,
+}
+
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+
+/// Encoder of frames exchanged with the child.
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+/// Decoder of frames exchanged with the child.
+struct Decoder<'a>(&'a [u8]);
+
+
+/// Running child process.
+struct Child {
+    pid: libc::pid_t,
+    /// Requests pipe
+    requests: RawFd,
+}
+
+/// Supervisor's state shared with the bridge thread.
+struct Link {
+    child: Option<Child>,
+    /// Last values of parameters, restored on restart
+    values: BTreeMap<ObjectIndex, Value>,
+    /// Shared memory passed to the child
+    mapping: Arc<Mapping>,
+}
+
+/// Handles child's lifecycle.
+struct Supervisor {
+    /// Name of the sandboxed DSP
+    name: String,
+    /// Child is running
+    alive: AtomicBool,
+    /// Sandbox has been dropped
+    stopped: AtomicBool,
+    /// Audio thread requested a restart of the child
+    restart: AtomicBool,
+    /// Sample rate passed to the child
+    rate: AtomicI32,
+    /// Minimum size of the rings, in samples
+    size: AtomicUsize,
+    link: Mutex<Link>,
+    /// Provides shared memory reallocated for larger blocks to the audio thread
+    loader: Loader<Arc<Mapping>>,
+}
+
+
+/// DSP running in a child process.
+pub struct Sandbox<PS: ProcessScope> {
+    supervisor: Arc<Supervisor>,
+    /// Shared memory of the current child
+    mapping: Arc<Mapping>,
+    swap: Swap<Arc<Mapping>>,
+    /// Generation of the child the pending block was sent to
+    generation: u32,
+    /// Description provided by the child, mirroring values
+    description: Description,
+    params: bus::Bus<Param>,
+    /// Interleaved block exchanged with the child
+    block: Vec<f32>,
+    n_channels: usize,
+    max_samples: NSamples,
+    rate: SampleRate,
+    /// Request and length of the block being processed by the child
+    pending: Option<(u32, usize)>,
+    /// Child didn't process last block in time
+    late: bool,
+    /// Length of the last block, delaying the output
+    delay: NSamples,
+    phantom: PhantomData<PS>,
+}
+
+unsafe impl<PS: ProcessScope> Sync for Sandbox<PS> {}
+
+
+impl ProcessScope for SandboxScope {
+    fn n_samples(&self) -> NSamples {
+        self.n_samples
+    }
+
+    fn last_frame_time(&self) -> NFrames {
+        self.last_frame_time
+    }
+
+    fn transport(&self) -> Option<Transport> {
+        self.transport
+    }
+}
+
+
+fn helper() -> &'static Mutex<Helper> {
+    static INIT: Once = Once::new();
+    static HELPER: AtomicPtr<Mutex<Helper>> = AtomicPtr::new(ptr::null_mut());
+
+    INIT.call_once(|| {
+        let helper = Box::leak(Box::new(Mutex::new(Helper {
+            constructors: BTreeMap::new(), command: None, strings: BTreeSet::new()
+        })));
+        HELPER.store(helper, Ordering::Release);
+    });
+    // helper is never dropped
+    unsafe { &*HELPER.load(Ordering::Acquire) }
+}
+
+/// Register constructor of a sandboxed DSP by name, in both engine and helper.
+pub fn register<F>(name: &str, constructor: F)
+    where F: 'static+Fn() -> SandboxedDSP+Send+Sync
+{
+    helper().lock().unwrap().constructors.insert(name.to_string(), Arc::new(constructor));
+}
+
+/// Set command executing the helper, instead of the current executable.
+pub fn set_helper<F>(command: F)
+    where F: 'static+Fn() -> Command+Send+Sync
+{
+    helper().lock().unwrap().command = Some(Box::new(command));
+}
+
+fn constructor(name: &str) -> Option<Arc<Constructor>> {
+    helper().lock().unwrap().constructors.get(name).cloned()
+}
+
+fn helper_command() -> io::Result<Command> {
+    let mut command = match helper().lock().unwrap().command.as_ref() {
+        Some(command) => command(),
+        None => Command::new(env::current_exe()?),
+    };
+    command.stdin(Stdio::null());
+    Ok(command)
+}
+
+/// Return a static string for metadatas received from children. Strings are interned so that
+/// restarting children doesn't leak memory.
+fn intern(s: String) -> &'static str {
+    let mut helper = helper().lock().unwrap();
+    match helper.strings.get(s.as_str()) {
+        Some(s) => s,
+        None => {
+            let s: &'static str = Box::leak(s.into_boxed_str());
+            helper.strings.insert(s);
+            s
+        },
+    }
+}
+
+/// Parse helper's environment: rate, shared memory, requests and responses pipes (-1 for
+/// none), DSP name.
+fn helper_args(env: &str) -> Option<(SampleRate, RawFd, RawFd, RawFd, &str)> {
+    let mut args = env.splitn(5, ',');
+    Some((args.next()?.parse().ok()?, args.next()?.parse().ok()?, args.next()?.parse().ok()?,
+          args.next()?.parse().ok()?, args.next()?))
+}
+
+/// When this process has been executed as a sandbox helper, run the sandboxed DSP and never
+/// return; return otherwise. It must be called at the start of `main`, after constructors
+/// have been registered.
+pub fn run_helper() {
+    let env = match env::var(HELPER_ENV) {
+        Ok(env) => env,
+        Err(_) => return,
+    };
+    let (rate, mapping, requests, responses, name) = match helper_args(&env) {
+        Some(args) => args,
+        None => {
+            log::error!("invalid sandbox helper environment: {}", env);
+            process::exit(1);
+        }
+    };
+    let constructor = match constructor(name) {
+        Some(constructor) => constructor,
+        None => {
+            log::error!("no sandboxed DSP registered as {}", name);
+            process::exit(1);
+        }
+    };
+    let mapping = match Mapping::open(mapping) {
+        Ok(mapping) => mapping,
+        Err(err) => {
+            log::error!("cannot map sandbox shared memory: {}", err);
+            process::exit(1);
+        }
+    };
+    let responses = if responses >= 0 { Some(responses) } else { None };
+    child_main(mapping.shared(), requests, responses, constructor(), rate)
+}
+
+
+fn futex_wait(word: &AtomicU32, expected: u32, timeout: Duration) {
+    let timeout = libc::timespec {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long,
+    };
+    unsafe { libc::syscall(libc::SYS_futex, word as *const AtomicU32, libc::FUTEX_WAIT, expected,
+                           &timeout as *const libc::timespec, ptr::null::<u32>(), 0) };
+}
+
+fn futex_wake(word: &AtomicU32) {
+    unsafe { libc::syscall(libc::SYS_futex, word as *const AtomicU32, libc::FUTEX_WAKE, 1,
+                           ptr::null::<libc::timespec>(), ptr::null::<u32>(), 0) };
+}
+
+
+impl ShmRing<'_> {
+    /// Drop available samples, only called by the consumer.
+    fn clear(&self) {
+        self.indices.tail.store(self.indices.head.load(Ordering::Acquire), Ordering::Release);
+    }
+
+    /// Push all samples, return false if there is not enough space.
+    fn push(&self, samples: &[f32]) -> bool {
+        let (head, tail) = (self.indices.head.load(Ordering::Acquire), self.indices.tail.load(Ordering::Acquire));
+        if self.size - (head - tail) < samples.len() {
+            return false;
+        }
+
+        for (i, sample) in samples.iter().enumerate() {
+            unsafe { *self.data.add((head + i) % self.size) = *sample };
+        }
+        self.indices.head.store(head + samples.len(), Ordering::Release);
+        true
+    }
+
+    /// Pop samples into the provided slice, returning the number of read samples.
+    fn pop(&self, samples: &mut [f32]) -> usize {
+        let (head, tail) = (self.indices.head.load(Ordering::Acquire), self.indices.tail.load(Ordering::Acquire));
+        let n = (head - tail).min(samples.len());
+
+        for (i, sample) in samples[..n].iter_mut().enumerate() {
+            *sample = unsafe { *self.data.add((tail + i) % self.size) };
+        }
+        self.indices.tail.store(tail + n, Ordering::Release);
+        n
+    }
+}
+
+
+impl Shared {
+    fn input(&self) -> ShmRing<'_> {
+        self.ring(&self.input, 0)
+    }
+
+    fn output(&self) -> ShmRing<'_> {
+        self.ring(&self.output, 1)
+    }
+
+    fn ring<'a>(&'a self, indices: &'a RingIndices, index: usize) -> ShmRing<'a> {
+        // rings' data follow `Shared` in the mapping
+        let data = unsafe { (self as *const Self).add(1) as *mut f32 };
+        ShmRing { indices, data: unsafe { data.add(index * self.size) }, size: self.size }
+    }
+}
+
+
+impl Mapping {
+    /// Create shared memory with rings of `size` samples.
+    fn new(size: usize) -> io::Result<Self> {
+        let fd = unsafe { libc::memfd_create(b"foxlive-sandbox\0".as_ptr() as *const libc::c_char,
+                                             libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let len = mem::size_of::<Shared>() + 2 * size * mem::size_of::<f32>();
+        if unsafe { libc::ftruncate(fd, len as libc::off_t) } != 0 {
+            let err = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(err);
+        }
+
+        // mapping is zeroed, except scope that may not be valid
+        let mapping = Self::map(fd, len)?;
+        unsafe {
+            ptr::write((*mapping.shared).scope.get(),
+                       SandboxScope { n_samples: 0, last_frame_time: 0, transport: None });
+            (*mapping.shared).size = size;
+        }
+        Ok(mapping)
+    }
+
+    /// Map shared memory created by the engine.
+    fn open(fd: RawFd) -> io::Result<Self> {
+        let mut stat: libc::stat = unsafe { mem::zeroed() };
+        if unsafe { libc::fstat(fd, &mut stat) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Self::map(fd, stat.st_size as usize)
+    }
+
+    fn map(fd: RawFd, len: usize) -> io::Result<Self> {
+        let shared = unsafe { libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE,
+                                         libc::MAP_SHARED, fd, 0) };
+        if shared == libc::MAP_FAILED {
+            let err = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(err);
+        }
+        Ok(Self { fd, shared: shared as *mut Shared, len })
+    }
+
+    fn shared(&self) -> &Shared {
+        unsafe { &*self.shared }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.shared as *mut libc::c_void, self.len);
+            libc::close(self.fd);
+        }
+    }
+}
+
+
+impl Encoder {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn f64(&mut self, value: f64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn str(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.bytes(value.as_bytes());
+    }
+
+    fn value_type(&mut self, value_type: ValueType) {
+        self.u8(VALUE_TYPES.iter().position(|t| *t == value_type).unwrap_or(0) as u8);
+    }
+
+    fn value(&mut self, value: &Value) {
+        self.value_type(value.get_type());
+        match value {
+            Value::Bool(v) => self.u8(*v as u8),
+            Value::U8(v) => self.u8(*v),
+            Value::I16(v) => self.bytes(&v.to_le_bytes()),
+            Value::I32(v) => self.bytes(&v.to_le_bytes()),
+            Value::F32(v) => self.bytes(&v.to_le_bytes()),
+            Value::F64(v) => self.f64(*v),
+            Value::Duration(v) => {
+                self.u64(v.as_secs());
+                self.u32(v.subsec_nanos());
+            },
+            Value::Index(v) => self.u64(*v as u64),
+            Value::String(v) => self.str(v),
+        }
+    }
+
+    fn range(&mut self, range: &Range) {
+        let (min, max, step) = range.as_f64();
+        self.value_type(range.get_type());
+        self.f64(min);
+        self.f64(max);
+        self.f64(step);
+    }
+
+    fn option<T, F: FnOnce(&mut Self, &T)>(&mut self, value: &Option<T>, encode: F) {
+        match value {
+            Some(value) => {
+                self.u8(1);
+                encode(self, value);
+            },
+            None => self.u8(0),
+        }
+    }
+
+    fn metadatas(&mut self, metadatas: &Metadatas) {
+        self.u32(metadatas.len() as u32);
+        for (key, value) in metadatas.iter() {
+            self.str(key);
+            self.str(value);
+        }
+    }
+
+    fn description(&mut self, description: &Description) {
+        self.str(&description.name);
+        self.metadatas(&description.metadatas);
+        self.u8(description.n_channels);
+        self.u64(description.latency as u64);
+        self.bytes(&description.wet.to_le_bytes());
+        self.u8(description.is_sink as u8);
+        self.u8(description.is_source as u8);
+        self.u32(description.fields.len() as u32);
+        for field in description.fields.iter() {
+            self.u32(field.index);
+            self.value_type(field.value_type);
+            self.option(&field.default, Self::value);
+            self.option(&field.range, Self::range);
+            self.metadatas(&field.metadatas);
+            self.option(&field.value, Self::value);
+        }
+    }
+}
+
+
+impl<'a> Decoder<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Some(array)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.array()?))
+    }
+
+    fn f64(&mut self) -> Option<f64> {
+        Some(f64::from_le_bytes(self.array()?))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).ok()
+    }
+
+    fn value_type(&mut self) -> Option<ValueType> {
+        VALUE_TYPES.get(self.u8()? as usize).copied()
+    }
+
+    fn value(&mut self) -> Option<Value> {
+        Some(match self.value_type()? {
+            ValueType::Bool => Value::Bool(self.u8()? != 0),
+            ValueType::U8 => Value::U8(self.u8()?),
+            ValueType::I16 => Value::I16(i16::from_le_bytes(self.array()?)),
+            ValueType::I32 => Value::I32(i32::from_le_bytes(self.array()?)),
+            ValueType::F32 => Value::F32(f32::from_le_bytes(self.array()?)),
+            ValueType::F64 => Value::F64(self.f64()?),
+            ValueType::Duration => Value::Duration(Duration::new(self.u64()?, self.u32()?)),
+            ValueType::Index => Value::Index(self.u64()? as usize),
+            ValueType::String => Value::String(self.string()?),
+        })
+    }
+
+    fn range(&mut self) -> Option<Range> {
+        let value_type = self.value_type()?;
+        let (min, max, step) = (self.f64()?, self.f64()?, self.f64()?);
+        match value_type {
+            ValueType::U8 => Some(Range::U8(min as u8, max as u8, step as u8)),
+            ValueType::I16 => Some(Range::I16(min as i16, max as i16, step as i16)),
+            ValueType::I32 => Some(Range::I32(min as i32, max as i32, step as i32)),
+            ValueType::F32 => Some(Range::F32(min as f32, max as f32, step as f32)),
+            ValueType::F64 => Some(Range::F64(min, max, step)),
+            _ => None,
+        }
+    }
+
+    /// Decode an optional value, returning `None` on invalid data.
+    fn option<T, F: FnOnce(&mut Self) -> Option<T>>(&mut self, decode: F) -> Option<Option<T>> {
+        match self.u8()? {
+            0 => Some(None),
+            _ => decode(self).map(Some),
+        }
+    }
+
+    fn metadatas(&mut self) -> Option<Metadatas> {
+        let n = self.u32()?;
+        (0..n).map(|_| Some((intern(self.string()?), intern(self.string()?)))).collect()
+    }
+
+    fn field(&mut self) -> Option<Field> {
+        Some(Field {
+            index: self.u32()?,
+            value_type: self.value_type()?,
+            default: self.option(Self::value)?,
+            range: self.option(Self::range)?,
+            metadatas: self.metadatas()?,
+            value: self.option(Self::value)?,
+        })
+    }
+
+    fn description(&mut self) -> Option<Description> {
+        Some(Description {
+            name: self.string()?,
+            metadatas: self.metadatas()?,
+            n_channels: self.u8()?,
+            latency: self.u64()? as NSamples,
+            wet: f32::from_le_bytes(self.array()?),
+            is_sink: self.u8()? != 0,
+            is_source: self.u8()? != 0,
+            fields: {
+                let n = self.u32()?;
+                (0..n).map(|_| self.field()).collect::<Option<Vec<_>>>()?
+            },
+        })
+    }
+}
+
+
+fn write_all(fd: RawFd, mut data: &[u8]) -> bool {
+    while !data.is_empty() {
+        let n = unsafe { libc::write(fd, data.as_ptr() as *const libc::c_void, data.len()) };
+        if n < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+            continue;
+        }
+        if n <= 0 {
+            return false;
+        }
+        data = &data[n as usize..];
+    }
+    true
+}
+
+fn read_exact(fd: RawFd, mut data: &mut [u8]) -> bool {
+    while !data.is_empty() {
+        let n = unsafe { libc::read(fd, data.as_mut_ptr() as *mut libc::c_void, data.len()) };
+        if n < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+            continue;
+        }
+        if n <= 0 {
+            return false;
+        }
+        data = &mut data[n as usize..];
+    }
+    true
+}
+
+/// Write frame as its length followed by its data, returning false on failure.
+fn write_frame(fd: RawFd, data: &[u8]) -> bool {
+    write_all(fd, &(data.len() as u32).to_le_bytes()) && write_all(fd, data)
+}
+
+/// Read a frame, returning `None` on failure or when pipe has been closed.
+fn read_frame(fd: RawFd) -> Option<Vec<u8>> {
+    let mut len = [0u8; 4];
+    if !read_exact(fd, &mut len) {
+        return None;
+    }
+    let mut data = vec![0u8; u32::from_le_bytes(len) as usize];
+    match read_exact(fd, &mut data) {
+        true => Some(data),
+        false => None,
+    }
+}
+
+/// Wait for `fd` to be readable (or closed), returning false on timeout.
+fn poll(fd: RawFd, timeout: Duration) -> bool {
+    let mut pollfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as libc::c_int) > 0 }
+}
+
+/// Send request to the child.
+fn write_request(fd: RawFd, request: &Request) -> bool {
+    let mut encoder = Encoder::default();
+    match request {
+        Request::SetValue(index, value) => {
+            encoder.u8(0);
+            encoder.u32(*index);
+            encoder.value(value);
+        },
+        Request::Describe() => encoder.u8(1),
+        _ => return false,
+    }
+    write_frame(fd, &encoder.0)
+}
+
+/// Receive request from the engine.
+fn read_request(fd: RawFd) -> Option<Request> {
+    let frame = read_frame(fd)?;
+    let mut decoder = Decoder(&frame);
+    match decoder.u8()? {
+        0 => Some(Request::SetValue(decoder.u32()?, decoder.value()?)),
+        1 => Some(Request::Describe()),
+        _ => None,
+    }
+}
+
+/// Send response to the engine.
+fn write_response(fd: RawFd, response: &Response) -> bool {
+    let mut encoder = Encoder::default();
+    match response {
+        Response::SetValue(value) => {
+            encoder.u8(0);
+            encoder.option(value, Encoder::value);
+        },
+        Response::Describe(description) => {
+            encoder.u8(1);
+            encoder.description(description);
+        },
+        _ => return false,
+    }
+    write_frame(fd, &encoder.0)
+}
+
+/// Receive response from the child.
+fn read_response(fd: RawFd) -> Option<Response> {
+    let frame = read_frame(fd)?;
+    let mut decoder = Decoder(&frame);
+    match decoder.u8()? {
+        0 => Some(Response::SetValue(decoder.option(Decoder::value)?)),
+        1 => Some(Response::Describe(decoder.description()?)),
+        _ => None,
+    }
+}
+
+
+#[service]
+impl Remote {
+    /// Set DSP's value, returning the value actually set.
+    pub fn set_value(&mut self, index: ObjectIndex, value: Value) -> Option<Value> {
+        self.dsp.set_value(index, value).ok()
+    }
+
+    /// Describe DSP's object and current values.
+    pub fn describe(&self) -> Description {
+        let mut fields: Vec<FieldInfo> = Vec::new();
+        self.dsp.map_object(&mut fields);
+        let meta = self.dsp.object_meta();
+        Description {
+            name: meta.name,
+            metadatas: meta.metadatas,
+            n_channels: self.dsp.n_channels(),
+            latency: self.dsp.latency(),
+            wet: self.dsp.wet(),
+            is_sink: self.dsp.is_sink(),
+            is_source: self.dsp.is_source(),
+            fields: fields.into_iter().map(|info| Field {
+                value: self.dsp.get_value(info.index),
+                index: info.index,
+                value_type: info.value_type,
+                default: info.default,
+                range: info.range,
+                metadatas: info.metadatas,
+            }).collect(),
+        }
+    }
+}
+
+
+impl Field {
+    fn info(&self) -> FieldInfo {
+        FieldInfo {
+            index: self.index,
+            value_type: self.value_type,
+            default: self.default.clone(),
+            range: self.range,
+            metadatas: self.metadatas.clone(),
+        }
+    }
+}
+
+
+/// Child process' main loop, never returns. Description is sent through `responses` if
+/// provided.
+fn child_main(shared: &Shared, requests: RawFd, responses: Option<RawFd>, mut dsp: SandboxedDSP,
+              rate: SampleRate) -> !
+{
+    let parent = unsafe { libc::getppid() };
+    let n_channels = (dsp.n_channels() as usize).clamp(1, MAX_CHANNELS);
+    let max_samples = shared.size / n_channels;
+    dsp.prepare(rate);
+    dsp.set_max_samples(max_samples);
+    let mut remote = Remote { dsp };
+
+    if let Some(fd) = responses {
+        if let Some(response) = remote.process_request(Request::Describe()) {
+            write_response(fd, &response);
+        }
+        unsafe { libc::close(fd) };
+    }
+
+    // read requests from pipe
+    let (sender, receiver) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        while let Some(request) = read_request(requests) {
+            sender.send(request).ok();
+        }
+    });
+
+    let mut input: Buffer<f32,Vec<f32>> = Buffer::with_capacity(true, n_channels as NChannels, max_samples);
+    let mut output: Buffer<f32,Vec<f32>> = Buffer::with_capacity(true, n_channels as NChannels, max_samples);
+
+    // only the consumer moves the input ring's tail: drop blocks sent to a previous child
+    let mut last = shared.request.load(Ordering::Acquire);
+    shared.input().clear();
+    shared.response.store(last, Ordering::Release);
+
+    loop {
+        // parent died
+        if unsafe { libc::getppid() } != parent {
+            unsafe { libc::_exit(0) };
+        }
+
+        futex_wait(&shared.request, last, Duration::from_secs(1));
+        let request = shared.request.load(Ordering::Acquire);
+        if request == last {
+            continue;
+        }
+
+        while let Ok(request) = receiver.try_recv() {
+            remote.process_request(request);
+        }
+
+        let mut scope = unsafe { *shared.scope.get() };
+        scope.n_samples = scope.n_samples.min(max_samples);
+        let len = scope.n_samples * n_channels;
+        input.resize(n_channels as NChannels, scope.n_samples);
+        output.resize(n_channels as NChannels, scope.n_samples);
+        shared.input().pop(&mut input.as_slice_mut()[..len]);
+        output.fill(0.0);
+
+        let dsp = &mut remote.dsp;
+        match (dsp.is_source(), dsp.is_sink()) {
+            (true, _) => dsp.process_audio(&scope, None, Some(&mut output)),
+            (false, true) => dsp.process_audio(&scope, Some(&input), None),
+            (false, false) => dsp.process_audio(&scope, Some(&input), Some(&mut output)),
+        };
+
+        shared.output().push(&output.as_slice()[..len]);
+        last = request;
+        shared.response.store(request, Ordering::Release);
+        futex_wake(&shared.response);
+    }
+}
+
+
+impl Supervisor {
+    /// Execute a new helper, restoring parameters' values. When `responses` is provided, the
+    /// child describes its DSP through it.
+    fn spawn(&self, link: &mut Link, responses: Option<RawFd>) -> io::Result<()> {
+        self.alive.store(false, Ordering::Release);
+
+        // rings are reallocated for larger blocks
+        let size = self.size.load(Ordering::Acquire);
+        if size > link.mapping.shared().size {
+            link.mapping = Arc::new(Mapping::new(size)?);
+            self.loader.set(link.mapping.clone());
+        }
+
+        let mut fds = [0 as RawFd; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        link.mapping.shared().generation.fetch_add(1, Ordering::AcqRel);
+
+        let rate = self.rate.load(Ordering::Acquire);
+        let (mapping, requests, responses) = (link.mapping.fd, fds[0], responses.unwrap_or(-1));
+        let child = helper_command().and_then(|mut command| {
+            command.env(HELPER_ENV, format!("{},{},{},{},{}", rate, mapping, requests, responses, self.name));
+            // only async-signal-safe calls between fork and exec: let helper inherit descriptors
+            unsafe {
+                command.pre_exec(move || {
+                    for fd in [mapping, requests, responses].iter().filter(|fd| **fd >= 0) {
+                        if libc::fcntl(*fd, libc::F_SETFD, 0) != 0 {
+                            return Err(io::Error::last_os_error());
+                        }
+                    }
+                    Ok(())
+                });
+            }
+            command.spawn()
+        });
+        unsafe { libc::close(fds[0]) };
+
+        match child {
+            Ok(child) => {
+                for (index, value) in link.values.iter() {
+                    write_request(fds[1], &Request::SetValue(*index, value.clone()));
+                }
+                link.child = Some(Child { pid: child.id() as libc::pid_t, requests: fds[1] });
+                self.alive.store(true, Ordering::Release);
+                Ok(())
+            },
+            Err(err) => {
+                unsafe { libc::close(fds[1]) };
+                Err(err)
+            }
+        }
+    }
+
+    /// Restart child when it exits or when requested by the audio thread, until sandbox is
+    /// dropped.
+    fn supervise(&self) {
+        loop {
+            let pid = match self.link.lock().unwrap().child.as_ref() {
+                Some(child) => child.pid,
+                None => return,
+            };
+
+            let mut status = 0;
+            let restart = loop {
+                if self.stopped.load(Ordering::Acquire) {
+                    // child has been killed by `stop`
+                    unsafe { libc::waitpid(pid, &mut status, 0) };
+                    return;
+                }
+                if unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) } != 0 {
+                    break false;
+                }
+                if self.restart.swap(false, Ordering::AcqRel) {
+                    unsafe {
+                        libc::kill(pid, libc::SIGKILL);
+                        libc::waitpid(pid, &mut status, 0);
+                    }
+                    break true;
+                }
+                thread::sleep(SUPERVISE_INTERVAL);
+            };
+            self.alive.store(false, Ordering::Release);
+
+            if let Some(child) = self.link.lock().unwrap().child.take() {
+                unsafe { libc::close(child.requests) };
+            }
+            if !restart {
+                thread::sleep(RESTART_DELAY);
+            }
+
+            loop {
+                // sandbox is stopped under lock, so no child is spawned once dropped
+                let mut link = self.link.lock().unwrap();
+                if self.stopped.load(Ordering::Acquire) {
+                    return;
+                }
+                match self.spawn(&mut link, None) {
+                    Ok(_) => break,
+                    Err(err) => log::error!("cannot restart sandboxed DSP {}: {}", self.name, err),
+                }
+                drop(link);
+                thread::sleep(RESTART_DELAY);
+            }
+        }
+    }
+
+    /// Forward parameters to the child, until sandbox is dropped.
+    fn bridge(&self, mut params: bus::BusReader<Param>) {
+        while !self.stopped.load(Ordering::Acquire) {
+            while let Ok((index, value)) = params.try_recv() {
+                let mut link = self.link.lock().unwrap();
+                if let Some(child) = link.child.as_ref() {
+                    write_request(child.requests, &Request::SetValue(index, value.clone()));
+                }
+                link.values.insert(index, value);
+            }
+            thread::sleep(PARAMS_INTERVAL);
+        }
+    }
+
+    /// Kill child without restarting it.
+    fn stop(&self) {
+        let link = self.link.lock().unwrap();
+        self.stopped.store(true, Ordering::Release);
+        if let Some(child) = link.child.as_ref() {
+            unsafe { libc::kill(child.pid, libc::SIGKILL) };
+        }
+    }
+}
+
+
+impl<PS: ProcessScope> Sandbox<PS> {
+    /// Create sandbox of the DSP registered as `name`, starting child process and waiting for
+    /// its description.
+    pub fn new(name: &str) -> io::Result<Self> {
+        if constructor(name).is_none() {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                                      format!("no sandboxed DSP registered as {}", name)));
+        }
+
+        let mapping = Arc::new(Mapping::new(MAX_CHANNELS * MAX_SAMPLES)?);
+        let swap = Swap::new();
+        let supervisor = Arc::new(Supervisor {
+            name: name.to_string(),
+            alive: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            restart: AtomicBool::new(false),
+            rate: AtomicI32::new(DEFAULT_RATE),
+            size: AtomicUsize::new(mapping.shared().size),
+            link: Mutex::new(Link { child: None, values: BTreeMap::new(), mapping: mapping.clone() }),
+            loader: swap.loader(),
+        });
+
+        let mut fds = [0 as RawFd; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let spawned = supervisor.spawn(&mut supervisor.link.lock().unwrap(), Some(fds[1]));
+        unsafe { libc::close(fds[1]) };
+        if let Err(err) = spawned {
+            unsafe { libc::close(fds[0]) };
+            return Err(err);
+        }
+
+        let (params, receiver) = bus::Bus::<Param>::channel(PARAMS_CAPACITY);
+        let s = supervisor.clone();
+        thread::spawn(move || s.supervise());
+        let s = supervisor.clone();
+        thread::spawn(move || s.bridge(receiver));
+
+        let response = match poll(fds[0], DESCRIBE_TIMEOUT) {
+            true => read_response(fds[0]),
+            false => None,
+        };
+        unsafe { libc::close(fds[0]) };
+        let description = match response {
+            Some(Response::Describe(description)) => description,
+            _ => {
+                supervisor.stop();
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("sandboxed DSP {} has not been described", name)));
+            }
+        };
+
+        let n_channels = (description.n_channels as usize).clamp(1, MAX_CHANNELS);
+        let generation = mapping.shared().generation.load(Ordering::Acquire);
+        Ok(Self {
+            supervisor, mapping, swap, generation, description, params,
+            block: vec![0.0; n_channels * MAX_SAMPLES],
+            n_channels,
+            max_samples: MAX_SAMPLES,
+            rate: DEFAULT_RATE,
+            pending: None,
+            late: false,
+            delay: MAX_SAMPLES,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Return true if child process is running.
+    pub fn is_alive(&self) -> bool {
+        self.supervisor.alive.load(Ordering::Acquire)
+    }
+
+    /// Return DSP's description provided by the child.
+    pub fn description(&self) -> &Description {
+        &self.description
+    }
+
+    /// Copy input to output, as when DSP is bypassed.
+    fn bypass(&self, input: Option<&dyn BufferView<Sample=f32>>,
+              output: Option<&mut dyn BufferView<Sample=f32>>) -> usize
+    {
+        match (input, output) {
+            (Some(input), Some(output)) => {
+                let mut n = 0;
+                for channel in 0..input.n_channels().min(output.n_channels()) {
+                    if let (Some(src), Some(dst)) = (input.channel(channel), output.channel_mut(channel)) {
+                        for (dst, src) in dst.zip(src) {
+                            *dst = *src;
+                            n += 1;
+                        }
+                    }
+                }
+                n
+            },
+            _ => 0,
+        }
+    }
+}
+
+impl<PS: ProcessScope> Drop for Sandbox<PS> {
+    fn drop(&mut self) {
+        self.supervisor.stop();
+    }
+}
+
+
+impl<PS: ProcessScope> Object for Sandbox<PS> {
+    fn object_meta(&self) -> ObjectMeta {
+        ObjectMeta::new(self.description.name.clone(), Some(self.description.metadatas.clone()))
+    }
+
+    fn get_value(&self, index: ObjectIndex) -> Option<Value> {
+        self.description.fields.iter().find(|f| f.index == index)?.value.clone()
+    }
+
+    fn set_value(&mut self, index: ObjectIndex, value: Value) -> Result<Value, ()> {
+        let field = self.description.fields.iter_mut().find(|f| f.index == index).ok_or(())?;
+        let value = match value.get_type() == field.value_type {
+            true => value,
+            false => value.as_f64().and_then(|v| Value::from_f64(field.value_type, v)).ok_or(())?,
+        };
+        self.params.try_send((index, value.clone())).map_err(|_| ())?;
+        field.value = Some(value.clone());
+        Ok(value)
+    }
+
+    fn map_object(&self, mapper: &mut dyn ObjectMapper) {
+        for field in self.description.fields.iter() {
+            mapper.declare(field.info());
+        }
+    }
+}
+
+
+impl<PS: ProcessScope> DSP for Sandbox<PS> {
+    type Sample = f32;
+    type Scope = PS;
+
+    fn prepare(&mut self, rate: SampleRate) {
+        if rate != self.rate {
+            self.rate = rate;
+            self.supervisor.rate.store(rate, Ordering::Release);
+            self.supervisor.restart.store(true, Ordering::Release);
+        }
+    }
+
+    fn set_max_samples(&mut self, max_samples: NSamples) {
+        self.max_samples = max_samples;
+        self.delay = max_samples;
+        self.block.resize(self.n_channels * max_samples, 0.0);
+
+        // restart child with larger rings
+        let size = self.n_channels * max_samples;
+        if self.supervisor.size.fetch_max(size, Ordering::AcqRel) < size {
+            self.supervisor.restart.store(true, Ordering::Release);
+        }
+    }
+
+    fn process_audio(&mut self, scope: &Self::Scope, input: Option<&dyn BufferView<Sample=Self::Sample>>,
+                     output: Option<&mut dyn BufferView<Sample=Self::Sample>>) -> usize
+    {
+        // shared memory reallocated by the supervisor, old one is released when swapped
+        if self.swap.swap(&mut self.mapping, |_| true) {
+            self.generation = self.mapping.shared().generation.load(Ordering::Acquire);
+            self.pending = None;
+            self.late = false;
+        }
+
+        let shared = self.mapping.shared();
+        let generation = shared.generation.load(Ordering::Acquire);
+        if generation != self.generation {
+            // child restarted: only the consumer moves the output ring's tail
+            shared.output().clear();
+            self.generation = generation;
+            self.pending = None;
+            self.late = false;
+        }
+
+        if !self.is_alive() {
+            return self.bypass(input, output);
+        }
+
+        let previous = match self.pending {
+            Some((request, len)) if shared.response.load(Ordering::Acquire) == request => {
+                self.pending = None;
+                Some(len)
+            },
+            // wait for child to catch up
+            Some(_) => {
+                self.late = true;
+                return self.bypass(input, output);
+            },
+            None => None,
+        };
+
+        let n_channels = self.n_channels;
+        let n_samples = scope.n_samples().min(self.max_samples).min(shared.size / n_channels);
+        let len = n_samples * n_channels;
+
+        // output previous block, discarding it when late
+        let n = match (previous, self.late, output) {
+            (Some(prev_len), false, Some(output)) => {
+                let popped = shared.output().pop(&mut self.block[..prev_len]);
+                for sample in self.block[popped..len.max(prev_len)].iter_mut() {
+                    *sample = 0.0;
+                }
+                for channel in 0..n_channels.min(output.n_channels() as usize) {
+                    if let Some(samples) = output.channel_mut(channel as NChannels) {
+                        for (i, sample) in samples.take(n_samples).enumerate() {
+                            *sample = self.block[i * n_channels + channel];
+                        }
+                    }
+                }
+                len
+            },
+            (Some(prev_len), false, None) => {
+                shared.output().pop(&mut self.block[..prev_len]);
+                0
+            },
+            (_, _, output) => {
+                shared.output().clear();
+                self.late = false;
+                self.bypass(input, output)
+            },
+        };
+
+        // send current block
+        unsafe { *shared.scope.get() = SandboxScope {
+            n_samples,
+            last_frame_time: scope.last_frame_time(),
+            transport: scope.transport(),
+        }};
+
+        for sample in self.block[..len].iter_mut() {
+            *sample = 0.0;
+        }
+        if let Some(input) = input {
+            for channel in 0..n_channels.min(input.n_channels() as usize) {
+                if let Some(samples) = input.channel(channel as NChannels) {
+                    for (i, sample) in samples.take(n_samples).enumerate() {
+                        self.block[i * n_channels + channel] = *sample;
+                    }
+                }
+            }
+        }
+        shared.input().push(&self.block[..len]);
+
+        let request = shared.request.fetch_add(1, Ordering::AcqRel).wrapping_add(1);
+        futex_wake(&shared.request);
+        self.pending = Some((request, len));
+        self.delay = n_samples;
+        n
+    }
+
+    fn n_channels(&self) -> NChannels {
+        self.description.n_channels
+    }
+
+    fn is_sink(&self) -> bool {
+        self.description.is_sink
+    }
+
+    fn is_source(&self) -> bool {
+        self.description.is_source
+    }
+
+    /// Child's output is delayed by one block
+    fn latency(&self) -> NSamples {
+        self.description.latency + self.delay
+    }
+
+    fn wet(&self) -> f32 {
+        self.description.wet
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate as libfoxlive;
+    use libfoxlive_derive::object;
+    use std::time::Instant;
+    use super::super::block::BlockScope;
+
+    /// Sample making the test DSP crash
+    const CRASH: f32 = 666.0;
+    const N_SAMPLES: NSamples = 256;
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Multiply input by gain, sleeping `delay` milliseconds per block
+    #[object("gain")]
+    struct Gain {
+        #[field("gain", F32(2.0))]
+        gain: f32,
+        #[field("delay", F32(0.0))]
+        delay: f32,
+    }
+
+    impl DSP for Gain {
+        type Sample = f32;
+        type Scope = SandboxScope;
+
+        fn process_audio(&mut self, _scope: &Self::Scope, input: Option<&dyn BufferView<Sample=f32>>,
+                         output: Option<&mut dyn BufferView<Sample=f32>>) -> usize
+        {
+            let (input, output) = (input.unwrap().as_slice(), output.unwrap().as_slice_mut());
+            if input.contains(&CRASH) {
+                process::abort();
+            }
+            thread::sleep(Duration::from_secs_f32(self.delay / 1000.0));
+            for (dst, src) in output.iter_mut().zip(input) {
+                *dst = *src * self.gain;
+            }
+            output.len()
+        }
+
+        fn n_channels(&self) -> NChannels { 1 }
+    }
+
+    fn setup() {
+        static SETUP: Once = Once::new();
+        SETUP.call_once(|| {
+            register("gain", || Box::new(Gain { gain: 2.0, delay: 0.0 }));
+            set_helper(|| {
+                let mut command = Command::new(env::current_exe().unwrap());
+                command.args(["dsp::sandbox::tests::helper", "--exact"])
+                       .stdout(Stdio::null()).stderr(Stdio::null());
+                command
+            });
+        });
+    }
+
+    /// Process blocks of `input` until output is `expected`, returning false on timeout.
+    fn wait(sandbox: &mut Sandbox<BlockScope>, input: f32, expected: f32) -> bool {
+        let scope = BlockScope::new(N_SAMPLES, 0, None);
+        let input: Buffer<f32,Vec<f32>> = (true, 1, vec![input; N_SAMPLES]).into();
+        let mut output: Buffer<f32,Vec<f32>> = (true, 1, vec![0.0; N_SAMPLES]).into();
+        let start = Instant::now();
+        while start.elapsed() < TIMEOUT {
+            sandbox.process_audio(&scope, Some(&input), Some(&mut output));
+            if output.as_slice().iter().all(|s| *s == expected) {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        false
+    }
+
+    /// Run sandboxed DSP when executed as helper by other tests
+    #[test]
+    fn helper() {
+        setup();
+        run_helper();
+    }
+
+    /// Test: crashed child is bypassed, then restarted with parameters' values
+    #[test]
+    fn crash() {
+        setup();
+        let mut sandbox = Sandbox::<BlockScope>::new("gain").unwrap();
+        assert!(wait(&mut sandbox, 1.0, 2.0));
+        sandbox.set_value(0, Value::F32(3.0)).unwrap();
+        assert!(wait(&mut sandbox, 1.0, 3.0));
+
+        // blocks are not sent while child is late, keep sending until it crashes
+        let start = Instant::now();
+        while sandbox.is_alive() && start.elapsed() < TIMEOUT {
+            assert!(wait(&mut sandbox, CRASH, CRASH));
+        }
+        assert!(!sandbox.is_alive());
+        assert!(wait(&mut sandbox, 1.0, 1.0));
+
+        assert!(wait(&mut sandbox, 1.0, 3.0));
+        assert!(sandbox.is_alive());
+    }
+
+    /// Test: late child is bypassed until it catches up, and restarted with larger blocks
+    #[test]
+    fn bypass() {
+        setup();
+        let mut sandbox = Sandbox::<BlockScope>::new("gain").unwrap();
+        assert!(wait(&mut sandbox, 1.0, 2.0));
+        sandbox.set_value(1, Value::F32(100.0)).unwrap();
+        assert!(wait(&mut sandbox, 1.0, 1.0));
+        assert!(sandbox.is_alive());
+        sandbox.set_value(1, Value::F32(0.0)).unwrap();
+        assert!(wait(&mut sandbox, 1.0, 2.0));
+
+        let max_samples = MAX_CHANNELS * MAX_SAMPLES * 2;
+        sandbox.set_max_samples(max_samples);
+        let start = Instant::now();
+        while sandbox.mapping.shared().size < max_samples && start.elapsed() < TIMEOUT {
+            wait(&mut sandbox, 1.0, 1.0);
+        }
+        assert_eq!(sandbox.mapping.shared().size, max_samples);
+        assert!(wait(&mut sandbox, 1.0, 2.0));
+    }
+
+    /// Test: metadata and values are described by the child, which is restarted on prepare
+    #[test]
+    fn describe() {
+        setup();
+        let mut sandbox = Sandbox::<BlockScope>::new("gain").unwrap();
+        assert_eq!(sandbox.object_meta().name, Gain { gain: 2.0, delay: 0.0 }.object_meta().name);
+        assert_eq!(sandbox.n_channels(), 1);
+        assert_eq!(sandbox.latency(), MAX_SAMPLES);
+        assert!(matches!(sandbox.get_value(0), Some(Value::F32(v)) if v == 2.0));
+
+        let mut fields: Vec<FieldInfo> = Vec::new();
+        sandbox.map_object(&mut fields);
+        assert_eq!(fields.iter().map(|f| f.index).collect::<Vec<_>>(), vec![0, 1]);
+
+        // values are converted to fields' type
+        assert!(matches!(sandbox.set_value(0, Value::F64(3.0)), Ok(Value::F32(v)) if v == 3.0));
+        assert!(wait(&mut sandbox, 1.0, 3.0));
+        assert_eq!(sandbox.latency(), N_SAMPLES);
+
+        let generation = sandbox.generation;
+        sandbox.prepare(DEFAULT_RATE * 2);
+        let start = Instant::now();
+        while sandbox.generation == generation && start.elapsed() < TIMEOUT {
+            wait(&mut sandbox, 1.0, 1.0);
+        }
+        assert_ne!(sandbox.generation, generation);
+        assert!(wait(&mut sandbox, 1.0, 3.0));
+    }
+
+    #[test]
+    fn unknown() {
+        setup();
+        assert!(Sandbox::<BlockScope>::new("unknown").is_err());
+    }
+}
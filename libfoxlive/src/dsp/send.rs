@@ -0,0 +1,129 @@
+//! Aux sends and return buses.
+//!
+//! A send is a node connected to its source either before or after the source's fader, whose
+//! output feeds a named return bus. Shared effects (e.g. reverb) are then added as children of
+//! the return bus. Sends and buses are created through `Graph::add_send`.
+use std::marker::PhantomData;
+
+use crate as libfoxlive;
+use libfoxlive_derive::object;
+use crate::data::*;
+use crate::rpc::*;
+
+use super::dsp::DSP;
+use super::graph::ProcessScope;
+
+
+/// Send to a return bus, applying a level to its input.
+#[object("send")]
+pub struct AuxSend<S,PS>
+    where S: 'static+Sample, PS: ProcessScope
+{
+    /// Send level
+    #[field("level", F32(1.0), range(0.0,2.0,0.01))]
+    pub level: f32,
+    n_channels: NChannels,
+    phantom: PhantomData<(S,PS)>,
+}
+
+/// Return bus, summing its sends.
+#[object("return")]
+pub struct AuxReturn<S,PS>
+    where S: 'static+Sample, PS: ProcessScope
+{
+    /// Return level
+    #[field("level", F32(1.0), range(0.0,2.0,0.01))]
+    pub level: f32,
+    /// Bus name
+    pub name: String,
+    n_channels: NChannels,
+    phantom: PhantomData<(S,PS)>,
+}
+
+
+/// Copy input to output applying gain, returning the number of written samples. Without
+/// input, gain is applied to output in place.
+fn apply_level<S: Sample>(level: f32, n_channels: NChannels, input: Option<&dyn BufferView<Sample=S>>,
+                          output: Option<&mut dyn BufferView<Sample=S>>) -> usize
+{
+    let level: S::Float = level.to_sample();
+    let (input, output) = match (input, output) {
+        (Some(input), Some(output)) => (input, output),
+        (None, Some(output)) => {
+            output.map_inplace(&|_, sample| sample.mul_amp(level));
+            return output.len();
+        },
+        _ => return 0,
+    };
+
+    let mut n = 0;
+    for channel in 0..n_channels {
+        if let (Some(src), Some(dst)) = (input.channel(channel), output.channel_mut(channel)) {
+            for (dst, src) in dst.zip(src) {
+                *dst = src.mul_amp(level);
+                n += 1;
+            }
+        }
+    }
+    n
+}
+
+
+impl<S,PS> AuxSend<S,PS>
+    where S: 'static+Sample, PS: ProcessScope
+{
+    pub fn new(n_channels: NChannels, level: f32) -> Self {
+        Self { level, n_channels, phantom: PhantomData }
+    }
+}
+
+impl<S,PS> DSP for AuxSend<S,PS>
+    where S: 'static+Sample, PS: ProcessScope
+{
+    type Sample = S;
+    type Scope = PS;
+
+    fn process_audio(&mut self, _scope: &Self::Scope, input: Option<&dyn BufferView<Sample=Self::Sample>>,
+                     output: Option<&mut dyn BufferView<Sample=Self::Sample>>) -> usize
+    {
+        apply_level(self.level, self.n_channels, input, output)
+    }
+
+    fn n_channels(&self) -> NChannels {
+        self.n_channels
+    }
+
+    fn in_place(&self) -> bool {
+        true
+    }
+}
+
+
+impl<S,PS> AuxReturn<S,PS>
+    where S: 'static+Sample, PS: ProcessScope
+{
+    pub fn new<N: Into<String>>(name: N, n_channels: NChannels) -> Self {
+        Self { level: 1.0, name: name.into(), n_channels, phantom: PhantomData }
+    }
+}
+
+impl<S,PS> DSP for AuxReturn<S,PS>
+    where S: 'static+Sample, PS: ProcessScope
+{
+    type Sample = S;
+    type Scope = PS;
+
+    fn process_audio(&mut self, _scope: &Self::Scope, input: Option<&dyn BufferView<Sample=Self::Sample>>,
+                     output: Option<&mut dyn BufferView<Sample=Self::Sample>>) -> usize
+    {
+        apply_level(self.level, self.n_channels, input, output)
+    }
+
+    fn n_channels(&self) -> NChannels {
+        self.n_channels
+    }
+
+    fn in_place(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,793 @@
+//! SoundFont (SF2) instrument.
+//!
+//! SoundFont files are parsed with `format::sf2` in background, then presets' and
+//! instruments' zones are resolved into playable regions: instrument generators override
+//! defaults, and preset generators are added to them. A note plays all regions of the
+//! selected preset matching its key and velocity, up to `MAX_LAYERS`.
+//!
+//! The loaded font is swapped in by the audio thread, the previous one being released once no
+//! voice plays it anymore. Files set through the `path` field are loaded by a `PathLoader`.
+//!
+//! Regions support sample offsets, loop modes, tuning, attenuation, pan and the volume
+//! envelope (delay and hold stages are ignored). Modulators are evaluated at note-on when
+//! their sources are the note's velocity or key, and their destinations one of these
+//! generators; the default velocity to attenuation modulator applies unless overridden.
+//! MIDI controllers, pressure and pitch wheel sources are ignored (pitch bend being applied
+//! by the voice).
+//!
+//! Bank and program are selected with the object's fields, or by MIDI bank select (CC 0) and
+//! program change messages. For General MIDI files, one node is used per MIDI channel, with
+//! bank 128 for percussions.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let mut player = SoundFontPlayer::<Scope>::new(2);
+//! player.load("gm.sf2".to_string());
+//! player.set_channel(10);
+//! player.bank = 128;
+//! graph.add_child(midi_input, player.into());
+//! ```
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use crate as libfoxlive;
+use libfoxlive_derive::object;
+use crate::data::*;
+use crate::format::Error;
+use crate::format::sf2::{self,*};
+use crate::rpc::*;
+
+use super::dsp::{DSP,DEFAULT_RATE};
+use super::graph::ProcessScope;
+use super::modulation::{Adsr,Modulator};
+use super::swap::{PathLoader,Swap};
+use super::voice::*;
+use super::voice::Instrument;
+
+
+/// Maximum number of regions played by a note
+pub const MAX_LAYERS: usize = 4;
+/// Percussion bank
+pub const PERCUSSION_BANK: i16 = 128;
+
+/// Generators that are not allowed at preset level
+const INSTRUMENT_ONLY: [u16; 14] = [
+    GEN_START_OFFSET, GEN_END_OFFSET, GEN_LOOP_START_OFFSET, GEN_LOOP_END_OFFSET,
+    GEN_START_COARSE_OFFSET, GEN_END_COARSE_OFFSET, GEN_LOOP_START_COARSE_OFFSET,
+    GEN_LOOP_END_COARSE_OFFSET, GEN_KEYNUM, GEN_VELOCITY, GEN_SAMPLE_ID, GEN_SAMPLE_MODES,
+    GEN_EXCLUSIVE_CLASS, GEN_ROOT_KEY,
+];
+
+/// Generators that can be modulated
+const MODULATED: [u16; 8] = [
+    GEN_ATTENUATION, GEN_PAN, GEN_COARSE_TUNE, GEN_FINE_TUNE,
+    GEN_ATTACK_VOL_ENV, GEN_DECAY_VOL_ENV, GEN_SUSTAIN_VOL_ENV, GEN_RELEASE_VOL_ENV,
+];
+
+/// Default modulators: note-on velocity to attenuation, concave negative unipolar
+const DEFAULT_MODULATORS: [sf2::Modulator; 1] = [
+    sf2::Modulator { source: 0x0502, destination: GEN_ATTENUATION, amount: 960, amount_source: 0, transform: 0 },
+];
+
+
+/// Generators' values and ranges of a zone
+#[derive(Copy,Clone)]
+struct Generators {
+    values: [i32; N_GENERATORS],
+    keys: (u8, u8),
+    velocities: (u8, u8),
+}
+
+/// Sample region played for a range of keys and velocities
+#[derive(Clone,Debug)]
+struct Region {
+    keys: (u8, u8),
+    velocities: (u8, u8),
+    /// Positions in font's samples
+    start: usize,
+    end: usize,
+    loop_start: usize,
+    loop_end: usize,
+    /// Sample mode, `0` if loop is invalid
+    mode: i32,
+    rate: f32,
+    root: u8,
+    /// Cents per key
+    scale: f32,
+    keynum: Option<u8>,
+    velocity: Option<u8>,
+    /// Values of modulated generators
+    generators: [i32; MODULATED.len()],
+    /// Sample's pitch correction, in cents
+    correction: i32,
+    modulators: Vec<sf2::Modulator>,
+}
+
+/// Region's parameters for a note
+struct Params {
+    /// Tuning in cents
+    tune: f32,
+    gain: f32,
+    /// Pan in `-1.0..=1.0`
+    pan: f32,
+    /// Volume envelope, in seconds
+    attack: f32,
+    decay: f32,
+    sustain: f32,
+    release: f32,
+}
+
+/// Preset and its regions
+struct PresetRegions {
+    name: String,
+    bank: u16,
+    program: u16,
+    regions: Vec<Region>,
+}
+
+/// SoundFont resolved into playable regions.
+#[derive(Default)]
+pub struct Font {
+    pub name: String,
+    samples: Vec<f32>,
+    presets: Vec<PresetRegions>,
+}
+
+/// Region played by a voice
+struct Layer {
+    region: usize,
+    /// Position in font's samples
+    position: f64,
+    /// Tuning in cents
+    tune: f32,
+    gain: f32,
+    /// Left and right gains
+    pan: [f32; 2],
+    envelope: Adsr,
+    active: bool,
+}
+
+/// SoundFont player's voice
+pub struct SoundFontVoice {
+    /// Current font and preset
+    font: Arc<Font>,
+    preset: Option<usize>,
+    /// Font and preset being played
+    playing: Option<(Arc<Font>, usize)>,
+    layers: Vec<Layer>,
+    n_layers: usize,
+    rate: SampleRate,
+    note: u8,
+    velocity: f32,
+    released: bool,
+    bend: f32,
+    /// Master transposition in semitones and gain
+    transpose: f32,
+    gain: f32,
+}
+
+
+/// SoundFont instrument.
+#[object("soundfont")]
+pub struct SoundFontPlayer<PS>
+    where PS: ProcessScope
+{
+    /// SoundFont file path
+    #[field("path", String, get(path), set(load))]
+    path: PathLoader<()>,
+    #[field("bank", I16(0), range(0,128,1))]
+    pub bank: i16,
+    #[field("program", U8(0), range(0,127,1))]
+    pub program: u8,
+    /// MIDI channel from 1 to 16, all channels if `0`
+    #[field("channel", U8(0), range(0,16,1), set(set_channel))]
+    channel: u8,
+    #[field("gain", F32(1.0), range(0.0,2.0,0.01))]
+    pub gain: f32,
+    /// Transposition in semitones
+    #[field("transpose", I16(0), range(-24,24,1))]
+    pub transpose: i16,
+    /// Fine tuning in cents
+    #[field("tune", F32(0.0), range(-100.0,100.0,1.0))]
+    pub tune: f32,
+    #[field("polyphony", U8(32), range(1,64,1), set(set_polyphony))]
+    polyphony: u8,
+    instrument: Instrument<SoundFontVoice>,
+    font: Arc<Font>,
+    swap: Swap<Arc<Font>>,
+    n_channels: NChannels,
+    phantom: PhantomData<PS>,
+}
+
+
+/// Timecents to seconds
+fn timecents(value: i32) -> f32 {
+    (value as f32 / 1200.0).exp2()
+}
+
+/// Centibels of attenuation to gain
+fn centibels(value: i32) -> f32 {
+    10.0f32.powf(-value.max(0).min(1440) as f32 / 200.0)
+}
+
+fn intersect(a: (u8, u8), b: (u8, u8)) -> (u8, u8) {
+    (a.0.max(b.0), a.1.min(b.1))
+}
+
+/// Concave curve, from `0.0` to `1.0`
+fn concave(x: f32) -> f32 {
+    (-(5.0 / 12.0) * (1.0 - x).log10()).clamp(0.0, 1.0)
+}
+
+/// Index of a modulated generator
+fn modulated(oper: u16) -> Option<usize> {
+    MODULATED.iter().position(|m| *m == oper)
+}
+
+/// Return modulators of a zone added to `base` ones, replacing the identical ones.
+fn merge(base: &[sf2::Modulator], zone: &Zone) -> Vec<sf2::Modulator> {
+    let mut modulators = base.to_vec();
+    for modulator in zone.modulators.iter() {
+        let identical = |m: &sf2::Modulator| m.source == modulator.source && m.destination == modulator.destination
+                                        && m.amount_source == modulator.amount_source;
+        match modulators.iter_mut().find(|m| identical(m)) {
+            Some(m) => *m = *modulator,
+            None => modulators.push(*modulator),
+        }
+    }
+    modulators
+}
+
+/// Value of a modulator's source for the provided note, `None` if source is not supported.
+fn source(source: u16, key: u8, velocity: u8) -> Option<f32> {
+    // general controllers only
+    let value = match (source & 0x80 != 0, source & 0x7f) {
+        (false, 0) => return Some(1.0),
+        (false, 2) => velocity as f32 / 127.0,
+        (false, 3) => key as f32 / 127.0,
+        _ => return None,
+    };
+    let value = if source & 0x100 != 0 { 1.0 - value } else { value };
+    let curve = |x: f32| match source >> 10 {
+        0 => Some(x),
+        1 => Some(concave(x)),
+        2 => Some(1.0 - concave(1.0 - x)),
+        3 => Some(if x >= 0.5 { 1.0 } else { 0.0 }),
+        _ => None,
+    };
+    match source & 0x200 != 0 {
+        false => curve(value),
+        true if value >= 0.5 => curve(2.0 * value - 1.0),
+        true => curve(1.0 - 2.0 * value).map(|v| -v),
+    }
+}
+
+/// Modulator's value for the provided note, `None` if a source is not supported.
+fn modulate(modulator: &sf2::Modulator, key: u8, velocity: u8) -> Option<f32> {
+    let value = modulator.amount as f32 * source(modulator.source, key, velocity)?
+                * source(modulator.amount_source, key, velocity)?;
+    match modulator.transform {
+        2 => Some(value.abs()),
+        _ => Some(value),
+    }
+}
+
+
+impl Generators {
+    /// Instrument level defaults
+    fn instrument() -> Self {
+        let mut values = [0; N_GENERATORS];
+        for oper in [21, 23, 25, 26, 27, 28, 30, GEN_DELAY_VOL_ENV, GEN_ATTACK_VOL_ENV, GEN_HOLD_VOL_ENV,
+                     GEN_DECAY_VOL_ENV, GEN_RELEASE_VOL_ENV].iter() {
+            values[*oper as usize] = -12000;
+        }
+        values[GEN_FILTER_CUTOFF as usize] = 13500;
+        values[GEN_KEYNUM as usize] = -1;
+        values[GEN_VELOCITY as usize] = -1;
+        values[GEN_SCALE_TUNING as usize] = 100;
+        values[GEN_ROOT_KEY as usize] = -1;
+        Self { values, keys: (0, 127), velocities: (0, 127) }
+    }
+
+    /// Preset level defaults, being added to instrument's values
+    fn preset() -> Self {
+        Self { values: [0; N_GENERATORS], keys: (0, 127), velocities: (0, 127) }
+    }
+
+    fn get(&self, oper: u16) -> i32 {
+        self.values[oper as usize]
+    }
+
+    /// Set zone's generators, overriding current values.
+    fn set(&mut self, zone: &Zone) {
+        for generator in zone.generators.iter() {
+            match generator.oper {
+                GEN_KEY_RANGE => self.keys = generator.range(),
+                GEN_VEL_RANGE => self.velocities = generator.range(),
+                oper if (oper as usize) < N_GENERATORS => self.values[oper as usize] = generator.value() as i32,
+                _ => {},
+            }
+        }
+    }
+
+    /// Add preset's generators, intersecting ranges.
+    fn add(&mut self, preset: &Generators) {
+        for (oper, value) in preset.values.iter().enumerate() {
+            if !INSTRUMENT_ONLY.contains(&(oper as u16)) {
+                self.values[oper] += value;
+            }
+        }
+        self.keys = intersect(self.keys, preset.keys);
+        self.velocities = intersect(self.velocities, preset.velocities);
+    }
+}
+
+
+impl Region {
+    /// Create region of a sample, `None` if it can't be played.
+    fn new(gens: &Generators, modulators: Vec<sf2::Modulator>, sample: &SampleHeader, n_samples: usize)
+        -> Option<Self>
+    {
+        // ROM samples are not provided
+        if gens.keys.0 > gens.keys.1 || gens.velocities.0 > gens.velocities.1 || sample.kind & 0x8000 != 0 {
+            return None;
+        }
+
+        let offset = |base: u32, fine: u16, coarse: u16|
+            (base as i64 + gens.get(fine) as i64 + gens.get(coarse) as i64 * 32768).max(0).min(n_samples as i64) as usize;
+        let start = offset(sample.start, GEN_START_OFFSET, GEN_START_COARSE_OFFSET);
+        let end = offset(sample.end, GEN_END_OFFSET, GEN_END_COARSE_OFFSET);
+        let loop_start = offset(sample.loop_start, GEN_LOOP_START_OFFSET, GEN_LOOP_START_COARSE_OFFSET).max(start);
+        let loop_end = offset(sample.loop_end, GEN_LOOP_END_OFFSET, GEN_LOOP_END_COARSE_OFFSET).min(end);
+        if start >= end || sample.rate == 0 {
+            return None;
+        }
+
+        let optional = |oper: u16| match gens.get(oper) {
+            value if (0..128).contains(&value) => Some(value as u8),
+            _ => None,
+        };
+        let mode = match gens.get(GEN_SAMPLE_MODES) & 3 {
+            mode @ 1 | mode @ 3 if loop_start < loop_end => mode,
+            _ => 0,
+        };
+
+        Some(Self {
+            keys: gens.keys, velocities: gens.velocities,
+            start, end, loop_start, loop_end, mode,
+            rate: sample.rate as f32,
+            root: optional(GEN_ROOT_KEY).unwrap_or(if sample.pitch < 128 { sample.pitch } else { 60 }),
+            scale: gens.get(GEN_SCALE_TUNING) as f32,
+            keynum: optional(GEN_KEYNUM),
+            velocity: optional(GEN_VELOCITY),
+            generators: MODULATED.map(|oper| gens.get(oper)),
+            correction: sample.correction as i32,
+            modulators,
+        })
+    }
+
+    /// Evaluate modulators for the provided key and velocity, returning parameters.
+    fn params(&self, key: u8, velocity: u8) -> Params {
+        let mut values = self.generators;
+        for modulator in self.modulators.iter() {
+            if let (Some(index), Some(value)) = (modulated(modulator.destination), modulate(modulator, key, velocity)) {
+                values[index] += value.round() as i32;
+            }
+        }
+
+        let get = |oper: u16| modulated(oper).map(|index| values[index]).unwrap_or(0);
+        Params {
+            tune: (get(GEN_COARSE_TUNE) * 100 + get(GEN_FINE_TUNE) + self.correction) as f32,
+            gain: centibels(get(GEN_ATTENUATION)),
+            pan: get(GEN_PAN).clamp(-500, 500) as f32 / 500.0,
+            attack: timecents(get(GEN_ATTACK_VOL_ENV)),
+            decay: timecents(get(GEN_DECAY_VOL_ENV)),
+            sustain: centibels(get(GEN_SUSTAIN_VOL_ENV)),
+            release: timecents(get(GEN_RELEASE_VOL_ENV)),
+        }
+    }
+
+    fn contains(&self, note: u8, velocity: u8) -> bool {
+        self.keys.0 <= note && note <= self.keys.1 &&
+            self.velocities.0 <= velocity && velocity <= self.velocities.1
+    }
+}
+
+
+impl Font {
+    /// Resolve SoundFont's presets into regions.
+    pub fn new(font: &SoundFont) -> Self {
+        let samples: Vec<f32> = font.data.iter().map(|s| *s as f32 / 32768.0).collect();
+        let mut presets = Vec::with_capacity(font.presets.len());
+        for preset in font.presets.iter() {
+            let mut preset_global = Generators::preset();
+            let mut preset_global_mods = Vec::new();
+            if let Some(zone) = preset.global.as_ref() {
+                preset_global.set(zone);
+                preset_global_mods = merge(&[], zone);
+            }
+
+            let mut regions = Vec::new();
+            for preset_zone in preset.zones.iter() {
+                let mut preset_gens = preset_global;
+                preset_gens.set(preset_zone);
+                let preset_mods = merge(&preset_global_mods, preset_zone);
+
+                let instrument = match preset_zone.instrument().and_then(|i| font.instruments.get(i)) {
+                    Some(instrument) => instrument,
+                    None => continue,
+                };
+                let mut global = Generators::instrument();
+                let mut global_mods = DEFAULT_MODULATORS.to_vec();
+                if let Some(zone) = instrument.global.as_ref() {
+                    global.set(zone);
+                    global_mods = merge(&global_mods, zone);
+                }
+
+                for zone in instrument.zones.iter() {
+                    let mut gens = global;
+                    gens.set(zone);
+                    gens.add(&preset_gens);
+                    // preset's modulators add to instrument's ones
+                    let mut modulators = merge(&global_mods, zone);
+                    modulators.extend_from_slice(&preset_mods);
+                    if let Some(sample) = zone.sample().and_then(|s| font.samples.get(s)) {
+                        regions.extend(Region::new(&gens, modulators, sample, samples.len()));
+                    }
+                }
+            }
+            presets.push(PresetRegions { name: preset.name.clone(), bank: preset.bank, program: preset.program,
+                                         regions });
+        }
+        Self { name: font.name.clone(), samples, presets }
+    }
+
+    /// Read and resolve SoundFont file. This must not be used from the audio thread.
+    pub fn load(path: &str) -> Result<Self, Error> {
+        SoundFont::open(path).map(|font| Self::new(&font))
+    }
+
+    /// Presets' bank, program and name
+    pub fn presets(&self) -> Vec<(u16, u16, String)> {
+        self.presets.iter().map(|p| (p.bank, p.program, p.name.clone())).collect()
+    }
+
+    /// Return preset index for the provided bank and program, falling back to the first
+    /// preset of the program (or of the bank for percussions) as General MIDI players do.
+    pub fn preset(&self, bank: u16, program: u16) -> Option<usize> {
+        let find = |bank: Option<u16>, program: Option<u16>|
+            self.presets.iter().position(|p| bank.map(|b| p.bank == b).unwrap_or(true) &&
+                                             program.map(|n| p.program == n).unwrap_or(true));
+        find(Some(bank), Some(program)).or_else(|| match bank as i16 == PERCUSSION_BANK {
+            true => find(Some(bank), None),
+            false => find(Some(0), Some(program)).or_else(|| find(None, Some(program))),
+        })
+    }
+}
+
+
+impl SoundFontVoice {
+    fn new(font: Arc<Font>) -> Self {
+        Self { font, preset: None, playing: None,
+               layers: (0..MAX_LAYERS).map(|_| Layer { region: 0, position: 0.0, tune: 0.0, gain: 0.0, pan: [1.0; 2],
+                                                        envelope: Adsr::new(0.0, 0.0, 1.0, 0.0),
+                                                        active: false })
+                                      .collect(),
+               n_layers: 0, rate: DEFAULT_RATE, note: 0, velocity: 0.0, released: false, bend: 0.0,
+               transpose: 0.0, gain: 1.0 }
+    }
+}
+
+impl Voice for SoundFontVoice {
+    fn prepare(&mut self, rate: SampleRate) {
+        self.rate = rate;
+        for layer in self.layers.iter_mut() {
+            layer.envelope.prepare(rate);
+        }
+    }
+
+    fn note_on(&mut self, note: u8, velocity: f32, legato: bool) {
+        self.note = note;
+        if legato && self.playing.is_some() {
+            return;
+        }
+
+        self.velocity = velocity;
+        self.released = false;
+        self.n_layers = 0;
+        self.playing = None;
+
+        let (font, preset) = match self.preset {
+            Some(preset) if preset < self.font.presets.len() => (self.font.clone(), preset),
+            _ => return,
+        };
+        let midi_velocity = (velocity * 127.0).round() as u8;
+        for (index, region) in font.presets[preset].regions.iter().enumerate() {
+            if self.n_layers == MAX_LAYERS {
+                break;
+            }
+            if !region.contains(note, midi_velocity) {
+                continue;
+            }
+
+            let params = region.params(region.keynum.unwrap_or(note), region.velocity.unwrap_or(midi_velocity));
+            let angle = (params.pan + 1.0) * std::f32::consts::FRAC_PI_4;
+            let layer = &mut self.layers[self.n_layers];
+            layer.region = index;
+            layer.position = region.start as f64;
+            layer.tune = params.tune;
+            layer.gain = params.gain;
+            layer.pan = [angle.cos() * std::f32::consts::SQRT_2, angle.sin() * std::f32::consts::SQRT_2];
+            layer.envelope.attack = params.attack;
+            layer.envelope.decay = params.decay;
+            layer.envelope.sustain = params.sustain;
+            layer.envelope.release = params.release;
+            layer.envelope.gate(true);
+            layer.active = true;
+            self.n_layers += 1;
+        }
+        if self.n_layers > 0 {
+            self.playing = Some((font, preset));
+        }
+    }
+
+    fn note_off(&mut self) {
+        self.released = true;
+        for layer in self.layers[..self.n_layers].iter_mut() {
+            layer.envelope.gate(false);
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.playing.is_some()
+    }
+
+    fn level(&self) -> f32 {
+        self.layers[..self.n_layers].iter().filter(|l| l.active)
+            .map(|l| l.envelope.level()).fold(0.0, f32::max) * self.velocity
+    }
+
+    fn set_param(&mut self, param: usize, value: f32) {
+        if param == PARAM_PITCH_BEND {
+            self.bend = value;
+        }
+    }
+
+    fn render(&mut self, output: &mut [f32], n_channels: usize) {
+        let (font, preset) = match self.playing.as_ref() {
+            Some((font, preset)) => (font, *preset),
+            None => return,
+        };
+        let regions = &font.presets[preset].regions;
+        let samples = &font.samples;
+
+        let mut active = false;
+        for layer in self.layers[..self.n_layers].iter_mut().filter(|l| l.active) {
+            let region = &regions[layer.region];
+            let key = region.keynum.unwrap_or(self.note) as f32;
+            let cents = (key - region.root as f32) * region.scale + layer.tune
+                        + (self.transpose + self.bend) * 100.0;
+            let increment = (cents / 1200.0).exp2() as f64 * region.rate as f64 / self.rate.max(1) as f64;
+            let looped = region.mode == 1 || (region.mode == 3 && !self.released);
+            let gain = layer.gain * self.gain;
+
+            for frame in output.chunks_mut(n_channels) {
+                let level = layer.envelope.next(1, None);
+                let pos = layer.position as usize;
+                if layer.envelope.is_idle() || pos >= region.end {
+                    layer.active = false;
+                    break;
+                }
+
+                // linear interpolation with next sample, wrapping at loop's end
+                let next = match pos + 1 {
+                    next if looped && next >= region.loop_end => region.loop_start,
+                    next => next,
+                };
+                let a = samples[pos];
+                let b = if next < region.end { samples[next] } else { 0.0 };
+                let x = (a + (b - a) * (layer.position - pos as f64) as f32) * level * gain;
+                match n_channels {
+                    1 => frame[0] += x,
+                    _ => for (channel, sample) in frame.iter_mut().enumerate() {
+                        *sample += x * layer.pan[channel % 2];
+                    },
+                }
+
+                layer.position += increment;
+                if looped {
+                    while layer.position >= region.loop_end as f64 {
+                        layer.position -= (region.loop_end - region.loop_start) as f64;
+                    }
+                }
+            }
+            active |= layer.active;
+        }
+
+        if !active {
+            self.playing = None;
+        }
+    }
+}
+
+
+impl<PS> SoundFontPlayer<PS>
+    where PS: ProcessScope
+{
+    pub fn new(n_channels: NChannels) -> Self {
+        // voices share the same font, so that it is retired at once
+        let font = Arc::new(Font::default());
+        let swap = Swap::new();
+        Self {
+            path: PathLoader::new(swap.loader(), |path, _| Font::load(path).map(Arc::new)),
+            bank: 0, program: 0, channel: 0, gain: 1.0, transpose: 0, tune: 0.0, polyphony: 32,
+            instrument: Instrument::new((0..MAX_VOICES).map(|_| SoundFontVoice::new(font.clone())).collect(), 32),
+            font,
+            swap,
+            n_channels: n_channels.max(1),
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn voices_mut(&mut self) -> &mut Voices<SoundFontVoice> {
+        &mut self.instrument.voices
+    }
+
+    /// Current font
+    pub fn font(&self) -> &Font {
+        &self.font
+    }
+
+    pub fn path(&self) -> String {
+        self.path.path()
+    }
+
+    /// Load SoundFont file in background. It will be used once loaded. It can be called from
+    /// the audio thread.
+    pub fn load(&mut self, path: String) -> Result<String, ()> {
+        match self.path.post(&path, ()) {
+            true => Ok(path),
+            false => Err(()),
+        }
+    }
+
+    /// Set font, which will be used at next block.
+    pub fn set_font(&mut self, font: Font) {
+        self.swap.set(Arc::new(font));
+    }
+
+    pub fn set_channel(&mut self, channel: u8) -> Result<u8, ()> {
+        if channel > 16 {
+            return Err(());
+        }
+        self.instrument.voices.channel = channel.checked_sub(1);
+        self.channel = channel;
+        Ok(channel)
+    }
+
+    pub fn set_polyphony(&mut self, polyphony: u8) -> Result<u8, ()> {
+        self.instrument.voices.set_polyphony(polyphony as usize);
+        self.polyphony = self.instrument.voices.polyphony() as u8;
+        Ok(self.polyphony)
+    }
+
+    /// Swap in loaded font, if any. The previous one is released once no voice plays it.
+    fn swap_font(&mut self) {
+        if self.swap.swap(&mut self.font, |font| Arc::strong_count(font) == 1) {
+            for voice in self.instrument.voices.voices_mut() {
+                voice.font = self.font.clone();
+            }
+        }
+    }
+}
+
+
+impl<PS> DSP for SoundFontPlayer<PS>
+    where PS: ProcessScope
+{
+    type Sample = f32;
+    type Scope = PS;
+
+    fn prepare(&mut self, rate: SampleRate) {
+        self.instrument.voices.prepare(rate);
+    }
+
+    /// Bank select and program changes are applied for the whole block.
+    fn process_events(&mut self, _scope: &Self::Scope, input: &EventBuffer, _output: &mut EventBuffer) {
+        self.instrument.events.clear();
+        self.instrument.events.merge(input);
+
+        for event in input.iter() {
+            match event.midi() {
+                Some(MidiMessage::ControlChange { channel, control: 0, value })
+                    if self.instrument.voices.channel.map(|c| c == *channel).unwrap_or(true) =>
+                        self.bank = *value as i16,
+                Some(MidiMessage::ProgramChange { channel, program })
+                    if self.instrument.voices.channel.map(|c| c == *channel).unwrap_or(true) =>
+                        self.program = *program,
+                _ => {},
+            }
+        }
+    }
+
+    fn process_audio(&mut self, scope: &Self::Scope, _input: Option<&dyn BufferView<Sample=Self::Sample>>,
+                     output: Option<&mut dyn BufferView<Sample=Self::Sample>>) -> usize
+    {
+        self.swap_font();
+
+        let output = match output {
+            Some(output) => output,
+            None => return 0,
+        };
+
+        for i in 0..self.instrument.events.len() {
+            if let EventData::Control(index, value) = self.instrument.events.as_slice()[i].data {
+                control(self, index, value);
+            }
+        }
+
+        let preset = self.font.preset(self.bank.max(0) as u16, self.program as u16);
+        let transpose = self.transpose as f32 + self.tune / 100.0;
+        for voice in self.instrument.voices.voices_mut() {
+            voice.preset = preset;
+            voice.transpose = transpose;
+            voice.gain = self.gain;
+        }
+
+        self.instrument.render(output, scope.n_samples())
+    }
+
+    fn n_channels(&self) -> NChannels {
+        self.n_channels
+    }
+
+    fn is_source(&self) -> bool {
+        true
+    }
+
+    fn has_event_input(&self) -> bool {
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::sf2::tests::build;
+
+    /// Test: regions resolution and looped playback
+    #[test]
+    fn render() {
+        let font = Font::new(&SoundFont::parse(&build()).unwrap());
+        assert_eq!(font.preset(0, 1), Some(0));
+        assert_eq!(font.preset(8, 1), Some(0));
+        assert_eq!(font.preset(0, 2), None);
+
+        let region = &font.presets[0].regions[0];
+        assert_eq!(region.keys, (40, 80));
+        assert_eq!((region.mode, region.loop_start, region.loop_end), (1, 2, 6));
+        let params = region.params(60, 127);
+        assert_eq!(params.tune, -10.0);
+        assert!((params.gain - centibels(20)).abs() < 1e-6);
+
+        // default and preset's velocity to attenuation modulators
+        let params = region.params(60, 64);
+        let velocity = 64.0f32 / 127.0;
+        assert!((params.gain - centibels(20) * velocity.powi(4)).abs() < 1e-3);
+
+        let mut voice = SoundFontVoice::new(Arc::new(font));
+        voice.preset = Some(0);
+        voice.note_on(20, 1.0, false);
+        assert!(!voice.is_active());
+
+        voice.note_on(60, 1.0, false);
+        assert!(voice.is_active());
+        let mut output = vec![0.0; 64];
+        voice.render(&mut output, 2);
+        assert!(voice.is_active());
+        assert!(output.iter().all(|s| s.is_finite() && s.abs() <= 0.25));
+        assert!(output[40..].iter().any(|s| *s != 0.0));
+    }
+}
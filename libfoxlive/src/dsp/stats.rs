@@ -0,0 +1,192 @@
+//! DSP load profiling and engine statistics.
+//!
+//! When profiling is enabled, graph measures processing duration of each node and of the
+//! whole block, compared to block's period. Statistics are queried through the graph service
+//! (`Graph::load`, `Graph::node_stats`, `Graph::stats`), and can be streamed periodically as
+//! `Stats` through the ringbuffer returned by `Graph::stats_stream`, apart from the service's
+//! responses.
+//!
+//! Driver's xruns are counted through the counter returned by `Graph::xrun_counter`, which can
+//! be incremented from the driver's notification thread.
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::time::Duration;
+
+use ringbuf::{Consumer,Producer,RingBuffer};
+
+use crate::data::{NSamples,SampleRate};
+use super::graph::NodeIndex;
+
+
+/// Smoothing of the average load, as previous value's weight
+const LOAD_SMOOTHING: f32 = 0.9;
+
+
+/// Processing durations of a node, in microseconds.
+#[derive(Copy,Clone,Debug,Default)]
+pub struct Timing {
+    pub min: f32,
+    pub max: f32,
+    total: f64,
+    count: u64,
+}
+
+/// Statistics of a node.
+#[derive(Copy,Clone,Debug)]
+pub struct NodeStats {
+    pub node: NodeIndex,
+    /// Processing duration per block, in microseconds
+    pub min: f32,
+    pub avg: f32,
+    pub max: f32,
+    /// Number of profiled blocks
+    pub blocks: u64,
+    /// Number of times the node lacked data (e.g. reader cache underruns)
+    pub underruns: usize,
+}
+
+/// Engine's load and errors.
+#[derive(Copy,Clone,Debug,Default)]
+pub struct EngineLoad {
+    /// Average DSP load, as percentage of block's period
+    pub load: f32,
+    /// Maximum DSP load
+    pub peak: f32,
+    /// Blocks whose processing took longer than their period
+    pub overloads: usize,
+    /// Driver's xruns
+    pub xruns: usize,
+    /// Number of profiled blocks
+    pub blocks: u64,
+}
+
+/// Streamed statistics.
+#[derive(Copy,Clone,Debug)]
+pub enum Stats {
+    Load(EngineLoad),
+    Node(NodeStats),
+}
+
+/// Engine's profiler.
+pub struct Profiler {
+    pub enabled: bool,
+    load: EngineLoad,
+    xruns: Arc<AtomicUsize>,
+    /// Streaming interval in milliseconds, `0` if disabled
+    interval_ms: u32,
+    elapsed: NSamples,
+    /// Streamed statistics' producer
+    stream: Option<Producer<Stats>>,
+}
+
+
+impl Timing {
+    /// Add a processing duration.
+    pub fn add(&mut self, duration: Duration) {
+        let us = duration.as_secs_f32() * 1.0e6;
+        if self.count == 0 || us < self.min {
+            self.min = us;
+        }
+        self.max = self.max.max(us);
+        self.total += us as f64;
+        self.count += 1;
+    }
+
+    /// Average duration
+    pub fn avg(&self) -> f32 {
+        match self.count {
+            0 => 0.0,
+            n => (self.total / n as f64) as f32,
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+
+impl NodeStats {
+    pub fn new(node: NodeIndex, timing: &Timing, underruns: usize) -> Self {
+        Self { node, min: timing.min, avg: timing.avg(), max: timing.max, blocks: timing.count,
+               underruns }
+    }
+}
+
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self { enabled: false, load: EngineLoad::default(), xruns: Arc::new(AtomicUsize::new(0)),
+               interval_ms: 0, elapsed: 0, stream: None }
+    }
+
+    /// Counter of driver's xruns.
+    pub fn xrun_counter(&self) -> Arc<AtomicUsize> {
+        self.xruns.clone()
+    }
+
+    /// Set streaming interval in milliseconds, `0` disabling it.
+    pub fn set_interval(&mut self, interval_ms: u32) {
+        self.interval_ms = interval_ms;
+        self.elapsed = 0;
+    }
+
+    /// Create the ringbuffer statistics are streamed to, replacing the previous one.
+    pub fn stream(&mut self, capacity: usize) -> Consumer<Stats> {
+        let (producer, consumer) = RingBuffer::new(capacity).split();
+        self.stream = Some(producer);
+        consumer
+    }
+
+    /// Return true if statistics are streamed.
+    pub fn is_streaming(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Stream statistics, dropping them when the ringbuffer is full.
+    pub fn send(&mut self, stats: Stats) {
+        if let Some(stream) = self.stream.as_mut() {
+            stream.push(stats).ok();
+        }
+    }
+
+    pub fn load(&self) -> EngineLoad {
+        EngineLoad { xruns: self.xruns.load(Ordering::Relaxed), ..self.load }
+    }
+
+    pub fn reset(&mut self) {
+        self.load = EngineLoad::default();
+        self.xruns.store(0, Ordering::Relaxed);
+    }
+
+    /// Add a processed block's duration. Return true when statistics should be streamed.
+    pub fn block(&mut self, duration: Duration, n_samples: NSamples, rate: SampleRate) -> bool {
+        if rate <= 0 || n_samples == 0 {
+            return false;
+        }
+
+        let period = n_samples as f32 / rate as f32;
+        let load = duration.as_secs_f32() / period * 100.0;
+        self.load.load = match self.load.blocks {
+            0 => load,
+            _ => self.load.load * LOAD_SMOOTHING + load * (1.0 - LOAD_SMOOTHING),
+        };
+        self.load.peak = self.load.peak.max(load);
+        self.load.blocks += 1;
+        if load > 100.0 {
+            self.load.overloads += 1;
+        }
+
+        if self.interval_ms == 0 {
+            return false;
+        }
+        self.elapsed += n_samples;
+        match self.elapsed as u64 * 1000 >= self.interval_ms as u64 * rate as u64 {
+            true => {
+                self.elapsed = 0;
+                true
+            },
+            false => false,
+        }
+    }
+}
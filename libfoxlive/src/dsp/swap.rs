@@ -0,0 +1,250 @@
+//! Values loaded in background, then swapped in by the audio thread.
+//!
+//! Resources such as samples or impulse responses are loaded outside of the audio thread and
+//! provided through a `Loader`. The audio thread swaps them in with `Swap::swap`, which never
+//! blocks: the value it replaces is retired until it is not used anymore, then released
+//! outside of the audio thread.
+//!
+//! Files requested from the audio thread, e.g. by a field's setter, are loaded by a
+//! `PathLoader`: paths are copied into preallocated memory and read by a worker thread.
+use std::fmt::Debug;
+use std::mem;
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::thread;
+use std::time::Duration;
+
+use super::release::Releaser;
+
+
+/// Capacity of the queue of values waiting to be released
+const RELEASE_CAPACITY: usize = 4;
+/// Maximum length of paths posted to a `PathLoader`, in bytes
+pub const PATH_CAPACITY: usize = 4096;
+/// Path loader's sleep duration between two checks of posted paths
+const IDLE: Duration = Duration::from_millis(20);
+
+
+/// Path posted to a `PathLoader`.
+struct Posted<A> {
+    path: String,
+    /// Loading arguments, when path has to be loaded
+    args: Option<A>,
+}
+
+/// Load files posted by the audio thread on a worker thread, providing them to a `Loader`.
+pub struct PathLoader<A: 'static+Copy+Send> {
+    posted: Arc<Mutex<Posted<A>>>,
+    /// Path loader has been dropped: worker stops.
+    stopped: Arc<AtomicBool>,
+}
+
+
+/// Handle used to provide loaded values, e.g. from a loading thread.
+pub struct Loader<T: 'static+Send>(Arc<Mutex<Option<T>>>);
+
+/// Value loaded in background, swapped by the audio thread.
+pub struct Swap<T: 'static+Send> {
+    loaded: Arc<Mutex<Option<T>>>,
+    /// Value replaced by the audio thread, kept until it is unused
+    retired: Option<T>,
+    releaser: Releaser<T>,
+}
+
+
+impl<T: 'static+Send> Loader<T> {
+    /// Provide value to be swapped in, replacing the one not yet swapped in, if any.
+    pub fn set(&self, value: T) {
+        *self.0.lock().unwrap() = Some(value);
+    }
+}
+
+impl<T: 'static+Send> Clone for Loader<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+
+impl<T: 'static+Send> Swap<T> {
+    pub fn new() -> Self {
+        Self { loaded: Arc::new(Mutex::new(None)), retired: None,
+               releaser: Releaser::new(RELEASE_CAPACITY) }
+    }
+
+    /// Return a handle to provide loaded values.
+    pub fn loader(&self) -> Loader<T> {
+        Loader(self.loaded.clone())
+    }
+
+    /// Provide value to be swapped in. This blocks while the audio thread swaps.
+    pub fn set(&self, value: T) {
+        self.loader().set(value)
+    }
+
+    /// Replace `current` by the loaded value, if any, returning true when swapped. The replaced
+    /// value is retired until `unused` returns true for it, then released; no value is swapped
+    /// in meanwhile.
+    pub fn swap<F: Fn(&T) -> bool>(&mut self, current: &mut T, unused: F) -> bool {
+        if let Some(retired) = self.retired.take() {
+            if !unused(&retired) {
+                self.retired = Some(retired);
+                return false;
+            }
+            self.releaser.release(retired);
+        }
+
+        let loaded = match self.loaded.try_lock() {
+            Ok(mut loaded) => loaded.take(),
+            Err(_) => None,
+        };
+        match loaded {
+            Some(value) => {
+                self.retired = Some(mem::replace(current, value));
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Retired value, if any
+    pub fn retired(&self) -> Option<&T> {
+        self.retired.as_ref()
+    }
+}
+
+impl<T: 'static+Send> Default for Swap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+impl<A: 'static+Copy+Send> PathLoader<A> {
+    /// Create a new path loader, spawning its worker which loads posted paths using `load` and
+    /// provides results to `loader`.
+    pub fn new<T, E, F>(loader: Loader<T>, load: F) -> Self
+        where T: 'static+Send, E: Debug, F: 'static+Fn(&str, A) -> Result<T, E>+Send
+    {
+        let posted = Arc::new(Mutex::new(Posted { path: String::with_capacity(PATH_CAPACITY), args: None }));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let (p, s) = (posted.clone(), stopped.clone());
+        thread::spawn(move || Self::run(p, loader, load, s));
+        Self { posted, stopped }
+    }
+
+    /// Post path to be loaded with `args`, replacing the one not yet loaded. This never blocks
+    /// nor allocates: it returns false when the worker is busy reading the posted path, or when
+    /// path is longer than `PATH_CAPACITY`.
+    pub fn post(&self, path: &str, args: A) -> bool {
+        let mut posted = match self.posted.try_lock() {
+            Ok(posted) if path.len() <= posted.path.capacity() => posted,
+            _ => return false,
+        };
+        posted.path.clear();
+        posted.path.push_str(path);
+        posted.args = Some(args);
+        true
+    }
+
+    /// Load last posted path again with `args`, if any. Return false when the worker is busy
+    /// reading the posted path.
+    pub fn reload(&self, args: A) -> bool {
+        match self.posted.try_lock() {
+            Ok(mut posted) => {
+                if !posted.path.is_empty() {
+                    posted.args = Some(args);
+                }
+                true
+            },
+            Err(_) => false,
+        }
+    }
+
+    /// Forget last posted path, cancelling its loading if not yet started.
+    pub fn clear(&self) {
+        let mut posted = self.posted.lock().unwrap();
+        posted.path.clear();
+        posted.args = None;
+    }
+
+    /// Last posted path
+    pub fn path(&self) -> String {
+        self.posted.lock().unwrap().path.clone()
+    }
+
+    fn run<T, E, F>(posted: Arc<Mutex<Posted<A>>>, loader: Loader<T>, load: F, stopped: Arc<AtomicBool>)
+        where T: 'static+Send, E: Debug, F: Fn(&str, A) -> Result<T, E>
+    {
+        while !stopped.load(Ordering::Acquire) {
+            let request = {
+                let mut posted = posted.lock().unwrap();
+                posted.args.take().map(|args| (posted.path.clone(), args))
+            };
+            match request {
+                Some((path, args)) => match load(&path, args) {
+                    Ok(value) => loader.set(value),
+                    Err(err) => log::error!("can't load {}: {:?}", path, err),
+                },
+                None => thread::sleep(IDLE),
+            }
+        }
+    }
+}
+
+impl<A: 'static+Copy+Send> Drop for PathLoader<A> {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Release);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test: loaded value is swapped in, and none while the retired one is used
+    #[test]
+    fn swap() {
+        let mut swap = Swap::new();
+        let mut current = Arc::new(1);
+        assert!(!swap.swap(&mut current, |_| true));
+
+        swap.loader().set(Arc::new(2));
+        let used = current.clone();
+        assert!(swap.swap(&mut current, |v| Arc::strong_count(v) == 1));
+        assert_eq!((*current, swap.retired().map(|v| **v)), (2, Some(1)));
+
+        swap.set(Arc::new(3));
+        assert!(!swap.swap(&mut current, |v| Arc::strong_count(v) == 1));
+        assert_eq!(*current, 2);
+
+        drop(used);
+        assert!(swap.swap(&mut current, |v| Arc::strong_count(v) == 1));
+        assert_eq!((*current, swap.retired().map(|v| **v)), (3, Some(2)));
+    }
+
+    /// Test: posted path is loaded by the worker, then swapped in
+    #[test]
+    fn path_loader() {
+        let mut swap = Swap::new();
+        let paths = PathLoader::new(swap.loader(), |path, n: usize| Ok::<_, ()>(path.repeat(n)));
+        assert!(!paths.post(&"a".repeat(PATH_CAPACITY + 1), 1));
+        assert!(paths.post("ab", 2));
+        assert_eq!(paths.path(), "ab");
+
+        let mut current = String::new();
+        let start = std::time::Instant::now();
+        while !swap.swap(&mut current, |_| true) && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(current, "abab");
+
+        assert!(paths.reload(1));
+        let start = std::time::Instant::now();
+        while !swap.swap(&mut current, |_| true) && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(current, "ab");
+    }
+}
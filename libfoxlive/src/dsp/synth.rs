@@ -0,0 +1,421 @@
+//! Polyphonic subtractive synthesizer.
+//!
+//! Each voice runs an anti-aliased oscillator into a resonant low-pass filter, shaped by an
+//! ADSR envelope which also modulates filter's cutoff. Notes are played from MIDI events,
+//! through `Voices`.
+//!
+//! `EventData::Control` events set the synth's fields, so it can be used as a modulation
+//! target.
+use std::f32::consts::PI;
+use std::marker::PhantomData;
+
+use crate as libfoxlive;
+use libfoxlive_derive::object;
+use crate::data::*;
+use crate::rpc::*;
+
+use super::dsp::{DSP,DEFAULT_RATE};
+use super::graph::ProcessScope;
+use super::modulation::{Adsr,Modulator};
+use super::voice::*;
+
+
+/// Number of samples between two updates of filter's coefficients
+const FILTER_UPDATE: usize = 32;
+/// Filter envelope's range, in octaves
+const ENVELOPE_OCTAVES: f32 = 4.0;
+
+
+/// Oscillator waveform
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Waveform {
+    Saw,
+    Square,
+    Triangle,
+    Sine,
+}
+
+/// Parameters shared by voices
+#[derive(Copy,Clone,Debug)]
+struct Params {
+    waveform: Waveform,
+    cutoff: f32,
+    resonance: f32,
+    envelope: f32,
+    attack: f32,
+    decay: f32,
+    sustain: f32,
+    release: f32,
+    glide: f32,
+    gain: f32,
+}
+
+/// Synth's voice
+pub struct SynthVoice {
+    params: Params,
+    rate: f32,
+    note: u8,
+    velocity: f32,
+    /// Current and target frequency, in Hz
+    frequency: f32,
+    target: f32,
+    phase: f32,
+    bend: f32,
+    mod_wheel: f32,
+    pressure: f32,
+    envelope: Adsr,
+    /// Filter's state and coefficients
+    ic1eq: f32,
+    ic2eq: f32,
+    coefs: (f32, f32, f32),
+    counter: usize,
+}
+
+
+/// Polyphonic subtractive synthesizer.
+#[object("synth")]
+pub struct Synth<PS>
+    where PS: ProcessScope
+{
+    /// Waveform: saw, square, triangle, sine
+    #[field("waveform", U8(0), range(0,3,1))]
+    pub waveform: u8,
+    /// Filter's cutoff in Hz
+    #[field("cutoff", F32(2000.0), range(20.0,20000.0,1.0))]
+    pub cutoff: f32,
+    #[field("resonance", F32(0.2), range(0.0,1.0,0.01))]
+    pub resonance: f32,
+    /// Envelope's modulation of cutoff, as a fraction of `ENVELOPE_OCTAVES`
+    #[field("filter envelope", F32(0.5), range(-1.0,1.0,0.01))]
+    pub filter_envelope: f32,
+    /// Envelope's attack in seconds
+    #[field("attack", F32(0.01), range(0.001,5.0,0.001))]
+    pub attack: f32,
+    #[field("decay", F32(0.2), range(0.001,5.0,0.001))]
+    pub decay: f32,
+    #[field("sustain", F32(0.7), range(0.0,1.0,0.01))]
+    pub sustain: f32,
+    #[field("release", F32(0.3), range(0.001,10.0,0.001))]
+    pub release: f32,
+    /// Glide time in seconds
+    #[field("glide", F32(0.0), range(0.0,2.0,0.01))]
+    pub glide: f32,
+    #[field("gain", F32(0.5), range(0.0,1.0,0.01))]
+    pub gain: f32,
+    #[field("polyphony", U8(8), range(1,64,1), set(set_polyphony))]
+    polyphony: u8,
+    /// Mode: poly, mono, legato
+    #[field("mode", U8(0), range(0,2,1), set(set_mode))]
+    mode: u8,
+    instrument: Instrument<SynthVoice>,
+    n_channels: NChannels,
+    phantom: PhantomData<PS>,
+}
+
+
+/// PolyBLEP residual for a discontinuity at phase `0`.
+fn poly_blep(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let t = t / dt;
+        t + t - t * t - 1.0
+    }
+    else if t > 1.0 - dt {
+        let t = (t - 1.0) / dt;
+        t * t + t + t + 1.0
+    }
+    else { 0.0 }
+}
+
+
+impl Waveform {
+    pub fn from_index(index: u8) -> Option<Self> {
+        [Waveform::Saw, Waveform::Square, Waveform::Triangle, Waveform::Sine].get(index as usize).copied()
+    }
+}
+
+
+impl SynthVoice {
+    fn new(params: Params) -> Self {
+        Self {
+            params, rate: DEFAULT_RATE as f32, note: 0, velocity: 0.0, frequency: 0.0, target: 0.0,
+            phase: 0.0, bend: 0.0, mod_wheel: 0.0, pressure: 0.0,
+            envelope: Adsr::new(params.attack, params.decay, params.sustain, params.release),
+            ic1eq: 0.0, ic2eq: 0.0, coefs: (0.0, 0.0, 0.0), counter: 0,
+        }
+    }
+
+    fn set_params(&mut self, params: Params) {
+        self.params = params;
+        self.envelope.attack = params.attack;
+        self.envelope.decay = params.decay;
+        self.envelope.sustain = params.sustain;
+        self.envelope.release = params.release;
+    }
+
+    /// Update filter's coefficients for the provided envelope level.
+    fn update_filter(&mut self, level: f32) {
+        let octaves = (self.params.envelope * level + self.mod_wheel) * ENVELOPE_OCTAVES;
+        let cutoff = (self.params.cutoff * octaves.exp2()).max(20.0).min(self.rate * 0.45);
+        let g = (PI * cutoff / self.rate).tan();
+        let k = 2.0 - 1.98 * self.params.resonance.max(0.0).min(1.0);
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        self.coefs = (a1, g * a1, g * g * a1);
+    }
+
+    fn oscillator(&self, dt: f32) -> f32 {
+        let phase = self.phase;
+        match self.params.waveform {
+            Waveform::Saw => 2.0 * phase - 1.0 - poly_blep(phase, dt),
+            Waveform::Square => (if phase < 0.5 { 1.0 } else { -1.0 })
+                                + poly_blep(phase, dt) - poly_blep((phase + 0.5).fract(), dt),
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Waveform::Sine => (2.0 * PI * phase).sin(),
+        }
+    }
+}
+
+impl Voice for SynthVoice {
+    fn prepare(&mut self, rate: SampleRate) {
+        self.rate = rate as f32;
+        self.envelope.prepare(rate);
+    }
+
+    fn note_on(&mut self, note: u8, velocity: f32, legato: bool) {
+        self.note = note;
+        self.target = 440.0 * ((note as f32 - 69.0) / 12.0).exp2();
+        if !legato {
+            self.velocity = velocity;
+            self.envelope.gate(true);
+        }
+        if self.frequency == 0.0 || (!legato && self.params.glide == 0.0) {
+            self.frequency = self.target;
+        }
+    }
+
+    fn note_off(&mut self) {
+        self.envelope.gate(false);
+    }
+
+    fn is_active(&self) -> bool {
+        !self.envelope.is_idle()
+    }
+
+    fn level(&self) -> f32 {
+        self.envelope.level() * self.velocity
+    }
+
+    fn set_param(&mut self, param: usize, value: f32) {
+        match param {
+            PARAM_PITCH_BEND => self.bend = value,
+            PARAM_PRESSURE => self.pressure = value,
+            PARAM_MOD_WHEEL => self.mod_wheel = value,
+            _ => {},
+        }
+    }
+
+    fn render(&mut self, output: &mut [f32], n_channels: usize) {
+        let target = self.target * (self.bend / 12.0).exp2();
+        let glide = match self.params.glide > 0.0 {
+            true => (-1.0 / (self.params.glide * self.rate)).exp(),
+            false => 0.0,
+        };
+        let gain = self.params.gain * self.velocity * (1.0 + self.pressure);
+
+        for frame in output.chunks_mut(n_channels) {
+            let level = self.envelope.next(1, None);
+            if self.counter % FILTER_UPDATE == 0 {
+                self.update_filter(level);
+            }
+            self.counter = self.counter.wrapping_add(1);
+
+            self.frequency = target + (self.frequency - target) * glide;
+            let dt = (self.frequency / self.rate).min(0.5);
+            let x = self.oscillator(dt);
+            self.phase = (self.phase + dt).fract();
+
+            // TPT state variable filter, low-pass output
+            let (a1, a2, a3) = self.coefs;
+            let v3 = x - self.ic2eq;
+            let v1 = a1 * self.ic1eq + a2 * v3;
+            let v2 = self.ic2eq + a2 * self.ic1eq + a3 * v3;
+            self.ic1eq = 2.0 * v1 - self.ic1eq;
+            self.ic2eq = 2.0 * v2 - self.ic2eq;
+
+            let y = v2 * level * gain;
+            for sample in frame.iter_mut() {
+                *sample += y;
+            }
+        }
+
+        if self.envelope.is_idle() {
+            self.ic1eq = 0.0;
+            self.ic2eq = 0.0;
+        }
+    }
+}
+
+
+impl<PS> Synth<PS>
+    where PS: ProcessScope
+{
+    pub fn new(n_channels: NChannels) -> Self {
+        let mut synth = Self {
+            waveform: 0, cutoff: 2000.0, resonance: 0.2, filter_envelope: 0.5,
+            attack: 0.01, decay: 0.2, sustain: 0.7, release: 0.3, glide: 0.0, gain: 0.5,
+            polyphony: 8, mode: 0,
+            instrument: Instrument::new(Vec::new(), 0),
+            n_channels: n_channels.max(1),
+            phantom: PhantomData,
+        };
+        let params = synth.params();
+        synth.instrument = Instrument::new((0..MAX_VOICES).map(|_| SynthVoice::new(params)).collect(),
+                                           synth.polyphony as usize);
+        synth
+    }
+
+    pub fn voices_mut(&mut self) -> &mut Voices<SynthVoice> {
+        &mut self.instrument.voices
+    }
+
+    pub fn set_polyphony(&mut self, polyphony: u8) -> Result<u8, ()> {
+        self.instrument.voices.set_polyphony(polyphony as usize);
+        self.polyphony = self.instrument.voices.polyphony() as u8;
+        Ok(self.polyphony)
+    }
+
+    pub fn set_mode(&mut self, mode: u8) -> Result<u8, ()> {
+        let voices_mode = match mode {
+            0 => Mode::Poly,
+            1 => Mode::Mono,
+            2 => Mode::Legato,
+            _ => return Err(()),
+        };
+        self.instrument.voices.set_mode(voices_mode);
+        self.mode = mode;
+        Ok(mode)
+    }
+
+    fn params(&self) -> Params {
+        Params {
+            waveform: Waveform::from_index(self.waveform).unwrap_or(Waveform::Saw),
+            cutoff: self.cutoff, resonance: self.resonance, envelope: self.filter_envelope,
+            attack: self.attack, decay: self.decay, sustain: self.sustain, release: self.release,
+            glide: self.glide, gain: self.gain,
+        }
+    }
+}
+
+
+impl<PS> DSP for Synth<PS>
+    where PS: ProcessScope
+{
+    type Sample = f32;
+    type Scope = PS;
+
+    fn prepare(&mut self, rate: SampleRate) {
+        self.instrument.voices.prepare(rate);
+    }
+
+    fn process_events(&mut self, _scope: &Self::Scope, input: &EventBuffer, _output: &mut EventBuffer) {
+        self.instrument.events.clear();
+        self.instrument.events.merge(input);
+    }
+
+    fn process_audio(&mut self, scope: &Self::Scope, _input: Option<&dyn BufferView<Sample=Self::Sample>>,
+                     output: Option<&mut dyn BufferView<Sample=Self::Sample>>) -> usize
+    {
+        let output = match output {
+            Some(output) => output,
+            None => return 0,
+        };
+
+        // controls are applied for the whole block
+        for i in 0..self.instrument.events.len() {
+            if let EventData::Control(index, value) = self.instrument.events.as_slice()[i].data {
+                control(self, index, value);
+            }
+        }
+        let params = self.params();
+        for voice in self.instrument.voices.voices_mut() {
+            voice.set_params(params);
+        }
+
+        self.instrument.render(output, scope.n_samples())
+    }
+
+    fn n_channels(&self) -> NChannels {
+        self.n_channels
+    }
+
+    fn is_source(&self) -> bool {
+        true
+    }
+
+    fn has_event_input(&self) -> bool {
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::block::BlockScope;
+
+    const N_SAMPLES: NSamples = 256;
+
+    fn note(time: NFrames, note: u8, velocity: u8) -> Event {
+        Event::new(time, EventData::Midi(MidiMessage::NoteOn { channel: 0, note, velocity }))
+    }
+
+    /// Process a block of the provided events, returning output's peak
+    fn process(synth: &mut Synth<BlockScope>, events: &[Event]) -> f32 {
+        let scope = BlockScope::new(N_SAMPLES, 0, None);
+        let mut input = EventBuffer::with_capacity(16);
+        for event in events {
+            input.push(*event);
+        }
+        synth.process_events(&scope, &input, &mut EventBuffer::with_capacity(0));
+
+        let mut output: Buffer<f32,Vec<f32>> = (true, 2, vec![0.0; N_SAMPLES * 2]).into();
+        assert_eq!(synth.process_audio(&scope, None, Some(&mut output)), N_SAMPLES * 2);
+        output.as_slice().iter().fold(0.0, |peak, s| peak.max(s.abs()))
+    }
+
+    /// Test: notes are played from MIDI events and released
+    #[test]
+    fn notes() {
+        let mut synth = Synth::<BlockScope>::new(2);
+        synth.prepare(48000);
+        assert_eq!(process(&mut synth, &[]), 0.0);
+
+        assert!(process(&mut synth, &[note(0, 60, 127), note(128, 64, 127)]) > 0.0);
+        assert_eq!(synth.voices_mut().active(), 2);
+
+        // release is over after 0.3 seconds
+        process(&mut synth, &[note(0, 60, 0), note(0, 64, 0)]);
+        for _ in 0..60 {
+            process(&mut synth, &[]);
+        }
+        assert_eq!(synth.voices_mut().active(), 0);
+        assert_eq!(process(&mut synth, &[]), 0.0);
+    }
+
+    /// Test: control events set fields, polyphony limits voices
+    #[test]
+    fn controls() {
+        let mut synth = Synth::<BlockScope>::new(2);
+        synth.prepare(48000);
+        process(&mut synth, &[Event::new(0, EventData::Control(1, 500.0)),
+                              Event::new(0, EventData::Control(10, 1.0))]);
+        assert_eq!(synth.cutoff, 500.0);
+        assert_eq!(synth.polyphony, 1);
+
+        process(&mut synth, &[note(0, 60, 100), note(0, 64, 100)]);
+        assert_eq!(synth.voices_mut().active(), 1);
+
+        assert_eq!(synth.set_mode(2), Ok(2));
+        assert_eq!(synth.voices_mut().mode(), Mode::Legato);
+        assert!(synth.set_mode(3).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! Transport informations shared among audio applications, such as JACK transport.
+//!
+//! `ProcessScope::transport()` provides the transport state for the current cycle, that
+//! tempo-synced DSPs can use in order to follow the transport.
+use crate::data::{NFrames,SampleRate};
+
+
+/// Transport state.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum TransportState {
+    Stopped,
+    Rolling,
+    Looping,
+    Starting,
+}
+
+
+/// Bar, beat and tick position, along with tempo and time signature.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct Bbt {
+    /// Current bar, starting at 1
+    pub bar: i32,
+    /// Current beat in bar, starting at 1
+    pub beat: i32,
+    /// Current tick in beat, starting at 0
+    pub tick: i32,
+    /// Number of ticks since start to the current bar
+    pub bar_start_tick: f64,
+    /// Time signature numerator
+    pub beats_per_bar: f32,
+    /// Time signature denominator
+    pub beat_type: f32,
+    /// Ticks resolution
+    pub ticks_per_beat: f64,
+    /// Tempo
+    pub beats_per_minute: f64,
+}
+
+
+/// Transport position at the start of a process cycle.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct Transport {
+    pub state: TransportState,
+    /// Frame position
+    pub frame: NFrames,
+    /// Frame rate
+    pub rate: SampleRate,
+    /// Bar, beat, tick position, if provided by the timebase master
+    pub bbt: Option<Bbt>,
+}
+
+
+/// Tempo and time signature, used to compute a `Bbt` position from frame position.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct Tempo {
+    pub beats_per_minute: f64,
+    pub beats_per_bar: f32,
+    pub beat_type: f32,
+    pub ticks_per_beat: f64,
+}
+
+
+/// Control transport of an audio backend.
+pub trait TransportControl {
+    /// Start transport rolling
+    fn start(&self);
+
+    /// Stop transport
+    fn stop(&self);
+
+    /// Relocate transport to the provided frame. Return `true` on success.
+    fn locate(&self, frame: NFrames) -> bool;
+}
+
+
+impl Transport {
+    /// Return true if transport is rolling
+    pub fn is_rolling(&self) -> bool {
+        match self.state {
+            TransportState::Rolling | TransportState::Looping => true,
+            _ => false,
+        }
+    }
+
+    /// Position in seconds
+    pub fn seconds(&self) -> f64 {
+        if self.rate > 0 { self.frame as f64 / self.rate as f64 }
+        else { 0.0 }
+    }
+
+    /// Absolute position in beats since start, if bbt is provided.
+    pub fn beats(&self) -> Option<f64> {
+        self.bbt.map(|bbt| bbt.beats())
+    }
+
+    /// Number of frames per beat, if bbt is provided.
+    pub fn frames_per_beat(&self) -> Option<f64> {
+        self.bbt.map(|bbt| self.rate as f64 * 60.0 / bbt.beats_per_minute)
+    }
+}
+
+
+impl Bbt {
+    /// Absolute position in beats since start.
+    pub fn beats(&self) -> f64 {
+        (self.bar - 1) as f64 * self.beats_per_bar as f64 + (self.beat - 1) as f64
+            + self.tick as f64 / self.ticks_per_beat
+    }
+}
+
+
+impl Tempo {
+    pub fn new(beats_per_minute: f64, beats_per_bar: f32, beat_type: f32) -> Self {
+        Self { beats_per_minute, beats_per_bar, beat_type, ticks_per_beat: 1920.0 }
+    }
+
+    /// Compute bar, beat and tick for the provided frame position.
+    pub fn bbt(&self, frame: NFrames, rate: SampleRate) -> Bbt {
+        let beats = frame as f64 * self.beats_per_minute / (60.0 * rate as f64);
+        let beats_per_bar = self.beats_per_bar as f64;
+        let bar = (beats / beats_per_bar).floor();
+        let beat = (beats - bar * beats_per_bar).floor();
+        let tick = ((beats - bar * beats_per_bar - beat) * self.ticks_per_beat).floor();
+
+        Bbt {
+            bar: bar as i32 + 1,
+            beat: beat as i32 + 1,
+            tick: tick as i32,
+            bar_start_tick: bar * beats_per_bar * self.ticks_per_beat,
+            beats_per_bar: self.beats_per_bar,
+            beat_type: self.beat_type,
+            ticks_per_beat: self.ticks_per_beat,
+            beats_per_minute: self.beats_per_minute,
+        }
+    }
+}
+
+impl Default for Tempo {
+    fn default() -> Self {
+        Self::new(120.0, 4.0, 4.0)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test: Tempo::bbt, Bbt::beats
+    #[test]
+    fn tempo_bbt() {
+        let tempo = Tempo::new(120.0, 4.0, 4.0);
+        let bbt = tempo.bbt(0, 48000);
+        assert_eq!((bbt.bar, bbt.beat, bbt.tick), (1, 1, 0));
+
+        // 120bpm: 2 beats per second, 5 beats after 2.5s
+        let bbt = tempo.bbt(120000, 48000);
+        assert_eq!((bbt.bar, bbt.beat, bbt.tick), (2, 2, 0));
+        assert_eq!(bbt.beats(), 5.0);
+        assert_eq!(bbt.bar_start_tick, 4.0 * 1920.0);
+
+        // half beat
+        let bbt = tempo.bbt(12000, 48000);
+        assert_eq!((bbt.bar, bbt.beat, bbt.tick), (1, 1, 960));
+    }
+}
@@ -0,0 +1,480 @@
+//! Polyphonic voice allocation for instrument DSPs.
+//!
+//! `Voices` drives a fixed set of `Voice`s from MIDI events: it allocates voices to notes up to
+//! a polyphony limit, steals voices when none is free, handles sustain pedal and mono/legato
+//! modes, and forwards per-voice parameters (pitch bend, pressure, modulation wheel).
+//!
+//! Voices are allocated once, so that note handling doesn't allocate on the audio thread.
+//! Instruments render blocks through `Voices::process`, which splits them at events' time
+//! for sample accuracy. `Instrument` holds the voices and events shared by instrument DSPs.
+use crate::data::*;
+use crate::rpc::*;
+
+use super::graph::EVENTS_CAPACITY;
+
+
+/// Pitch bend, in semitones
+pub const PARAM_PITCH_BEND: usize = 0;
+/// Channel or polyphonic pressure, in `0.0..=1.0`
+pub const PARAM_PRESSURE: usize = 1;
+/// Modulation wheel, in `0.0..=1.0`
+pub const PARAM_MOD_WHEEL: usize = 2;
+
+/// Number of allocated voices, being the maximum polyphony
+pub const MAX_VOICES: usize = 64;
+/// Maximum number of held notes tracked in mono mode
+const MAX_HELD: usize = 128;
+
+
+/// Voice of an instrument.
+pub trait Voice: Send {
+    /// Prepare voice for the provided sample rate.
+    fn prepare(&mut self, _rate: SampleRate) {}
+
+    /// Start playing a note. When `legato` is true, the voice goes on from its current note
+    /// without retriggering.
+    fn note_on(&mut self, note: u8, velocity: f32, legato: bool);
+
+    /// Release the note.
+    fn note_off(&mut self);
+
+    /// Return true while voice is sounding, including its release.
+    fn is_active(&self) -> bool;
+
+    /// Current output level, used to steal the quietest voice.
+    fn level(&self) -> f32 { 1.0 }
+
+    /// Set a per-voice parameter (e.g. `PARAM_PITCH_BEND`).
+    fn set_param(&mut self, _param: usize, _value: f32) {}
+
+    /// Render voice, adding it to the interleaved output.
+    fn render(&mut self, output: &mut [f32], n_channels: usize);
+}
+
+
+/// Voice stealing policy, when all voices are used.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Stealing {
+    /// Don't play new note
+    None,
+    Oldest,
+    Quietest,
+    LowestNote,
+    HighestNote,
+}
+
+/// Voice allocation mode
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Mode {
+    Poly,
+    /// Single voice, retriggered by each note
+    Mono,
+    /// Single voice, only triggered when no other note is held
+    Legato,
+}
+
+/// Voices of an instrument DSP, with the events they play.
+pub struct Instrument<V: Voice> {
+    /// Voice allocator, e.g. to set stealing policy or MIDI channel.
+    pub voices: Voices<V>,
+    /// Events of the current block
+    pub events: EventBuffer,
+}
+
+/// Voice's allocation state
+struct Slot<V: Voice> {
+    voice: V,
+    /// Note being played
+    note: Option<u8>,
+    /// Key is held
+    held: bool,
+    /// Key has been released while sustain pedal is down
+    sustained: bool,
+    /// Note-on order, used to steal the oldest voice
+    age: u64,
+}
+
+/// Voice allocator
+pub struct Voices<V: Voice> {
+    slots: Vec<Slot<V>>,
+    polyphony: usize,
+    pub stealing: Stealing,
+    mode: Mode,
+    /// Sustain pedal is down
+    sustain: bool,
+    counter: u64,
+    /// Held notes and their velocity in mono modes, the last one being played
+    held: Vec<(u8, f32)>,
+    /// Pitch bend range in semitones
+    pub bend_range: f32,
+    /// MIDI channel to listen to, all if `None`
+    pub channel: Option<u8>,
+}
+
+
+impl<V: Voice> Voices<V> {
+    /// Create allocator for the provided voices, polyphony being the number of voices.
+    pub fn new(voices: Vec<V>) -> Self {
+        let polyphony = voices.len();
+        Self {
+            slots: voices.into_iter().map(|voice| Slot { voice, note: None, held: false, sustained: false,
+                                                         age: 0 })
+                         .collect(),
+            polyphony,
+            stealing: Stealing::Oldest,
+            mode: Mode::Poly,
+            sustain: false,
+            counter: 0,
+            held: Vec::with_capacity(MAX_HELD),
+            bend_range: 2.0,
+            channel: None,
+        }
+    }
+
+    pub fn polyphony(&self) -> usize {
+        self.polyphony
+    }
+
+    /// Set polyphony, up to the number of voices. Voices above it are released.
+    pub fn set_polyphony(&mut self, polyphony: usize) {
+        self.polyphony = polyphony.max(1).min(self.slots.len());
+        for slot in self.slots[self.polyphony..].iter_mut() {
+            if slot.note.take().is_some() {
+                slot.voice.note_off();
+            }
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Set allocation mode, releasing all notes.
+    pub fn set_mode(&mut self, mode: Mode) {
+        if mode != self.mode {
+            self.all_notes_off();
+            self.mode = mode;
+        }
+    }
+
+    /// Number of sounding voices
+    pub fn active(&self) -> usize {
+        self.slots.iter().filter(|slot| slot.voice.is_active()).count()
+    }
+
+    /// Iterate over voices
+    pub fn voices_mut(&mut self) -> impl Iterator<Item=&mut V> {
+        self.slots.iter_mut().map(|slot| &mut slot.voice)
+    }
+
+    pub fn prepare(&mut self, rate: SampleRate) {
+        for slot in self.slots.iter_mut() {
+            slot.voice.prepare(rate);
+        }
+    }
+
+    pub fn note_on(&mut self, note: u8, velocity: f32) {
+        self.counter += 1;
+        match self.mode {
+            Mode::Poly => self.poly_note_on(note, velocity),
+            Mode::Mono | Mode::Legato => {
+                let legato = self.mode == Mode::Legato && !self.held.is_empty();
+                self.held.retain(|(n, _)| *n != note);
+                if self.held.len() < MAX_HELD {
+                    self.held.push((note, velocity));
+                }
+                let slot = &mut self.slots[0];
+                slot.voice.note_on(note, velocity, legato && slot.voice.is_active());
+                slot.note = Some(note);
+                slot.held = true;
+                slot.sustained = false;
+                slot.age = self.counter;
+            },
+        }
+    }
+
+    fn poly_note_on(&mut self, note: u8, velocity: f32) {
+        let (stealing, counter) = (self.stealing, self.counter);
+        let slots = &mut self.slots[..self.polyphony];
+        let index = slots.iter().position(|slot| slot.note == Some(note))
+            .or_else(|| slots.iter().position(|slot| !slot.voice.is_active()))
+            .or_else(|| {
+                let slots = slots.iter().enumerate();
+                let key = |slot: &Slot<V>| slot.note.unwrap_or(0);
+                match stealing {
+                    Stealing::None => None,
+                    Stealing::Oldest => slots.min_by_key(|(_, slot)| slot.age),
+                    Stealing::Quietest => slots.min_by(|(_, a), (_, b)|
+                        a.voice.level().partial_cmp(&b.voice.level()).unwrap_or(std::cmp::Ordering::Equal)),
+                    Stealing::LowestNote => slots.min_by_key(|(_, slot)| key(slot)),
+                    Stealing::HighestNote => slots.max_by_key(|(_, slot)| key(slot)),
+                }.map(|(index, _)| index)
+            });
+
+        if let Some(index) = index {
+            let slot = &mut slots[index];
+            slot.voice.note_on(note, velocity, false);
+            slot.note = Some(note);
+            slot.held = true;
+            slot.sustained = false;
+            slot.age = counter;
+        }
+    }
+
+    pub fn note_off(&mut self, note: u8) {
+        if self.mode != Mode::Poly {
+            let last = self.held.last().map(|(n, _)| *n) == Some(note);
+            self.held.retain(|(n, _)| *n != note);
+            match (last, self.held.last()) {
+                // go back to previous held note
+                (true, Some(&(previous, velocity))) => {
+                    let slot = &mut self.slots[0];
+                    slot.voice.note_on(previous, velocity, self.mode == Mode::Legato);
+                    slot.note = Some(previous);
+                    return;
+                },
+                (false, _) => return,
+                _ => {},
+            }
+        }
+
+        let sustain = self.sustain;
+        for slot in self.slots.iter_mut().filter(|slot| slot.note == Some(note) && slot.held) {
+            slot.held = false;
+            match sustain {
+                true => slot.sustained = true,
+                false => {
+                    slot.note = None;
+                    slot.voice.note_off();
+                },
+            }
+        }
+    }
+
+    /// Press or release sustain pedal.
+    pub fn set_sustain(&mut self, sustain: bool) {
+        self.sustain = sustain;
+        if !sustain {
+            for slot in self.slots.iter_mut().filter(|slot| slot.sustained) {
+                slot.sustained = false;
+                slot.note = None;
+                slot.voice.note_off();
+            }
+        }
+    }
+
+    /// Release all notes.
+    pub fn all_notes_off(&mut self) {
+        self.held.clear();
+        self.sustain = false;
+        for slot in self.slots.iter_mut() {
+            slot.held = false;
+            slot.sustained = false;
+            if slot.note.take().is_some() {
+                slot.voice.note_off();
+            }
+        }
+    }
+
+    /// Set parameter of all voices.
+    pub fn set_param(&mut self, param: usize, value: f32) {
+        for slot in self.slots.iter_mut() {
+            slot.voice.set_param(param, value);
+        }
+    }
+
+    /// Set parameter of the voice playing note.
+    pub fn set_note_param(&mut self, note: u8, param: usize, value: f32) {
+        for slot in self.slots.iter_mut().filter(|slot| slot.note == Some(note)) {
+            slot.voice.set_param(param, value);
+        }
+    }
+
+    /// Handle a MIDI message.
+    pub fn midi(&mut self, message: &MidiMessage) {
+        let channel = match *message {
+            MidiMessage::NoteOff { channel, .. } | MidiMessage::NoteOn { channel, .. } |
+            MidiMessage::PolyPressure { channel, .. } | MidiMessage::ControlChange { channel, .. } |
+            MidiMessage::ProgramChange { channel, .. } | MidiMessage::ChannelPressure { channel, .. } |
+            MidiMessage::PitchBend { channel, .. } => channel,
+        };
+        if self.channel.map(|c| c != channel).unwrap_or(false) {
+            return;
+        }
+
+        match *message {
+            MidiMessage::NoteOn { note, velocity: 0, .. } | MidiMessage::NoteOff { note, .. } =>
+                self.note_off(note),
+            MidiMessage::NoteOn { note, velocity, .. } => self.note_on(note, velocity as f32 / 127.0),
+            MidiMessage::PolyPressure { note, pressure, .. } =>
+                self.set_note_param(note, PARAM_PRESSURE, pressure as f32 / 127.0),
+            MidiMessage::ChannelPressure { pressure, .. } =>
+                self.set_param(PARAM_PRESSURE, pressure as f32 / 127.0),
+            MidiMessage::PitchBend { value, .. } =>
+                self.set_param(PARAM_PITCH_BEND, (value as f32 - 8192.0) / 8192.0 * self.bend_range),
+            MidiMessage::ControlChange { control: 1, value, .. } =>
+                self.set_param(PARAM_MOD_WHEEL, value as f32 / 127.0),
+            MidiMessage::ControlChange { control: 64, value, .. } => self.set_sustain(value >= 64),
+            MidiMessage::ControlChange { control: 120, .. } | MidiMessage::ControlChange { control: 123, .. } =>
+                self.all_notes_off(),
+            _ => {},
+        }
+    }
+
+    /// Render voices, adding them to the interleaved output.
+    pub fn render(&mut self, output: &mut [f32], n_channels: usize) {
+        for slot in self.slots.iter_mut().filter(|slot| slot.voice.is_active()) {
+            slot.voice.render(output, n_channels);
+        }
+    }
+
+    /// Render a block of `n_samples` into the interleaved output, handling MIDI events at
+    /// their time. Other events are passed to `control`.
+    pub fn process(&mut self, events: &EventBuffer, output: &mut [f32], n_channels: usize, n_samples: NSamples,
+                   control: &mut dyn FnMut(&Event))
+    {
+        let n_channels = n_channels.max(1);
+        let n_samples = n_samples.min(output.len() / n_channels);
+        let mut pos = 0;
+        for event in events.iter() {
+            let time = (event.time as usize).min(n_samples);
+            if time > pos {
+                self.render(&mut output[pos * n_channels..time * n_channels], n_channels);
+                pos = time;
+            }
+            match event.midi() {
+                Some(message) => self.midi(message),
+                None => control(event),
+            }
+        }
+        if n_samples > pos {
+            self.render(&mut output[pos * n_channels..n_samples * n_channels], n_channels);
+        }
+    }
+}
+
+
+impl<V: Voice> Instrument<V> {
+    /// Create instrument for the provided voices, at the provided polyphony.
+    pub fn new(voices: Vec<V>, polyphony: usize) -> Self {
+        let mut voices = Voices::new(voices);
+        voices.set_polyphony(polyphony);
+        Self { voices, events: EventBuffer::with_capacity(EVENTS_CAPACITY) }
+    }
+
+    /// Render block's events into output, which is cleared first, then clear events. Return
+    /// output's length.
+    pub fn render(&mut self, output: &mut dyn BufferView<Sample=f32>, n_samples: NSamples) -> usize {
+        output.set_interleaved(true);
+        output.fill(0.0);
+        let n_channels = output.n_channels() as usize;
+        self.voices.process(&self.events, output.as_slice_mut(), n_channels, n_samples, &mut |_| {});
+        self.events.clear();
+        output.len()
+    }
+}
+
+
+/// Set object's field from a control event's value, converted to field's type.
+pub fn control<O: Object+?Sized>(object: &mut O, index: ObjectIndex, value: f64) {
+    if let Some(value) = object.get_value(index).and_then(|v| Value::from_f64(v.get_type(), value)) {
+        object.set_value(index, value).ok();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestVoice {
+        note: Option<u8>,
+        velocity: f32,
+        released: bool,
+        legato: bool,
+    }
+
+    impl Voice for TestVoice {
+        fn note_on(&mut self, note: u8, velocity: f32, legato: bool) {
+            self.note = Some(note);
+            self.velocity = velocity;
+            self.released = false;
+            self.legato = legato;
+        }
+
+        fn note_off(&mut self) {
+            self.released = true;
+        }
+
+        fn is_active(&self) -> bool {
+            self.note.is_some() && !self.released
+        }
+
+        fn render(&mut self, _output: &mut [f32], _n_channels: usize) {}
+    }
+
+    fn voices(n: usize) -> Voices<TestVoice> {
+        Voices::new((0..n).map(|_| TestVoice { note: None, velocity: 0.0, released: false, legato: false }).collect())
+    }
+
+    fn notes(voices: &Voices<TestVoice>) -> Vec<Option<u8>> {
+        voices.slots.iter().map(|s| if s.voice.is_active() { s.voice.note } else { None }).collect()
+    }
+
+    /// Test: allocation, stealing and sustain
+    #[test]
+    fn poly() {
+        let mut v = voices(2);
+        v.note_on(60, 1.0);
+        v.note_on(64, 1.0);
+        v.note_on(67, 1.0);
+        assert_eq!(notes(&v), vec![Some(67), Some(64)]);
+
+        v.stealing = Stealing::HighestNote;
+        v.note_on(50, 1.0);
+        assert_eq!(notes(&v), vec![Some(50), Some(64)]);
+
+        v.set_sustain(true);
+        v.note_off(50);
+        assert_eq!(notes(&v), vec![Some(50), Some(64)]);
+        v.set_sustain(false);
+        assert_eq!(notes(&v), vec![None, Some(64)]);
+
+        v.note_on(64, 1.0);
+        assert_eq!(v.active(), 1);
+    }
+
+    /// Test: legato mode goes back to held notes
+    #[test]
+    fn legato() {
+        let mut v = voices(2);
+        v.set_mode(Mode::Legato);
+        v.note_on(60, 1.0);
+        assert!(!v.slots[0].voice.legato);
+        v.note_on(62, 1.0);
+        assert!(v.slots[0].voice.legato);
+        assert_eq!(notes(&v), vec![Some(62), None]);
+
+        v.note_off(62);
+        assert_eq!(notes(&v), vec![Some(60), None]);
+        v.note_off(60);
+        assert_eq!(notes(&v), vec![None, None]);
+    }
+
+    /// Test: mono mode retriggers held notes with their velocity
+    #[test]
+    fn mono() {
+        let mut v = voices(1);
+        v.set_mode(Mode::Mono);
+        v.note_on(60, 0.25);
+        v.note_on(62, 1.0);
+        v.note_on(64, 0.75);
+        assert!(!v.slots[0].voice.legato);
+
+        v.note_off(62);
+        assert_eq!((notes(&v), v.slots[0].voice.velocity), (vec![Some(64)], 0.75));
+        v.note_off(64);
+        assert_eq!((notes(&v), v.slots[0].voice.velocity), (vec![Some(60)], 0.25));
+        assert!(!v.slots[0].voice.legato);
+    }
+}
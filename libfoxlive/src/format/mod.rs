@@ -17,6 +17,7 @@ pub mod codec;
 pub mod stream;
 pub mod format;
 pub mod reader;
+pub mod sf2;
 
 
 pub use error::Error;
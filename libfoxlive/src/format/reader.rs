@@ -6,7 +6,7 @@ use std::sync::*;
 
 use core::pin::Pin;
 use futures;
-use ringbuf::Producer;
+use ringbuf::{Producer,RingBuffer};
 
 use crate::data::*;
 
@@ -101,6 +101,10 @@ impl<S> Deref for ReaderContext<S>
 }
 
 
+/// Size of the cache used by `Reader::read_all`
+const READ_ALL_CACHE: usize = 1 << 16;
+
+
 /*
 pub struct ReadFrame<S> {
     pub pos: Duration,
@@ -156,6 +160,34 @@ impl<S> Reader<S>
             })
     }
 
+    /// Read a whole file at once into an interleaved buffer, resampled to the provided rate.
+    /// Channels are kept as is when no layout is provided.
+    ///
+    /// This blocks until file is fully decoded and must not be used from the audio thread.
+    pub fn read_all(path: &str, rate: SampleRate, layout: Option<ChannelLayout>) -> Result<VecBuffer<S>, Error> {
+        let (cache, mut consumer) = RingBuffer::new(READ_ALL_CACHE).split();
+        let mut reader = Self::new(cache, rate, layout);
+        reader.open(path, None)?;
+
+        let layout = match layout {
+            Some(layout) => layout,
+            None => reader.stream().and_then(|s| ChannelLayout::from_n_channels(s.n_channels()))
+                           .ok_or_else(|| Error::reader("unsupported channel layout"))?,
+        };
+
+        let mut samples = Vec::new();
+        loop {
+            let r = reader.read_packet();
+            consumer.pop_each(|sample| { samples.push(sample); true }, None);
+            match r {
+                Poll::Ready(Ok(_)) => break,
+                Poll::Ready(Err(err)) => return Err(err),
+                Poll::Pending => {},
+            }
+        }
+        Ok((true, layout, samples).into())
+    }
+
     pub fn close(&mut self) {
         if self.context.is_some() {
             self.buffer.clear();
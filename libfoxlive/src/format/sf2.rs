@@ -0,0 +1,472 @@
+//! SoundFont 2 (SF2) files parsing.
+//!
+//! A SoundFont is a RIFF file holding 16 bits sample data, instruments and presets. Presets
+//! and instruments are made of zones, each one having generators (e.g. key range, tuning,
+//! envelope) and modulators. Preset zones refer to an instrument, instrument zones refer to
+//! a sample. The first zone without such reference is the global zone, whose generators
+//! apply to all other zones.
+//!
+//! This module only parses the file's structure: generators are kept as is, resolving them
+//! into playable regions is up to the instrument.
+use std::fs;
+
+use super::error::Error;
+
+
+/// Start address offset, in sample frames
+pub const GEN_START_OFFSET: u16 = 0;
+pub const GEN_END_OFFSET: u16 = 1;
+pub const GEN_LOOP_START_OFFSET: u16 = 2;
+pub const GEN_LOOP_END_OFFSET: u16 = 3;
+/// Start address offset, in 32768 sample frames
+pub const GEN_START_COARSE_OFFSET: u16 = 4;
+pub const GEN_FILTER_CUTOFF: u16 = 8;
+pub const GEN_FILTER_Q: u16 = 9;
+pub const GEN_END_COARSE_OFFSET: u16 = 12;
+/// Pan in `-500..=500`, 0.1% units
+pub const GEN_PAN: u16 = 17;
+/// Volume envelope's times in timecents, sustain in centibels of attenuation
+pub const GEN_DELAY_VOL_ENV: u16 = 33;
+pub const GEN_ATTACK_VOL_ENV: u16 = 34;
+pub const GEN_HOLD_VOL_ENV: u16 = 35;
+pub const GEN_DECAY_VOL_ENV: u16 = 36;
+pub const GEN_SUSTAIN_VOL_ENV: u16 = 37;
+pub const GEN_RELEASE_VOL_ENV: u16 = 38;
+/// Preset zone's instrument index
+pub const GEN_INSTRUMENT: u16 = 41;
+pub const GEN_KEY_RANGE: u16 = 43;
+pub const GEN_VEL_RANGE: u16 = 44;
+pub const GEN_LOOP_START_COARSE_OFFSET: u16 = 45;
+/// Forced key number
+pub const GEN_KEYNUM: u16 = 46;
+/// Forced velocity
+pub const GEN_VELOCITY: u16 = 47;
+/// Attenuation in centibels
+pub const GEN_ATTENUATION: u16 = 48;
+pub const GEN_LOOP_END_COARSE_OFFSET: u16 = 50;
+/// Tuning in semitones
+pub const GEN_COARSE_TUNE: u16 = 51;
+/// Tuning in cents
+pub const GEN_FINE_TUNE: u16 = 52;
+/// Instrument zone's sample index
+pub const GEN_SAMPLE_ID: u16 = 53;
+/// `0`: no loop, `1`: continuous loop, `3`: loop until release
+pub const GEN_SAMPLE_MODES: u16 = 54;
+/// Cents per key
+pub const GEN_SCALE_TUNING: u16 = 56;
+pub const GEN_EXCLUSIVE_CLASS: u16 = 57;
+pub const GEN_ROOT_KEY: u16 = 58;
+/// Number of defined generators
+pub const N_GENERATORS: usize = 61;
+
+/// Sizes of pdta records
+const PHDR_SIZE: usize = 38;
+const BAG_SIZE: usize = 4;
+const MOD_SIZE: usize = 10;
+const GEN_SIZE: usize = 4;
+const INST_SIZE: usize = 22;
+const SHDR_SIZE: usize = 46;
+
+
+/// Zone's generator, whose amount is either a signed value or a range.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct Generator {
+    pub oper: u16,
+    pub amount: u16,
+}
+
+/// Zone's modulator
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct Modulator {
+    pub source: u16,
+    /// Destination generator
+    pub destination: u16,
+    pub amount: i16,
+    /// Source modulating the amount
+    pub amount_source: u16,
+    pub transform: u16,
+}
+
+/// Preset or instrument zone.
+#[derive(Clone,Debug,Default)]
+pub struct Zone {
+    pub generators: Vec<Generator>,
+    pub modulators: Vec<Modulator>,
+}
+
+#[derive(Clone,Debug)]
+pub struct Preset {
+    pub name: String,
+    pub bank: u16,
+    pub program: u16,
+    pub global: Option<Zone>,
+    pub zones: Vec<Zone>,
+}
+
+#[derive(Clone,Debug)]
+pub struct Instrument {
+    pub name: String,
+    pub global: Option<Zone>,
+    pub zones: Vec<Zone>,
+}
+
+/// Sample's header. Positions are in sample frames, relative to the start of sample data.
+#[derive(Clone,Debug)]
+pub struct SampleHeader {
+    pub name: String,
+    pub start: u32,
+    pub end: u32,
+    pub loop_start: u32,
+    pub loop_end: u32,
+    pub rate: u32,
+    /// Original key
+    pub pitch: u8,
+    /// Pitch correction in cents
+    pub correction: i8,
+    /// Index of the other sample of a stereo pair
+    pub link: u16,
+    pub kind: u16,
+}
+
+/// Parsed SoundFont.
+#[derive(Clone,Debug,Default)]
+pub struct SoundFont {
+    pub name: String,
+    pub presets: Vec<Preset>,
+    pub instruments: Vec<Instrument>,
+    pub samples: Vec<SampleHeader>,
+    /// Mono 16 bits sample data
+    pub data: Vec<i16>,
+}
+
+
+fn u16_at(data: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([data[pos], data[pos+1]])
+}
+
+fn u32_at(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([data[pos], data[pos+1], data[pos+2], data[pos+3]])
+}
+
+/// Read a zero terminated name
+fn name_at(data: &[u8], pos: usize, len: usize) -> String {
+    let name = &data[pos..pos+len];
+    let end = name.iter().position(|c| *c == 0).unwrap_or(len);
+    String::from_utf8_lossy(&name[..end]).trim_end().to_string()
+}
+
+/// Split RIFF chunks, returning their id and data.
+fn chunks(mut data: &[u8]) -> Result<Vec<(&[u8], &[u8])>, Error> {
+    let mut chunks = Vec::new();
+    while data.len() >= 8 {
+        let size = u32_at(data, 4) as usize;
+        if data.len() < 8 + size {
+            return Err(Error::format(format!("truncated chunk {}", String::from_utf8_lossy(&data[0..4]))));
+        }
+        chunks.push((&data[0..4], &data[8..8+size]));
+        data = &data[(8 + size + (size & 1)).min(data.len())..];
+    }
+    Ok(chunks)
+}
+
+/// Return data of the `LIST` chunk of the provided type.
+fn list<'a>(chunks: &[(&[u8], &'a [u8])], kind: &[u8]) -> Option<&'a [u8]> {
+    chunks.iter().find(|(id, data)| *id == b"LIST" && data.len() >= 4 && &data[0..4] == kind)
+          .map(|(_, data)| &data[4..])
+}
+
+/// Return data of the sub-chunk of the provided id.
+fn chunk<'a>(chunks: &[(&[u8], &'a [u8])], id: &[u8]) -> Option<&'a [u8]> {
+    chunks.iter().find(|(chunk_id, _)| *chunk_id == id).map(|(_, data)| *data)
+}
+
+/// Return records of a pdta chunk, excluding the terminal one.
+fn records<'a>(chunks: &[(&[u8], &'a [u8])], id: &str, size: usize) -> Result<Vec<&'a [u8]>, Error> {
+    let data = chunk(chunks, id.as_bytes()).ok_or_else(|| Error::format(format!("missing {} chunk", id)))?;
+    if data.len() % size != 0 || data.len() < size {
+        return Err(Error::format(format!("invalid {} chunk size", id)));
+    }
+    Ok(data.chunks(size).take(data.len() / size - 1).collect())
+}
+
+
+impl Generator {
+    /// Amount as a signed value
+    pub fn value(&self) -> i16 {
+        self.amount as i16
+    }
+
+    /// Amount as a `(low, high)` range
+    pub fn range(&self) -> (u8, u8) {
+        let [low, high] = self.amount.to_le_bytes();
+        (low, high)
+    }
+}
+
+
+impl Zone {
+    /// Return generator of the provided operator, if any.
+    pub fn generator(&self, oper: u16) -> Option<&Generator> {
+        self.generators.iter().find(|g| g.oper == oper)
+    }
+
+    /// Return instrument (for preset zones) or sample (for instrument zones) index.
+    fn target(&self, oper: u16) -> Option<usize> {
+        self.generator(oper).map(|g| g.amount as usize)
+    }
+
+    pub fn instrument(&self) -> Option<usize> {
+        self.target(GEN_INSTRUMENT)
+    }
+
+    pub fn sample(&self) -> Option<usize> {
+        self.target(GEN_SAMPLE_ID)
+    }
+}
+
+
+impl SoundFont {
+    /// Read and parse file.
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let data = fs::read(path).map_err(|err| Error::format(format!("{}: {}", path, err)))?;
+        Self::parse(&data)
+    }
+
+    /// Parse SoundFont from file's content.
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"sfbk" {
+            return Err(Error::format("not a SoundFont file"));
+        }
+        let size = (u32_at(data, 4) as usize).min(data.len() - 8);
+        if size < 4 {
+            return Err(Error::format("invalid RIFF chunk size"));
+        }
+        let riff = chunks(&data[12..8+size])?;
+
+        let mut font = Self::default();
+        if let Some(info) = list(&riff, b"INFO") {
+            if let Some(name) = chunk(&chunks(info)?, b"INAM") {
+                font.name = name_at(name, 0, name.len());
+            }
+        }
+
+        let sdta = list(&riff, b"sdta").ok_or_else(|| Error::format("missing sdta chunk"))?;
+        if let Some(smpl) = chunk(&chunks(sdta)?, b"smpl") {
+            font.data = smpl.chunks_exact(2).map(|s| i16::from_le_bytes([s[0], s[1]])).collect();
+        }
+
+        let pdta = chunks(list(&riff, b"pdta").ok_or_else(|| Error::format("missing pdta chunk"))?)?;
+        let pzones = Self::zones(&pdta, "pbag", "pmod", "pgen")?;
+        let izones = Self::zones(&pdta, "ibag", "imod", "igen")?;
+
+        // headers' bag indexes include the terminal record
+        let phdr = chunk(&pdta, b"phdr").unwrap_or(&[]);
+        for (i, record) in records(&pdta, "phdr", PHDR_SIZE)?.into_iter().enumerate() {
+            let (start, end) = (u16_at(record, 24) as usize, u16_at(&phdr[(i+1) * PHDR_SIZE..], 24) as usize);
+            let (global, zones) = Self::split_zones(&pzones, start, end, GEN_INSTRUMENT)?;
+            font.presets.push(Preset { name: name_at(record, 0, 20), program: u16_at(record, 20),
+                                       bank: u16_at(record, 22), global, zones });
+        }
+
+        let inst = chunk(&pdta, b"inst").unwrap_or(&[]);
+        for (i, record) in records(&pdta, "inst", INST_SIZE)?.into_iter().enumerate() {
+            let (start, end) = (u16_at(record, 20) as usize, u16_at(&inst[(i+1) * INST_SIZE..], 20) as usize);
+            let (global, zones) = Self::split_zones(&izones, start, end, GEN_SAMPLE_ID)?;
+            font.instruments.push(Instrument { name: name_at(record, 0, 20), global, zones });
+        }
+
+        for record in records(&pdta, "shdr", SHDR_SIZE)? {
+            font.samples.push(SampleHeader {
+                name: name_at(record, 0, 20),
+                start: u32_at(record, 20), end: u32_at(record, 24),
+                loop_start: u32_at(record, 28), loop_end: u32_at(record, 32),
+                rate: u32_at(record, 36), pitch: record[40], correction: record[41] as i8,
+                link: u16_at(record, 42), kind: u16_at(record, 44),
+            });
+        }
+
+        font.validate()?;
+        Ok(font)
+    }
+
+    /// Read zones from bag, modulator and generator chunks.
+    fn zones(pdta: &[(&[u8], &[u8])], bag: &str, modulators: &str, generators: &str) -> Result<Vec<Zone>, Error> {
+        let bag_data = chunk(pdta, bag.as_bytes()).unwrap_or(&[]);
+        let bags = records(pdta, bag, BAG_SIZE)?;
+        let mods = records(pdta, modulators, MOD_SIZE)?;
+        let gens = records(pdta, generators, GEN_SIZE)?;
+
+        let mut zones = Vec::with_capacity(bags.len());
+        for (i, record) in bags.iter().enumerate() {
+            let next = &bag_data[(i+1) * BAG_SIZE..];
+            let (gen_start, gen_end) = (u16_at(record, 0) as usize, u16_at(next, 0) as usize);
+            let (mod_start, mod_end) = (u16_at(record, 2) as usize, u16_at(next, 2) as usize);
+            if gen_start > gen_end || gen_end > gens.len() || mod_start > mod_end || mod_end > mods.len() {
+                return Err(Error::format(format!("invalid {} indexes", bag)));
+            }
+
+            zones.push(Zone {
+                generators: gens[gen_start..gen_end].iter()
+                    .map(|g| Generator { oper: u16_at(g, 0), amount: u16_at(g, 2) })
+                    .collect(),
+                modulators: mods[mod_start..mod_end].iter()
+                    .map(|m| Modulator { source: u16_at(m, 0), destination: u16_at(m, 2),
+                                         amount: u16_at(m, 4) as i16, amount_source: u16_at(m, 6),
+                                         transform: u16_at(m, 8) })
+                    .collect(),
+            });
+        }
+        Ok(zones)
+    }
+
+    /// Return global zone and zones of a preset or instrument. The global zone is the first
+    /// one, if it doesn't have the terminal generator.
+    fn split_zones(zones: &[Zone], start: usize, end: usize, terminal: u16)
+        -> Result<(Option<Zone>, Vec<Zone>), Error>
+    {
+        if start > end || end > zones.len() {
+            return Err(Error::format("invalid zone indexes"));
+        }
+        let mut zones = zones[start..end].to_vec();
+        let global = match zones.first() {
+            Some(zone) if zone.generator(terminal).is_none() => Some(zones.remove(0)),
+            _ => None,
+        };
+        // other zones without terminal generator are ignored
+        zones.retain(|zone| zone.generator(terminal).is_some());
+        Ok((global, zones))
+    }
+
+    /// Check zones' references to instruments and samples.
+    fn validate(&self) -> Result<(), Error> {
+        for preset in self.presets.iter() {
+            if preset.zones.iter().any(|z| z.instrument().map(|i| i >= self.instruments.len()).unwrap_or(false)) {
+                return Err(Error::format(format!("preset {}: invalid instrument", preset.name)));
+            }
+        }
+        for instrument in self.instruments.iter() {
+            if instrument.zones.iter().any(|z| z.sample().map(|s| s >= self.samples.len()).unwrap_or(false)) {
+                return Err(Error::format(format!("instrument {}: invalid sample", instrument.name)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Return preset index for the provided bank and program.
+    pub fn preset(&self, bank: u16, program: u16) -> Option<usize> {
+        self.presets.iter().position(|p| p.bank == bank && p.program == program)
+    }
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn list(kind: &[u8], chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = kind.to_vec();
+        chunks.iter().for_each(|c| data.extend_from_slice(c));
+        chunk(b"LIST", &data)
+    }
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut data = name.as_bytes().to_vec();
+        data.resize(20, 0);
+        data
+    }
+
+    fn words(words: &[u16]) -> Vec<u8> {
+        words.iter().flat_map(|w| w.to_le_bytes().to_vec()).collect()
+    }
+
+    /// Build a SoundFont with a preset "Piano" (bank 0, program 1) whose single instrument
+    /// plays an 8 frames looped sample over keys 40 to 80, root key 60.
+    pub(crate) fn build() -> Vec<u8> {
+        let mut phdr = Vec::new();
+        for (n, program, bag) in [("Piano", 1u16, 0u16), ("EOP", 0, 2)].iter() {
+            phdr.extend(name(n));
+            phdr.extend(words(&[*program, 0, *bag]));
+            phdr.extend(vec![0; 12]);
+        }
+        let mut inst = Vec::new();
+        for (n, bag) in [("Piano", 0u16), ("EOI", 1)].iter() {
+            inst.extend(name(n));
+            inst.extend(words(&[*bag]));
+        }
+        let mut shdr = Vec::new();
+        for (n, start, end) in [("A", 0u32, 8u32), ("EOS", 0, 0)].iter() {
+            shdr.extend(name(n));
+            for v in [*start, *end, 2, 6, 48000].iter() {
+                shdr.extend_from_slice(&v.to_le_bytes());
+            }
+            shdr.extend_from_slice(&[60, 0]);
+            shdr.extend(words(&[0, 1]));
+        }
+
+        let smpl: Vec<u8> = (0..8i16).flat_map(|s| (s * 1000).to_le_bytes().to_vec()).collect();
+        let pdta = list(b"pdta", &[
+            chunk(b"phdr", &phdr),
+            // global zone with attenuation, zone with instrument
+            chunk(b"pbag", &words(&[0, 0, 1, 0, 2, 1])),
+            chunk(b"pmod", &words(&[0x0502, GEN_ATTENUATION, 960, 0, 0, 0, 0, 0, 0, 0])),
+            chunk(b"pgen", &words(&[GEN_ATTENUATION, 20, GEN_INSTRUMENT, 0, 0, 0])),
+            chunk(b"inst", &inst),
+            chunk(b"ibag", &words(&[0, 0, 4, 0])),
+            chunk(b"imod", &words(&[0; 5])),
+            chunk(b"igen", &words(&[GEN_SAMPLE_MODES, 1, GEN_KEY_RANGE, 40 | 80 << 8, GEN_FINE_TUNE, (-10i16) as u16,
+                                    GEN_SAMPLE_ID, 0, 0, 0])),
+            chunk(b"shdr", &shdr),
+        ]);
+
+        let mut data = b"sfbk".to_vec();
+        data.extend(list(b"INFO", &[chunk(b"INAM", b"Test\0")]));
+        data.extend(list(b"sdta", &[chunk(b"smpl", &smpl)]));
+        data.extend(pdta);
+        chunk(b"RIFF", &data)
+    }
+
+    /// Test: presets, instruments, zones, generators, modulators and samples
+    #[test]
+    fn parse() {
+        let font = SoundFont::parse(&build()).unwrap();
+        assert_eq!(font.name, "Test");
+        assert_eq!(font.data.len(), 8);
+        assert_eq!(font.data[3], 3000);
+
+        assert_eq!(font.presets.len(), 1);
+        let preset = &font.presets[0];
+        assert_eq!((preset.name.as_str(), preset.bank, preset.program), ("Piano", 0, 1));
+        assert_eq!(font.preset(0, 1), Some(0));
+        let global = preset.global.as_ref().unwrap();
+        assert_eq!(global.generator(GEN_ATTENUATION).map(|g| g.value()), Some(20));
+        assert_eq!(preset.zones.len(), 1);
+        assert_eq!(preset.zones[0].instrument(), Some(0));
+        assert_eq!(preset.zones[0].modulators[0].destination, GEN_ATTENUATION);
+        assert_eq!(preset.zones[0].modulators[0].amount, 960);
+
+        let instrument = &font.instruments[0];
+        assert!(instrument.global.is_none());
+        assert_eq!(instrument.zones.len(), 1);
+        let zone = &instrument.zones[0];
+        assert_eq!(zone.sample(), Some(0));
+        assert_eq!(zone.generator(GEN_KEY_RANGE).map(|g| g.range()), Some((40, 80)));
+        assert_eq!(zone.generator(GEN_FINE_TUNE).map(|g| g.value()), Some(-10));
+
+        let sample = &font.samples[0];
+        assert_eq!((sample.start, sample.end, sample.loop_start, sample.loop_end), (0, 8, 2, 6));
+        assert_eq!((sample.rate, sample.pitch), (48000, 60));
+
+        assert!(SoundFont::parse(b"RIFF\0\0\0\0WAVE").is_err());
+        assert!(SoundFont::parse(b"RIFF\0\0\0\0sfbk").is_err());
+    }
+}
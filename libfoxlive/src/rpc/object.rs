@@ -84,3 +84,11 @@ pub trait ObjectMapper{
     fn declare(&mut self, field_info: FieldInfo);
 }
 
+
+/// Collect declared fields
+impl ObjectMapper for Vec<FieldInfo> {
+    fn declare(&mut self, field_info: FieldInfo) {
+        self.push(field_info);
+    }
+}
+
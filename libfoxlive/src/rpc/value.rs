@@ -29,6 +29,7 @@ macro_rules! ValueEnum {
     };
 
     ($($variant:ident => $type:ty $(| $info:ident)?),*) => {
+        #[derive(Copy,Clone,Debug,PartialEq)]
         pub enum ValueType {
             $($variant),*
         }
@@ -39,7 +40,7 @@ macro_rules! ValueEnum {
         }
 
         impl Value {
-            fn get_type(&self) -> ValueType {
+            pub fn get_type(&self) -> ValueType {
                 match self {
                     $(Self::$variant(_) => ValueType::$variant),*
                 }
@@ -52,34 +53,42 @@ macro_rules! ValueEnum {
 
 macro_rules! RangeEnum {
     ($($variant:ident => $type:ty $(| $info:ident)?),*) => {
+        #[derive(Copy,Clone,Debug)]
         pub enum Range {
             $($variant($type,$type,$type)),*
         }
 
         impl Range {
-            fn get_type(&self) -> ValueType {
+            pub fn get_type(&self) -> ValueType {
                 match self {
                     $(Self::$variant(_,_,_) => ValueType::$variant),*
                 }
             }
 
-            fn min(&self) -> Value {
+            pub fn min(&self) -> Value {
                 match self {
                     $(Self::$variant(a,_,_) => Value::$variant(*a)),*
                 }
             }
 
-            fn max(&self) -> Value {
+            pub fn max(&self) -> Value {
                 match self {
                     $(Self::$variant(_,a,_) => Value::$variant(*a)),*
                 }
             }
 
-            fn step(&self) -> Value {
+            pub fn step(&self) -> Value {
                 match self {
                     $(Self::$variant(_,_,a) => Value::$variant(*a)),*
                 }
             }
+
+            /// Return range as `(min, max, step)` floats.
+            pub fn as_f64(&self) -> (f64,f64,f64) {
+                match self {
+                    $(Self::$variant(a,b,c) => (*a as f64, *b as f64, *c as f64)),*
+                }
+            }
         }
 
         // TODO: IntoRange => tuple
@@ -98,3 +107,36 @@ RangeEnum!{
 }
 
 
+impl Value {
+    /// Return value as float, when it has a numeric representation.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Bool(v) => Some(if *v { 1.0 } else { 0.0 }),
+            Value::U8(v) => Some(*v as f64),
+            Value::I16(v) => Some(*v as f64),
+            Value::I32(v) => Some(*v as f64),
+            Value::F32(v) => Some(*v as f64),
+            Value::F64(v) => Some(*v),
+            Value::Duration(v) => Some(v.as_secs_f64()),
+            Value::Index(v) => Some(*v as f64),
+            Value::String(_) => None,
+        }
+    }
+
+    /// Create a value of the provided type from a float.
+    pub fn from_f64(value_type: ValueType, value: f64) -> Option<Value> {
+        match value_type {
+            ValueType::Bool => Some(Value::Bool(value >= 0.5)),
+            ValueType::U8 => Some(Value::U8(value.round() as u8)),
+            ValueType::I16 => Some(Value::I16(value.round() as i16)),
+            ValueType::I32 => Some(Value::I32(value.round() as i32)),
+            ValueType::F32 => Some(Value::F32(value as f32)),
+            ValueType::F64 => Some(Value::F64(value)),
+            ValueType::Duration => Some(Value::Duration(Duration::from_secs_f64(value.max(0.0)))),
+            ValueType::Index => Some(Value::Index(value.max(0.0).round() as usize)),
+            ValueType::String => None,
+        }
+    }
+}
+
+
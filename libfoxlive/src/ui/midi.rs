@@ -0,0 +1,346 @@
+//! Map MIDI controllers to objects' fields.
+//!
+//! A `MidiMap` binds control change, note and pitch bend messages to an `Object`'s fields,
+//! scaling values into the field's `Range`. It supports MIDI-learn, relative encoders and
+//! soft takeover.
+//!
+//! # Example
+//!
+//! ```
+//! use libfoxlive::data::MidiSource;
+//! use libfoxlive::rpc::Object;
+//! use libfoxlive::ui::midi::{MidiMap,MidiMode};
+//!
+//! fn run(object: &mut dyn Object, source: &mut dyn MidiSource) {
+//!     let mut map = MidiMap::new(object);
+//!     // bind next received controller to field 0
+//!     map.learn(0, MidiMode::Absolute);
+//!
+//!     loop {
+//!         map.process(source, object);
+//!     }
+//! }
+//! ```
+use crate::data::midi::{MidiMessage,MidiSource};
+use crate::rpc::*;
+
+
+/// Controller of a binding
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum MidiControl {
+    ControlChange(u8),
+    Note(u8),
+    PitchBend,
+}
+
+
+/// How controller values are applied to the field.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum MidiMode {
+    /// Controller value is scaled into field's range
+    Absolute,
+    /// Relative encoder sending two's complement offsets (`1` is +1, `127` is -1)
+    RelativeTwos,
+    /// Relative encoder sending offsets around 64 (`65` is +1, `63` is -1)
+    RelativeBinOffset,
+    /// Relative encoder using bit 6 as sign (`1` is +1, `65` is -1)
+    RelativeSignBit,
+    /// Note on toggles value between range's min and max
+    Toggle,
+}
+
+
+/// Binding of a MIDI controller to an object's field
+#[derive(Clone,Debug)]
+pub struct MidiBinding {
+    /// Channel, `None` for all channels
+    pub channel: Option<u8>,
+    pub control: MidiControl,
+    pub field: ObjectIndex,
+    pub mode: MidiMode,
+    /// Wait for controller to reach field's value before applying it
+    pub soft_takeover: bool,
+    value_type: ValueType,
+    /// Field's range as `(min, max, step)`
+    range: (f64,f64,f64),
+    /// Last value set by the binding, used to detect changes from elsewhere
+    last: Option<f64>,
+}
+
+
+/// Maps MIDI controllers to an object's fields.
+pub struct MidiMap {
+    fields: Vec<FieldInfo>,
+    bindings: Vec<MidiBinding>,
+    learning: Option<(ObjectIndex, MidiMode)>,
+    /// Soft takeover of new bindings
+    pub soft_takeover: bool,
+}
+
+
+/// Controller data of a message
+#[derive(Copy,Clone)]
+enum ControlData {
+    Value(u8),
+    NoteOn(u8),
+    NoteOff,
+    PitchBend(u16),
+}
+
+
+impl ControlData {
+    /// Value normalized in `[0,1]`
+    fn normalized(&self) -> f64 {
+        match *self {
+            ControlData::Value(v) | ControlData::NoteOn(v) => v as f64 / 127.0,
+            ControlData::NoteOff => 0.0,
+            ControlData::PitchBend(v) => v as f64 / 16383.0,
+        }
+    }
+}
+
+
+/// Return offset sent by a relative encoder
+fn relative_offset(mode: MidiMode, value: u8) -> i32 {
+    let value = value as i32;
+    match mode {
+        MidiMode::RelativeTwos => if value < 64 { value } else { value - 128 },
+        MidiMode::RelativeBinOffset => value - 64,
+        MidiMode::RelativeSignBit => if value & 0x40 != 0 { -(value & 0x3F) } else { value & 0x3F },
+        _ => 0,
+    }
+}
+
+/// Range used for fields that don't provide one.
+fn default_range(value_type: ValueType) -> (f64,f64,f64) {
+    match value_type {
+        ValueType::U8 => (0.0, 127.0, 1.0),
+        ValueType::I16 | ValueType::I32 | ValueType::Index => (0.0, 127.0, 1.0),
+        ValueType::Bool => (0.0, 1.0, 1.0),
+        _ => (0.0, 1.0, 0.0),
+    }
+}
+
+
+impl MidiBinding {
+    /// Apply controller data to object. Return true if field has been updated.
+    fn apply(&mut self, object: &mut dyn Object, data: ControlData) -> bool {
+        let (min, max, step) = self.range;
+        let current = object.get_value(self.field).and_then(|v| v.as_f64());
+
+        let value = match (self.mode, data) {
+            (MidiMode::Toggle, ControlData::NoteOn(_)) => match current {
+                Some(v) if v > min => min,
+                _ => max,
+            },
+            (MidiMode::Toggle, _) => return false,
+            (MidiMode::Absolute, data) => {
+                let value = min + data.normalized() * (max - min);
+                if self.soft_takeover && !self.picked_up(current, value) {
+                    return false;
+                }
+                value
+            },
+            (mode, ControlData::Value(v)) => {
+                let step = if step > 0.0 { step } else { (max - min) / 127.0 };
+                let value = current.unwrap_or(min) + relative_offset(mode, v) as f64 * step;
+                value.max(min).min(max)
+            },
+            _ => return false,
+        };
+
+        match Value::from_f64(self.value_type, value) {
+            Some(value) => match object.set_value(self.field, value) {
+                Ok(value) => {
+                    self.last = value.as_f64();
+                    true
+                },
+                Err(_) => false,
+            },
+            None => false,
+        }
+    }
+
+    /// Soft takeover: controller value is applied only once it reaches field's value. It stays
+    /// picked up until field is changed by something else.
+    fn picked_up(&mut self, current: Option<f64>, value: f64) -> bool {
+        let current = match current {
+            Some(current) => current,
+            None => return true,
+        };
+
+        let (min, max, step) = self.range;
+        let tolerance = step.max((max - min) / 64.0);
+        match self.last {
+            Some(last) if (last - current).abs() <= f64::EPSILON.max(step) => true,
+            _ => {
+                self.last = None;
+                (value - current).abs() <= tolerance
+            },
+        }
+    }
+}
+
+
+impl MidiMap {
+    /// Create a new map for the provided object
+    pub fn new(object: &dyn Object) -> Self {
+        let mut fields = Vec::new();
+        object.map_object(&mut fields);
+        Self {
+            fields,
+            bindings: Vec::new(),
+            learning: None,
+            soft_takeover: true,
+        }
+    }
+
+    /// Bind next received controller to the provided field
+    pub fn learn(&mut self, field: ObjectIndex, mode: MidiMode) {
+        self.learning = Some((field, mode));
+    }
+
+    /// Cancel MIDI-learn
+    pub fn cancel_learn(&mut self) {
+        self.learning = None;
+    }
+
+    /// Return true if waiting for a controller to learn
+    pub fn is_learning(&self) -> bool {
+        self.learning.is_some()
+    }
+
+    /// Current bindings
+    pub fn bindings(&self) -> &[MidiBinding] {
+        &self.bindings
+    }
+
+    /// Bind a controller to a field, replacing existing binding for this controller.
+    pub fn bind(&mut self, channel: Option<u8>, control: MidiControl, field: ObjectIndex,
+                mode: MidiMode) -> Result<(), ()>
+    {
+        let (value_type, range) = match self.fields.iter().find(|f| f.index == field) {
+            Some(info) => (info.value_type,
+                           info.range.map(|r| r.as_f64()).unwrap_or_else(|| default_range(info.value_type))),
+            None => return Err(()),
+        };
+
+        self.bindings.retain(|b| b.control != control || b.channel != channel);
+        self.bindings.push(MidiBinding {
+            channel, control, field, mode, value_type, range,
+            soft_takeover: self.soft_takeover,
+            last: None,
+        });
+        Ok(())
+    }
+
+    /// Remove all bindings to the provided field
+    pub fn unbind(&mut self, field: ObjectIndex) {
+        self.bindings.retain(|b| b.field != field);
+    }
+
+    /// Handle a message, updating object's fields. Return true if a field has been updated.
+    pub fn handle(&mut self, object: &mut dyn Object, message: &MidiMessage) -> bool {
+        let (control, data) = match *message {
+            MidiMessage::ControlChange { control, value, .. } =>
+                (MidiControl::ControlChange(control), ControlData::Value(value)),
+            MidiMessage::NoteOn { note, velocity, .. } =>
+                (MidiControl::Note(note), ControlData::NoteOn(velocity)),
+            MidiMessage::NoteOff { note, .. } =>
+                (MidiControl::Note(note), ControlData::NoteOff),
+            MidiMessage::PitchBend { value, .. } =>
+                (MidiControl::PitchBend, ControlData::PitchBend(value)),
+            _ => return false,
+        };
+        let channel = message.channel();
+
+        if let Some((field, mode)) = self.learning.take() {
+            self.bind(Some(channel), control, field, mode).ok();
+            return false;
+        }
+
+        let mut updated = false;
+        for binding in self.bindings.iter_mut() {
+            if binding.control == control && binding.channel.map(|c| c == channel).unwrap_or(true) {
+                updated |= binding.apply(object, data);
+            }
+        }
+        updated
+    }
+
+    /// Handle all events available from source.
+    pub fn process(&mut self, source: &mut dyn MidiSource, object: &mut dyn Object) {
+        while let Some(event) = source.next_event() {
+            self.handle(object, &event.message);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate as libfoxlive;
+    use libfoxlive_derive::object;
+    use super::*;
+
+    #[object("test")]
+    struct TestObject {
+        #[field("gain", F32(0.0), range(0.0,1.0,0.0))]
+        gain: f32,
+        #[field("enabled", Bool(false))]
+        enabled: bool,
+    }
+
+    fn cc(control: u8, value: u8) -> MidiMessage {
+        MidiMessage::ControlChange { channel: 0, control, value }
+    }
+
+    /// Test: MidiMap::learn, absolute and toggle bindings
+    #[test]
+    fn midi_map_learn() {
+        let mut object = TestObject { gain: 0.0, enabled: false };
+        let mut map = MidiMap::new(&object);
+        map.soft_takeover = false;
+
+        map.learn(0, MidiMode::Absolute);
+        assert!(!map.handle(&mut object, &cc(7, 0)));
+        assert_eq!(map.bindings()[0].control, MidiControl::ControlChange(7));
+
+        assert!(map.handle(&mut object, &cc(7, 127)));
+        assert_eq!(object.gain, 1.0);
+        assert!(!map.handle(&mut object, &cc(8, 0)));
+
+        map.bind(None, MidiControl::Note(60), 1, MidiMode::Toggle).unwrap();
+        let note_on = MidiMessage::NoteOn { channel: 2, note: 60, velocity: 100 };
+        map.handle(&mut object, &note_on);
+        assert!(object.enabled);
+        map.handle(&mut object, &MidiMessage::NoteOff { channel: 2, note: 60, velocity: 0 });
+        assert!(object.enabled);
+        map.handle(&mut object, &note_on);
+        assert!(!object.enabled);
+    }
+
+    /// Test: soft takeover and relative encoders
+    #[test]
+    fn midi_map_takeover() {
+        let mut object = TestObject { gain: 0.5, enabled: false };
+        let mut map = MidiMap::new(&object);
+        map.bind(Some(0), MidiControl::ControlChange(1), 0, MidiMode::Absolute).unwrap();
+
+        // controller far from field's value
+        assert!(!map.handle(&mut object, &cc(1, 0)));
+        assert_eq!(object.gain, 0.5);
+        // controller reaches it
+        assert!(map.handle(&mut object, &cc(1, 64)));
+        assert!(map.handle(&mut object, &cc(1, 0)));
+        assert_eq!(object.gain, 0.0);
+
+        // value changed elsewhere
+        object.gain = 1.0;
+        assert!(!map.handle(&mut object, &cc(1, 10)));
+
+        map.bind(Some(0), MidiControl::ControlChange(2), 0, MidiMode::RelativeTwos).unwrap();
+        assert!(map.handle(&mut object, &cc(2, 127)));
+        assert!((object.gain - (1.0 - 1.0/127.0)).abs() < 1e-6);
+    }
+}
@@ -1 +1 @@
-
+pub mod midi;